@@ -0,0 +1,80 @@
+// lint detects unexpected conditional compilation conditions
+#![allow(unexpected_cfgs)]
+// lint detects deprecated items
+#![allow(deprecated)]
+use anchor_lang::prelude::*;
+
+declare_id!("5j8eRJm2uocQts8V3mihE3DMiVmaiY2BehuZyLVJM3PM");
+
+// stand-in for a loyalty program in the vault tests; it only logs each credit so the
+// tests can see the CPI made by deposit_and_notify, keeps a flag account that
+// withdraw_if can be made to depend on, and doubles as a withdraw_to validation hook
+#[program]
+pub mod mock_reward {
+    use super::*;
+
+    // the vault calls this with REWARD_CREDIT_DISCRIMINATOR and the credited lamports
+    pub fn credit_points(ctx: Context<CreditPoints>, amount: u64) -> Result<()> {
+        msg!("credited {} points to {}", amount, ctx.accounts.user.key());
+        Ok(())
+    }
+
+    // creates or updates the signer's flag; `set` lives at byte 40 (after the
+    // discriminator and authority)
+    pub fn set_flag(ctx: Context<SetFlag>, set: bool) -> Result<()> {
+        ctx.accounts.flag.authority = ctx.accounts.authority.key();
+        ctx.accounts.flag.set = set;
+        Ok(())
+    }
+
+    // the vault calls this with VALIDATE_TRANSFER_DISCRIMINATOR; approves sends of up to
+    // APPROVAL_LIMIT lamports and rejects anything larger
+    pub fn validate_transfer(_ctx: Context<ValidateTransfer>, amount: u64) -> Result<()> {
+        require!(amount <= APPROVAL_LIMIT, HookError::TransferRejected);
+        Ok(())
+    }
+}
+
+pub const APPROVAL_LIMIT: u64 = 1_000_000_000;
+
+#[derive(Accounts)]
+pub struct CreditPoints<'info> {
+    pub user: Signer<'info>,
+    /// CHECK: only identifies the vault the deposit went to
+    pub vault_state: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetFlag<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = 8 + 32 + 1,
+        seeds = [b"flag", authority.key().as_ref()],
+        bump
+    )]
+    pub flag: Account<'info, Flag>,
+    pub system_program: Program<'info, System>,
+}
+
+#[account]
+pub struct Flag {
+    pub authority: Pubkey,
+    pub set: bool,
+}
+
+#[derive(Accounts)]
+pub struct ValidateTransfer<'info> {
+    /// CHECK: only identifies who the vault is sending to
+    pub recipient: UncheckedAccount<'info>,
+    /// CHECK: only identifies the vault the send comes from
+    pub vault_state: UncheckedAccount<'info>,
+}
+
+#[error_code]
+pub enum HookError {
+    #[msg("Transfer exceeds the hook's approval limit")]
+    TransferRejected,
+}
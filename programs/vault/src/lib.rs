@@ -4,7 +4,27 @@
 #![allow(deprecated)]
 use anchor_lang::{
     prelude::*,
-    system_program::{transfer, Transfer},
+    solana_program::{
+        hash::hashv,
+        incinerator,
+        instruction::{AccountMeta, Instruction},
+        native_token::LAMPORTS_PER_SOL,
+        program::{invoke, invoke_signed},
+        stake::{
+            self, instruction as stake_instruction,
+            state::{Authorized, Lockup, StakeStateV2},
+        },
+        system_instruction::MAX_PERMITTED_DATA_LENGTH,
+        sysvar,
+    },
+    system_program::{self, create_account, transfer, CreateAccount, Transfer},
+};
+use anchor_spl::{
+    associated_token::AssociatedToken,
+    token::{
+        self, spl_token::native_mint, CloseAccount, Mint, SyncNative, Token, TokenAccount,
+        TransferChecked,
+    },
 };
 
 declare_id!("UCrARA7PhDE2jwhXLj8jUUptRRjXZjneUViRFRYCJt1");
@@ -15,7 +35,8 @@ pub mod vault {
     use super::*;
     // Context is used to pass accounts and bumps to the instruction functions
     // if you want to access accounts and bumps, you need to use Context
-    pub fn initialize(ctx: Context<Initialize>) -> Result<()> {
+    // index lets one user own several vaults; each index gets its own state/vault pair
+    pub fn initialize(ctx: Context<Initialize>, index: u8) -> Result<()> {
         // ? is used to handle Result types
         // it will return an error if the operation fails
         // if it succeeds, it will return Ok(())
@@ -23,204 +44,7207 @@ pub mod vault {
         //     Ok(_) => {}
         //     Err(e) => return Err(e),
         // }
-        ctx.accounts.initialize(&ctx.bumps)?;
+        ctx.accounts.initialize(index, &ctx.bumps)?;
         // ctx.bumps is a struct that contains the bump values for the accounts
         Ok(())
     }
 
-    pub fn deposit(ctx: Context<Payment>, amount: u64) -> Result<()> {
+    // initialize, tagging the vault's DepositEvent/WithdrawEvent with `topic`
+    pub fn initialize_with_topic(
+        ctx: Context<Initialize>,
+        index: u8,
+        topic: [u8; 16],
+    ) -> Result<()> {
+        ctx.accounts.initialize(index, &ctx.bumps)?;
+        ctx.accounts.vault_state.topic = topic;
+        Ok(())
+    }
+
+    // like initialize, but rent_payer funds the state, vault and registry rent (e.g. a service
+    // sponsoring account creation) while user becomes the owner
+    pub fn initialize_sponsored(ctx: Context<InitializeSponsored>, index: u8) -> Result<()> {
+        ctx.accounts.initialize_sponsored(index, &ctx.bumps)
+    }
+
+    // like initialize, but seed_version picks how the vault PDA is derived: 0 from the
+    // state address, 1 from [creator, index] so clients can derive it without the state
+    pub fn initialize_with_seed_version(
+        ctx: Context<InitializeWithSeedVersion>,
+        index: u8,
+        seed_version: u8,
+    ) -> Result<()> {
+        ctx.accounts
+            .initialize_with_seed_version(index, seed_version, &ctx.bumps)
+    }
+
+    // opens the vault and makes its first deposit in one transaction
+    // only amount counts as deposited; the rent funding is not a user deposit
+    pub fn initialize_with_deposit(ctx: Context<Initialize>, index: u8, amount: u64) -> Result<()> {
+        ctx.accounts.initialize(index, &ctx.bumps)?;
         ctx.accounts.deposit(amount)
     }
 
+    // returns the accepted amount, which can be lower than amount when rounding is configured
+    pub fn deposit(ctx: Context<Payment>, amount: u64) -> Result<u64> {
+        ctx.accounts.deposit(amount)
+    }
+
+    // deposit that fails unless `amount` is worth at least min_usd (micro-USD) at the
+    // PriceFeed's current SOL price; the feed must be passed and fresh
+    pub fn deposit_min_usd(ctx: Context<Payment>, amount: u64, min_usd: u64) -> Result<u64> {
+        ctx.accounts.deposit_min_usd(amount, min_usd)
+    }
+
+    // deposit, then CPI the configured reward program so it can credit the depositor; with
+    // no reward program configured this is a plain deposit
+    pub fn deposit_and_notify(ctx: Context<Payment>, amount: u64) -> Result<u64> {
+        let credited = ctx.accounts.deposit(amount)?;
+        ctx.accounts.notify_reward(credited)?;
+        Ok(credited)
+    }
+
+    // deposit crediting `referrer`: emits ReferralDeposit and, with referral_fee_bps set,
+    // pays the referrer that share of the credited amount from the reward pool
+    pub fn deposit_with_referral(
+        ctx: Context<Payment>,
+        amount: u64,
+        referrer: Pubkey,
+    ) -> Result<u64> {
+        ctx.accounts.deposit_with_referral(amount, referrer)
+    }
+
+    // what deposit(amount) would credit to the vault and charge as deposit fee; never mutates
+    pub fn quote_deposit(ctx: Context<VaultView>, amount: u64) -> Result<DepositQuote> {
+        ctx.accounts.quote_deposit(amount)
+    }
+
+    // like deposit, but accepts only up to deposit_cap instead of failing; returns the accepted amount
+    pub fn deposit_capped(ctx: Context<Payment>, amount: u64) -> Result<u64> {
+        ctx.accounts.deposit_capped(amount)
+    }
+
+    // deposits bps/10_000 of the user's balance above DEPOSIT_FEE_RESERVE; returns the accepted amount
+    pub fn deposit_percent_of_balance(ctx: Context<Payment>, bps: u16) -> Result<u64> {
+        ctx.accounts.deposit_percent_of_balance(bps)
+    }
+
+    // third-party deposit for vaults with a deposit_merkle_root: proof must lead from the
+    // depositor's leaf to the root
+    pub fn deposit_with_proof(
+        ctx: Context<DepositWithProof>,
+        amount: u64,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        ctx.accounts.deposit_with_proof(amount, &proof)
+    }
+
+    // anyone can deposit into a vault this way and gets a Receipt PDA as proof-of-deposit
+    pub fn deposit_with_receipt(
+        ctx: Context<DepositWithReceipt>,
+        receipt_id: u64,
+        amount: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .deposit_with_receipt(receipt_id, amount, &ctx.bumps)
+    }
+
+    // the depositor reclaims the receipt rent once they no longer need the proof
+    pub fn close_receipt(ctx: Context<CloseReceipt>) -> Result<()> {
+        ctx.accounts.close_receipt()
+    }
+
+    // share of a referred deposit paid to the referrer from the reward pool (0 = event only)
+    pub fn set_referral_fee(ctx: Context<Configure>, referral_fee_bps: u16) -> Result<()> {
+        ctx.accounts.set_referral_fee(referral_fee_bps)
+    }
+
+    // every deposit earns bonus_bps of itself from the owner-funded reward pool, up to
+    // bonus_cap in total and as long as the pool has lamports left (bps 0 = off)
+    pub fn set_deposit_bonus(
+        ctx: Context<Configure>,
+        bonus_bps: u16,
+        bonus_cap: u64,
+    ) -> Result<()> {
+        ctx.accounts.set_deposit_bonus(bonus_bps, bonus_cap)
+    }
+
+    // escrow mode: a receipted deposit still unclaimed `seconds` after it was made can be sent
+    // back to its depositor by anyone through trigger_refund (0 = off)
+    pub fn set_auto_refund(ctx: Context<Configure>, seconds: i64) -> Result<()> {
+        ctx.accounts.set_auto_refund(seconds)
+    }
+
+    // permissionless: refunds an expired receipt's deposit (or what is left of it once the
+    // owner's withdrawals ran past their own lamports) to the depositor and closes the receipt
+    pub fn trigger_refund(ctx: Context<TriggerRefund>) -> Result<()> {
+        ctx.accounts.trigger_refund()
+    }
+
+    // lets `delegate` deposit its own lamports into this vault, up to max_amount in total;
+    // approving again replaces the remaining allowance
+    pub fn approve_depositor(
+        ctx: Context<ApproveDepositor>,
+        delegate: Pubkey,
+        max_amount: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .approve_depositor(delegate, max_amount, &ctx.bumps)
+    }
+
+    pub fn deposit_delegated(ctx: Context<DepositDelegated>, amount: u64) -> Result<()> {
+        ctx.accounts.deposit_delegated(amount)
+    }
+
+    // withdrawals above approval_above then need `threshold` of `signers` to approve first
+    pub fn configure_multisig(
+        ctx: Context<ConfigureMultisig>,
+        signers: Vec<Pubkey>,
+        threshold: u8,
+        approval_above: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .configure_multisig(signers, threshold, approval_above, &ctx.bumps)
+    }
+
+    // one multisig signer approves withdrawing `amount`; the pending approval is reset
+    // once it is used or the signer set changes
+    pub fn approve_withdrawal(ctx: Context<ApproveWithdrawal>, amount: u64) -> Result<()> {
+        ctx.accounts.approve_withdrawal(amount, &ctx.bumps)
+    }
+
+    // starts recording deposit/withdraw amounts in a ring buffer of the last LOG_CAPACITY entries
+    pub fn init_activity_log(ctx: Context<InitActivityLog>) -> Result<()> {
+        ctx.accounts.init_activity_log(&ctx.bumps)
+    }
+
+    // number of buffered entries and their net lamport change
+    pub fn log_summary(ctx: Context<LogView>) -> Result<LogSummary> {
+        Ok(ctx.accounts.activity_log.summary())
+    }
+
     pub fn withdraw(ctx: Context<Payment>, amount: u64) -> Result<()> {
+        ctx.accounts.vault_state.check_no_release_condition()?;
+        ctx.accounts.withdraw(amount)
+    }
+
+    // withdraw, allowed only while condition_account satisfies the vault's release_condition
+    pub fn withdraw_if(ctx: Context<Payment>, amount: u64) -> Result<()> {
+        ctx.accounts.check_release_condition()?;
         ctx.accounts.withdraw(amount)
     }
 
+    // (None clears) ties withdraw to another program's account: withdraw_if pays out only
+    // while that account, owned by `owner`, holds `value` at byte `offset`
+    pub fn set_release_condition(
+        ctx: Context<Configure>,
+        condition: Option<ReleaseCondition>,
+    ) -> Result<()> {
+        ctx.accounts.set_release_condition(condition)
+    }
+
     pub fn close(ctx: Context<Close>) -> Result<()> {
         ctx.accounts.close()
     }
-}
 
-// trait is used to define common functionality for structs
-// derive is import macro that automatically implements the trait for the struct
-// this example uses Accounts trait to define the accounts that are used in the instruction
-#[derive(Accounts)]
-// info is a lifetime parameter that is used to define the lifetime of the accounts
-pub struct Initialize<'info> {
-    // Signer is used to define the account that is signing the transaction
-    // mut is used to define that the account is mutable
-    // this means that the account can be modified by the instruction
-    // if you use mut, you need use existing account
-    #[account(mut)]
-    pub user: Signer<'info>,
-    // if you want to use an non-existing account, you need to use init and create a new account
-    #[account(
-        init,
-        payer = user,
-        space = VaultState::INIT_SPACE,
-        // Checks that given account is a PDA derived from the currently executing program, the seeds, and if provided, the bump seed.
-        seeds = [b"state", user.key().as_ref()],
-        bump
-    )]
-    pub vault_state: Account<'info, VaultState>, // PDA
-    #[account(
-        mut,
-        seeds = [b"vault", vault_state.key().as_ref()],
-        bump
-    )]
-    // Type validating that the account is of type SystemAccount, which is a wrapper around the system program account.
-    // You can use SystemAccount<'info> in your instruction context, especially for accounts like program-derived addresses (PDAs) intended to hold SOL. For example, to create a PDA with no data, you can use:
-    pub vault: SystemAccount<'info>,
+    // only for a vault flagged at_rent_floor: amount > 0 tops it up, amount == 0 closes it
+    pub fn top_up_or_close(ctx: Context<TopUpOrClose>, amount: u64) -> Result<()> {
+        ctx.accounts.top_up_or_close(amount)
+    }
 
-    pub system_program: Program<'info, System>,
-}
+    // like close, but `keep` lamports go to residual_recipient instead of the owner
+    pub fn close_partial(ctx: Context<ClosePartial>, keep: u64) -> Result<()> {
+        ctx.accounts.close_partial(keep)
+    }
 
-// https://www.anchor-lang.com/docs/references/account-types
-// https://www.anchor-lang.com/docs/references/account-constraints
+    // anyone may close a vault that has been idle for keeper_after_seconds; the caller
+    // earns keeper_bounty_bps of the balance and the owner gets the rest plus the state rent
+    pub fn keeper_close(ctx: Context<KeeperClose>) -> Result<()> {
+        ctx.accounts.keeper_close()
+    }
 
-// // without lifetime annotations, the compiler cannot determine how long the reference is valid
-// struct Bad {
-//     data: &str,  // compiler cannot determine the lifetime. This will cause an error
-// }
+    // opts the vault into keeper_close (inactivity_seconds == 0 opts out)
+    pub fn set_keeper_close(
+        ctx: Context<Configure>,
+        inactivity_seconds: i64,
+        bounty_bps: u16,
+    ) -> Result<()> {
+        ctx.accounts
+            .set_keeper_close(inactivity_seconds, bounty_bps)
+    }
 
-// // with lifetime annotations, the compiler can determine how long the reference is valid
-// struct Good<'a> {
-//     data: &'a str,  // 'a is a lifetime parameter
-// }
+    // moves `amount` of idle vault SOL into the vault's stake account and delegates it to
+    // `vote_account`; the vault PDA is both staker and withdrawer
+    pub fn delegate_stake(ctx: Context<DelegateStake>, amount: u64) -> Result<()> {
+        ctx.accounts.delegate_stake(amount, &ctx.bumps)
+    }
 
-impl<'info> Initialize<'info> {
-    pub fn initialize(&mut self, bumps: &InitializeBumps) -> Result<()> {
-        // Ensure the vault account is rent-exempt
-        // Rent is a system that ensures that accounts have enough SOL to be kept alive
-        // calculate the minimum balance required for the vault account
-        let rent_exempt = Rent::get()?.minimum_balance(self.vault.to_account_info().data_len());
-        //
-        let cpi_program = self.system_program.to_account_info();
-        // Transfer is a CPI (Cross-Program Invocation) that allows transferring SOL from one account to another
-        // Transfer struct
-        let cpi_accounts = Transfer {
-            from: self.user.to_account_info(),
-            to: self.vault.to_account_info(),
-        };
+    pub fn deactivate_stake(ctx: Context<StakeAction>) -> Result<()> {
+        ctx.accounts.deactivate_stake()
+    }
 
-        // CpiContext is used to create a context for the CPI
-        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+    // returns everything in the stake account to the vault once it is fully deactivated
+    pub fn withdraw_stake(ctx: Context<StakeAction>) -> Result<()> {
+        ctx.accounts.withdraw_stake()
+    }
 
-        transfer(cpi_ctx, rent_exempt)?;
+    // read-only: annualized stake yield in bps; while staked it comes from the rewards
+    // accrued in stake_account so far, afterwards from the last completed stake
+    pub fn estimated_apy(ctx: Context<EstimatedApy>) -> Result<u64> {
+        ctx.accounts.estimated_apy()
+    }
 
-        self.vault_state.vault_bump = bumps.vault;
-        self.vault_state.state_bump = bumps.vault_state;
-        Ok(())
+    // like close, but the vault's lamports are wrapped into the user's WSOL ATA; the state
+    // rent still comes back as native SOL
+    pub fn close_to_wsol(ctx: Context<CloseToWsol>) -> Result<()> {
+        ctx.accounts.close_to_wsol()
     }
-}
 
-#[derive(Accounts)]
-pub struct Payment<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
-    #[account(
-        seeds = [b"state", user.key().as_ref()],
-        bump = vault_state.state_bump,
-    )]
-    pub vault_state: Account<'info, VaultState>,
-    #[account(
-        mut,
-        seeds = [b"vault", vault_state.key().as_ref()],
-        bump = vault_state.vault_bump,
-    )]
-    pub vault: SystemAccount<'info>,
-    pub system_program: Program<'info, System>,
-}
+    // close for a vault with a token vault: tokens go to the user's ATA, then the token
+    // account, the lamport vault and the state are all closed to the user
+    pub fn close_mixed(ctx: Context<CloseMixed>) -> Result<()> {
+        ctx.accounts.close_mixed()
+    }
 
-impl<'info> Payment<'info> {
-    pub fn deposit(&mut self, amount: u64) -> Result<()> {
-        let cpi_program = self.system_program.to_account_info();
-        let cpi_account = Transfer {
-            from: self.user.to_account_info(),
-            to: self.vault.to_account_info(),
-        };
-        let cpi_ctx = CpiContext::new(cpi_program, cpi_account);
-        transfer(cpi_ctx, amount)?;
-        Ok(())
+    // stores a code that close_confirmed must repeat; while armed, plain close is refused
+    pub fn arm_close(ctx: Context<Configure>, code: [u8; 4]) -> Result<()> {
+        ctx.accounts.arm_close(code)
     }
 
-    pub fn withdraw(&mut self, amount: u64) -> Result<()> {
-        let cpi_program = self.system_program.to_account_info();
-        let cpi_account = Transfer {
-            from: self.vault.to_account_info(),
-            to: self.user.to_account_info(),
-        };
-        // PDA signing is required for the transfer
-        // because the vault account is a PDA (Program Derived Address)
-        // and it needs to be signed by the PDA's seeds
-        // to ensure that the transfer is valid
-        // this is done by using CpiContext::new_with_signer
-        // which allows you to specify the seeds that will be used to sign the transaction
+    pub fn close_confirmed(ctx: Context<Close>, confirm: [u8; 4]) -> Result<()> {
+        ctx.accounts.close_confirmed(confirm)
+    }
 
-        // & is used to create a reference to the seeds
-        let seeds = &[
-            b"vault",
-            self.vault_state.to_account_info().key.as_ref(),
-            &[self.vault_state.vault_bump],
-        ];
+    // first half of a two-step ownership transfer, completed by accept_owner (None cancels);
+    // only one pending operation, an armed close or a proposed owner, may exist at a time
+    pub fn propose_owner(ctx: Context<Configure>, new_owner: Option<Pubkey>) -> Result<()> {
+        ctx.accounts.propose_owner(new_owner)
+    }
 
-        // Why use a reference?
-        // Because the seeds are used to derive the PDA, and we need to pass a reference
-        // let seeds = [b"vault", ...];  // move ownership of the array
-        // // create a reference to the array
-        // let seeds = &[b"vault", ...]; // borrow the array
+    pub fn accept_owner(ctx: Context<AcceptOwner>) -> Result<()> {
+        ctx.accounts.accept_owner()
+    }
 
-        let signer_seeds = &[&seeds[..]];
-        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_account, signer_seeds);
-        transfer(cpi_ctx, amount)?;
-        Ok(())
+    // second half of a two-step teardown: the vault must already be emptied by withdraw
+    pub fn close_state_only(ctx: Context<CloseStateOnly>) -> Result<()> {
+        ctx.accounts.close_state_only()
     }
-}
 
-#[derive(Accounts)]
-pub struct Close<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
-    #[account(
-        mut,
-        seeds = [b"vault", vault_state.key().as_ref()],
-        bump = vault_state.vault_bump,)]
-    pub vault: SystemAccount<'info>,
-    #[account(
-        mut,
-        seeds = [b"state", user.key().as_ref()],
-        bump = vault_state.state_bump,
-        close = user,
-    )]
-    pub vault_state: Account<'info, VaultState>,
-    pub system_program: Program<'info, System>,
-}
+    // finishes a close that stopped after the vault was drained: an empty vault whose state
+    // account is still live has its state closed and its registry bit cleared, if any
+    pub fn reconcile(ctx: Context<Reconcile>) -> Result<()> {
+        ctx.accounts.reconcile()
+    }
 
-impl<'info> Close<'info> {
-    pub fn close(&mut self) -> Result<()> {
-        let cpi_program = self.system_program.to_account_info();
-        let cpi_account = Transfer {
-            from: self.vault.to_account_info(),
-            to: self.user.to_account_info(),
-        };
-        let pda_signing_seeds = [
-            b"vault",
-            self.vault_state.to_account_info().key.as_ref(),
-            &[self.vault_state.vault_bump],
-        ];
-        let seeds = &[&pda_signing_seeds[..]];
-        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_account, seeds);
-        transfer(cpi_ctx, self.vault.lamports())?;
-        Ok(())
+    pub fn refresh_bumps(ctx: Context<RefreshBumps>) -> Result<()> {
+        ctx.accounts.refresh_bumps()
     }
-}
 
-#[account]
-pub struct VaultState {
-    pub vault_bump: u8,
-    pub state_bump: u8,
-}
+    // the owner allows a recipient for ttl_seconds; after that withdraw_to ignores the entry
+    pub fn allow_recipient(
+        ctx: Context<AllowRecipient>,
+        recipient: Pubkey,
+        ttl_seconds: i64,
+    ) -> Result<()> {
+        ctx.accounts
+            .allow_recipient(recipient, ttl_seconds, &ctx.bumps)
+    }
 
-impl Space for VaultState {
-    const INIT_SPACE: usize = 8 + 1 * 2; // 8 bytes for discriminator + 1 byte for vault_bump + 1 byte for state_bump
+    pub fn remove_recipient(ctx: Context<RemoveRecipient>) -> Result<()> {
+        ctx.accounts.remove_recipient()
+    }
+
+    // limits third-party deposits to the depositors in a Merkle tree (None = open); see
+    // deposit_with_proof
+    pub fn set_deposit_merkle_root(ctx: Context<Configure>, root: Option<[u8; 32]>) -> Result<()> {
+        ctx.accounts.set_deposit_merkle_root(root)
+    }
+
+    // restricts withdraw_to, withdraw_to_many and guardian_withdraw to the owner's own address
+    pub fn set_self_custody_only(ctx: Context<Configure>, self_custody_only: bool) -> Result<()> {
+        ctx.accounts.set_self_custody_only(self_custody_only)
+    }
+
+    // hands withdraw and pause rights to `guardian` until expires_at (None revokes)
+    pub fn set_guardian(
+        ctx: Context<Configure>,
+        guardian: Option<Pubkey>,
+        expires_at: i64,
+    ) -> Result<()> {
+        ctx.accounts.set_guardian(guardian, expires_at)
+    }
+
+    // guardian withdrawal to any recipient, under the same locks as withdraw_to
+    pub fn guardian_withdraw(ctx: Context<GuardianWithdraw>, amount: u64) -> Result<()> {
+        require!(
+            ctx.accounts
+                .vault_state
+                .reserved
+                .validation_hook()?
+                .is_none(),
+            VaultError::ValidationHookRequired
+        );
+        ctx.accounts.guardian_withdraw(amount)
+    }
+
+    pub fn guardian_set_paused(ctx: Context<GuardianPause>, paused: bool) -> Result<()> {
+        ctx.accounts.guardian_set_paused(paused)
+    }
+
+    // the recipient must be on the inline allowlist or have an active allowlist entry
+    pub fn withdraw_to(ctx: Context<WithdrawTo>, amount: u64) -> Result<()> {
+        ctx.accounts.withdraw_to(amount)
+    }
+
+    // withdraw_to a PDA of program_id, e.g. another program's escrow; seeds (bump last) must
+    // derive the recipient, and the recipient still has to be allowlisted
+    pub fn withdraw_to_pda(
+        ctx: Context<WithdrawTo>,
+        amount: u64,
+        program_id: Pubkey,
+        seeds: Vec<Vec<u8>>,
+    ) -> Result<()> {
+        ctx.accounts.withdraw_to_pda(amount, program_id, &seeds)
+    }
+
+    // up to MAX_INLINE_RECIPIENTS recipients withdraw_to accepts without an allowlist entry;
+    // replaces the previous list
+    pub fn set_inline_allowlist(ctx: Context<Configure>, recipients: Vec<Pubkey>) -> Result<()> {
+        ctx.accounts.set_inline_allowlist(recipients)
+    }
+
+    // batch variants take their per-item accounts from remaining_accounts,
+    // capped at MAX_BATCH_ACCOUNTS so a batch can't run out of compute halfway
+    // both return the indices of the items BatchMode::BestEffort skipped
+    pub fn deposit_many<'info>(
+        ctx: Context<'_, '_, 'info, 'info, DepositMany<'info>>,
+        amounts: Vec<u64>,
+        mode: BatchMode,
+    ) -> Result<Vec<u8>> {
+        ctx.accounts
+            .deposit_many(amounts, mode, ctx.remaining_accounts)
+    }
+
+    pub fn withdraw_to_many<'info>(
+        ctx: Context<'_, '_, 'info, 'info, WithdrawToMany<'info>>,
+        amounts: Vec<u64>,
+        mode: BatchMode,
+    ) -> Result<Vec<u8>> {
+        require!(
+            ctx.accounts
+                .vault_state
+                .reserved
+                .validation_hook()?
+                .is_none(),
+            VaultError::ValidationHookRequired
+        );
+        ctx.accounts
+            .withdraw_to_many(amounts, mode, ctx.remaining_accounts)
+    }
+
+    // the reward pool is a lamport-only PDA next to the vault that the owner tops up
+    pub fn fund_reward_pool(ctx: Context<FundRewardPool>, amount: u64) -> Result<()> {
+        ctx.accounts.fund_reward_pool(amount)
+    }
+
+    // migration from the pre-PDA release: closes the owner's LegacyVault into this vault
+    // and counts its lamports as a deposit
+    pub fn import_legacy(ctx: Context<ImportLegacy>) -> Result<()> {
+        ctx.accounts.import_legacy()
+    }
+
+    // escrows `total` lamports from source in a Stream that releases rate_per_second into the vault
+    pub fn open_stream(ctx: Context<OpenStream>, rate_per_second: u64, total: u64) -> Result<()> {
+        ctx.accounts.open_stream(rate_per_second, total, &ctx.bumps)
+    }
+
+    // moves whatever the stream has accrued since the last claim into the vault; callable by anyone
+    pub fn claim_stream(ctx: Context<ClaimStream>) -> Result<u64> {
+        ctx.accounts.claim_stream()
+    }
+
+    // anyone can send lamports to the vault; they are tallied in donated_total, not total_deposited
+    pub fn donate(ctx: Context<Donate>, amount: u64) -> Result<()> {
+        ctx.accounts.donate(amount)
+    }
+
+    // sweep_donations needs donated_total above this (0 allows any non-zero amount)
+    pub fn set_donation_threshold(ctx: Context<Configure>, threshold: u64) -> Result<()> {
+        ctx.accounts.set_donation_threshold(threshold)
+    }
+
+    // folds donated_total into total_deposited
+    pub fn sweep_donations(ctx: Context<Configure>) -> Result<()> {
+        ctx.accounts.sweep_donations()
+    }
+
+    pub fn issue_coupon(ctx: Context<IssueCoupon>, coupon_id: u64, amount: u64) -> Result<()> {
+        ctx.accounts.issue_coupon(coupon_id, amount, &ctx.bumps)
+    }
+
+    pub fn redeem_coupon(ctx: Context<RedeemCoupon>) -> Result<()> {
+        ctx.accounts.redeem_coupon(&ctx.bumps)
+    }
+
+    // the user receives exactly desired_net while the fee payer (e.g. a relayer) is
+    // reimbursed fee_estimate for the network fee it paid on the user's behalf
+    pub fn withdraw_net(
+        ctx: Context<WithdrawNet>,
+        desired_net: u64,
+        fee_estimate: u64,
+    ) -> Result<()> {
+        ctx.accounts.withdraw_net(desired_net, fee_estimate)
+    }
+
+    // permissionless: anyone (typically another program via CPI) can refresh the snapshot
+    pub fn snapshot_balance(ctx: Context<SnapshotBalance>) -> Result<()> {
+        ctx.accounts.snapshot_balance(&ctx.bumps)
+    }
+
+    // penalty_bps of amount is burned through the incinerator, the rest goes to the user
+    pub fn withdraw_with_penalty(
+        ctx: Context<WithdrawWithPenalty>,
+        amount: u64,
+        penalty_bps: u16,
+    ) -> Result<()> {
+        ctx.accounts.withdraw_with_penalty(amount, penalty_bps)
+    }
+
+    // creates vaults 0..count for the signer in one transaction
+    // remaining_accounts must hold the (vault_state, vault) pair for each index, in index order
+    pub fn initialize_many<'info>(
+        ctx: Context<'_, '_, 'info, 'info, InitializeMany<'info>>,
+        count: u8,
+    ) -> Result<()> {
+        ctx.accounts
+            .initialize_many(count, ctx.remaining_accounts, &ctx.bumps)
+    }
+
+    // read-only: indices of the user's open vaults, so clients don't have to scan PDAs;
+    // archived vaults are left out
+    pub fn list_vaults(ctx: Context<ListVaults>) -> Result<Vec<u8>> {
+        Ok(ctx.accounts.registry.indices())
+    }
+
+    // read-only: the lamport balance of each vault, in order; remaining_accounts holds a
+    // (vault_state, vault) pair per vault so every vault can be checked against its state
+    pub fn multi_balance<'info>(
+        ctx: Context<'_, '_, 'info, 'info, MultiBalance>,
+    ) -> Result<Vec<u64>> {
+        ctx.accounts.multi_balance(ctx.remaining_accounts)
+    }
+
+    // read-only: indices of the user's archived vaults
+    pub fn list_archived_vaults(ctx: Context<ListVaults>) -> Result<Vec<u8>> {
+        Ok(ctx.accounts.registry.archived_indices())
+    }
+
+    // hides a dormant vault from list_vaults; it keeps its funds and PDA, rejects deposits
+    // and still allows withdrawals
+    pub fn archive(ctx: Context<Archive>) -> Result<()> {
+        ctx.accounts.set_archived(true)
+    }
+
+    pub fn unarchive(ctx: Context<Archive>) -> Result<()> {
+        ctx.accounts.set_archived(false)
+    }
+
+    // shrinks a vault_state padded past the current layout (e.g. after deprecated fields are
+    // removed) and refunds the freed rent to the owner
+    pub fn migrate_state(ctx: Context<MigrateState>) -> Result<()> {
+        ctx.accounts.migrate_state()
+    }
+
+    // copies the vault's state to the v2 PDA scheme, moves every lamport across and retires
+    // the old pair; the retired vault_state rejects anything that moves funds or changes terms
+    pub fn migrate_to_v2(ctx: Context<MigrateToV2>) -> Result<()> {
+        ctx.accounts.migrate_to_v2(&ctx.bumps)
+    }
+
+    // moves amount from an existing vault into a freshly created vault at new_index
+    pub fn split_vault(ctx: Context<SplitVault>, new_index: u8, amount: u64) -> Result<()> {
+        ctx.accounts.split_vault(new_index, amount, &ctx.bumps)
+    }
+
+    // creates the vault's token account for one mint, owned by the vault PDA
+    pub fn init_token_vault(ctx: Context<InitTokenVault>) -> Result<()> {
+        ctx.accounts.init_token_vault(&ctx.bumps)
+    }
+
+    // init_token_vault for a SOL vault holding nothing above rent, so it takes token deposits
+    // from then on; the state PDA, created_at and history are kept
+    pub fn convert_to_token_vault(ctx: Context<InitTokenVault>) -> Result<()> {
+        ctx.accounts.convert_to_token_vault(&ctx.bumps)
+    }
+
+    // closes the empty token account so init_token_vault can set up a different mint
+    pub fn close_token_vault(ctx: Context<CloseTokenVault>) -> Result<()> {
+        ctx.accounts.close_token_vault()
+    }
+
+    // irreversible: the vault keeps its current mint forever, so close_token_vault (the only
+    // way to switch assets) is refused from then on
+    pub fn lock_mint(ctx: Context<Configure>) -> Result<()> {
+        ctx.accounts.lock_mint()
+    }
+
+    // token vault limits in whole tokens; stored as raw amounts using the mint's decimals
+    // (0 disables either limit)
+    pub fn set_token_limits(
+        ctx: Context<SetTokenLimits>,
+        cap_tokens: u64,
+        min_deposit_tokens: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .set_token_limits(cap_tokens, min_deposit_tokens)
+    }
+
+    // moves everything in a swap's output account into the token vault, so it can be the
+    // last instruction of a transaction that swaps into the vault's mint
+    pub fn deposit_from_swap(ctx: Context<DepositFromSwap>) -> Result<u64> {
+        ctx.accounts.deposit_from_swap()
+    }
+
+    // rent-exempt minimum for an account of data_len bytes, for clients planning account creation
+    pub fn rent_for(_ctx: Context<NoAccounts>, data_len: u64) -> Result<u64> {
+        require!(
+            data_len <= MAX_PERMITTED_DATA_LENGTH,
+            VaultError::InvalidDataLength
+        );
+        Ok(Rent::get()?.minimum_balance(data_len as usize))
+    }
+
+    // the ID this build was declared with, so clients can confirm which deployment they reach
+    pub fn program_id(_ctx: Context<NoAccounts>) -> Result<Pubkey> {
+        Ok(crate::ID)
+    }
+
+    // summary of the vault's operational state for monitoring; never mutates
+    pub fn health(ctx: Context<VaultView>) -> Result<VaultHealth> {
+        ctx.accounts.health()
+    }
+
+    pub fn set_min_deposit(ctx: Context<Configure>, min_deposit: u64) -> Result<()> {
+        ctx.accounts.set_min_deposit(min_deposit)
+    }
+
+    // every withdrawal rejects amounts below min_withdraw (0 = no minimum); batches check
+    // each item
+    pub fn set_min_withdraw(ctx: Context<Configure>, min_withdraw: u64) -> Result<()> {
+        ctx.accounts.set_min_withdraw(min_withdraw)
+    }
+
+    // a paused vault rejects every instruction that moves lamports in or out of it
+    pub fn set_paused(ctx: Context<Configure>, paused: bool) -> Result<()> {
+        ctx.accounts.set_paused(paused)
+    }
+
+    // withdraw and withdraw_to reject amounts above `multiple` times the moving average
+    // of recent withdrawals (0 disables)
+    pub fn set_withdrawal_spike_multiple(ctx: Context<Configure>, multiple: u16) -> Result<()> {
+        ctx.accounts.set_withdrawal_spike_multiple(multiple)
+    }
+
+    // pauses only sends to third parties (withdraw_to and friends); the owner's own
+    // withdrawals keep working
+    pub fn set_external_withdraw_paused(ctx: Context<Configure>, paused: bool) -> Result<()> {
+        ctx.accounts.set_external_withdraw_paused(paused)
+    }
+
+    // program withdraw_to asks to approve each send (validate_transfer with the recipient and
+    // amount); None removes it. The batch and guardian sends are refused while one is set
+    pub fn set_validation_hook(ctx: Context<Configure>, hook: Option<Pubkey>) -> Result<()> {
+        ctx.accounts.set_validation_hook(hook)
+    }
+
+    // lets the owner keep withdrawing to themselves while the vault is paused
+    pub fn set_owner_bypass_pause(ctx: Context<Configure>, owner_bypass_pause: bool) -> Result<()> {
+        ctx.accounts.set_owner_bypass_pause(owner_bypass_pause)
+    }
+
+    // accept deposits while paused and hold them in pending_deposit until unpause
+    pub fn set_queue_on_pause(ctx: Context<Configure>, queue_on_pause: bool) -> Result<()> {
+        ctx.accounts.set_queue_on_pause(queue_on_pause)
+    }
+
+    // starts the accounting over: total_deposited, withdrawal_count and the TWAB restart
+    // from now; the balance is untouched
+    pub fn reset_stats(ctx: Context<Configure>) -> Result<()> {
+        ctx.accounts.reset_stats()
+    }
+
+    // permanently blocks every setter that changes the vault's terms (limits, fees, locks, label)
+    pub fn freeze_config(ctx: Context<Configure>) -> Result<()> {
+        ctx.accounts.freeze_config()
+    }
+
+    // hands the vault to new_owner and locks withdrawals for lock_seconds from now
+    pub fn rotate_and_lock(
+        ctx: Context<Configure>,
+        new_owner: Pubkey,
+        lock_seconds: i64,
+    ) -> Result<()> {
+        ctx.accounts.rotate_and_lock(new_owner, lock_seconds)
+    }
+
+    // display currency for the vault's balances, uppercase ASCII zero padded to 8 bytes
+    pub fn set_symbol(ctx: Context<Configure>, symbol: [u8; 8]) -> Result<()> {
+        ctx.accounts.set_symbol(symbol)
+    }
+
+    // display name for wallets and indexers; not used by any program logic
+    pub fn set_label(ctx: Context<Configure>, label: [u8; 32]) -> Result<()> {
+        ctx.accounts.set_label(label)
+    }
+
+    // Option<Pubkey> lets the owner both link (Some) and unlink (None) with one instruction
+    pub fn set_forward_to(ctx: Context<Configure>, forward_to: Option<Pubkey>) -> Result<()> {
+        ctx.accounts.set_forward_to(forward_to)
+    }
+
+    // high-frequency vaults can turn off per-operation events to save compute
+    pub fn set_verbose_events(ctx: Context<Configure>, verbose_events: bool) -> Result<()> {
+        ctx.accounts.set_verbose_events(verbose_events)
+    }
+
+    // unlock_at can only move forward, so a lock cannot be undone by the owner
+    pub fn set_unlock_at(ctx: Context<Configure>, unlock_at: i64) -> Result<()> {
+        ctx.accounts.set_unlock_at(unlock_at)
+    }
+
+    // each deposit pushes unlock_at out by extension_seconds, but never past
+    // max_lock_seconds from the time of the deposit
+    pub fn set_deposit_extends_lock(
+        ctx: Context<Configure>,
+        enabled: bool,
+        extension_seconds: i64,
+        max_lock_seconds: i64,
+    ) -> Result<()> {
+        ctx.accounts
+            .set_deposit_extends_lock(enabled, extension_seconds, max_lock_seconds)
+    }
+
+    // like unlock_at, unlock_slot can only move forward
+    pub fn set_unlock_slot(ctx: Context<Configure>, unlock_slot: u64) -> Result<()> {
+        ctx.accounts.set_unlock_slot(unlock_slot)
+    }
+
+    // selects whether unlock_at (timestamp) or unlock_slot governs the lock
+    pub fn set_lock_mode(ctx: Context<Configure>, lock_mode: LockMode) -> Result<()> {
+        ctx.accounts.set_lock_mode(lock_mode)
+    }
+
+    pub fn set_allow_early_withdraw(
+        ctx: Context<Configure>,
+        allow_early_withdraw: bool,
+    ) -> Result<()> {
+        ctx.accounts.set_allow_early_withdraw(allow_early_withdraw)
+    }
+
+    // points deposit/withdraw notifications at a watcher; every such call then bumps the
+    // sequence in the vault's NotifyMarker PDA, which the watcher can observe
+    pub fn set_notify(ctx: Context<SetNotify>, notify: Option<Pubkey>) -> Result<()> {
+        ctx.accounts.set_notify(notify, &ctx.bumps)
+    }
+
+    pub fn set_deposit_fee(ctx: Context<SetFee>, deposit_fee_bps: u16) -> Result<()> {
+        ctx.accounts.set_deposit_fee(deposit_fee_bps)
+    }
+
+    // designates the fee treasury and the penalty charged on early withdrawals
+    pub fn set_fee(ctx: Context<SetFee>, early_penalty_bps: u16) -> Result<()> {
+        ctx.accounts.set_fee(early_penalty_bps)
+    }
+
+    // linearly releases vesting_amount between vesting_start and vesting_start + vesting_duration
+    // the schedule can only be set once
+    pub fn set_vesting(
+        ctx: Context<Configure>,
+        vesting_start: i64,
+        vesting_duration: i64,
+        vesting_amount: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .set_vesting(vesting_start, vesting_duration, vesting_amount)
+    }
+
+    // read-only: what could be withdrawn right now under the vesting schedule
+    pub fn vested_available(ctx: Context<VaultView>) -> Result<u64> {
+        ctx.accounts.vested_available()
+    }
+
+    // read-only: every boolean setting and derived state packed into one FLAG_* bitmask
+    pub fn flags(ctx: Context<VaultView>) -> Result<u32> {
+        ctx.accounts.flags()
+    }
+
+    // read-only: the vault's whole policy (caps, fees, limits, flags, timestamps) in one call
+    pub fn get_config(ctx: Context<VaultView>) -> Result<VaultConfig> {
+        ctx.accounts.get_config()
+    }
+
+    // read-only: the vault balance against its savings goal
+    pub fn get_progress(ctx: Context<VaultView>) -> Result<GoalProgress> {
+        ctx.accounts.get_progress()
+    }
+
+    // read-only: the vault's time-weighted average balance since initialize
+    pub fn get_twab(ctx: Context<VaultView>) -> Result<u64> {
+        ctx.accounts.get_twab()
+    }
+
+    // read-only: the largest amount withdraw would accept right now (0 if it would fail);
+    // a vault with a release condition reports 0, as only withdraw_if can pay it out
+    pub fn withdrawable(ctx: Context<VaultView>) -> Result<u64> {
+        ctx.accounts.withdrawable()
+    }
+
+    // read-only: whether the signer may perform an ACTION_* right now, given its role (owner,
+    // guardian, or program authority) and the vault's current flags; meant for enabling UI
+    pub fn can(ctx: Context<Can>, action: u8) -> Result<bool> {
+        ctx.accounts.can(action)
+    }
+
+    // read-only: what the daily withdraw limit still allows in the current window
+    // (u64::MAX when there is no limit)
+    pub fn remaining_daily(ctx: Context<VaultView>) -> Result<u64> {
+        Ok(ctx
+            .accounts
+            .vault_state
+            .remaining_daily(Clock::get()?.unix_timestamp))
+    }
+
+    // deposits are rounded down to a multiple of round_deposits_to (0 disables)
+    pub fn set_round_deposits_to(ctx: Context<Configure>, round_deposits_to: u64) -> Result<()> {
+        ctx.accounts.set_round_deposits_to(round_deposits_to)
+    }
+
+    // one-time setup of the program-wide config; only the upgrade authority can call it
+    pub fn initialize_config(
+        ctx: Context<InitializeConfig>,
+        program_authority: Pubkey,
+    ) -> Result<()> {
+        ctx.accounts
+            .initialize_config(program_authority, &ctx.bumps)
+    }
+
+    // opt-in: lets the program authority return this vault's funds to the owner in an emergency
+    pub fn set_allow_authority_recovery(ctx: Context<Configure>, allow: bool) -> Result<()> {
+        ctx.accounts.set_allow_authority_recovery(allow)
+    }
+
+    pub fn authority_recover(ctx: Context<AuthorityRecover>) -> Result<()> {
+        ctx.accounts.authority_recover()
+    }
+
+    // the program authority publishes the SOL price used by deposit_min_usd
+    pub fn set_sol_price(ctx: Context<SetSolPrice>, micro_usd_per_sol: u64) -> Result<()> {
+        ctx.accounts.set_sol_price(micro_usd_per_sol, &ctx.bumps)
+    }
+
+    // the program authority sets (or clears) the program deposit_and_notify CPIs into
+    pub fn set_reward_program(
+        ctx: Context<SetRewardProgram>,
+        reward_program: Option<Pubkey>,
+    ) -> Result<()> {
+        ctx.accounts.set_reward_program(reward_program, &ctx.bumps)
+    }
+
+    pub fn set_max_lifetime_withdrawals(
+        ctx: Context<Configure>,
+        max_lifetime_withdrawals: u32,
+    ) -> Result<()> {
+        ctx.accounts
+            .set_max_lifetime_withdrawals(max_lifetime_withdrawals)
+    }
+
+    // limits deposits to period_deposit_cap per window of period_seconds (cap 0 = no limit)
+    pub fn set_period_deposit_cap(
+        ctx: Context<Configure>,
+        period_deposit_cap: u64,
+        period_seconds: i64,
+    ) -> Result<()> {
+        ctx.accounts
+            .set_period_deposit_cap(period_deposit_cap, period_seconds)
+    }
+
+    // deposits are only accepted from start until end (0 leaves that side open); withdrawals
+    // are unaffected
+    pub fn set_campaign(ctx: Context<Configure>, start: i64, end: i64) -> Result<()> {
+        ctx.accounts.set_campaign(start, end)
+    }
+
+    // the balance a savings vault works towards (0 = no goal); GoalReached is emitted the
+    // first time a deposit reaches it, and setting a new goal re-arms the event
+    pub fn set_goal(ctx: Context<Configure>, goal: u64) -> Result<()> {
+        ctx.accounts.set_goal(goal)
+    }
+
+    pub fn set_deposit_cap(ctx: Context<Configure>, deposit_cap: u64) -> Result<()> {
+        ctx.accounts.set_deposit_cap(deposit_cap)
+    }
+
+    // limits withdrawals to `limit` lamports per window of window_seconds, normally
+    // 86_400 for a daily limit (limit 0 = no limit)
+    pub fn set_withdraw_limit(
+        ctx: Context<Configure>,
+        limit: u64,
+        window_seconds: i64,
+    ) -> Result<()> {
+        ctx.accounts.set_withdraw_limit(limit, window_seconds)
+    }
+
+    // withdrawals are blocked until the vault is at least min_age_seconds old
+    pub fn set_min_age(ctx: Context<Configure>, min_age_seconds: i64) -> Result<()> {
+        ctx.accounts.set_min_age(min_age_seconds)
+    }
+}
+
+// trait is used to define common functionality for structs
+// derive is import macro that automatically implements the trait for the struct
+// this example uses Accounts trait to define the accounts that are used in the instruction
+#[derive(Accounts)]
+// instruction arguments used in constraints must be declared here in the same order as the handler
+#[instruction(index: u8)]
+// info is a lifetime parameter that is used to define the lifetime of the accounts
+pub struct Initialize<'info> {
+    // Signer is used to define the account that is signing the transaction
+    // mut is used to define that the account is mutable
+    // this means that the account can be modified by the instruction
+    // if you use mut, you need use existing account
+    #[account(mut)]
+    pub user: Signer<'info>,
+    // if you want to use an non-existing account, you need to use init and create a new account
+    #[account(
+        init,
+        payer = user,
+        space = VaultState::INIT_SPACE,
+        // Checks that given account is a PDA derived from the currently executing program, the seeds, and if provided, the bump seed.
+        seeds = [b"state", user.key().as_ref(), index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub vault_state: Account<'info, VaultState>, // PDA
+    #[account(
+        mut,
+        seeds = [b"vault", vault_state.key().as_ref()],
+        bump
+    )]
+    // Type validating that the account is of type SystemAccount, which is a wrapper around the system program account.
+    // You can use SystemAccount<'info> in your instruction context, especially for accounts like program-derived addresses (PDAs) intended to hold SOL. For example, to create a PDA with no data, you can use:
+    pub vault: SystemAccount<'info>,
+    // tracks which indices the user has open; created together with the user's first vault
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserRegistry::INIT_SPACE,
+        seeds = [b"registry", user.key().as_ref()],
+        bump
+    )]
+    pub registry: Account<'info, UserRegistry>,
+
+    // Program<System> already checks the key; the explicit address constraint keeps
+    // that check in place even if the field type is ever loosened, and is used on
+    // every context that moves lamports through the system program
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+// https://www.anchor-lang.com/docs/references/account-types
+// https://www.anchor-lang.com/docs/references/account-constraints
+
+// // without lifetime annotations, the compiler cannot determine how long the reference is valid
+// struct Bad {
+//     data: &str,  // compiler cannot determine the lifetime. This will cause an error
+// }
+
+// // with lifetime annotations, the compiler can determine how long the reference is valid
+// struct Good<'a> {
+//     data: &'a str,  // 'a is a lifetime parameter
+// }
+
+impl<'info> Initialize<'info> {
+    pub fn initialize(&mut self, index: u8, bumps: &InitializeBumps) -> Result<()> {
+        // the "state" and "vault" seeds can never derive the same address today; this keeps a
+        // future seed change from silently making the state account double as the vault
+        require_keys_neq!(
+            self.vault_state.key(),
+            self.vault.key(),
+            VaultError::SeedCollision
+        );
+        // Ensure the vault account is rent-exempt
+        // Rent is a system that ensures that accounts have enough SOL to be kept alive
+        // calculate the minimum balance required for the vault account
+        let rent_exempt = Rent::get()?.minimum_balance(self.vault.to_account_info().data_len());
+        //
+        let cpi_program = self.system_program.to_account_info();
+        // Transfer is a CPI (Cross-Program Invocation) that allows transferring SOL from one account to another
+        // Transfer struct
+        let cpi_accounts = Transfer {
+            from: self.user.to_account_info(),
+            to: self.vault.to_account_info(),
+        };
+
+        // CpiContext is used to create a context for the CPI
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+
+        transfer(cpi_ctx, rent_exempt)?;
+
+        self.vault_state.set_inner(VaultState::new(
+            self.user.key(),
+            index,
+            bumps.vault_state,
+            bumps.vault,
+            Clock::get()?.unix_timestamp,
+        ));
+        self.registry
+            .mark_open(self.user.key(), index, bumps.registry);
+
+        emit!(VaultInitialized {
+            vault_state: self.vault_state.key(),
+            owner: self.user.key(),
+            index,
+        });
+        Ok(())
+    }
+
+    pub fn deposit(&mut self, amount: u64) -> Result<()> {
+        require!(amount > 0, VaultError::InvalidAmount);
+        self.vault_state.check_not_paused()?;
+        let pulled = self.vault_state.round_deposit(amount)?;
+        let (amount, fee) = pull_deposit(
+            &self.system_program,
+            &self.user,
+            &self.vault,
+            &mut self.vault_state,
+            pulled,
+        )?;
+        // a vault created in this instruction has no deposit fee or treasury yet
+        require!(fee == 0, VaultError::InvalidTreasury);
+        let Some(committed) = self.vault_state.queue_deposit(amount)? else {
+            return Ok(());
+        };
+        self.vault_state.record_deposit(committed)?;
+
+        if self.vault_state.verbose_events {
+            emit!(DepositEvent {
+                vault_state: self.vault_state.key(),
+                topic: self.vault_state.topic,
+                user: self.user.key(),
+                amount: committed,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(index: u8)]
+pub struct InitializeWithSeedVersion<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        init,
+        payer = user,
+        space = VaultState::INIT_SPACE,
+        seeds = [b"state", user.key().as_ref(), index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    // the seeds depend on the seed_version argument, so the address is checked in the handler
+    #[account(mut)]
+    pub vault: SystemAccount<'info>,
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserRegistry::INIT_SPACE,
+        seeds = [b"registry", user.key().as_ref()],
+        bump
+    )]
+    pub registry: Account<'info, UserRegistry>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> InitializeWithSeedVersion<'info> {
+    pub fn initialize_with_seed_version(
+        &mut self,
+        index: u8,
+        seed_version: u8,
+        bumps: &InitializeWithSeedVersionBumps,
+    ) -> Result<()> {
+        require!(
+            seed_version <= SEED_VERSION_CREATOR,
+            VaultError::InvalidSeedVersion
+        );
+        let mut state = VaultState::new(
+            self.user.key(),
+            index,
+            bumps.vault_state,
+            0,
+            Clock::get()?.unix_timestamp,
+        );
+        state.seed_version = seed_version;
+        self.vault_state.set_inner(state);
+        let (vault_address, vault_bump) = Pubkey::find_program_address(
+            &[
+                b"vault",
+                vault_seed_key(&self.vault_state),
+                vault_seed_tail(&self.vault_state),
+            ],
+            &crate::ID,
+        );
+        require_keys_eq!(self.vault.key(), vault_address, VaultError::InvalidPda);
+        self.vault_state.vault_bump = vault_bump;
+
+        // same rent funding as initialize
+        let rent_exempt = Rent::get()?.minimum_balance(self.vault.data_len());
+        transfer(
+            CpiContext::new(
+                self.system_program.to_account_info(),
+                Transfer {
+                    from: self.user.to_account_info(),
+                    to: self.vault.to_account_info(),
+                },
+            ),
+            rent_exempt,
+        )?;
+
+        self.registry
+            .mark_open(self.user.key(), index, bumps.registry);
+        emit!(VaultInitialized {
+            vault_state: self.vault_state.key(),
+            owner: self.user.key(),
+            index,
+        });
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(index: u8)]
+pub struct InitializeSponsored<'info> {
+    // the owner only signs; the sponsor pays for everything
+    pub user: Signer<'info>,
+    #[account(mut)]
+    pub rent_payer: Signer<'info>,
+    #[account(
+        init,
+        payer = rent_payer,
+        space = VaultState::INIT_SPACE,
+        seeds = [b"state", user.key().as_ref(), index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_state.key().as_ref()],
+        bump
+    )]
+    pub vault: SystemAccount<'info>,
+    #[account(
+        init_if_needed,
+        payer = rent_payer,
+        space = UserRegistry::INIT_SPACE,
+        seeds = [b"registry", user.key().as_ref()],
+        bump
+    )]
+    pub registry: Account<'info, UserRegistry>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> InitializeSponsored<'info> {
+    pub fn initialize_sponsored(
+        &mut self,
+        index: u8,
+        bumps: &InitializeSponsoredBumps,
+    ) -> Result<()> {
+        // same rent funding as initialize, from the sponsor
+        let rent_exempt = Rent::get()?.minimum_balance(self.vault.data_len());
+        transfer(
+            CpiContext::new(
+                self.system_program.to_account_info(),
+                Transfer {
+                    from: self.rent_payer.to_account_info(),
+                    to: self.vault.to_account_info(),
+                },
+            ),
+            rent_exempt,
+        )?;
+
+        self.vault_state.set_inner(VaultState::new(
+            self.user.key(),
+            index,
+            bumps.vault_state,
+            bumps.vault,
+            Clock::get()?.unix_timestamp,
+        ));
+        self.registry
+            .mark_open(self.user.key(), index, bumps.registry);
+        emit!(VaultInitialized {
+            vault_state: self.vault_state.key(),
+            owner: self.user.key(),
+            index,
+        });
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct InitializeMany<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    // tracks which indices the user has open; created together with the user's first vault
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserRegistry::INIT_SPACE,
+        seeds = [b"registry", user.key().as_ref()],
+        bump
+    )]
+    pub registry: Account<'info, UserRegistry>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> InitializeMany<'info> {
+    pub fn initialize_many(
+        &mut self,
+        count: u8,
+        remaining_accounts: &'info [AccountInfo<'info>],
+        bumps: &InitializeManyBumps,
+    ) -> Result<()> {
+        require!(
+            count > 0 && count <= MAX_BULK_INITIALIZE,
+            VaultError::BatchTooLarge
+        );
+        check_batch(remaining_accounts, usize::from(count), 2)?;
+
+        let rent = Rent::get()?;
+        let now = Clock::get()?.unix_timestamp;
+        let user_key = self.user.key();
+        for (index, pair) in (0..count).zip(remaining_accounts.chunks_exact(2)) {
+            let (state_info, vault_info) = (&pair[0], &pair[1]);
+            // the Accounts derive can't express a variable number of PDAs, so derive them here
+            let (state_address, state_bump) = Pubkey::find_program_address(
+                &[b"state", user_key.as_ref(), index.to_le_bytes().as_ref()],
+                &crate::ID,
+            );
+            require_keys_eq!(state_info.key(), state_address, VaultError::InvalidPda);
+            let (vault_address, vault_bump) =
+                Pubkey::find_program_address(&[b"vault", state_address.as_ref()], &crate::ID);
+            require_keys_eq!(vault_info.key(), vault_address, VaultError::InvalidPda);
+
+            // same as init: allocate the state PDA owned by this program
+            // create_account fails if the index is already in use
+            let state_seeds = &[
+                b"state",
+                user_key.as_ref(),
+                &index.to_le_bytes(),
+                &[state_bump],
+            ];
+            create_account(
+                CpiContext::new_with_signer(
+                    self.system_program.to_account_info(),
+                    CreateAccount {
+                        from: self.user.to_account_info(),
+                        to: state_info.clone(),
+                    },
+                    &[&state_seeds[..]],
+                ),
+                rent.minimum_balance(VaultState::INIT_SPACE),
+                VaultState::INIT_SPACE as u64,
+                &crate::ID,
+            )?;
+            // try_serialize writes the account discriminator followed by the data
+            VaultState::new(user_key, index, state_bump, vault_bump, now)
+                .try_serialize(&mut &mut state_info.try_borrow_mut_data()?[..])?;
+
+            transfer(
+                CpiContext::new(
+                    self.system_program.to_account_info(),
+                    Transfer {
+                        from: self.user.to_account_info(),
+                        to: vault_info.clone(),
+                    },
+                ),
+                rent.minimum_balance(0),
+            )?;
+            self.registry.mark_open(user_key, index, bumps.registry);
+
+            emit!(VaultInitialized {
+                vault_state: state_address,
+                owner: user_key,
+                index,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct Payment<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+    // Only required when vault_state.forward_to is set.
+    // Option accounts can be omitted by the client (Anchor passes the program ID as a placeholder)
+    #[account(mut)]
+    pub forward_state: Option<Account<'info, VaultState>>,
+    #[account(mut)]
+    pub forward_vault: Option<SystemAccount<'info>>,
+    // only required for early withdrawals that pay a penalty
+    #[account(mut)]
+    pub fee_treasury: Option<SystemAccount<'info>>,
+    // only required for withdrawals above the multisig approval limit
+    pub multisig: Option<Account<'info, MultisigConfig>>,
+    #[account(mut)]
+    pub approval: Option<Account<'info, WithdrawApproval>>,
+    // required once init_activity_log has been called
+    #[account(mut)]
+    pub activity_log: Option<Account<'info, ActivityLog>>,
+    // required while vault_state.notify is set
+    #[account(mut)]
+    pub notify_marker: Option<Account<'info, NotifyMarker>>,
+    // only required by deposit_min_usd
+    pub price_feed: Option<Account<'info, PriceFeed>>,
+    // only read by deposit_and_notify
+    pub reward_config: Option<Account<'info, RewardConfig>>,
+    /// CHECK: must match reward_config.reward_program, checked in notify_reward
+    pub reward_program: Option<UncheckedAccount<'info>>,
+    /// CHECK: must be release_condition.account, checked in check_release_condition
+    pub condition_account: Option<UncheckedAccount<'info>>,
+    // pays the deposit bonus; required while deposit_bonus_bps is set, and checked against
+    // its PDA in transfer_from_reward_pool
+    #[account(mut)]
+    pub reward_pool: Option<SystemAccount<'info>>,
+    // only read by deposit_with_referral, which checks it against the referrer argument
+    #[account(mut)]
+    pub referrer: Option<SystemAccount<'info>>,
+}
+
+impl<'info> Payment<'info> {
+    pub fn deposit(&mut self, amount: u64) -> Result<u64> {
+        // with queue_on_pause the lamports are taken now and committed by set_paused(false);
+        // a forwarding vault cannot queue since unpausing has no forward accounts
+        let queue = self.vault_state.paused
+            && self.vault_state.queue_on_pause
+            && self.vault_state.forward_to.is_none();
+        if !queue {
+            self.vault_state.check_not_paused()?;
+        }
+        // only the rounded amount is pulled from the user, so the remainder never leaves their wallet
+        let pulled = self.vault_state.round_deposit(amount)?;
+        let (amount, fee) = pull_deposit(
+            &self.system_program,
+            &self.user,
+            &self.vault,
+            &mut self.vault_state,
+            pulled,
+        )?;
+        if fee > 0 {
+            let Some(fee_treasury) = &self.fee_treasury else {
+                return err!(VaultError::InvalidTreasury);
+            };
+            require_keys_eq!(
+                fee_treasury.key(),
+                self.vault_state.fee_treasury,
+                VaultError::InvalidTreasury
+            );
+            transfer(
+                CpiContext::new(
+                    self.system_program.to_account_info(),
+                    Transfer {
+                        from: self.user.to_account_info(),
+                        to: fee_treasury.to_account_info(),
+                    },
+                ),
+                fee,
+            )?;
+        }
+        // a forwarding vault only passes lamports through, so its balance does not change
+        if self.vault_state.forward_to.is_none() {
+            self.log(i64::try_from(amount).map_err(|_| VaultError::Overflow)?)?;
+        }
+        self.notify()?;
+
+        if queue {
+            self.vault_state.pending_deposit = self
+                .vault_state
+                .pending_deposit
+                .checked_add(amount)
+                .ok_or(VaultError::Overflow)?;
+            return Ok(pulled);
+        }
+
+        // amounts below min_deposit wait in the vault until the queue crosses the threshold
+        let Some(committed) = self.vault_state.queue_deposit(amount)? else {
+            return Ok(pulled);
+        };
+
+        // a forwarding vault acts as a "mailbox": whatever lands here moves on to the linked vault
+        // the deposit is credited to whichever vault ends up holding it
+        if let Some(target) = self.vault_state.forward_to {
+            self.forward(target, committed)?;
+        } else {
+            self.vault_state.record_deposit(committed)?;
+            self.pay_bonus(committed)?;
+            let key = self.vault_state.key();
+            self.vault_state.track_goal(key, self.vault.lamports());
+        }
+
+        if self.vault_state.verbose_events {
+            emit!(DepositEvent {
+                vault_state: self.vault_state.key(),
+                topic: self.vault_state.topic,
+                user: self.user.key(),
+                amount: committed,
+            });
+        }
+        Ok(pulled)
+    }
+
+    // appends a balance change to the activity log if the vault keeps one
+    fn log(&mut self, delta: i64) -> Result<()> {
+        if !self.vault_state.activity_log_enabled {
+            return Ok(());
+        }
+        let Some(activity_log) = &mut self.activity_log else {
+            return err!(VaultError::ActivityLogMissing);
+        };
+        require_keys_eq!(
+            activity_log.vault_state,
+            self.vault_state.key(),
+            VaultError::InvalidPda
+        );
+        activity_log.push(delta, Clock::get()?.unix_timestamp);
+        Ok(())
+    }
+
+    pub fn deposit_min_usd(&mut self, amount: u64, min_usd: u64) -> Result<u64> {
+        let Some(price_feed) = &self.price_feed else {
+            return err!(VaultError::PriceFeedMissing);
+        };
+        // the feed is only ever created at its PDA by set_sol_price
+        let price = price_feed.fresh_price(Clock::get()?.unix_timestamp)?;
+        // priced on what is actually pulled from the user after rounding
+        let pulled = self.vault_state.round_deposit(amount)?;
+        let usd = u128::from(pulled) * u128::from(price) / u128::from(LAMPORTS_PER_SOL);
+        require!(usd >= u128::from(min_usd), VaultError::BelowUsdFloor);
+        self.deposit(amount)
+    }
+
+    pub fn deposit_with_referral(&mut self, amount: u64, referrer: Pubkey) -> Result<u64> {
+        require_keys_neq!(referrer, self.user.key(), VaultError::InvalidReferrer);
+        let pulled = self.deposit(amount)?;
+        let (credited, _) = self.vault_state.split_deposit_fee(pulled)?;
+        let mut fee = 0;
+        let referral_fee_bps = self.vault_state.reserved.referral_fee_bps()?;
+        if referral_fee_bps > 0 {
+            let (Some(reward_pool), Some(referrer_account)) = (&self.reward_pool, &self.referrer)
+            else {
+                return err!(VaultError::RewardPoolMissing);
+            };
+            require_keys_eq!(
+                referrer_account.key(),
+                referrer,
+                VaultError::InvalidReferrer
+            );
+            // like the bonus, a dry pool pays less instead of failing the deposit
+            fee = bps_of(credited, referral_fee_bps)?.min(reward_pool_available(reward_pool)?);
+            if fee > 0 {
+                transfer_from_reward_pool(
+                    &self.system_program,
+                    reward_pool,
+                    referrer_account.to_account_info(),
+                    self.vault_state.key(),
+                    fee,
+                )?;
+            }
+        }
+        emit!(ReferralDeposit {
+            vault_state: self.vault_state.key(),
+            depositor: self.user.key(),
+            referrer,
+            amount: credited,
+            fee,
+        });
+        Ok(pulled)
+    }
+
+    // tops a committed deposit up from the reward pool; a dry pool or a spent cap just
+    // pays less (down to nothing) rather than failing the deposit
+    fn pay_bonus(&mut self, amount: u64) -> Result<()> {
+        let state = &self.vault_state;
+        if state.deposit_bonus_bps == 0 {
+            return Ok(());
+        }
+        let Some(reward_pool) = &self.reward_pool else {
+            return err!(VaultError::RewardPoolMissing);
+        };
+        let bonus = bps_of(amount, state.deposit_bonus_bps)?
+            .min(state.bonus_cap.saturating_sub(state.bonus_paid))
+            .min(reward_pool_available(reward_pool)?);
+        if bonus == 0 {
+            return Ok(());
+        }
+        transfer_from_reward_pool(
+            &self.system_program,
+            reward_pool,
+            self.vault.to_account_info(),
+            state.key(),
+            bonus,
+        )?;
+        self.vault_state.bonus_paid += bonus;
+        emit!(BonusPaid {
+            vault_state: self.vault_state.key(),
+            recipient: self.vault.key(),
+            amount: bonus,
+        });
+        Ok(())
+    }
+
+    fn check_release_condition(&self) -> Result<()> {
+        let Some(condition) = self.vault_state.release_condition else {
+            return err!(VaultError::NoReleaseCondition);
+        };
+        let Some(account) = &self.condition_account else {
+            return err!(VaultError::ConditionAccountMismatch);
+        };
+        require_keys_eq!(
+            account.key(),
+            condition.account,
+            VaultError::ConditionAccountMismatch
+        );
+        // the owner check keeps anyone else from writing the byte the vault relies on
+        require_keys_eq!(
+            *account.owner,
+            condition.owner,
+            VaultError::ConditionAccountMismatch
+        );
+        let data = account.try_borrow_data()?;
+        require!(
+            data.get(usize::from(condition.offset)) == Some(&condition.value),
+            VaultError::ReleaseConditionNotMet
+        );
+        Ok(())
+    }
+
+    // the reward program receives credit_points(amount) with the depositor and vault_state
+    fn notify_reward(&self, amount: u64) -> Result<()> {
+        // like the price feed, a RewardConfig only ever exists at its PDA
+        let Some(expected) = self
+            .reward_config
+            .as_ref()
+            .and_then(|config| config.reward_program)
+        else {
+            return Ok(());
+        };
+        let Some(reward_program) = &self.reward_program else {
+            return err!(VaultError::RewardProgramMissing);
+        };
+        require_keys_eq!(
+            reward_program.key(),
+            expected,
+            VaultError::RewardProgramMismatch
+        );
+        let mut data = REWARD_CREDIT_DISCRIMINATOR.to_vec();
+        data.extend_from_slice(&amount.to_le_bytes());
+        invoke(
+            &Instruction {
+                program_id: expected,
+                accounts: vec![
+                    AccountMeta::new_readonly(self.user.key(), true),
+                    AccountMeta::new_readonly(self.vault_state.key(), false),
+                ],
+                data,
+            },
+            &[
+                self.user.to_account_info(),
+                self.vault_state.to_account_info(),
+                reward_program.to_account_info(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    // bumps the marker's sequence so the watcher sees that this vault changed
+    fn notify(&mut self) -> Result<()> {
+        if self.vault_state.notify.is_none() {
+            return Ok(());
+        }
+        let Some(marker) = &mut self.notify_marker else {
+            return err!(VaultError::NotifyMarkerMissing);
+        };
+        require_keys_eq!(
+            marker.vault_state,
+            self.vault_state.key(),
+            VaultError::InvalidPda
+        );
+        marker.sequence = marker.sequence.wrapping_add(1);
+        Ok(())
+    }
+
+    // a withdrawal above the approval limit uses up the pending WithdrawApproval
+    fn consume_approval(&mut self, amount: u64) -> Result<()> {
+        if !self.vault_state.requires_approval(amount) {
+            return Ok(());
+        }
+        let (Some(multisig), Some(approval)) = (&self.multisig, &mut self.approval) else {
+            return err!(VaultError::ApprovalRequired);
+        };
+        // both accounts are only ever created at PDAs of the vault_state they record
+        require_keys_eq!(
+            multisig.vault_state,
+            self.vault_state.key(),
+            VaultError::InvalidPda
+        );
+        require_keys_eq!(
+            approval.vault_state,
+            self.vault_state.key(),
+            VaultError::InvalidPda
+        );
+        require!(
+            approval.nonce == multisig.nonce
+                && approval.amount == amount
+                && approval.approvals.count_ones() >= u32::from(multisig.threshold),
+            VaultError::ApprovalRequired
+        );
+        approval.approvals = 0;
+        Ok(())
+    }
+
+    pub fn deposit_capped(&mut self, amount: u64) -> Result<u64> {
+        // the excess is never pulled from the user, which refunds it without a second transfer
+        let headroom = self.vault_state.cap_headroom(self.vault.lamports());
+        self.deposit(amount.min(headroom))
+    }
+
+    pub fn deposit_percent_of_balance(&mut self, bps: u16) -> Result<u64> {
+        // the reserve stays out of the base, so even bps = 10_000 leaves the user able to pay fees
+        let spendable = self.user.lamports().saturating_sub(DEPOSIT_FEE_RESERVE);
+        self.deposit(bps_of(spendable, bps)?)
+    }
+
+    fn forward(&mut self, target: Pubkey, amount: u64) -> Result<()> {
+        let (Some(forward_state), Some(forward_vault)) =
+            (&mut self.forward_state, &self.forward_vault)
+        else {
+            return err!(VaultError::ForwardAccountsMissing);
+        };
+        require_keys_eq!(
+            forward_state.key(),
+            target,
+            VaultError::ForwardTargetMismatch
+        );
+        // forwarding depth is limited to one hop, so a target that forwards itself
+        // (including chains that loop back to this vault) is rejected
+        require!(
+            forward_state.forward_to.is_none(),
+            VaultError::ForwardingDepthExceeded
+        );
+        // the target vault is not constrained by Anchor because it is optional, so re-derive it here
+        require_keys_eq!(
+            forward_vault.key(),
+            vault_address(forward_state)?,
+            VaultError::ForwardTargetMismatch
+        );
+
+        forward_state.accrue_twab(Clock::get()?.unix_timestamp, forward_vault.lamports())?;
+        forward_state.record_deposit(amount)?;
+        transfer_from_vault(
+            &self.system_program,
+            &self.vault,
+            forward_vault.to_account_info(),
+            &mut self.vault_state,
+            amount,
+        )
+    }
+
+    pub fn withdraw(&mut self, amount: u64) -> Result<()> {
+        check_funded(&self.vault)?;
+        self.vault_state.check_min_withdraw(amount)?;
+        self.vault_state.check_self_withdraw_not_paused()?;
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+        self.vault_state.check_min_age(now)?;
+        self.vault_state
+            .check_vested(now, self.vault.lamports(), amount)?;
+        self.vault_state.record_withdrawal_size(amount)?;
+        self.consume_approval(amount)?;
+        self.vault_state.record_daily_withdrawal(now, amount)?;
+        self.vault_state.record_withdrawals(1)?;
+        self.log(-i64::try_from(amount).map_err(|_| VaultError::Overflow)?)?;
+        self.notify()?;
+        // before unlock_at a withdrawal is only possible as an early withdrawal, which pays
+        // early_penalty_bps to the fee treasury; after unlock_at there is no penalty
+        let penalty = if self.vault_state.is_locked(&clock) {
+            require!(
+                self.vault_state.allow_early_withdraw,
+                VaultError::VaultLocked
+            );
+            bps_of(amount, self.vault_state.early_penalty_bps)?
+        } else {
+            0
+        };
+        let net = amount - penalty;
+
+        // this transfer doesn't go through transfer_from_vault, so it accrues the TWAB itself
+        self.vault_state.accrue_twab(now, self.vault.lamports())?;
+        let cpi_program = self.system_program.to_account_info();
+        let cpi_account = Transfer {
+            from: self.vault.to_account_info(),
+            to: self.user.to_account_info(),
+        };
+        // PDA signing is required for the transfer
+        // because the vault account is a PDA (Program Derived Address)
+        // and it needs to be signed by the PDA's seeds
+        // to ensure that the transfer is valid
+        // this is done by using CpiContext::new_with_signer
+        // which allows you to specify the seeds that will be used to sign the transaction
+
+        // & is used to create a reference to the seeds
+        let seeds = &[
+            b"vault",
+            vault_seed_key(&self.vault_state),
+            vault_seed_tail(&self.vault_state),
+            &[self.vault_state.vault_bump],
+        ];
+
+        // Why use a reference?
+        // Because the seeds are used to derive the PDA, and we need to pass a reference
+        // let seeds = [b"vault", ...];  // move ownership of the array
+        // // create a reference to the array
+        // let seeds = &[b"vault", ...]; // borrow the array
+
+        let signer_seeds = &[&seeds[..]];
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_account, signer_seeds);
+        transfer(cpi_ctx, net)?;
+        self.vault_state.settle_receipts(&self.vault)?;
+
+        if penalty > 0 {
+            let Some(fee_treasury) = &self.fee_treasury else {
+                return err!(VaultError::InvalidTreasury);
+            };
+            require_keys_eq!(
+                fee_treasury.key(),
+                self.vault_state.fee_treasury,
+                VaultError::InvalidTreasury
+            );
+            transfer_from_vault(
+                &self.system_program,
+                &self.vault,
+                fee_treasury.to_account_info(),
+                &mut self.vault_state,
+                penalty,
+            )?;
+        }
+
+        if self.vault_state.verbose_events {
+            emit!(WithdrawEvent {
+                vault_state: self.vault_state.key(),
+                topic: self.vault_state.topic,
+                recipient: self.user.key(),
+                amount: net,
+            });
+        }
+        self.vault_state.update_rent_floor(&self.vault)?;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct Close<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,)]
+    pub vault: SystemAccount<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+        close = user,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"registry", vault_state.creator.as_ref()],
+        bump = registry.bump,
+    )]
+    pub registry: Account<'info, UserRegistry>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> Close<'info> {
+    pub fn close(&mut self) -> Result<()> {
+        // the seeds already tie vault_state to the signer; this keeps close safe even if
+        // the seed scheme is ever loosened
+        require_keys_eq!(
+            self.user.key(),
+            self.vault_state.owner,
+            VaultError::Unauthorized
+        );
+        self.vault_state.check_closable(self.vault.lamports())?;
+        // the vault is owned by the system program, and only an account's owner may debit it,
+        // so this move has to go through a system transfer CPI; direct lamport mutation would
+        // fail the runtime's ownership check
+        // vault_state is owned by this program, and its rent is already reclaimed without a CPI:
+        // the close = user constraint moves its lamports directly and zeroes the data
+        let cpi_program = self.system_program.to_account_info();
+        let cpi_account = Transfer {
+            from: self.vault.to_account_info(),
+            to: self.user.to_account_info(),
+        };
+        let pda_signing_seeds = [
+            b"vault",
+            vault_seed_key(&self.vault_state),
+            vault_seed_tail(&self.vault_state),
+            &[self.vault_state.vault_bump],
+        ];
+        let seeds = &[&pda_signing_seeds[..]];
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_account, seeds);
+        transfer(cpi_ctx, self.vault.lamports())?;
+        self.registry.mark_closed(self.vault_state.index);
+        Ok(())
+    }
+
+    pub fn close_confirmed(&mut self, confirm: [u8; 4]) -> Result<()> {
+        require!(
+            self.vault_state.close_code == Some(confirm),
+            VaultError::InvalidConfirmationCode
+        );
+        // consumed here, so the same code can't be replayed
+        self.vault_state.close_code = None;
+        self.close()
+    }
+}
+
+#[derive(Accounts)]
+pub struct AcceptOwner<'info> {
+    pub new_owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.pending_owner == Some(new_owner.key()) @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+}
+
+impl<'info> AcceptOwner<'info> {
+    pub fn accept_owner(&mut self) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        self.vault_state.owner = self.new_owner.key();
+        self.vault_state.pending_owner = None;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct Archive<'info> {
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"registry", vault_state.creator.as_ref()],
+        bump = registry.bump,
+    )]
+    pub registry: Account<'info, UserRegistry>,
+}
+
+impl<'info> Archive<'info> {
+    pub fn set_archived(&mut self, archived: bool) -> Result<()> {
+        self.vault_state.archived = archived;
+        self.registry.set_archived(self.vault_state.index, archived);
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct TopUpOrClose<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"registry", vault_state.creator.as_ref()],
+        bump = registry.bump,
+    )]
+    pub registry: Account<'info, UserRegistry>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> TopUpOrClose<'info> {
+    pub fn top_up_or_close(&mut self, amount: u64) -> Result<()> {
+        require!(self.vault_state.at_rent_floor, VaultError::NotAtRentFloor);
+        if amount > 0 {
+            self.vault_state.check_not_paused()?;
+            self.vault_state.check_cap(self.vault.lamports(), amount)?;
+            transfer_to_vault(
+                &self.system_program,
+                self.user.to_account_info(),
+                &self.vault,
+                &mut self.vault_state,
+                amount,
+            )?;
+            // clears at_rent_floor
+            return self.vault_state.record_deposit(amount);
+        }
+
+        self.vault_state.check_closable(self.vault.lamports())?;
+        transfer_from_vault(
+            &self.system_program,
+            &self.vault,
+            self.user.to_account_info(),
+            &mut self.vault_state,
+            self.vault.lamports(),
+        )?;
+        self.registry.mark_closed(self.vault_state.index);
+        // the close = user constraint can't be conditional, so the state is closed by hand
+        self.vault_state.close(self.user.to_account_info())
+    }
+}
+
+#[derive(Accounts)]
+pub struct ClosePartial<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+        close = user,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"registry", vault_state.creator.as_ref()],
+        bump = registry.bump,
+    )]
+    pub registry: Account<'info, UserRegistry>,
+    #[account(mut)]
+    pub residual_recipient: SystemAccount<'info>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> ClosePartial<'info> {
+    pub fn close_partial(&mut self, keep: u64) -> Result<()> {
+        let balance = self.vault.lamports();
+        require!(keep <= balance, VaultError::InsufficientVaultBalance);
+        self.vault_state.check_closable(balance)?;
+        // the residual is a send to a third party like withdraw_to
+        if keep > 0 {
+            self.vault_state
+                .check_recipient(self.residual_recipient.key())?;
+        }
+        transfer_from_vault(
+            &self.system_program,
+            &self.vault,
+            self.user.to_account_info(),
+            &mut self.vault_state,
+            balance - keep,
+        )?;
+        transfer_from_vault(
+            &self.system_program,
+            &self.vault,
+            self.residual_recipient.to_account_info(),
+            &mut self.vault_state,
+            keep,
+        )?;
+        self.registry.mark_closed(self.vault_state.index);
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct KeeperClose<'info> {
+    #[account(mut)]
+    pub keeper: Signer<'info>,
+    #[account(mut, address = vault_state.owner @ VaultError::Unauthorized)]
+    pub owner: SystemAccount<'info>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        close = owner,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"registry", vault_state.creator.as_ref()],
+        bump = registry.bump,
+    )]
+    pub registry: Account<'info, UserRegistry>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> KeeperClose<'info> {
+    pub fn keeper_close(&mut self) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        require!(
+            self.vault_state.keeper_after_seconds > 0
+                && now
+                    >= self
+                        .vault_state
+                        .last_activity
+                        .saturating_add(self.vault_state.keeper_after_seconds),
+            VaultError::VaultStillActive
+        );
+        let balance = self.vault.lamports();
+        self.vault_state.check_closable(balance)?;
+        let bounty = bps_of(balance, self.vault_state.keeper_bounty_bps)?;
+        transfer_from_vault(
+            &self.system_program,
+            &self.vault,
+            self.keeper.to_account_info(),
+            &mut self.vault_state,
+            bounty,
+        )?;
+        transfer_from_vault(
+            &self.system_program,
+            &self.vault,
+            self.owner.to_account_info(),
+            &mut self.vault_state,
+            balance - bounty,
+        )?;
+        self.registry.mark_closed(self.vault_state.index);
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct DelegateStake<'info> {
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    /// CHECK: created here as a stake account at this PDA
+    #[account(mut, seeds = [b"stake", vault_state.key().as_ref()], bump)]
+    pub stake_account: UncheckedAccount<'info>,
+    /// CHECK: validated by the stake program when delegating
+    pub vote_account: UncheckedAccount<'info>,
+    /// CHECK: sysvar, checked by address
+    #[account(address = sysvar::stake_history::ID)]
+    pub stake_history: UncheckedAccount<'info>,
+    /// CHECK: still required by DelegateStake, checked by address
+    #[account(address = stake::config::ID)]
+    pub stake_config: UncheckedAccount<'info>,
+    pub clock: Sysvar<'info, Clock>,
+    pub rent: Sysvar<'info, Rent>,
+    /// CHECK: checked by address
+    #[account(address = stake::program::ID)]
+    pub stake_program: UncheckedAccount<'info>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> DelegateStake<'info> {
+    pub fn delegate_stake(&mut self, amount: u64, bumps: &DelegateStakeBumps) -> Result<()> {
+        self.vault_state.check_not_paused()?;
+        require!(
+            self.vault_state.stake_account.is_none(),
+            VaultError::StakeAlreadyActive
+        );
+        self.vault_state
+            .accrue_twab(Clock::get()?.unix_timestamp, self.vault.lamports())?;
+        let vault_state_key = self.vault_state.key();
+        let vault_seeds: &[&[u8]] = &[
+            b"vault",
+            vault_seed_key(&self.vault_state),
+            vault_seed_tail(&self.vault_state),
+            &[self.vault_state.vault_bump],
+        ];
+        let stake_seeds: &[&[u8]] = &[b"stake", vault_state_key.as_ref(), &[bumps.stake_account]];
+
+        // amount has to cover the stake account's rent as well as the delegation
+        create_account(
+            CpiContext::new_with_signer(
+                self.system_program.to_account_info(),
+                CreateAccount {
+                    from: self.vault.to_account_info(),
+                    to: self.stake_account.to_account_info(),
+                },
+                &[vault_seeds, stake_seeds],
+            ),
+            amount,
+            StakeStateV2::size_of() as u64,
+            &stake::program::ID,
+        )?;
+        invoke_signed(
+            &stake_instruction::initialize(
+                &self.stake_account.key(),
+                &Authorized::auto(&self.vault.key()),
+                &Lockup::default(),
+            ),
+            &[
+                self.stake_account.to_account_info(),
+                self.rent.to_account_info(),
+            ],
+            &[],
+        )?;
+        invoke_signed(
+            &stake_instruction::delegate_stake(
+                &self.stake_account.key(),
+                &self.vault.key(),
+                &self.vote_account.key(),
+            ),
+            &[
+                self.stake_account.to_account_info(),
+                self.vote_account.to_account_info(),
+                self.clock.to_account_info(),
+                self.stake_history.to_account_info(),
+                self.stake_config.to_account_info(),
+                self.vault.to_account_info(),
+            ],
+            &[vault_seeds],
+        )?;
+        self.vault_state.stake_account = Some(self.stake_account.key());
+        self.vault_state.stake_principal = amount;
+        self.vault_state.stake_started_at = Clock::get()?.unix_timestamp;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct StakeAction<'info> {
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    /// CHECK: must be the stake account recorded by delegate_stake
+    #[account(
+        mut,
+        constraint = vault_state.stake_account == Some(stake_account.key()) @ VaultError::NoActiveStake,
+    )]
+    pub stake_account: UncheckedAccount<'info>,
+    /// CHECK: sysvar, checked by address
+    #[account(address = sysvar::stake_history::ID)]
+    pub stake_history: UncheckedAccount<'info>,
+    pub clock: Sysvar<'info, Clock>,
+    /// CHECK: checked by address
+    #[account(address = stake::program::ID)]
+    pub stake_program: UncheckedAccount<'info>,
+}
+
+impl<'info> StakeAction<'info> {
+    pub fn deactivate_stake(&mut self) -> Result<()> {
+        let seeds: &[&[u8]] = &[
+            b"vault",
+            vault_seed_key(&self.vault_state),
+            vault_seed_tail(&self.vault_state),
+            &[self.vault_state.vault_bump],
+        ];
+        invoke_signed(
+            &stake_instruction::deactivate_stake(&self.stake_account.key(), &self.vault.key()),
+            &[
+                self.stake_account.to_account_info(),
+                self.clock.to_account_info(),
+                self.vault.to_account_info(),
+            ],
+            &[seeds],
+        )?;
+        Ok(())
+    }
+
+    pub fn withdraw_stake(&mut self) -> Result<()> {
+        self.vault_state
+            .accrue_twab(Clock::get()?.unix_timestamp, self.vault.lamports())?;
+        let seeds: &[&[u8]] = &[
+            b"vault",
+            vault_seed_key(&self.vault_state),
+            vault_seed_tail(&self.vault_state),
+            &[self.vault_state.vault_bump],
+        ];
+        let returned = self.stake_account.lamports();
+        // the stake program refuses this until the stake has fully cooled down
+        invoke_signed(
+            &stake_instruction::withdraw(
+                &self.stake_account.key(),
+                &self.vault.key(),
+                &self.vault.key(),
+                self.stake_account.lamports(),
+                None,
+            ),
+            &[
+                self.stake_account.to_account_info(),
+                self.vault.to_account_info(),
+                self.clock.to_account_info(),
+                self.stake_history.to_account_info(),
+                self.vault.to_account_info(),
+            ],
+            &[seeds],
+        )?;
+        self.vault_state.stake_account = None;
+        let now = Clock::get()?.unix_timestamp;
+        self.vault_state.last_stake_apy_bps = annualized_bps(
+            returned.saturating_sub(self.vault_state.stake_principal),
+            self.vault_state.stake_principal,
+            now.saturating_sub(self.vault_state.stake_started_at),
+        );
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct EstimatedApy<'info> {
+    pub vault_state: Account<'info, VaultState>,
+    /// CHECK: only its lamports are read, and it must be the recorded stake account
+    #[account(constraint = vault_state.stake_account == Some(stake_account.key()) @ VaultError::NoActiveStake)]
+    pub stake_account: Option<UncheckedAccount<'info>>,
+}
+
+impl<'info> EstimatedApy<'info> {
+    pub fn estimated_apy(&self) -> Result<u64> {
+        let state = &self.vault_state;
+        if state.stake_account.is_none() {
+            return Ok(state.last_stake_apy_bps);
+        }
+        let Some(stake_account) = &self.stake_account else {
+            return err!(VaultError::NoActiveStake);
+        };
+        let now = Clock::get()?.unix_timestamp;
+        // anything above the delegated amount was paid in as rewards
+        Ok(annualized_bps(
+            stake_account
+                .lamports()
+                .saturating_sub(state.stake_principal),
+            state.stake_principal,
+            now.saturating_sub(state.stake_started_at),
+        ))
+    }
+}
+
+#[derive(Accounts)]
+pub struct CloseMixed<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+        close = user,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"registry", vault_state.creator.as_ref()],
+        bump = registry.bump,
+    )]
+    pub registry: Account<'info, UserRegistry>,
+    #[account(address = vault_state.token_mint)]
+    pub mint: Account<'info, Mint>,
+    #[account(
+        mut,
+        seeds = [b"token_vault", vault_state.key().as_ref()],
+        bump = vault_state.token_vault_bump,
+    )]
+    pub token_vault: Account<'info, TokenAccount>,
+    #[account(
+        init_if_needed,
+        payer = user,
+        associated_token::mint = mint,
+        associated_token::authority = user,
+    )]
+    pub user_token: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> CloseMixed<'info> {
+    pub fn close_mixed(&mut self) -> Result<()> {
+        // the token account is emptied and closed below, so only the locks apply
+        self.vault_state.check_drainable(self.vault.lamports())?;
+        let seeds = &[
+            b"vault",
+            vault_seed_key(&self.vault_state),
+            vault_seed_tail(&self.vault_state),
+            &[self.vault_state.vault_bump],
+        ];
+        let signer_seeds = &[&seeds[..]];
+
+        // the token account can only be closed once it is empty
+        if self.token_vault.amount > 0 {
+            token::transfer_checked(
+                CpiContext::new_with_signer(
+                    self.token_program.to_account_info(),
+                    TransferChecked {
+                        from: self.token_vault.to_account_info(),
+                        mint: self.mint.to_account_info(),
+                        to: self.user_token.to_account_info(),
+                        authority: self.vault.to_account_info(),
+                    },
+                    signer_seeds,
+                ),
+                self.token_vault.amount,
+                self.mint.decimals,
+            )?;
+        }
+        token::close_account(CpiContext::new_with_signer(
+            self.token_program.to_account_info(),
+            CloseAccount {
+                account: self.token_vault.to_account_info(),
+                destination: self.user.to_account_info(),
+                authority: self.vault.to_account_info(),
+            },
+            signer_seeds,
+        ))?;
+
+        // the vault PDA still signs with no lamports, so draining it comes last; the state
+        // rent is returned after the handler by close = user
+        transfer_from_vault(
+            &self.system_program,
+            &self.vault,
+            self.user.to_account_info(),
+            &mut self.vault_state,
+            self.vault.lamports(),
+        )?;
+        self.registry.mark_closed(self.vault_state.index);
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct CloseToWsol<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+        close = user,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"registry", vault_state.creator.as_ref()],
+        bump = registry.bump,
+    )]
+    pub registry: Account<'info, UserRegistry>,
+    #[account(address = native_mint::ID)]
+    pub wsol_mint: Account<'info, Mint>,
+    #[account(
+        init_if_needed,
+        payer = user,
+        associated_token::mint = wsol_mint,
+        associated_token::authority = user,
+    )]
+    pub user_wsol: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> CloseToWsol<'info> {
+    pub fn close_to_wsol(&mut self) -> Result<()> {
+        let balance = self.vault.lamports();
+        self.vault_state.check_closable(balance)?;
+        transfer_from_vault(
+            &self.system_program,
+            &self.vault,
+            self.user_wsol.to_account_info(),
+            &mut self.vault_state,
+            balance,
+        )?;
+        // lamports sent to a native token account only count as tokens once synced
+        token::sync_native(CpiContext::new(
+            self.token_program.to_account_info(),
+            SyncNative {
+                account: self.user_wsol.to_account_info(),
+            },
+        ))?;
+        self.registry.mark_closed(self.vault_state.index);
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct CloseStateOnly<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    // close = user only runs after the instruction succeeds, so the state rent is reclaimed
+    // only when the vault check below passes
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+        close = user,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"registry", vault_state.creator.as_ref()],
+        bump = registry.bump,
+    )]
+    pub registry: Account<'info, UserRegistry>,
+}
+
+impl<'info> CloseStateOnly<'info> {
+    pub fn close_state_only(&mut self) -> Result<()> {
+        self.vault_state
+            .check_state_closable(self.vault.lamports())?;
+        self.registry.mark_closed(self.vault_state.index);
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct Reconcile<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+        close = user,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    // optional: a state left behind by an older layout may have no registry to clear
+    #[account(
+        mut,
+        seeds = [b"registry", vault_state.creator.as_ref()],
+        bump = registry.bump,
+    )]
+    pub registry: Option<Account<'info, UserRegistry>>,
+}
+
+impl<'info> Reconcile<'info> {
+    pub fn reconcile(&mut self) -> Result<()> {
+        // only the leftover of a close is reconciled, under the same checks as
+        // close_state_only; anything still holding funds or awaiting a close confirmation
+        // goes through close so its locks and confirmations apply
+        self.vault_state
+            .check_state_closable(self.vault.lamports())?;
+        if let Some(registry) = self.registry.as_mut() {
+            registry.mark_closed(self.vault_state.index);
+        }
+        emit!(VaultReconciled {
+            vault_state: self.vault_state.key(),
+            owner: self.vault_state.owner,
+        });
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct RefreshBumps<'info> {
+    pub user: Signer<'info>,
+    // no seeds constraint here: the stored bumps are exactly what is being checked,
+    // so the addresses are validated against the canonical derivation instead
+    #[account(mut, constraint = vault_state.owner == user.key() @ VaultError::Unauthorized)]
+    pub vault_state: Account<'info, VaultState>,
+    pub vault: SystemAccount<'info>,
+}
+
+impl<'info> RefreshBumps<'info> {
+    pub fn refresh_bumps(&mut self) -> Result<()> {
+        // find_program_address returns the canonical (highest valid) bump
+        let (state_address, state_bump) = Pubkey::find_program_address(
+            &[
+                state_seed_prefix(&self.vault_state),
+                self.vault_state.creator.as_ref(),
+                self.vault_state.index.to_le_bytes().as_ref(),
+            ],
+            &crate::ID,
+        );
+        require_keys_eq!(
+            state_address,
+            self.vault_state.key(),
+            VaultError::InvalidPda
+        );
+        let (vault_address, vault_bump) = Pubkey::find_program_address(
+            &[
+                b"vault",
+                vault_seed_key(&self.vault_state),
+                vault_seed_tail(&self.vault_state),
+            ],
+            &crate::ID,
+        );
+        require_keys_eq!(vault_address, self.vault.key(), VaultError::InvalidPda);
+
+        // only write when something changed to avoid needless account writes
+        if self.vault_state.state_bump != state_bump {
+            self.vault_state.state_bump = state_bump;
+        }
+        if self.vault_state.vault_bump != vault_bump {
+            self.vault_state.vault_bump = vault_bump;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(recipient: Pubkey)]
+pub struct AllowRecipient<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    // one PDA per (vault, recipient) pair, so lookups in withdraw_to are a single seeds check
+    #[account(
+        init,
+        payer = user,
+        space = AllowlistEntry::INIT_SPACE,
+        seeds = [b"allow", vault_state.key().as_ref(), recipient.as_ref()],
+        bump
+    )]
+    pub allowlist_entry: Account<'info, AllowlistEntry>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> AllowRecipient<'info> {
+    pub fn allow_recipient(
+        &mut self,
+        recipient: Pubkey,
+        ttl_seconds: i64,
+        bumps: &AllowRecipientBumps,
+    ) -> Result<()> {
+        require!(ttl_seconds > 0, VaultError::InvalidTtl);
+        let now = Clock::get()?.unix_timestamp;
+        self.allowlist_entry.set_inner(AllowlistEntry {
+            recipient,
+            expires_at: now.checked_add(ttl_seconds).ok_or(VaultError::InvalidTtl)?,
+            bump: bumps.allowlist_entry,
+        });
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct GuardianWithdraw<'info> {
+    pub guardian: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    #[account(mut)]
+    pub recipient: SystemAccount<'info>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> GuardianWithdraw<'info> {
+    pub fn guardian_withdraw(&mut self, amount: u64) -> Result<()> {
+        check_funded(&self.vault)?;
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+        self.vault_state.check_guardian(self.guardian.key(), now)?;
+        self.vault_state.check_min_withdraw(amount)?;
+        self.vault_state.check_recipient(self.recipient.key())?;
+        self.vault_state.check_not_paused()?;
+        self.vault_state.check_unlocked(&clock)?;
+        self.vault_state.check_no_release_condition()?;
+        self.vault_state
+            .check_vested(now, self.vault.lamports(), amount)?;
+        self.vault_state.check_approval_limit(amount)?;
+        self.vault_state.record_withdrawal_size(amount)?;
+        self.vault_state.record_daily_withdrawal(now, amount)?;
+        self.vault_state.record_withdrawals(1)?;
+        transfer_from_vault(
+            &self.system_program,
+            &self.vault,
+            self.recipient.to_account_info(),
+            &mut self.vault_state,
+            amount,
+        )?;
+        if self.vault_state.verbose_events {
+            emit!(WithdrawEvent {
+                vault_state: self.vault_state.key(),
+                topic: self.vault_state.topic,
+                recipient: self.recipient.key(),
+                amount,
+            });
+        }
+        self.vault_state.update_rent_floor(&self.vault)?;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct GuardianPause<'info> {
+    pub guardian: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+}
+
+impl<'info> GuardianPause<'info> {
+    pub fn guardian_set_paused(&mut self, paused: bool) -> Result<()> {
+        self.vault_state
+            .check_guardian(self.guardian.key(), Clock::get()?.unix_timestamp)?;
+        self.vault_state.set_paused(paused)
+    }
+}
+
+#[derive(Accounts)]
+pub struct RemoveRecipient<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"allow", vault_state.key().as_ref(), allowlist_entry.recipient.as_ref()],
+        bump = allowlist_entry.bump,
+        close = user,
+    )]
+    pub allowlist_entry: Account<'info, AllowlistEntry>,
+}
+
+impl<'info> RemoveRecipient<'info> {
+    pub fn remove_recipient(&mut self) -> Result<()> {
+        // the entry is closed by the close constraint; nothing else to do
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct WithdrawTo<'info> {
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    #[account(mut)]
+    pub recipient: SystemAccount<'info>,
+    // the seeds tie the entry to this vault and this recipient; not needed for a
+    // recipient on the inline allowlist
+    #[account(
+        seeds = [b"allow", vault_state.key().as_ref(), recipient.key().as_ref()],
+        bump = allowlist_entry.bump,
+    )]
+    pub allowlist_entry: Option<Account<'info, AllowlistEntry>>,
+    /// CHECK: must match the vault's validation hook, checked in validate
+    pub validation_hook: Option<UncheckedAccount<'info>>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> WithdrawTo<'info> {
+    // the hook approves a send by returning success; any error it raises aborts the withdrawal
+    fn validate(&self, amount: u64) -> Result<()> {
+        let Some(expected) = self.vault_state.reserved.validation_hook()? else {
+            return Ok(());
+        };
+        let Some(hook) = &self.validation_hook else {
+            return err!(VaultError::ValidationHookMissing);
+        };
+        require_keys_eq!(hook.key(), expected, VaultError::ValidationHookMismatch);
+        let mut data = VALIDATE_TRANSFER_DISCRIMINATOR.to_vec();
+        data.extend_from_slice(&amount.to_le_bytes());
+        invoke(
+            &Instruction {
+                program_id: expected,
+                accounts: vec![
+                    AccountMeta::new_readonly(self.recipient.key(), false),
+                    AccountMeta::new_readonly(self.vault_state.key(), false),
+                ],
+                data,
+            },
+            &[
+                self.recipient.to_account_info(),
+                self.vault_state.to_account_info(),
+                hook.to_account_info(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn withdraw_to_pda(
+        &mut self,
+        amount: u64,
+        program_id: Pubkey,
+        seeds: &[Vec<u8>],
+    ) -> Result<()> {
+        let seeds: Vec<&[u8]> = seeds.iter().map(Vec::as_slice).collect();
+        // fails for too many or too long seeds, and for seeds that land on the curve
+        let derived = Pubkey::create_program_address(&seeds, &program_id)
+            .map_err(|_| error!(VaultError::InvalidPda))?;
+        require_keys_eq!(self.recipient.key(), derived, VaultError::InvalidPda);
+        self.withdraw_to(amount)
+    }
+
+    pub fn withdraw_to(&mut self, amount: u64) -> Result<()> {
+        check_funded(&self.vault)?;
+        self.vault_state.check_min_withdraw(amount)?;
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+        self.vault_state.check_not_paused()?;
+        self.vault_state.check_unlocked(&clock)?;
+        self.vault_state.check_no_release_condition()?;
+        self.vault_state
+            .check_vested(now, self.vault.lamports(), amount)?;
+        self.vault_state.check_approval_limit(amount)?;
+        require!(
+            self.vault_state.inline_allows(self.recipient.key())
+                || self
+                    .allowlist_entry
+                    .as_ref()
+                    .is_some_and(|entry| entry.is_active(now)),
+            VaultError::RecipientNotAllowed
+        );
+        self.vault_state.check_recipient(self.recipient.key())?;
+        self.validate(amount)?;
+        self.vault_state.record_withdrawal_size(amount)?;
+        self.vault_state.record_daily_withdrawal(now, amount)?;
+        self.vault_state.record_withdrawals(1)?;
+        transfer_from_vault(
+            &self.system_program,
+            &self.vault,
+            self.recipient.to_account_info(),
+            &mut self.vault_state,
+            amount,
+        )?;
+
+        if self.vault_state.verbose_events {
+            emit!(WithdrawEvent {
+                vault_state: self.vault_state.key(),
+                topic: self.vault_state.topic,
+                recipient: self.recipient.key(),
+                amount,
+            });
+        }
+        self.vault_state.update_rent_floor(&self.vault)?;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(receipt_id: u64)]
+pub struct DepositWithReceipt<'info> {
+    #[account(mut)]
+    pub depositor: Signer<'info>,
+    // no owner seeds: third parties deposit into someone else's vault
+    #[account(mut)]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    #[account(
+        init,
+        payer = depositor,
+        space = Receipt::INIT_SPACE,
+        seeds = [
+            b"receipt",
+            vault_state.key().as_ref(),
+            depositor.key().as_ref(),
+            receipt_id.to_le_bytes().as_ref(),
+        ],
+        bump
+    )]
+    pub receipt: Account<'info, Receipt>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> DepositWithReceipt<'info> {
+    pub fn deposit_with_receipt(
+        &mut self,
+        receipt_id: u64,
+        amount: u64,
+        bumps: &DepositWithReceiptBumps,
+    ) -> Result<()> {
+        self.vault_state.check_not_paused()?;
+        // a receipt carries no proof, so gated vaults only take deposit_with_proof
+        require!(
+            self.vault_state.deposit_merkle_root.is_none(),
+            VaultError::DepositProofRequired
+        );
+        // the receipt must describe where the lamports actually end up
+        require!(
+            self.vault_state.forward_to.is_none(),
+            VaultError::ForwardAccountsMissing
+        );
+        let amount = self.vault_state.round_deposit(amount)?;
+        self.vault_state.check_cap(self.vault.lamports(), amount)?;
+        self.vault_state
+            .record_window_deposit(Clock::get()?.unix_timestamp, amount)?;
+
+        transfer_to_vault(
+            &self.system_program,
+            self.depositor.to_account_info(),
+            &self.vault,
+            &mut self.vault_state,
+            amount,
+        )?;
+        self.vault_state.record_deposit(amount)?;
+        let unclaimed = self.vault_state.reserved.unclaimed_receipts()?;
+        self.vault_state
+            .reserved
+            .set_unclaimed_receipts(unclaimed.checked_add(amount).ok_or(VaultError::Overflow)?)?;
+
+        self.receipt.set_inner(Receipt {
+            vault_state: self.vault_state.key(),
+            depositor: self.depositor.key(),
+            receipt_id,
+            amount,
+            timestamp: Clock::get()?.unix_timestamp,
+            bump: bumps.receipt,
+        });
+
+        if self.vault_state.verbose_events {
+            emit!(DepositEvent {
+                vault_state: self.vault_state.key(),
+                topic: self.vault_state.topic,
+                user: self.depositor.key(),
+                amount,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct DepositWithProof<'info> {
+    #[account(mut)]
+    pub depositor: Signer<'info>,
+    // no owner seeds: third parties deposit into someone else's vault
+    #[account(mut)]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> DepositWithProof<'info> {
+    pub fn deposit_with_proof(&mut self, amount: u64, proof: &[[u8; 32]]) -> Result<()> {
+        let Some(root) = self.vault_state.deposit_merkle_root else {
+            return err!(VaultError::NoMerkleRoot);
+        };
+        require!(
+            proof.len() <= MAX_MERKLE_PROOF_LEN,
+            VaultError::InvalidMerkleProof
+        );
+        require!(
+            merkle_root_of(self.depositor.key(), proof) == root,
+            VaultError::InvalidMerkleProof
+        );
+        self.vault_state.check_not_paused()?;
+        // same restrictions as deposit_with_receipt
+        require!(
+            self.vault_state.forward_to.is_none(),
+            VaultError::ForwardAccountsMissing
+        );
+        let amount = self.vault_state.round_deposit(amount)?;
+        self.vault_state.check_cap(self.vault.lamports(), amount)?;
+        self.vault_state
+            .record_window_deposit(Clock::get()?.unix_timestamp, amount)?;
+        transfer_to_vault(
+            &self.system_program,
+            self.depositor.to_account_info(),
+            &self.vault,
+            &mut self.vault_state,
+            amount,
+        )?;
+        self.vault_state.record_deposit(amount)?;
+
+        if self.vault_state.verbose_events {
+            emit!(DepositEvent {
+                vault_state: self.vault_state.key(),
+                topic: self.vault_state.topic,
+                user: self.depositor.key(),
+                amount,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct CloseReceipt<'info> {
+    #[account(mut)]
+    pub depositor: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [
+            b"receipt",
+            receipt.vault_state.as_ref(),
+            depositor.key().as_ref(),
+            receipt.receipt_id.to_le_bytes().as_ref(),
+        ],
+        bump = receipt.bump,
+        close = depositor,
+    )]
+    pub receipt: Account<'info, Receipt>,
+}
+
+impl<'info> CloseReceipt<'info> {
+    pub fn close_receipt(&mut self) -> Result<()> {
+        // the receipt is closed by the close constraint; the deposit itself stays in the vault
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct TriggerRefund<'info> {
+    // anyone may trigger a refund, so the vault is not tied to a signer
+    #[account(mut)]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    #[account(mut, address = receipt.depositor @ VaultError::Unauthorized)]
+    pub depositor: SystemAccount<'info>,
+    #[account(
+        mut,
+        seeds = [
+            b"receipt",
+            vault_state.key().as_ref(),
+            receipt.depositor.as_ref(),
+            receipt.receipt_id.to_le_bytes().as_ref(),
+        ],
+        bump = receipt.bump,
+        close = depositor,
+    )]
+    pub receipt: Account<'info, Receipt>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> TriggerRefund<'info> {
+    pub fn trigger_refund(&mut self) -> Result<()> {
+        let after = self.vault_state.auto_refund_after;
+        require!(after > 0, VaultError::AutoRefundDisabled);
+        let now = Clock::get()?.unix_timestamp;
+        require!(
+            now >= self.receipt.timestamp.saturating_add(after),
+            VaultError::RefundNotDue
+        );
+        self.vault_state.check_not_paused()?;
+        // only receipted lamports the owner hasn't claimed go back, never the owner's own
+        // deposits; a partial refund leaves the vault rent exempt
+        let rent_exempt = Rent::get()?.minimum_balance(self.vault.data_len());
+        let unclaimed = self.vault_state.reserved.unclaimed_receipts()?;
+        let refund = self
+            .receipt
+            .amount
+            .min(unclaimed)
+            .min(self.vault.lamports().saturating_sub(rent_exempt));
+        self.vault_state
+            .reserved
+            .set_unclaimed_receipts(unclaimed - refund)?;
+        // the refunded lamports no longer count as deposited
+        self.vault_state.total_deposited = self.vault_state.total_deposited.saturating_sub(refund);
+        transfer_from_vault(
+            &self.system_program,
+            &self.vault,
+            self.depositor.to_account_info(),
+            &mut self.vault_state,
+            refund,
+        )?;
+        self.vault_state.update_rent_floor(&self.vault)?;
+        emit!(DepositRefunded {
+            vault_state: self.vault_state.key(),
+            depositor: self.depositor.key(),
+            receipt_id: self.receipt.receipt_id,
+            amount: refund,
+        });
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(delegate: Pubkey)]
+pub struct ApproveDepositor<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = DepositAllowance::INIT_SPACE,
+        seeds = [b"allowance", vault_state.key().as_ref(), delegate.as_ref()],
+        bump
+    )]
+    pub allowance: Account<'info, DepositAllowance>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> ApproveDepositor<'info> {
+    pub fn approve_depositor(
+        &mut self,
+        delegate: Pubkey,
+        max_amount: u64,
+        bumps: &ApproveDepositorBumps,
+    ) -> Result<()> {
+        self.allowance.set_inner(DepositAllowance {
+            delegate,
+            remaining: max_amount,
+            bump: bumps.allowance,
+        });
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct DepositDelegated<'info> {
+    #[account(mut)]
+    pub delegate: Signer<'info>,
+    #[account(mut)]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    #[account(
+        mut,
+        seeds = [b"allowance", vault_state.key().as_ref(), delegate.key().as_ref()],
+        bump = allowance.bump,
+    )]
+    pub allowance: Account<'info, DepositAllowance>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> DepositDelegated<'info> {
+    pub fn deposit_delegated(&mut self, amount: u64) -> Result<()> {
+        self.vault_state.check_not_paused()?;
+        // like receipts, a delegated deposit has to land in this vault
+        require!(
+            self.vault_state.forward_to.is_none(),
+            VaultError::ForwardAccountsMissing
+        );
+        let amount = self.vault_state.round_deposit(amount)?;
+        self.vault_state.check_cap(self.vault.lamports(), amount)?;
+        self.vault_state
+            .record_window_deposit(Clock::get()?.unix_timestamp, amount)?;
+        self.allowance.remaining = self
+            .allowance
+            .remaining
+            .checked_sub(amount)
+            .ok_or(VaultError::AllowanceExceeded)?;
+
+        transfer_to_vault(
+            &self.system_program,
+            self.delegate.to_account_info(),
+            &self.vault,
+            &mut self.vault_state,
+            amount,
+        )?;
+        self.vault_state.record_deposit(amount)?;
+
+        if self.vault_state.verbose_events {
+            emit!(DepositEvent {
+                vault_state: self.vault_state.key(),
+                topic: self.vault_state.topic,
+                user: self.delegate.key(),
+                amount,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct DepositMany<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> DepositMany<'info> {
+    // remaining_accounts: (vault_state, vault) per amount
+    pub fn deposit_many(
+        &mut self,
+        amounts: Vec<u64>,
+        mode: BatchMode,
+        remaining_accounts: &'info [AccountInfo<'info>],
+    ) -> Result<Vec<u8>> {
+        check_batch(remaining_accounts, amounts.len(), 2)?;
+
+        let mut skipped = Vec::new();
+        for (index, (amount, pair)) in amounts
+            .into_iter()
+            .zip(remaining_accounts.chunks_exact(2))
+            .enumerate()
+        {
+            let (mut vault_state, amount) = match self.check_item(amount, pair) {
+                Ok(item) => item,
+                Err(_) if mode == BatchMode::BestEffort => {
+                    skipped.push(index as u8);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            vault_state.accrue_twab(Clock::get()?.unix_timestamp, pair[1].lamports())?;
+            transfer(
+                CpiContext::new(
+                    self.system_program.to_account_info(),
+                    Transfer {
+                        from: self.user.to_account_info(),
+                        to: pair[1].clone(),
+                    },
+                ),
+                amount,
+            )?;
+            vault_state.record_deposit(amount)?;
+            if vault_state.verbose_events {
+                emit!(DepositEvent {
+                    vault_state: vault_state.key(),
+                    topic: vault_state.topic,
+                    user: self.user.key(),
+                    amount,
+                });
+            }
+            // accounts loaded by hand are not written back automatically
+            vault_state.exit(&crate::ID)?;
+        }
+        Ok(skipped)
+    }
+
+    // everything that can reject an item runs before any lamports move, so a skipped item
+    // leaves no trace; returns the loaded state and the amount to transfer
+    fn check_item(
+        &self,
+        amount: u64,
+        pair: &'info [AccountInfo<'info>],
+    ) -> Result<(Account<'info, VaultState>, u64)> {
+        let mut vault_state = Account::<VaultState>::try_from(&pair[0])?;
+        // batch deposits go to the signer's own vaults, like deposit
+        require_keys_eq!(vault_state.owner, self.user.key(), VaultError::Unauthorized);
+        require_keys_eq!(
+            pair[1].key(),
+            vault_address(&vault_state)?,
+            VaultError::InvalidPda
+        );
+        vault_state.check_not_paused()?;
+        // forwarding needs the target accounts, which a batch item does not carry
+        require!(
+            vault_state.forward_to.is_none(),
+            VaultError::ForwardAccountsMissing
+        );
+        let amount = vault_state.round_deposit(amount)?;
+        require!(
+            self.user.lamports() >= amount,
+            VaultError::InsufficientUserBalance
+        );
+        vault_state.check_cap(pair[1].lamports(), amount)?;
+        vault_state.record_window_deposit(Clock::get()?.unix_timestamp, amount)?;
+        Ok((vault_state, amount))
+    }
+}
+
+#[derive(Accounts)]
+pub struct WithdrawToMany<'info> {
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> WithdrawToMany<'info> {
+    // remaining_accounts: (recipient, allowlist_entry) per amount
+    pub fn withdraw_to_many(
+        &mut self,
+        amounts: Vec<u64>,
+        mode: BatchMode,
+        remaining_accounts: &'info [AccountInfo<'info>],
+    ) -> Result<Vec<u8>> {
+        check_batch(remaining_accounts, amounts.len(), 2)?;
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+        self.vault_state.check_not_paused()?;
+        self.vault_state.check_unlocked(&clock)?;
+        self.vault_state.check_no_release_condition()?;
+
+        // recipients are checked first so the vault-wide limits only count the items that pay out
+        let mut skipped = Vec::new();
+        let mut items = Vec::with_capacity(amounts.len());
+        for (index, (amount, pair)) in amounts
+            .into_iter()
+            .zip(remaining_accounts.chunks_exact(2))
+            .enumerate()
+        {
+            // each recipient is a separate withdrawal for the minimum and the spike check
+            match self
+                .check_recipient(pair, now)
+                .and_then(|()| self.vault_state.check_min_withdraw(amount))
+                .and_then(|()| self.vault_state.record_withdrawal_size(amount))
+            {
+                Ok(()) => items.push((amount, &pair[0])),
+                Err(_) if mode == BatchMode::BestEffort => skipped.push(index as u8),
+                Err(e) => return Err(e),
+            }
+        }
+        let total = items
+            .iter()
+            .try_fold(0u64, |sum, (amount, _)| sum.checked_add(*amount))
+            .ok_or(VaultError::Overflow)?;
+        self.vault_state
+            .check_vested(now, self.vault.lamports(), total)?;
+        self.vault_state.check_approval_limit(total)?;
+        self.vault_state.record_daily_withdrawal(now, total)?;
+        // every recipient is a separate distribution
+        self.vault_state.record_withdrawals(items.len() as u32)?;
+
+        for (amount, recipient) in items {
+            transfer_from_vault(
+                &self.system_program,
+                &self.vault,
+                recipient.clone(),
+                &mut self.vault_state,
+                amount,
+            )?;
+            if self.vault_state.verbose_events {
+                emit!(WithdrawEvent {
+                    vault_state: self.vault_state.key(),
+                    topic: self.vault_state.topic,
+                    recipient: recipient.key(),
+                    amount,
+                });
+            }
+        }
+        self.vault_state.update_rent_floor(&self.vault)?;
+        Ok(skipped)
+    }
+
+    fn check_recipient(&self, pair: &'info [AccountInfo<'info>], now: i64) -> Result<()> {
+        let (recipient, entry_info) = (&pair[0], &pair[1]);
+        let entry = Account::<AllowlistEntry>::try_from(entry_info)?;
+        // same seeds check WithdrawTo gets from its constraints
+        let expected_entry = Pubkey::create_program_address(
+            &[
+                b"allow",
+                self.vault_state.key().as_ref(),
+                recipient.key().as_ref(),
+                &[entry.bump],
+            ],
+            &crate::ID,
+        )
+        .map_err(|_| error!(VaultError::InvalidPda))?;
+        require_keys_eq!(entry_info.key(), expected_entry, VaultError::InvalidPda);
+        require!(entry.is_active(now), VaultError::RecipientNotAllowed);
+        self.vault_state.check_recipient(recipient.key())
+    }
+}
+
+#[derive(Accounts)]
+pub struct ImportLegacy<'info> {
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    // Account<LegacyVault> checks that this program owns it and that it carries the
+    // LegacyVault discriminator; close moves every lamport into the vault
+    #[account(
+        mut,
+        constraint = legacy.owner == user.key() @ VaultError::Unauthorized,
+        close = vault,
+    )]
+    pub legacy: Account<'info, LegacyVault>,
+}
+
+impl<'info> ImportLegacy<'info> {
+    pub fn import_legacy(&mut self) -> Result<()> {
+        self.vault_state.check_not_paused()?;
+        let amount = self.legacy.get_lamports();
+        self.vault_state.check_cap(self.vault.lamports(), amount)?;
+        // the legacy account is only closed into the vault after the handler returns
+        self.vault_state
+            .accrue_twab(Clock::get()?.unix_timestamp, self.vault.lamports())?;
+        self.vault_state.record_deposit(amount)
+    }
+}
+
+#[derive(Accounts)]
+pub struct OpenStream<'info> {
+    #[account(mut)]
+    pub source: Signer<'info>,
+    #[account(
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    // one stream per (vault, source); it holds the escrow itself
+    #[account(
+        init,
+        payer = source,
+        space = Stream::INIT_SPACE,
+        seeds = [b"stream", vault_state.key().as_ref(), source.key().as_ref()],
+        bump
+    )]
+    pub stream: Account<'info, Stream>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> OpenStream<'info> {
+    pub fn open_stream(
+        &mut self,
+        rate_per_second: u64,
+        total: u64,
+        bumps: &OpenStreamBumps,
+    ) -> Result<()> {
+        require!(rate_per_second > 0 && total > 0, VaultError::InvalidAmount);
+        transfer(
+            CpiContext::new(
+                self.system_program.to_account_info(),
+                Transfer {
+                    from: self.source.to_account_info(),
+                    to: self.stream.to_account_info(),
+                },
+            ),
+            total,
+        )?;
+        let now = Clock::get()?.unix_timestamp;
+        self.stream.set_inner(Stream {
+            vault_state: self.vault_state.key(),
+            source: self.source.key(),
+            rate_per_second,
+            total,
+            claimed: 0,
+            start: now,
+            last_claim_at: now,
+            bump: bumps.stream,
+        });
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct ClaimStream<'info> {
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    #[account(
+        mut,
+        seeds = [b"stream", vault_state.key().as_ref(), stream.source.as_ref()],
+        bump = stream.bump,
+    )]
+    pub stream: Account<'info, Stream>,
+}
+
+impl<'info> ClaimStream<'info> {
+    pub fn claim_stream(&mut self) -> Result<u64> {
+        self.vault_state.check_not_paused()?;
+        let now = Clock::get()?.unix_timestamp;
+        let amount = self.stream.accrued(now) - self.stream.claimed;
+        self.vault_state.check_cap(self.vault.lamports(), amount)?;
+        self.vault_state.accrue_twab(now, self.vault.lamports())?;
+        // the stream is owned by this program, so its escrow can be debited directly
+        self.stream.sub_lamports(amount)?;
+        self.vault.add_lamports(amount)?;
+        self.stream.claimed += amount;
+        self.stream.last_claim_at = now;
+        self.vault_state.record_deposit(amount)?;
+        Ok(amount)
+    }
+}
+
+#[derive(Accounts)]
+pub struct Donate<'info> {
+    #[account(mut)]
+    pub donor: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> Donate<'info> {
+    pub fn donate(&mut self, amount: u64) -> Result<()> {
+        require!(amount > 0, VaultError::InvalidAmount);
+        self.vault_state.check_not_paused()?;
+        self.vault_state.check_cap(self.vault.lamports(), amount)?;
+        transfer_to_vault(
+            &self.system_program,
+            self.donor.to_account_info(),
+            &self.vault,
+            &mut self.vault_state,
+            amount,
+        )?;
+        self.vault_state.donated_total = self
+            .vault_state
+            .donated_total
+            .checked_add(amount)
+            .ok_or(VaultError::Overflow)?;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct FundRewardPool<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    // the first transfer must cover the rent-exempt minimum, like the vault itself
+    #[account(
+        mut,
+        seeds = [b"reward_pool", vault_state.key().as_ref()],
+        bump
+    )]
+    pub reward_pool: SystemAccount<'info>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> FundRewardPool<'info> {
+    pub fn fund_reward_pool(&mut self, amount: u64) -> Result<()> {
+        let cpi_program = self.system_program.to_account_info();
+        let cpi_account = Transfer {
+            from: self.user.to_account_info(),
+            to: self.reward_pool.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_account);
+        transfer(cpi_ctx, amount)
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(coupon_id: u64)]
+pub struct IssueCoupon<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        init,
+        payer = user,
+        space = CouponState::INIT_SPACE,
+        seeds = [b"coupon", vault_state.key().as_ref(), coupon_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub coupon: Account<'info, CouponState>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> IssueCoupon<'info> {
+    pub fn issue_coupon(
+        &mut self,
+        coupon_id: u64,
+        amount: u64,
+        bumps: &IssueCouponBumps,
+    ) -> Result<()> {
+        require!(amount > 0, VaultError::InvalidAmount);
+        self.coupon.set_inner(CouponState {
+            vault_state: self.vault_state.key(),
+            coupon_id,
+            amount,
+            redeemed: false,
+            bump: bumps.coupon,
+        });
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct RedeemCoupon<'info> {
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    #[account(
+        mut,
+        seeds = [b"reward_pool", vault_state.key().as_ref()],
+        bump
+    )]
+    pub reward_pool: SystemAccount<'info>,
+    #[account(
+        mut,
+        seeds = [b"coupon", vault_state.key().as_ref(), coupon.coupon_id.to_le_bytes().as_ref()],
+        bump = coupon.bump,
+    )]
+    pub coupon: Account<'info, CouponState>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> RedeemCoupon<'info> {
+    pub fn redeem_coupon(&mut self, bumps: &RedeemCouponBumps) -> Result<()> {
+        require!(!self.coupon.redeemed, VaultError::AlreadyRedeemed);
+        // mark first so the coupon can never be counted twice, even if more logic is added below
+        self.coupon.redeemed = true;
+        self.vault_state
+            .accrue_twab(Clock::get()?.unix_timestamp, self.vault.lamports())?;
+
+        let cpi_program = self.system_program.to_account_info();
+        let cpi_account = Transfer {
+            from: self.reward_pool.to_account_info(),
+            to: self.vault.to_account_info(),
+        };
+        let vault_state_key = self.vault_state.key();
+        let seeds = &[
+            b"reward_pool",
+            vault_state_key.as_ref(),
+            &[bumps.reward_pool],
+        ];
+        let signer_seeds = &[&seeds[..]];
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_account, signer_seeds);
+        transfer(cpi_ctx, self.coupon.amount)
+    }
+}
+
+#[derive(Accounts)]
+pub struct WithdrawNet<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    // must sign so the reimbursement can only go to whoever actually pays the transaction fee
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> WithdrawNet<'info> {
+    pub fn withdraw_net(&mut self, desired_net: u64, fee_estimate: u64) -> Result<()> {
+        check_funded(&self.vault)?;
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+        self.vault_state.check_self_withdraw_not_paused()?;
+        self.vault_state.check_unlocked(&clock)?;
+        self.vault_state.check_no_release_condition()?;
+        require!(
+            fee_estimate <= MAX_FEE_REIMBURSEMENT,
+            VaultError::FeeEstimateTooHigh
+        );
+        // gross = what actually leaves the vault, checked up front for a clear error
+        let gross = desired_net
+            .checked_add(fee_estimate)
+            .ok_or(VaultError::Overflow)?;
+        require!(
+            self.vault.lamports() >= gross,
+            VaultError::InsufficientVaultBalance
+        );
+        self.vault_state
+            .check_vested(now, self.vault.lamports(), gross)?;
+        self.vault_state.check_min_withdraw(gross)?;
+        self.vault_state.check_approval_limit(gross)?;
+        self.vault_state.record_withdrawal_size(gross)?;
+        self.vault_state.record_daily_withdrawal(now, gross)?;
+        self.vault_state.record_withdrawals(1)?;
+
+        transfer_from_vault(
+            &self.system_program,
+            &self.vault,
+            self.user.to_account_info(),
+            &mut self.vault_state,
+            desired_net,
+        )?;
+        if fee_estimate > 0 {
+            transfer_from_vault(
+                &self.system_program,
+                &self.vault,
+                self.fee_payer.to_account_info(),
+                &mut self.vault_state,
+                fee_estimate,
+            )?;
+        }
+        self.vault_state.update_rent_floor(&self.vault)?;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct WithdrawWithPenalty<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    /// CHECK: only the address matters; lamports sent here are burned by the runtime
+    #[account(mut, address = incinerator::ID)]
+    pub incinerator: UncheckedAccount<'info>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> WithdrawWithPenalty<'info> {
+    pub fn withdraw_with_penalty(&mut self, amount: u64, penalty_bps: u16) -> Result<()> {
+        check_funded(&self.vault)?;
+        self.vault_state.check_min_withdraw(amount)?;
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+        self.vault_state.check_self_withdraw_not_paused()?;
+        self.vault_state.check_unlocked(&clock)?;
+        self.vault_state.check_no_release_condition()?;
+        self.vault_state
+            .check_vested(now, self.vault.lamports(), amount)?;
+        self.vault_state.check_approval_limit(amount)?;
+        self.vault_state.record_withdrawal_size(amount)?;
+        self.vault_state.record_daily_withdrawal(now, amount)?;
+        self.vault_state.record_withdrawals(1)?;
+        let penalty = bps_of(amount, penalty_bps)?;
+        let net = amount - penalty;
+
+        transfer_from_vault(
+            &self.system_program,
+            &self.vault,
+            self.user.to_account_info(),
+            &mut self.vault_state,
+            net,
+        )?;
+        if penalty > 0 {
+            transfer_from_vault(
+                &self.system_program,
+                &self.vault,
+                self.incinerator.to_account_info(),
+                &mut self.vault_state,
+                penalty,
+            )?;
+        }
+
+        if self.vault_state.verbose_events {
+            emit!(WithdrawEvent {
+                vault_state: self.vault_state.key(),
+                topic: self.vault_state.topic,
+                recipient: self.user.key(),
+                amount: net,
+            });
+        }
+        self.vault_state.update_rent_floor(&self.vault)?;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct SnapshotBalance<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    // one snapshot per vault, overwritten on every call
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = BalanceSnapshot::INIT_SPACE,
+        seeds = [b"snapshot", vault_state.key().as_ref()],
+        bump
+    )]
+    pub snapshot: Account<'info, BalanceSnapshot>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> SnapshotBalance<'info> {
+    pub fn snapshot_balance(&mut self, bumps: &SnapshotBalanceBumps) -> Result<()> {
+        let clock = Clock::get()?;
+        // lamports are read directly from the vault account, so the snapshot reflects every
+        // transfer that happened earlier in the transaction
+        self.snapshot.set_inner(BalanceSnapshot {
+            vault_state: self.vault_state.key(),
+            balance: self.vault.lamports(),
+            slot: clock.slot,
+            timestamp: clock.unix_timestamp,
+            bump: bumps.snapshot,
+        });
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct MigrateState<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    // realloc moves the rent above the new minimum back to user. Only shrinking can happen
+    // here: Account<VaultState> fails to deserialize an account shorter than the current
+    // layout, so realloc::payer is never charged
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+        realloc = VaultState::INIT_SPACE,
+        realloc::payer = user,
+        realloc::zero = false,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> MigrateState<'info> {
+    pub fn migrate_state(&mut self) -> Result<()> {
+        // the resize happens in the constraint; the exit write-back serializes the current layout
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct MigrateToV2<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    // the v2 state keeps the creator and index, so the registry entry carries over and init
+    // fails on a second migration; state_seed_prefix lets every instruction load it
+    #[account(
+        init,
+        payer = user,
+        space = VaultState::INIT_SPACE,
+        seeds = [b"state_v2", vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub new_vault_state: Account<'info, VaultState>,
+    // the SEED_VERSION_STATE derivation from the new state, which vault_seed_key signs for
+    #[account(
+        mut,
+        seeds = [b"vault", new_vault_state.key().as_ref()],
+        bump
+    )]
+    pub new_vault: SystemAccount<'info>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> MigrateToV2<'info> {
+    pub fn migrate_to_v2(&mut self, bumps: &MigrateToV2Bumps) -> Result<()> {
+        self.vault_state.check_not_paused()?;
+        // staked lamports are not in the vault and would be left behind
+        require!(
+            self.vault_state.stake_account.is_none(),
+            VaultError::StakeAlreadyActive
+        );
+        require!(
+            self.vault_state.seed_version != SEED_VERSION_V2,
+            VaultError::VaultMigrated
+        );
+        let mut migrated = (*self.vault_state).clone();
+        migrated.seed_version = SEED_VERSION_V2;
+        migrated.state_bump = bumps.new_vault_state;
+        migrated.vault_bump = bumps.new_vault;
+        self.new_vault_state.set_inner(migrated);
+
+        // the whole balance moves, so the new vault starts out rent exempt like the old one
+        let amount = self.vault.lamports();
+        transfer_from_vault(
+            &self.system_program,
+            &self.vault,
+            self.new_vault.to_account_info(),
+            &mut self.vault_state,
+            amount,
+        )?;
+        self.vault_state.migrated_to = Some(self.new_vault_state.key());
+        emit!(Migrated {
+            from: self.vault_state.key(),
+            to: self.new_vault_state.key(),
+            amount,
+        });
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(new_index: u8)]
+pub struct SplitVault<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    // init fails if new_index is already in use, so a split can never merge into an existing vault
+    #[account(
+        init,
+        payer = user,
+        space = VaultState::INIT_SPACE,
+        seeds = [b"state", user.key().as_ref(), new_index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub new_vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"vault", new_vault_state.key().as_ref()],
+        bump
+    )]
+    pub new_vault: SystemAccount<'info>,
+    // tracks which indices the user has open; created together with the user's first vault
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserRegistry::INIT_SPACE,
+        seeds = [b"registry", user.key().as_ref()],
+        bump
+    )]
+    pub registry: Account<'info, UserRegistry>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> SplitVault<'info> {
+    pub fn split_vault(
+        &mut self,
+        new_index: u8,
+        amount: u64,
+        bumps: &SplitVaultBumps,
+    ) -> Result<()> {
+        require!(amount > 0, VaultError::InvalidAmount);
+        // the new vault starts unlocked and without limits, so the split goes through the same
+        // checks and counters as a withdrawal; otherwise splitting everything out and
+        // withdrawing from the new vault would bypass them
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+        self.vault_state.check_min_withdraw(amount)?;
+        self.vault_state.check_not_paused()?;
+        self.vault_state.check_min_age(now)?;
+        self.vault_state.check_unlocked(&clock)?;
+        self.vault_state.check_no_release_condition()?;
+        self.vault_state
+            .check_vested(now, self.vault.lamports(), amount)?;
+        self.vault_state.check_approval_limit(amount)?;
+        self.vault_state.record_withdrawal_size(amount)?;
+        self.vault_state.record_daily_withdrawal(now, amount)?;
+        self.vault_state.record_withdrawals(1)?;
+
+        // rent for the new vault comes from the user, exactly like initialize
+        let rent_exempt = Rent::get()?.minimum_balance(self.new_vault.to_account_info().data_len());
+        let cpi_ctx = CpiContext::new(
+            self.system_program.to_account_info(),
+            Transfer {
+                from: self.user.to_account_info(),
+                to: self.new_vault.to_account_info(),
+            },
+        );
+        transfer(cpi_ctx, rent_exempt)?;
+
+        self.new_vault_state.set_inner(VaultState::new(
+            self.user.key(),
+            new_index,
+            bumps.new_vault_state,
+            bumps.new_vault,
+            Clock::get()?.unix_timestamp,
+        ));
+        self.registry
+            .mark_open(self.user.key(), new_index, bumps.registry);
+        emit!(VaultInitialized {
+            vault_state: self.new_vault_state.key(),
+            owner: self.user.key(),
+            index: new_index,
+        });
+
+        transfer_from_vault(
+            &self.system_program,
+            &self.vault,
+            self.new_vault.to_account_info(),
+            &mut self.vault_state,
+            amount,
+        )?;
+
+        // the moved lamports stop counting for the source and start counting for the new vault
+        self.vault_state.total_deposited = self.vault_state.total_deposited.saturating_sub(amount);
+        self.new_vault_state.record_deposit(amount)
+    }
+}
+
+#[derive(Accounts)]
+pub struct InitTokenVault<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    pub mint: Account<'info, Mint>,
+    // one token vault per vault_state; the vault PDA is its authority so it signs
+    // token transfers with the same seeds as lamport transfers
+    #[account(
+        init,
+        payer = user,
+        seeds = [b"token_vault", vault_state.key().as_ref()],
+        bump,
+        token::mint = mint,
+        token::authority = vault,
+    )]
+    pub token_vault: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> InitTokenVault<'info> {
+    pub fn init_token_vault(&mut self, bumps: &InitTokenVaultBumps) -> Result<()> {
+        require!(!self.vault_state.mint_locked, VaultError::MintLocked);
+        self.vault_state.token_mint = self.mint.key();
+        self.vault_state.token_vault_bump = bumps.token_vault;
+        Ok(())
+    }
+
+    pub fn convert_to_token_vault(&mut self, bumps: &InitTokenVaultBumps) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        require!(
+            self.vault_state.token_mint == Pubkey::default(),
+            VaultError::TokenVaultExists
+        );
+        // lamports above the rent floor, or delegated to a stake account, are still the
+        // owner's SOL and have to be withdrawn first
+        let floor = Rent::get()?.minimum_balance(self.vault.data_len());
+        require!(
+            self.vault.lamports() <= floor && self.vault_state.stake_account.is_none(),
+            VaultError::VaultNotEmpty
+        );
+        self.init_token_vault(bumps)
+    }
+}
+
+#[derive(Accounts)]
+pub struct CloseTokenVault<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    #[account(
+        mut,
+        seeds = [b"token_vault", vault_state.key().as_ref()],
+        bump = vault_state.token_vault_bump,
+    )]
+    pub token_vault: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+impl<'info> CloseTokenVault<'info> {
+    pub fn close_token_vault(&mut self) -> Result<()> {
+        require!(!self.vault_state.mint_locked, VaultError::MintLocked);
+        require!(self.token_vault.amount == 0, VaultError::VaultNotEmpty);
+        let seeds = &[
+            b"vault",
+            vault_seed_key(&self.vault_state),
+            vault_seed_tail(&self.vault_state),
+            &[self.vault_state.vault_bump],
+        ];
+        token::close_account(CpiContext::new_with_signer(
+            self.token_program.to_account_info(),
+            CloseAccount {
+                account: self.token_vault.to_account_info(),
+                destination: self.user.to_account_info(),
+                authority: self.vault.to_account_info(),
+            },
+            &[&seeds[..]],
+        ))?;
+        self.vault_state.token_mint = Pubkey::default();
+        self.vault_state.token_vault_bump = 0;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct DepositFromSwap<'info> {
+    pub user: Signer<'info>,
+    #[account(
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(address = vault_state.token_mint)]
+    pub mint: Account<'info, Mint>,
+    #[account(
+        mut,
+        seeds = [b"token_vault", vault_state.key().as_ref()],
+        bump = vault_state.token_vault_bump,
+    )]
+    pub token_vault: Account<'info, TokenAccount>,
+    // the account the swap paid out to; the mint is checked in the handler for a clear error
+    #[account(mut, token::authority = user)]
+    pub swap_output: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+impl<'info> DepositFromSwap<'info> {
+    pub fn deposit_from_swap(&mut self) -> Result<u64> {
+        self.vault_state.check_not_paused()?;
+        require_keys_eq!(
+            self.swap_output.mint,
+            self.vault_state.token_mint,
+            VaultError::MintMismatch
+        );
+        // earlier instructions in the transaction have already run, so this includes the swap output
+        let amount = self.swap_output.amount;
+        require!(amount > 0, VaultError::InvalidAmount);
+        self.vault_state
+            .check_token_limits(self.token_vault.amount, amount)?;
+
+        token::transfer_checked(
+            CpiContext::new(
+                self.token_program.to_account_info(),
+                TransferChecked {
+                    from: self.swap_output.to_account_info(),
+                    mint: self.mint.to_account_info(),
+                    to: self.token_vault.to_account_info(),
+                    authority: self.user.to_account_info(),
+                },
+            ),
+            amount,
+            self.mint.decimals,
+        )?;
+        Ok(amount)
+    }
+}
+
+#[derive(Accounts)]
+pub struct SetTokenLimits<'info> {
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    // only the vault's own mint can supply the decimals
+    #[account(address = vault_state.token_mint @ VaultError::MintMismatch)]
+    pub mint: Account<'info, Mint>,
+}
+
+impl<'info> SetTokenLimits<'info> {
+    pub fn set_token_limits(&mut self, cap_tokens: u64, min_deposit_tokens: u64) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        let unit = 10u64
+            .checked_pow(u32::from(self.mint.decimals))
+            .ok_or(VaultError::Overflow)?;
+        let token_deposit_cap = cap_tokens.checked_mul(unit).ok_or(VaultError::Overflow)?;
+        let token_min_deposit = min_deposit_tokens
+            .checked_mul(unit)
+            .ok_or(VaultError::Overflow)?;
+        let key = self.vault_state.key();
+        emit_limit_change(
+            key,
+            LimitField::TokenDepositCap,
+            self.vault_state.token_deposit_cap,
+            token_deposit_cap,
+        );
+        emit_limit_change(
+            key,
+            LimitField::TokenMinDeposit,
+            self.vault_state.token_min_deposit,
+            token_min_deposit,
+        );
+        self.vault_state.token_deposit_cap = token_deposit_cap;
+        self.vault_state.token_min_deposit = token_min_deposit;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct ConfigureMultisig<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = MultisigConfig::INIT_SPACE,
+        seeds = [b"multisig", vault_state.key().as_ref()],
+        bump
+    )]
+    pub multisig: Account<'info, MultisigConfig>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> ConfigureMultisig<'info> {
+    pub fn configure_multisig(
+        &mut self,
+        signers: Vec<Pubkey>,
+        threshold: u8,
+        approval_above: u64,
+        bumps: &ConfigureMultisigBumps,
+    ) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        require!(
+            !signers.is_empty() && signers.len() <= MAX_MULTISIG_SIGNERS,
+            VaultError::InvalidMultisig
+        );
+        require!(
+            threshold > 0 && usize::from(threshold) <= signers.len(),
+            VaultError::InvalidMultisig
+        );
+        for (i, signer) in signers.iter().enumerate() {
+            require!(!signers[..i].contains(signer), VaultError::InvalidMultisig);
+        }
+
+        let mut slots = [Pubkey::default(); MAX_MULTISIG_SIGNERS];
+        slots[..signers.len()].copy_from_slice(&signers);
+        self.multisig.set_inner(MultisigConfig {
+            vault_state: self.vault_state.key(),
+            signers: slots,
+            signer_count: signers.len() as u8,
+            threshold,
+            // approvals gathered under the previous signer set no longer count
+            nonce: self.multisig.nonce.wrapping_add(1),
+            bump: bumps.multisig,
+        });
+        self.vault_state.approval_above = Some(approval_above);
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct ApproveWithdrawal<'info> {
+    #[account(mut)]
+    pub signer: Signer<'info>,
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        seeds = [b"multisig", vault_state.key().as_ref()],
+        bump = multisig.bump,
+    )]
+    pub multisig: Account<'info, MultisigConfig>,
+    // a single pending approval per vault, reused for every gated withdrawal
+    #[account(
+        init_if_needed,
+        payer = signer,
+        space = WithdrawApproval::INIT_SPACE,
+        seeds = [b"approval", vault_state.key().as_ref()],
+        bump
+    )]
+    pub approval: Account<'info, WithdrawApproval>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> ApproveWithdrawal<'info> {
+    pub fn approve_withdrawal(
+        &mut self,
+        amount: u64,
+        bumps: &ApproveWithdrawalBumps,
+    ) -> Result<()> {
+        let index = self
+            .multisig
+            .signer_index(&self.signer.key())
+            .ok_or(VaultError::NotMultisigSigner)?;
+        // start a new approval if none is pending, otherwise join the pending one
+        if self.approval.approvals == 0 || self.approval.nonce != self.multisig.nonce {
+            self.approval.set_inner(WithdrawApproval {
+                vault_state: self.vault_state.key(),
+                amount,
+                nonce: self.multisig.nonce,
+                approvals: 0,
+                bump: bumps.approval,
+            });
+        }
+        require!(self.approval.amount == amount, VaultError::ApprovalMismatch);
+        self.approval.approvals |= 1 << index;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct InitActivityLog<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        init,
+        payer = user,
+        space = ActivityLog::INIT_SPACE,
+        seeds = [b"log", vault_state.key().as_ref()],
+        bump
+    )]
+    pub activity_log: Account<'info, ActivityLog>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> InitActivityLog<'info> {
+    pub fn init_activity_log(&mut self, bumps: &InitActivityLogBumps) -> Result<()> {
+        self.activity_log.set_inner(ActivityLog {
+            vault_state: self.vault_state.key(),
+            bump: bumps.activity_log,
+            ..Default::default()
+        });
+        self.vault_state.activity_log_enabled = true;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct SetNotify<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = NotifyMarker::INIT_SPACE,
+        seeds = [b"notify", vault_state.key().as_ref()],
+        bump
+    )]
+    pub notify_marker: Account<'info, NotifyMarker>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> SetNotify<'info> {
+    pub fn set_notify(&mut self, notify: Option<Pubkey>, bumps: &SetNotifyBumps) -> Result<()> {
+        // the sequence keeps counting across watcher changes so it never repeats
+        self.notify_marker.vault_state = self.vault_state.key();
+        self.notify_marker.watcher = notify;
+        self.notify_marker.bump = bumps.notify_marker;
+        self.vault_state.notify = notify;
+        Ok(())
+    }
+}
+
+// shared by every owner-only instruction that only edits vault_state
+#[derive(Accounts)]
+pub struct Configure<'info> {
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+}
+
+impl<'info> Configure<'info> {
+    pub fn set_forward_to(&mut self, forward_to: Option<Pubkey>) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        // a vault forwarding to itself would never hold or deliver anything
+        require!(
+            forward_to != Some(self.vault_state.key()),
+            VaultError::ForwardingDepthExceeded
+        );
+        self.vault_state.forward_to = forward_to;
+        Ok(())
+    }
+
+    pub fn set_verbose_events(&mut self, verbose_events: bool) -> Result<()> {
+        self.vault_state.verbose_events = verbose_events;
+        Ok(())
+    }
+
+    pub fn set_unlock_at(&mut self, unlock_at: i64) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        require!(
+            unlock_at >= self.vault_state.unlock_at,
+            VaultError::LockShortened
+        );
+        self.vault_state.unlock_at = unlock_at;
+        Ok(())
+    }
+
+    pub fn set_deposit_extends_lock(
+        &mut self,
+        enabled: bool,
+        extension_seconds: i64,
+        max_lock_seconds: i64,
+    ) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        if enabled {
+            require!(
+                self.vault_state.lock_mode == LockMode::Timestamp,
+                VaultError::LockModeMismatch
+            );
+            require!(
+                extension_seconds > 0 && max_lock_seconds >= extension_seconds,
+                VaultError::InvalidDuration
+            );
+        }
+        self.vault_state.deposit_extends_lock = enabled;
+        self.vault_state.lock_extension_seconds = extension_seconds;
+        self.vault_state.max_lock_seconds = max_lock_seconds;
+        Ok(())
+    }
+
+    pub fn set_unlock_slot(&mut self, unlock_slot: u64) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        require!(
+            unlock_slot >= self.vault_state.unlock_slot,
+            VaultError::LockShortened
+        );
+        self.vault_state.unlock_slot = unlock_slot;
+        Ok(())
+    }
+
+    pub fn set_lock_mode(&mut self, lock_mode: LockMode) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        // switching modes while locked could swap a long lock for an expired one
+        require!(
+            !self.vault_state.is_locked(&Clock::get()?),
+            VaultError::VaultLocked
+        );
+        self.vault_state.lock_mode = lock_mode;
+        Ok(())
+    }
+
+    pub fn set_allow_early_withdraw(&mut self, allow_early_withdraw: bool) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        self.vault_state.allow_early_withdraw = allow_early_withdraw;
+        Ok(())
+    }
+
+    pub fn set_vesting(
+        &mut self,
+        vesting_start: i64,
+        vesting_duration: i64,
+        vesting_amount: u64,
+    ) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        require!(
+            self.vault_state.vesting_duration == 0,
+            VaultError::VestingAlreadySet
+        );
+        require!(vesting_duration > 0, VaultError::InvalidDuration);
+        self.vault_state.vesting_start = vesting_start;
+        self.vault_state.vesting_duration = vesting_duration;
+        self.vault_state.vesting_amount = vesting_amount;
+        Ok(())
+    }
+
+    pub fn set_round_deposits_to(&mut self, round_deposits_to: u64) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        self.vault_state.round_deposits_to = round_deposits_to;
+        Ok(())
+    }
+
+    pub fn set_allow_authority_recovery(&mut self, allow: bool) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        self.vault_state.allow_authority_recovery = allow;
+        Ok(())
+    }
+
+    pub fn lock_mint(&mut self) -> Result<()> {
+        require!(
+            self.vault_state.token_mint != Pubkey::default(),
+            VaultError::NoTokenVault
+        );
+        self.vault_state.mint_locked = true;
+        Ok(())
+    }
+
+    pub fn set_referral_fee(&mut self, referral_fee_bps: u16) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        require!(
+            u64::from(referral_fee_bps) <= BPS_DENOMINATOR,
+            VaultError::InvalidBps
+        );
+        self.vault_state
+            .reserved
+            .set_referral_fee_bps(referral_fee_bps)
+    }
+
+    pub fn set_deposit_bonus(&mut self, bonus_bps: u16, bonus_cap: u64) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        require!(
+            u64::from(bonus_bps) <= BPS_DENOMINATOR,
+            VaultError::InvalidBps
+        );
+        self.vault_state.deposit_bonus_bps = bonus_bps;
+        self.vault_state.bonus_cap = bonus_cap;
+        Ok(())
+    }
+
+    pub fn set_auto_refund(&mut self, seconds: i64) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        require!(seconds >= 0, VaultError::InvalidDuration);
+        self.vault_state.auto_refund_after = seconds;
+        Ok(())
+    }
+
+    pub fn set_release_condition(&mut self, condition: Option<ReleaseCondition>) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        self.vault_state.release_condition = condition;
+        Ok(())
+    }
+
+    pub fn arm_close(&mut self, code: [u8; 4]) -> Result<()> {
+        require!(
+            self.vault_state.pending_owner.is_none(),
+            VaultError::PendingOperationExists
+        );
+        self.vault_state.close_code = Some(code);
+        Ok(())
+    }
+
+    pub fn propose_owner(&mut self, new_owner: Option<Pubkey>) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        require!(
+            new_owner.is_none() || self.vault_state.close_code.is_none(),
+            VaultError::PendingOperationExists
+        );
+        self.vault_state.pending_owner = new_owner;
+        Ok(())
+    }
+
+    pub fn set_max_lifetime_withdrawals(&mut self, max_lifetime_withdrawals: u32) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        emit_limit_change(
+            self.vault_state.key(),
+            LimitField::MaxLifetimeWithdrawals,
+            u64::from(self.vault_state.max_lifetime_withdrawals),
+            u64::from(max_lifetime_withdrawals),
+        );
+        self.vault_state.max_lifetime_withdrawals = max_lifetime_withdrawals;
+        Ok(())
+    }
+
+    pub fn set_period_deposit_cap(
+        &mut self,
+        period_deposit_cap: u64,
+        period_seconds: i64,
+    ) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        require!(
+            period_deposit_cap == 0 || period_seconds > 0,
+            VaultError::InvalidDuration
+        );
+        emit_limit_change(
+            self.vault_state.key(),
+            LimitField::PeriodDepositCap,
+            self.vault_state.period_deposit_cap,
+            period_deposit_cap,
+        );
+        self.vault_state.period_deposit_cap = period_deposit_cap;
+        self.vault_state.deposit_period_seconds = period_seconds;
+        Ok(())
+    }
+
+    pub fn set_campaign(&mut self, start: i64, end: i64) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        require!(end == 0 || end > start, VaultError::InvalidDuration);
+        self.vault_state.campaign_start = start;
+        self.vault_state.campaign_end = end;
+        Ok(())
+    }
+
+    pub fn set_goal(&mut self, goal: u64) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        self.vault_state.goal = goal;
+        self.vault_state.goal_reached = false;
+        Ok(())
+    }
+
+    pub fn set_deposit_cap(&mut self, deposit_cap: u64) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        emit_limit_change(
+            self.vault_state.key(),
+            LimitField::DepositCap,
+            self.vault_state.deposit_cap,
+            deposit_cap,
+        );
+        self.vault_state.deposit_cap = deposit_cap;
+        Ok(())
+    }
+
+    pub fn set_withdraw_limit(&mut self, limit: u64, window_seconds: i64) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        require!(
+            limit == 0 || window_seconds > 0,
+            VaultError::InvalidDuration
+        );
+        emit_limit_change(
+            self.vault_state.key(),
+            LimitField::DailyWithdrawLimit,
+            self.vault_state.daily_withdraw_limit,
+            limit,
+        );
+        self.vault_state.daily_withdraw_limit = limit;
+        self.vault_state.withdraw_window_seconds = window_seconds;
+        Ok(())
+    }
+
+    pub fn set_symbol(&mut self, symbol: [u8; 8]) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        check_symbol(&symbol)?;
+        self.vault_state.symbol = symbol;
+        // like LabelChanged, always emitted so display caches stay in sync
+        emit!(SymbolChanged {
+            vault_state: self.vault_state.key(),
+            new_symbol: symbol,
+        });
+        Ok(())
+    }
+
+    pub fn set_label(&mut self, label: [u8; 32]) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        self.vault_state.label = label;
+        // emitted regardless of verbose_events so off-chain name caches never go stale
+        emit!(LabelChanged {
+            vault_state: self.vault_state.key(),
+            new_label: label,
+        });
+        Ok(())
+    }
+
+    pub fn set_min_deposit(&mut self, min_deposit: u64) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        emit_limit_change(
+            self.vault_state.key(),
+            LimitField::MinDeposit,
+            self.vault_state.min_deposit,
+            min_deposit,
+        );
+        self.vault_state.min_deposit = min_deposit;
+        Ok(())
+    }
+
+    pub fn set_min_withdraw(&mut self, min_withdraw: u64) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        emit_limit_change(
+            self.vault_state.key(),
+            LimitField::MinWithdraw,
+            self.vault_state.min_withdraw,
+            min_withdraw,
+        );
+        self.vault_state.min_withdraw = min_withdraw;
+        Ok(())
+    }
+
+    pub fn rotate_and_lock(&mut self, new_owner: Pubkey, lock_seconds: i64) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        require!(lock_seconds > 0, VaultError::InvalidDuration);
+        // the fresh lock is a timestamp lock; in slot mode it would have no effect
+        require!(
+            self.vault_state.lock_mode == LockMode::Timestamp,
+            VaultError::LockModeMismatch
+        );
+        let unlock_at = Clock::get()?
+            .unix_timestamp
+            .checked_add(lock_seconds)
+            .ok_or(VaultError::Overflow)?;
+        // same rule as set_unlock_at: a handoff never shortens an existing lock
+        require!(
+            unlock_at >= self.vault_state.unlock_at,
+            VaultError::LockShortened
+        );
+        self.vault_state.owner = new_owner;
+        self.vault_state.unlock_at = unlock_at;
+        // a proposal made by the previous owner no longer applies
+        self.vault_state.pending_owner = None;
+        Ok(())
+    }
+
+    pub fn reset_stats(&mut self) -> Result<()> {
+        // withdrawal_count is what enforces the lifetime quota, so it can't be wiped under one
+        require!(
+            self.vault_state.max_lifetime_withdrawals == 0,
+            VaultError::WithdrawalQuotaActive
+        );
+        let now = Clock::get()?.unix_timestamp;
+        let state = &mut self.vault_state;
+        emit!(StatsReset {
+            vault_state: state.key(),
+            total_deposited: state.total_deposited,
+            withdrawal_count: state.withdrawal_count,
+        });
+        state.total_deposited = 0;
+        state.withdrawal_count = 0;
+        state.twab_accumulator = 0;
+        state.twab_last_update = now;
+        state.stats_reset_at = now;
+        Ok(())
+    }
+
+    pub fn freeze_config(&mut self) -> Result<()> {
+        self.vault_state.config_frozen = true;
+        Ok(())
+    }
+
+    pub fn set_paused(&mut self, paused: bool) -> Result<()> {
+        self.vault_state.set_paused(paused)
+    }
+
+    pub fn set_inline_allowlist(&mut self, recipients: Vec<Pubkey>) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        require!(
+            recipients.len() <= MAX_INLINE_RECIPIENTS,
+            VaultError::InvalidInlineAllowlist
+        );
+        // the default pubkey marks an empty slot, so it can't be allowed itself
+        require!(
+            recipients.iter().all(|r| *r != Pubkey::default()),
+            VaultError::InvalidInlineAllowlist
+        );
+        let mut inline_allowlist = [Pubkey::default(); MAX_INLINE_RECIPIENTS];
+        inline_allowlist[..recipients.len()].copy_from_slice(&recipients);
+        self.vault_state.inline_allowlist = inline_allowlist;
+        Ok(())
+    }
+
+    pub fn set_deposit_merkle_root(&mut self, root: Option<[u8; 32]>) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        self.vault_state.deposit_merkle_root = root;
+        Ok(())
+    }
+
+    pub fn set_self_custody_only(&mut self, self_custody_only: bool) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        self.vault_state.self_custody_only = self_custody_only;
+        Ok(())
+    }
+
+    pub fn set_guardian(&mut self, guardian: Option<Pubkey>, expires_at: i64) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        if guardian.is_some() {
+            require!(
+                expires_at > Clock::get()?.unix_timestamp,
+                VaultError::InvalidDuration
+            );
+        }
+        self.vault_state.guardian = guardian;
+        self.vault_state.guardian_expires_at = expires_at;
+        Ok(())
+    }
+
+    pub fn set_keeper_close(&mut self, inactivity_seconds: i64, bounty_bps: u16) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        require!(inactivity_seconds >= 0, VaultError::InvalidDuration);
+        require!(
+            u64::from(bounty_bps) <= BPS_DENOMINATOR,
+            VaultError::InvalidBps
+        );
+        self.vault_state.keeper_after_seconds = inactivity_seconds;
+        self.vault_state.keeper_bounty_bps = bounty_bps;
+        Ok(())
+    }
+
+    pub fn set_donation_threshold(&mut self, threshold: u64) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        emit_limit_change(
+            self.vault_state.key(),
+            LimitField::DonationThreshold,
+            self.vault_state.donation_threshold,
+            threshold,
+        );
+        self.vault_state.donation_threshold = threshold;
+        Ok(())
+    }
+
+    pub fn sweep_donations(&mut self) -> Result<()> {
+        let donated = self.vault_state.donated_total;
+        require!(
+            donated > 0 && donated > self.vault_state.donation_threshold,
+            VaultError::DonationsBelowThreshold
+        );
+        self.vault_state.record_deposit(donated)?;
+        self.vault_state.donated_total = 0;
+        Ok(())
+    }
+
+    pub fn set_withdrawal_spike_multiple(&mut self, multiple: u16) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        emit_limit_change(
+            self.vault_state.key(),
+            LimitField::WithdrawalSpikeMultiple,
+            u64::from(self.vault_state.withdrawal_spike_multiple),
+            u64::from(multiple),
+        );
+        self.vault_state.withdrawal_spike_multiple = multiple;
+        Ok(())
+    }
+
+    pub fn set_external_withdraw_paused(&mut self, paused: bool) -> Result<()> {
+        self.vault_state
+            .reserved
+            .set_external_withdraw_paused(paused)
+    }
+
+    pub fn set_validation_hook(&mut self, hook: Option<Pubkey>) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        self.vault_state.reserved.set_validation_hook(hook)
+    }
+
+    pub fn set_owner_bypass_pause(&mut self, owner_bypass_pause: bool) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        self.vault_state.owner_bypass_pause = owner_bypass_pause;
+        Ok(())
+    }
+
+    pub fn set_queue_on_pause(&mut self, queue_on_pause: bool) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        self.vault_state.queue_on_pause = queue_on_pause;
+        Ok(())
+    }
+
+    pub fn set_min_age(&mut self, min_age_seconds: i64) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        require!(min_age_seconds >= 0, VaultError::InvalidDuration);
+        self.vault_state.min_age_seconds = min_age_seconds;
+        Ok(())
+    }
+}
+
+// hard cap on remaining_accounts processed by any batch instruction
+pub const MAX_BATCH_ACCOUNTS: usize = 20;
+
+// each vault adds two account keys and two CPIs, so this keeps
+// initialize_many inside the transaction size and compute limits
+pub const MAX_BULK_INITIALIZE: u8 = 8;
+
+// upper bound for the fee reimbursement in withdraw_net
+// a few signatures at any realistic priority fee stay well below this
+pub const MAX_FEE_REIMBURSEMENT: u64 = 1_000_000;
+
+// kept back by deposit_percent_of_balance: the wallet's rent-exempt minimum plus room for
+// later transaction fees
+pub const DEPOSIT_FEE_RESERVE: u64 = 10_000_000;
+
+#[account]
+#[derive(Default)]
+pub struct VaultState {
+    // the user that controls the vault
+    pub owner: Pubkey,
+    // the user that created the vault; seeds are derived from it so the
+    // addresses stay fixed when ownership is rotated
+    pub creator: Pubkey,
+    pub vault_bump: u8,
+    pub state_bump: u8,
+    // seed that distinguishes a user's vaults
+    pub index: u8,
+    // lifetime sum of lamports credited by deposits
+    pub total_deposited: u64,
+    // vault_state of the vault that deposits are forwarded to
+    pub forward_to: Option<Pubkey>,
+    // unix timestamp of initialize
+    pub created_at: i64,
+    // seconds after created_at before any withdrawal is allowed (0 disables)
+    pub min_age_seconds: i64,
+    // linear vesting of vesting_amount; vesting_duration == 0 means no schedule
+    pub vesting_start: i64,
+    pub vesting_duration: i64,
+    pub vesting_amount: u64,
+    // emit DepositEvent/WithdrawEvent (enabled for new vaults)
+    pub verbose_events: bool,
+    // unix timestamp before which withdrawals are locked (0 = never locked)
+    pub unlock_at: i64,
+    // slot before which withdrawals are locked when lock_mode is Slot
+    pub unlock_slot: u64,
+    pub lock_mode: LockMode,
+    // lets withdraw bypass the lock by paying early_penalty_bps to fee_treasury
+    pub allow_early_withdraw: bool,
+    pub early_penalty_bps: u16,
+    pub fee_treasury: Pubkey,
+    // deposits are accepted in multiples of this many lamports (0 = any amount)
+    pub round_deposits_to: u64,
+    // set by arm_close; close_confirmed must present the same code
+    pub close_code: Option<[u8; 4]>,
+    // opt-in emergency recovery by ProgramConfig.program_authority (off by default)
+    pub allow_authority_recovery: bool,
+    // withdrawals allowed over the vault's lifetime (0 = unlimited)
+    pub max_lifetime_withdrawals: u32,
+    pub withdrawal_count: u32,
+    // maximum vault balance that deposits may reach (0 = uncapped)
+    pub deposit_cap: u64,
+    // mint of the token vault created by init_token_vault (Pubkey::default() if none)
+    pub token_mint: Pubkey,
+    pub token_vault_bump: u8,
+    // set by the owner to stop deposits and withdrawals
+    pub paused: bool,
+    // owner-chosen name, zero padded
+    pub label: [u8; 32],
+    // deposit commits once pending_deposit + amount reaches min_deposit (0 = commit every deposit);
+    // queued lamports already sit in the vault but are not yet counted in total_deposited
+    pub min_deposit: u64,
+    pub pending_deposit: u64,
+    // set once by freeze_config and never cleared
+    pub config_frozen: bool,
+    // deposits while paused go to pending_deposit instead of failing
+    pub queue_on_pause: bool,
+    // withdrawals above this need a MultisigConfig approval (None = no multisig)
+    pub approval_above: Option<u64>,
+    // deposit and withdraw must then pass the ActivityLog
+    pub activity_log_enabled: bool,
+    // share of each deposit (and deposit_capped) paid to fee_treasury
+    pub deposit_fee_bps: u16,
+    // watcher notified through the NotifyMarker on deposit/withdraw (None = off)
+    pub notify: Option<Pubkey>,
+    // display symbol, "SOL" for new vaults
+    pub symbol: [u8; 8],
+    // withdraw, withdraw_net and withdraw_with_penalty ignore the pause
+    pub owner_bypass_pause: bool,
+    // deposits per window of deposit_period_seconds are limited to period_deposit_cap (0 = off)
+    pub period_deposit_cap: u64,
+    pub deposit_period_seconds: i64,
+    pub deposit_window_start: i64,
+    pub deposited_in_window: u64,
+    // stake account created by delegate_stake, until withdraw_stake empties it
+    pub stake_account: Option<Pubkey>,
+    // lamports received through donate since the last sweep_donations
+    pub donated_total: u64,
+    pub donation_threshold: u64,
+    // unix timestamp of the last deposit or withdrawal (initialize counts as one)
+    pub last_activity: i64,
+    // keeper_close is allowed this long after last_activity (0 = never)
+    pub keeper_after_seconds: i64,
+    pub keeper_bounty_bps: u16,
+    // copied into DepositEvent/WithdrawEvent so indexers can group vaults (zeroed = untagged)
+    pub topic: [u8; 16],
+    // deposits extend unlock_at by lock_extension_seconds, up to max_lock_seconds ahead
+    pub deposit_extends_lock: bool,
+    pub lock_extension_seconds: i64,
+    pub max_lock_seconds: i64,
+    // sum of balance * seconds held, brought up to twab_last_update by deposit and withdraw
+    pub twab_accumulator: u128,
+    pub twab_last_update: i64,
+    // moving average of withdraw/withdraw_to amounts, checked against
+    // withdrawal_spike_multiple (0 = no spike check)
+    pub withdrawal_ema: u64,
+    pub withdrawal_spike_multiple: u16,
+    // set by migrate_to_v2 on the retired vault_state
+    pub migrated_to: Option<Pubkey>,
+    // raw token amounts (set in whole tokens by set_token_limits; 0 = no limit)
+    pub token_deposit_cap: u64,
+    pub token_min_deposit: u64,
+    // may withdraw and pause until guardian_expires_at
+    pub guardian: Option<Pubkey>,
+    pub guardian_expires_at: i64,
+    // every withdrawal must go to the owner's own address
+    pub self_custody_only: bool,
+    // withdrawals per window of withdraw_window_seconds are limited to daily_withdraw_limit (0 = off)
+    pub daily_withdraw_limit: u64,
+    pub withdraw_window_seconds: i64,
+    pub withdraw_window_start: i64,
+    pub withdrawn_in_window: u64,
+    // how the vault PDA is derived, fixed at creation; see vault_seed_key
+    pub seed_version: u8,
+    // recipients withdraw_to accepts without an allowlist entry; unused slots are the default pubkey
+    pub inline_allowlist: [Pubkey; MAX_INLINE_RECIPIENTS],
+    // third-party deposits need a proof against this root; see merkle_root_of
+    pub deposit_merkle_root: Option<[u8; 32]>,
+    // set by reset_stats; the TWAB averages from here instead of created_at (0 = never reset)
+    pub stats_reset_at: i64,
+    // set when a withdrawal leaves the vault holding exactly its rent-exempt minimum;
+    // cleared by the next deposit, see top_up_or_close
+    pub at_rent_floor: bool,
+    // deposits are accepted in [campaign_start, campaign_end); 0 leaves that side open
+    pub campaign_start: i64,
+    pub campaign_end: i64,
+    // savings goal for get_progress; goal_reached keeps GoalReached to a single emission
+    pub goal: u64,
+    pub goal_reached: bool,
+    // hidden from list_vaults and closed to deposits; see archive
+    pub archived: bool,
+    // smallest amount withdraw and withdraw_to accept (0 = no minimum)
+    pub min_withdraw: u64,
+    // set by propose_owner until accept_owner completes the transfer
+    pub pending_owner: Option<Pubkey>,
+    // what delegate_stake moved into stake_account and when, for estimated_apy
+    pub stake_principal: u64,
+    pub stake_started_at: i64,
+    // realised yield of the last stake, recorded by withdraw_stake
+    pub last_stake_apy_bps: u64,
+    // when set, withdraw is replaced by withdraw_if
+    pub release_condition: Option<ReleaseCondition>,
+    // receipted deposits become refundable this long after they were made (0 = never)
+    pub auto_refund_after: i64,
+    // set once by lock_mint; token_mint can never change afterwards
+    pub mint_locked: bool,
+    // deposit bonus paid from the reward pool; bonus_paid never exceeds bonus_cap
+    pub deposit_bonus_bps: u16,
+    pub bonus_cap: u64,
+    pub bonus_paid: u64,
+    // the fields above are laid out inline and sized into INIT_SPACE, and they carry no
+    // layout guarantee: each one added after this block was introduced moved it and grew
+    // INIT_SPACE, so an account created before such a field does not decode under the
+    // current layout. The guarantee only covers fields stored here at the fixed RESERVED_*
+    // offsets, which leave the account size and every other offset alone. Moving the
+    // inline fields in here would shift the layout once more, so they stay where they are
+    pub reserved: Reserved,
+}
+
+impl Space for VaultState {
+    const INIT_SPACE: usize = 8 // discriminator
+        + 32 // owner
+        + 32 // creator
+        + 1 // vault_bump
+        + 1 // state_bump
+        + 1 // index
+        + 8 // total_deposited
+        + (1 + 32) // forward_to (Option tag + Pubkey)
+        + 8 // created_at
+        + 8 // min_age_seconds
+        + 8 // vesting_start
+        + 8 // vesting_duration
+        + 8 // vesting_amount
+        + 1 // verbose_events
+        + 8 // unlock_at
+        + 8 // unlock_slot
+        + 1 // lock_mode
+        + 1 // allow_early_withdraw
+        + 2 // early_penalty_bps
+        + 32 // fee_treasury
+        + 8 // round_deposits_to
+        + (1 + 4) // close_code
+        + 1 // allow_authority_recovery
+        + 4 // max_lifetime_withdrawals
+        + 4 // withdrawal_count
+        + 8 // deposit_cap
+        + 32 // token_mint
+        + 1 // token_vault_bump
+        + 1 // paused
+        + 32 // label
+        + 8 // min_deposit
+        + 8 // pending_deposit
+        + 1 // config_frozen
+        + 1 // queue_on_pause
+        + (1 + 8) // approval_above
+        + 1 // activity_log_enabled
+        + 2 // deposit_fee_bps
+        + (1 + 32) // notify
+        + 8 // symbol
+        + 1 // owner_bypass_pause
+        + 8 // period_deposit_cap
+        + 8 // deposit_period_seconds
+        + 8 // deposit_window_start
+        + 8 // deposited_in_window
+        + (1 + 32) // stake_account
+        + 8 // donated_total
+        + 8 // donation_threshold
+        + 8 // last_activity
+        + 8 // keeper_after_seconds
+        + 2 // keeper_bounty_bps
+        + 16 // topic
+        + 1 // deposit_extends_lock
+        + 8 // lock_extension_seconds
+        + 8 // max_lock_seconds
+        + 16 // twab_accumulator
+        + 8 // twab_last_update
+        + 8 // withdrawal_ema
+        + 2 // withdrawal_spike_multiple
+        + (1 + 32) // migrated_to
+        + 8 // token_deposit_cap
+        + 8 // token_min_deposit
+        + (1 + 32) // guardian
+        + 8 // guardian_expires_at
+        + 1 // self_custody_only
+        + 8 // daily_withdraw_limit
+        + 8 // withdraw_window_seconds
+        + 8 // withdraw_window_start
+        + 8 // withdrawn_in_window
+        + 1 // seed_version
+        + 32 * MAX_INLINE_RECIPIENTS // inline_allowlist
+        + (1 + 32) // deposit_merkle_root
+        + 8 // stats_reset_at
+        + 1 // at_rent_floor
+        + 8 // campaign_start
+        + 8 // campaign_end
+        + 8 // goal
+        + 1 // goal_reached
+        + 1 // archived
+        + 8 // min_withdraw
+        + (1 + 32) // pending_owner
+        + 8 // stake_principal
+        + 8 // stake_started_at
+        + 8 // last_stake_apy_bps
+        + (1 + 32 + 32 + 2 + 1) // release_condition
+        + 8 // auto_refund_after
+        + 1 // mint_locked
+        + 2 // deposit_bonus_bps
+        + 8 // bonus_cap
+        + 8 // bonus_paid
+        + RESERVED_LEN; // reserved
+}
+
+pub const DEFAULT_SYMBOL: [u8; 8] = *b"SOL\0\0\0\0\0";
+
+// bits returned by the flags view
+pub const FLAG_PAUSED: u32 = 1 << 0;
+pub const FLAG_CONFIG_FROZEN: u32 = 1 << 1;
+pub const FLAG_LOCKED: u32 = 1 << 2;
+pub const FLAG_VERBOSE_EVENTS: u32 = 1 << 3;
+pub const FLAG_ALLOW_EARLY_WITHDRAW: u32 = 1 << 4;
+pub const FLAG_ALLOW_AUTHORITY_RECOVERY: u32 = 1 << 5;
+pub const FLAG_QUEUE_ON_PAUSE: u32 = 1 << 6;
+pub const FLAG_ACTIVITY_LOG: u32 = 1 << 7;
+pub const FLAG_OWNER_BYPASS_PAUSE: u32 = 1 << 8;
+pub const FLAG_DEPOSIT_EXTENDS_LOCK: u32 = 1 << 9;
+pub const FLAG_SELF_CUSTODY_ONLY: u32 = 1 << 10;
+pub const FLAG_CLOSE_ARMED: u32 = 1 << 11;
+pub const FLAG_MIGRATED: u32 = 1 << 12;
+pub const FLAG_GUARDIAN_ACTIVE: u32 = 1 << 13;
+pub const FLAG_FORWARDING: u32 = 1 << 14;
+pub const FLAG_STAKED: u32 = 1 << 15;
+pub const FLAG_AT_RENT_FLOOR: u32 = 1 << 16;
+pub const FLAG_EXTERNAL_WITHDRAW_PAUSED: u32 = 1 << 17;
+
+// actions understood by can()
+pub const ACTION_WITHDRAW: u8 = 0;
+pub const ACTION_PAUSE: u8 = 1;
+pub const ACTION_CLOSE: u8 = 2;
+
+// smoothing of withdrawal_ema: each withdrawal moves it 1/WITHDRAWAL_EMA_PERIOD of the way
+pub const WITHDRAWAL_EMA_PERIOD: u64 = 4;
+
+// seed_version values: the vault PDA is derived from the state address, or from
+// [creator, index] so it can be derived without the state. SEED_VERSION_V2 is set by
+// migrate_to_v2: the state lives at [b"state_v2", creator, index] and the vault is
+// derived from the state address as under SEED_VERSION_STATE
+pub const SEED_VERSION_STATE: u8 = 0;
+pub const SEED_VERSION_CREATOR: u8 = 1;
+pub const SEED_VERSION_V2: u8 = 2;
+
+// slots in VaultState::inline_allowlist
+pub const MAX_INLINE_RECIPIENTS: usize = 3;
+
+pub const RESERVED_LEN: usize = 64;
+
+// offsets of the fields stored in Reserved; a new field takes the next free offset and
+// existing ones never move
+// referrer's share of deposit_with_referral deposits, paid from the reward pool (u16)
+pub const RESERVED_REFERRAL_FEE_BPS: usize = 0;
+// blocks sends to anyone but the owner; see check_recipient (bool)
+pub const RESERVED_EXTERNAL_WITHDRAW_PAUSED: usize = 2;
+// program that must approve every withdraw_to; see WithdrawTo::validate (Option<Pubkey>)
+pub const RESERVED_VALIDATION_HOOK: usize = 3;
+// receipted deposits not yet claimed by the owner or refunded; see trigger_refund (u64)
+pub const RESERVED_UNCLAIMED_RECEIPTS: usize = 36;
+// first byte not yet taken by a field
+pub const RESERVED_USED: usize = 36 + 8;
+const _: () = assert!(RESERVED_USED <= RESERVED_LEN);
+
+// zeroed padding at the end of VaultState; a field carved out of it decodes as its zero
+// value on accounts created with the current inline layout before the field existed
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub struct Reserved(pub [u8; RESERVED_LEN]);
+
+impl Default for Reserved {
+    fn default() -> Self {
+        Self([0; RESERVED_LEN])
+    }
+}
+
+impl Reserved {
+    // reads a borsh-encoded value stored at `offset`
+    pub fn read<T: AnchorDeserialize>(&self, offset: usize) -> Result<T> {
+        let mut bytes = self
+            .0
+            .get(offset..)
+            .ok_or(ErrorCode::AccountDidNotDeserialize)?;
+        T::deserialize(&mut bytes).map_err(|_| error!(ErrorCode::AccountDidNotDeserialize))
+    }
+
+    // writes a borsh-encoded value into the `len` bytes at `offset`, zeroing whatever the
+    // value doesn't cover (a None leaves no stale key behind); fails if it doesn't fit
+    pub fn write<T: AnchorSerialize>(
+        &mut self,
+        offset: usize,
+        len: usize,
+        value: &T,
+    ) -> Result<()> {
+        let slot = offset
+            .checked_add(len)
+            .and_then(|end| self.0.get_mut(offset..end))
+            .ok_or(ErrorCode::AccountDidNotSerialize)?;
+        slot.fill(0);
+        let mut bytes = &mut slot[..];
+        value
+            .serialize(&mut bytes)
+            .map_err(|_| error!(ErrorCode::AccountDidNotSerialize))
+    }
+
+    pub fn referral_fee_bps(&self) -> Result<u16> {
+        self.read(RESERVED_REFERRAL_FEE_BPS)
+    }
+
+    pub fn set_referral_fee_bps(&mut self, bps: u16) -> Result<()> {
+        self.write(RESERVED_REFERRAL_FEE_BPS, 2, &bps)
+    }
+
+    pub fn external_withdraw_paused(&self) -> Result<bool> {
+        self.read(RESERVED_EXTERNAL_WITHDRAW_PAUSED)
+    }
+
+    pub fn set_external_withdraw_paused(&mut self, paused: bool) -> Result<()> {
+        self.write(RESERVED_EXTERNAL_WITHDRAW_PAUSED, 1, &paused)
+    }
+
+    pub fn validation_hook(&self) -> Result<Option<Pubkey>> {
+        self.read(RESERVED_VALIDATION_HOOK)
+    }
+
+    pub fn set_validation_hook(&mut self, hook: Option<Pubkey>) -> Result<()> {
+        self.write(RESERVED_VALIDATION_HOOK, 1 + 32, &hook)
+    }
+
+    pub fn unclaimed_receipts(&self) -> Result<u64> {
+        self.read(RESERVED_UNCLAIMED_RECEIPTS)
+    }
+
+    pub fn set_unclaimed_receipts(&mut self, amount: u64) -> Result<()> {
+        self.write(RESERVED_UNCLAIMED_RECEIPTS, 8, &amount)
+    }
+}
+
+// how deposit_many/withdraw_to_many treat an item that fails its checks
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum BatchMode {
+    // the whole transaction reverts
+    AllOrNothing,
+    // the item is skipped and its index returned
+    BestEffort,
+}
+
+// which limit a LimitsChanged event refers to
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum LimitField {
+    DepositCap,
+    MinDeposit,
+    PeriodDepositCap,
+    MaxLifetimeWithdrawals,
+    WithdrawalSpikeMultiple,
+    TokenDepositCap,
+    TokenMinDeposit,
+    DonationThreshold,
+    DailyWithdrawLimit,
+    MinWithdraw,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LockMode {
+    // unlock_at is compared against Clock::unix_timestamp
+    #[default]
+    Timestamp,
+    // unlock_slot is compared against Clock::slot, which is deterministic
+    Slot,
+}
+
+impl VaultState {
+    // defaults for a freshly created vault; every creation path goes through here
+    pub fn new(owner: Pubkey, index: u8, state_bump: u8, vault_bump: u8, created_at: i64) -> Self {
+        Self {
+            owner,
+            creator: owner,
+            symbol: DEFAULT_SYMBOL,
+            vault_bump,
+            state_bump,
+            index,
+            created_at,
+            last_activity: created_at,
+            twab_last_update: created_at,
+            verbose_events: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn record_deposit(&mut self, amount: u64) -> Result<()> {
+        self.total_deposited = self
+            .total_deposited
+            .checked_add(amount)
+            .ok_or(VaultError::Overflow)?;
+        let now = Clock::get()?.unix_timestamp;
+        self.last_activity = now;
+        self.at_rent_floor = false;
+        if self.deposit_extends_lock {
+            self.extend_lock(now);
+        }
+        Ok(())
+    }
+
+    // adds the lamport-seconds held at `balance` since the last update; called before
+    // every change to the vault's balance (transfer_to_vault and transfer_from_vault do
+    // it for the common transfers)
+    pub fn accrue_twab(&mut self, now: i64, balance: u64) -> Result<()> {
+        self.twab_accumulator = self.twab_at(now, balance)?;
+        self.twab_last_update = now;
+        Ok(())
+    }
+
+    fn twab_at(&self, now: i64, balance: u64) -> Result<u128> {
+        // accounts created before the tracker existed start counting at created_at
+        let since = self.twab_last_update.max(self.created_at);
+        let elapsed = now.saturating_sub(since).max(0) as u128;
+        self.twab_accumulator
+            .checked_add(u128::from(balance) * elapsed)
+            .ok_or(error!(VaultError::Overflow))
+    }
+
+    // average balance over the vault's life (or since reset_stats), with `balance` held
+    // since the last update
+    pub fn twab(&self, now: i64, balance: u64) -> Result<u64> {
+        let lifetime = now.saturating_sub(self.created_at.max(self.stats_reset_at));
+        if lifetime <= 0 {
+            return Ok(balance);
+        }
+        Ok((self.twab_at(now, balance)? / lifetime as u128) as u64)
+    }
+
+    // an existing lock beyond the cap is left as it is; the extension only ever lengthens it
+    fn extend_lock(&mut self, now: i64) {
+        let extended = self
+            .unlock_at
+            .max(now)
+            .saturating_add(self.lock_extension_seconds)
+            .min(now.saturating_add(self.max_lock_seconds));
+        self.unlock_at = self.unlock_at.max(extended);
+    }
+
+    // counts `count` withdrawals against max_lifetime_withdrawals
+    pub fn record_withdrawals(&mut self, count: u32) -> Result<()> {
+        let withdrawal_count = self
+            .withdrawal_count
+            .checked_add(count)
+            .ok_or(VaultError::Overflow)?;
+        require!(
+            self.max_lifetime_withdrawals == 0 || withdrawal_count <= self.max_lifetime_withdrawals,
+            VaultError::WithdrawalQuotaExhausted
+        );
+        self.withdrawal_count = withdrawal_count;
+        self.last_activity = Clock::get()?.unix_timestamp;
+        Ok(())
+    }
+
+    pub fn set_paused(&mut self, paused: bool) -> Result<()> {
+        self.paused = paused;
+        // deposits queued during the pause are committed under the usual min_deposit rule
+        if !paused {
+            if let Some(committed) = self.queue_deposit(0)? {
+                self.record_deposit(committed)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn inline_allows(&self, recipient: Pubkey) -> bool {
+        recipient != Pubkey::default() && self.inline_allowlist.contains(&recipient)
+    }
+
+    pub fn check_recipient(&self, recipient: Pubkey) -> Result<()> {
+        require!(
+            !self.self_custody_only || recipient == self.owner,
+            VaultError::SelfCustodyOnly
+        );
+        require!(
+            !self.reserved.external_withdraw_paused()? || recipient == self.owner,
+            VaultError::ExternalWithdrawPaused
+        );
+        Ok(())
+    }
+
+    pub fn check_guardian(&self, signer: Pubkey, now: i64) -> Result<()> {
+        require!(self.guardian == Some(signer), VaultError::Unauthorized);
+        require!(now < self.guardian_expires_at, VaultError::GuardianExpired);
+        Ok(())
+    }
+
+    // rejects a spike above withdrawal_spike_multiple * withdrawal_ema, then folds amount
+    // into the average; the first withdrawal only seeds it
+    pub fn record_withdrawal_size(&mut self, amount: u64) -> Result<()> {
+        let ema = u128::from(self.withdrawal_ema);
+        if ema == 0 {
+            self.withdrawal_ema = amount;
+            return Ok(());
+        }
+        require!(
+            self.withdrawal_spike_multiple == 0
+                || u128::from(amount) <= ema * u128::from(self.withdrawal_spike_multiple),
+            VaultError::WithdrawalSpike
+        );
+        let period = u128::from(WITHDRAWAL_EMA_PERIOD);
+        self.withdrawal_ema = ((ema * (period - 1) + u128::from(amount)) / period) as u64;
+        Ok(())
+    }
+
+    // lamports the vault can still accept before hitting deposit_cap
+    pub fn cap_headroom(&self, balance: u64) -> u64 {
+        if self.deposit_cap == 0 {
+            return u64::MAX;
+        }
+        self.deposit_cap.saturating_sub(balance)
+    }
+
+    // counts amount against period_deposit_cap, starting a new window once the last one ended
+    pub fn record_window_deposit(&mut self, now: i64, amount: u64) -> Result<()> {
+        if self.period_deposit_cap == 0 {
+            return Ok(());
+        }
+        if now
+            >= self
+                .deposit_window_start
+                .saturating_add(self.deposit_period_seconds)
+        {
+            self.deposit_window_start = now;
+            self.deposited_in_window = 0;
+        }
+        let deposited = self
+            .deposited_in_window
+            .checked_add(amount)
+            .ok_or(VaultError::Overflow)?;
+        require!(
+            deposited <= self.period_deposit_cap,
+            VaultError::PeriodDepositCapExceeded
+        );
+        self.deposited_in_window = deposited;
+        Ok(())
+    }
+
+    // what daily_withdraw_limit still allows at `now`; a window that has ended counts as reset
+    pub fn remaining_daily(&self, now: i64) -> u64 {
+        if self.daily_withdraw_limit == 0 {
+            return u64::MAX;
+        }
+        if now
+            >= self
+                .withdraw_window_start
+                .saturating_add(self.withdraw_window_seconds)
+        {
+            return self.daily_withdraw_limit;
+        }
+        self.daily_withdraw_limit
+            .saturating_sub(self.withdrawn_in_window)
+    }
+
+    // counts amount against daily_withdraw_limit, starting a new window once the last one ended
+    pub fn record_daily_withdrawal(&mut self, now: i64, amount: u64) -> Result<()> {
+        if self.daily_withdraw_limit == 0 {
+            return Ok(());
+        }
+        require!(
+            amount <= self.remaining_daily(now),
+            VaultError::DailyWithdrawLimitExceeded
+        );
+        if now
+            >= self
+                .withdraw_window_start
+                .saturating_add(self.withdraw_window_seconds)
+        {
+            self.withdraw_window_start = now;
+            self.withdrawn_in_window = 0;
+        }
+        self.withdrawn_in_window += amount;
+        Ok(())
+    }
+
+    // token_vault_balance is the raw amount already held by the token vault
+    pub fn check_token_limits(&self, token_vault_balance: u64, amount: u64) -> Result<()> {
+        require!(amount >= self.token_min_deposit, VaultError::InvalidAmount);
+        if self.token_deposit_cap > 0 {
+            let total = token_vault_balance
+                .checked_add(amount)
+                .ok_or(VaultError::Overflow)?;
+            require!(
+                total <= self.token_deposit_cap,
+                VaultError::DepositCapExceeded
+            );
+        }
+        Ok(())
+    }
+
+    // every deposit path checks the cap, so the archive flag and campaign window are
+    // enforced here too
+    pub fn check_cap(&self, balance: u64, amount: u64) -> Result<()> {
+        require!(!self.archived, VaultError::VaultArchived);
+        self.check_campaign_open(Clock::get()?.unix_timestamp)?;
+        require!(
+            amount <= self.cap_headroom(balance),
+            VaultError::DepositCapExceeded
+        );
+        Ok(())
+    }
+
+    // emits GoalReached the first time balance reaches goal
+    pub fn track_goal(&mut self, vault_state: Pubkey, balance: u64) {
+        if self.goal == 0 || self.goal_reached || balance < self.goal {
+            return;
+        }
+        self.goal_reached = true;
+        emit!(GoalReached {
+            vault_state,
+            goal: self.goal,
+            balance,
+        });
+    }
+
+    pub fn check_min_withdraw(&self, amount: u64) -> Result<()> {
+        require!(
+            amount >= self.min_withdraw,
+            VaultError::WithdrawBelowMinimum
+        );
+        Ok(())
+    }
+
+    pub fn check_campaign_open(&self, now: i64) -> Result<()> {
+        require!(
+            now >= self.campaign_start && (self.campaign_end == 0 || now < self.campaign_end),
+            VaultError::CampaignClosed
+        );
+        Ok(())
+    }
+
+    // adds amount to the queue and returns the total to commit once it reaches min_deposit
+    pub fn queue_deposit(&mut self, amount: u64) -> Result<Option<u64>> {
+        let queued = self
+            .pending_deposit
+            .checked_add(amount)
+            .ok_or(VaultError::Overflow)?;
+        if queued < self.min_deposit {
+            self.pending_deposit = queued;
+            return Ok(None);
+        }
+        self.pending_deposit = 0;
+        Ok(Some(queued))
+    }
+
+    pub fn requires_approval(&self, amount: u64) -> bool {
+        self.approval_above.is_some_and(|limit| amount > limit)
+    }
+
+    // for withdrawal paths that cannot consume a multisig approval
+    pub fn check_approval_limit(&self, amount: u64) -> Result<()> {
+        require!(
+            !self.requires_approval(amount),
+            VaultError::ApprovalRequired
+        );
+        Ok(())
+    }
+
+    // called after lamports leave the vault: the owner's withdrawals spend their own lamports
+    // first, and only the receipted deposits the remaining balance can't cover count as claimed
+    pub fn settle_receipts(&mut self, vault: &SystemAccount) -> Result<()> {
+        let available = vault
+            .lamports()
+            .saturating_sub(Rent::get()?.minimum_balance(vault.data_len()));
+        let unclaimed = self.reserved.unclaimed_receipts()?;
+        if unclaimed > available {
+            self.reserved.set_unclaimed_receipts(available)?;
+        }
+        Ok(())
+    }
+
+    // called after every withdrawal, once the lamports have left the vault
+    pub fn update_rent_floor(&mut self, vault: &SystemAccount) -> Result<()> {
+        self.at_rent_floor = vault.lamports() == Rent::get()?.minimum_balance(vault.data_len());
+        Ok(())
+    }
+
+    // the vault PDA is left behind when only the state is closed; closing state while the
+    // vault, its token account or its stake account still held funds would strand them
+    // because nothing could sign for them afterwards
+    pub fn check_state_closable(&self, vault_lamports: u64) -> Result<()> {
+        require!(
+            vault_lamports == 0
+                && self.token_mint == Pubkey::default()
+                && self.stake_account.is_none(),
+            VaultError::VaultNotEmpty
+        );
+        require!(self.close_code.is_none(), VaultError::CloseNotConfirmed);
+        Ok(())
+    }
+
+    // for the closes that only move lamports; a token vault goes through close_mixed (or
+    // close_token_vault first), since closing the state would leave the token account with
+    // no one to sign for it
+    pub fn check_closable(&self, balance: u64) -> Result<()> {
+        require!(
+            self.token_mint == Pubkey::default(),
+            VaultError::VaultNotEmpty
+        );
+        self.check_drainable(balance)
+    }
+
+    // closing drains the vault, so it is subject to the same locks as a withdrawal; delegated
+    // lamports have to come back through withdraw_stake first, as nothing could sign for the
+    // stake account once the state is gone
+    pub fn check_drainable(&self, balance: u64) -> Result<()> {
+        require!(self.stake_account.is_none(), VaultError::VaultNotEmpty);
+        require!(self.close_code.is_none(), VaultError::CloseNotConfirmed);
+        let clock = Clock::get()?;
+        self.check_not_paused()?;
+        self.check_unlocked(&clock)?;
+        self.check_no_release_condition()?;
+        self.check_vested(clock.unix_timestamp, balance, balance)?;
+        self.check_approval_limit(balance)
+    }
+
+    // a vault with a release condition only pays out through withdraw_if, which checks the
+    // condition account before calling Payment::withdraw; every other exit refuses it
+    pub fn check_no_release_condition(&self) -> Result<()> {
+        require!(
+            self.release_condition.is_none(),
+            VaultError::ReleaseConditionRequired
+        );
+        Ok(())
+    }
+
+    pub fn check_config_mutable(&self) -> Result<()> {
+        require!(self.migrated_to.is_none(), VaultError::VaultMigrated);
+        require!(!self.config_frozen, VaultError::ConfigFrozen);
+        Ok(())
+    }
+
+    pub fn check_not_paused(&self) -> Result<()> {
+        require!(self.migrated_to.is_none(), VaultError::VaultMigrated);
+        require!(!self.paused, VaultError::VaultPaused);
+        Ok(())
+    }
+
+    // for withdrawals from the vault to its owner, which owner_bypass_pause exempts
+    pub fn check_self_withdraw_not_paused(&self) -> Result<()> {
+        require!(self.migrated_to.is_none(), VaultError::VaultMigrated);
+        if self.owner_bypass_pause {
+            return Ok(());
+        }
+        self.check_not_paused()
+    }
+
+    // (credited to the vault, paid to fee_treasury) for a deposit of `amount`
+    pub fn split_deposit_fee(&self, amount: u64) -> Result<(u64, u64)> {
+        let fee = bps_of(amount, self.deposit_fee_bps)?;
+        Ok((amount - fee, fee))
+    }
+
+    pub fn check_min_age(&self, now: i64) -> Result<()> {
+        let opens_at = self.created_at.saturating_add(self.min_age_seconds);
+        require!(now >= opens_at, VaultError::VaultTooYoung);
+        Ok(())
+    }
+
+    pub fn round_deposit(&self, amount: u64) -> Result<u64> {
+        if self.round_deposits_to == 0 {
+            return Ok(amount);
+        }
+        let accepted = amount - amount % self.round_deposits_to;
+        require!(accepted > 0, VaultError::DepositBelowRoundingUnit);
+        Ok(accepted)
+    }
+
+    pub fn is_locked(&self, clock: &Clock) -> bool {
+        match self.lock_mode {
+            LockMode::Timestamp => clock.unix_timestamp < self.unlock_at,
+            LockMode::Slot => clock.slot < self.unlock_slot,
+        }
+    }
+
+    // part of vesting_amount that has not been released yet
+    pub fn unvested(&self, now: i64) -> u64 {
+        if self.vesting_duration == 0 {
+            return 0;
+        }
+        let elapsed = now
+            .saturating_sub(self.vesting_start)
+            .clamp(0, self.vesting_duration);
+        let vested =
+            u128::from(self.vesting_amount) * elapsed as u128 / self.vesting_duration as u128;
+        self.vesting_amount - vested as u64
+    }
+
+    // the unvested part stays in the vault; everything above it can leave
+    pub fn vested_available(&self, now: i64, balance: u64) -> u64 {
+        balance.saturating_sub(self.unvested(now))
+    }
+
+    pub fn check_vested(&self, now: i64, balance: u64, amount: u64) -> Result<()> {
+        require!(
+            amount <= self.vested_available(now, balance),
+            VaultError::NotYetVested
+        );
+        Ok(())
+    }
+
+    // guards shared by every path that moves funds out of the vault without a penalty
+    pub fn check_unlocked(&self, clock: &Clock) -> Result<()> {
+        self.check_min_age(clock.unix_timestamp)?;
+        require!(!self.is_locked(clock), VaultError::VaultLocked);
+        Ok(())
+    }
+}
+
+// read-only instructions take the vault pair without mut and return values via return data
+// no signer is needed, and Account<VaultState> already guarantees the state belongs to this program
+#[derive(Accounts)]
+pub struct VaultView<'info> {
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+}
+
+impl<'info> VaultView<'info> {
+    pub fn vested_available(&self) -> Result<u64> {
+        let now = Clock::get()?.unix_timestamp;
+        Ok(self
+            .vault_state
+            .vested_available(now, self.vault.lamports()))
+    }
+
+    // mirrors the guards in Payment::withdraw; an early withdrawal counts at its gross amount
+    pub fn withdrawable(&self) -> Result<u64> {
+        let state = &self.vault_state;
+        let clock = Clock::get()?;
+        let balance = self.vault.lamports();
+        let quota_left = state.max_lifetime_withdrawals == 0
+            || state.withdrawal_count < state.max_lifetime_withdrawals;
+        if state.release_condition.is_some()
+            || state.check_self_withdraw_not_paused().is_err()
+            || state.check_min_age(clock.unix_timestamp).is_err()
+            || (state.is_locked(&clock) && !state.allow_early_withdraw)
+            || !quota_left
+        {
+            return Ok(0);
+        }
+        let mut amount = state.vested_available(clock.unix_timestamp, balance);
+        if let Some(limit) = state.approval_above {
+            amount = amount.min(limit);
+        }
+        amount = amount.min(state.remaining_daily(clock.unix_timestamp));
+        // the cap record_withdrawal_size enforces once there is an average to compare with
+        if state.withdrawal_ema > 0 && state.withdrawal_spike_multiple > 0 {
+            amount = amount.min(
+                state
+                    .withdrawal_ema
+                    .saturating_mul(u64::from(state.withdrawal_spike_multiple)),
+            );
+        }
+        // a partial withdrawal must leave the vault rent exempt; emptying it is fine
+        if amount < balance {
+            let floor = Rent::get()?.minimum_balance(self.vault.data_len());
+            amount = amount.min(balance.saturating_sub(floor));
+        }
+        if amount < state.min_withdraw {
+            return Ok(0);
+        }
+        Ok(amount)
+    }
+
+    pub fn flags(&self) -> Result<u32> {
+        let state = &self.vault_state;
+        let clock = Clock::get()?;
+        let guardian_active =
+            state.guardian.is_some() && clock.unix_timestamp < state.guardian_expires_at;
+        Ok([
+            (FLAG_PAUSED, state.paused),
+            (FLAG_CONFIG_FROZEN, state.config_frozen),
+            (FLAG_LOCKED, state.is_locked(&clock)),
+            (FLAG_VERBOSE_EVENTS, state.verbose_events),
+            (FLAG_ALLOW_EARLY_WITHDRAW, state.allow_early_withdraw),
+            (
+                FLAG_ALLOW_AUTHORITY_RECOVERY,
+                state.allow_authority_recovery,
+            ),
+            (FLAG_QUEUE_ON_PAUSE, state.queue_on_pause),
+            (FLAG_ACTIVITY_LOG, state.activity_log_enabled),
+            (FLAG_OWNER_BYPASS_PAUSE, state.owner_bypass_pause),
+            (FLAG_DEPOSIT_EXTENDS_LOCK, state.deposit_extends_lock),
+            (FLAG_SELF_CUSTODY_ONLY, state.self_custody_only),
+            (FLAG_CLOSE_ARMED, state.close_code.is_some()),
+            (FLAG_MIGRATED, state.migrated_to.is_some()),
+            (FLAG_GUARDIAN_ACTIVE, guardian_active),
+            (FLAG_FORWARDING, state.forward_to.is_some()),
+            (FLAG_STAKED, state.stake_account.is_some()),
+            (FLAG_AT_RENT_FLOOR, state.at_rent_floor),
+            (
+                FLAG_EXTERNAL_WITHDRAW_PAUSED,
+                state.reserved.external_withdraw_paused()?,
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, set)| *set)
+        .fold(0, |flags, (flag, _)| flags | flag))
+    }
+
+    pub fn get_twab(&self) -> Result<u64> {
+        let now = Clock::get()?.unix_timestamp;
+        self.vault_state.twab(now, self.vault.lamports())
+    }
+
+    pub fn get_config(&self) -> Result<VaultConfig> {
+        let state = &self.vault_state;
+        Ok(VaultConfig {
+            deposit_cap: state.deposit_cap,
+            min_deposit: state.min_deposit,
+            period_deposit_cap: state.period_deposit_cap,
+            deposit_period_seconds: state.deposit_period_seconds,
+            token_deposit_cap: state.token_deposit_cap,
+            token_min_deposit: state.token_min_deposit,
+            round_deposits_to: state.round_deposits_to,
+            deposit_fee_bps: state.deposit_fee_bps,
+            early_penalty_bps: state.early_penalty_bps,
+            keeper_bounty_bps: state.keeper_bounty_bps,
+            min_withdraw: state.min_withdraw,
+            max_lifetime_withdrawals: state.max_lifetime_withdrawals,
+            withdrawal_spike_multiple: state.withdrawal_spike_multiple,
+            daily_withdraw_limit: state.daily_withdraw_limit,
+            withdraw_window_seconds: state.withdraw_window_seconds,
+            approval_above: state.approval_above,
+            flags: self.flags()?,
+            created_at: state.created_at,
+            min_age_seconds: state.min_age_seconds,
+            unlock_at: state.unlock_at,
+            unlock_slot: state.unlock_slot,
+            vesting_start: state.vesting_start,
+            vesting_duration: state.vesting_duration,
+            campaign_start: state.campaign_start,
+            campaign_end: state.campaign_end,
+            keeper_after_seconds: state.keeper_after_seconds,
+            auto_refund_after: state.auto_refund_after,
+        })
+    }
+
+    pub fn get_progress(&self) -> Result<GoalProgress> {
+        let balance = self.vault.lamports();
+        let goal = self.vault_state.goal;
+        let bps_complete = if goal == 0 {
+            0
+        } else {
+            (u128::from(balance) * u128::from(BPS_DENOMINATOR) / u128::from(goal))
+                .min(u128::from(BPS_DENOMINATOR)) as u16
+        };
+        Ok(GoalProgress {
+            balance,
+            goal,
+            bps_complete,
+        })
+    }
+
+    pub fn quote_deposit(&self, amount: u64) -> Result<DepositQuote> {
+        let amount = self.vault_state.round_deposit(amount)?;
+        let (net_credited, fee) = self.vault_state.split_deposit_fee(amount)?;
+        Ok(DepositQuote { net_credited, fee })
+    }
+
+    pub fn health(&self) -> Result<VaultHealth> {
+        let balance = self.vault.lamports();
+        Ok(VaultHealth {
+            rent_exempt: Rent::get()?.is_exempt(balance, self.vault.data_len()),
+            balance,
+            paused: self.vault_state.paused,
+            locked: self.vault_state.is_locked(&Clock::get()?),
+        })
+    }
+}
+
+// see set_release_condition
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub struct ReleaseCondition {
+    pub account: Pubkey,
+    pub owner: Pubkey,
+    pub offset: u16,
+    pub value: u8,
+}
+
+// snapshot returned by get_config; flags is the same FLAG_* bitmask as the flags view
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub struct VaultConfig {
+    // deposits
+    pub deposit_cap: u64,
+    pub min_deposit: u64,
+    pub period_deposit_cap: u64,
+    pub deposit_period_seconds: i64,
+    pub token_deposit_cap: u64,
+    pub token_min_deposit: u64,
+    pub round_deposits_to: u64,
+    // fees
+    pub deposit_fee_bps: u16,
+    pub early_penalty_bps: u16,
+    pub keeper_bounty_bps: u16,
+    // withdrawals
+    pub min_withdraw: u64,
+    pub max_lifetime_withdrawals: u32,
+    pub withdrawal_spike_multiple: u16,
+    pub daily_withdraw_limit: u64,
+    pub withdraw_window_seconds: i64,
+    pub approval_above: Option<u64>,
+    pub flags: u32,
+    // timestamps and durations
+    pub created_at: i64,
+    pub min_age_seconds: i64,
+    pub unlock_at: i64,
+    pub unlock_slot: u64,
+    pub vesting_start: i64,
+    pub vesting_duration: i64,
+    pub campaign_start: i64,
+    pub campaign_end: i64,
+    pub keeper_after_seconds: i64,
+    pub auto_refund_after: i64,
+}
+
+// bps_complete is capped at 10_000 once the goal is reached, and 0 without a goal
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub struct GoalProgress {
+    pub balance: u64,
+    pub goal: u64,
+    pub bps_complete: u16,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub struct DepositQuote {
+    pub net_credited: u64,
+    pub fee: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub struct VaultHealth {
+    pub rent_exempt: bool,
+    pub balance: u64,
+    pub paused: bool,
+    pub locked: bool,
+}
+
+#[derive(Accounts)]
+pub struct LogView<'info> {
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        seeds = [b"log", vault_state.key().as_ref()],
+        bump = activity_log.bump,
+    )]
+    pub activity_log: Account<'info, ActivityLog>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub struct LogSummary {
+    pub count: u8,
+    pub net: i64,
+}
+
+// for instructions that only read sysvars or constants
+#[derive(Accounts)]
+pub struct NoAccounts {}
+
+#[derive(Accounts)]
+pub struct ListVaults<'info> {
+    pub registry: Account<'info, UserRegistry>,
+}
+
+// everything multi_balance reads comes from remaining_accounts
+#[derive(Accounts)]
+pub struct MultiBalance {}
+
+impl MultiBalance {
+    pub fn multi_balance<'info>(
+        &self,
+        remaining_accounts: &'info [AccountInfo<'info>],
+    ) -> Result<Vec<u64>> {
+        check_batch(remaining_accounts, remaining_accounts.len() / 2, 2)?;
+        remaining_accounts
+            .chunks_exact(2)
+            .map(|pair| {
+                let vault_state = Account::<VaultState>::try_from(&pair[0])?;
+                require_keys_eq!(
+                    pair[1].key(),
+                    vault_address(&vault_state)?,
+                    VaultError::InvalidPda
+                );
+                Ok(pair[1].lamports())
+            })
+            .collect()
+    }
+}
+
+#[derive(Accounts)]
+pub struct SetFee<'info> {
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [state_seed_prefix(&vault_state), vault_state.creator.as_ref(), vault_state.index.to_le_bytes().as_ref()],
+        bump = vault_state.state_bump,
+        constraint = vault_state.owner == user.key() @ VaultError::Unauthorized,
+    )]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    /// CHECK: validated in set_fee so every bad destination maps to VaultError::InvalidTreasury
+    /// writable because fees are later transferred into it
+    #[account(mut)]
+    pub fee_treasury: UncheckedAccount<'info>,
+}
+
+impl<'info> SetFee<'info> {
+    pub fn set_fee(&mut self, early_penalty_bps: u16) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        // validates the range up front instead of failing on the first early withdrawal
+        bps_of(0, early_penalty_bps)?;
+        self.set_treasury()?;
+        self.vault_state.early_penalty_bps = early_penalty_bps;
+        Ok(())
+    }
+
+    pub fn set_deposit_fee(&mut self, deposit_fee_bps: u16) -> Result<()> {
+        self.vault_state.check_config_mutable()?;
+        bps_of(0, deposit_fee_bps)?;
+        self.set_treasury()?;
+        self.vault_state.deposit_fee_bps = deposit_fee_bps;
+        Ok(())
+    }
+
+    // both fees go to the same treasury, so setting either one replaces it
+    fn set_treasury(&mut self) -> Result<()> {
+        // fees sent to a program account or back into the vault would be unspendable or circular
+        let treasury = self.fee_treasury.to_account_info();
+        require!(
+            treasury.is_writable
+                && !treasury.executable
+                && treasury.owner == &anchor_lang::system_program::ID
+                && treasury.key() != self.vault.key(),
+            VaultError::InvalidTreasury
+        );
+        self.vault_state.fee_treasury = self.fee_treasury.key();
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct InitializeConfig<'info> {
+    #[account(mut)]
+    pub upgrade_authority: Signer<'info>,
+    #[account(
+        init,
+        payer = upgrade_authority,
+        space = ProgramConfig::INIT_SPACE,
+        seeds = [b"config"],
+        bump
+    )]
+    pub config: Account<'info, ProgramConfig>,
+    // the ProgramData account holds the upgrade authority of this deployment
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()))]
+    pub program: Program<'info, crate::program::Vault>,
+    #[account(constraint = program_data.upgrade_authority_address == Some(upgrade_authority.key()) @ VaultError::Unauthorized)]
+    pub program_data: Account<'info, ProgramData>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> InitializeConfig<'info> {
+    pub fn initialize_config(
+        &mut self,
+        program_authority: Pubkey,
+        bumps: &InitializeConfigBumps,
+    ) -> Result<()> {
+        self.config.set_inner(ProgramConfig {
+            program_authority,
+            bump: bumps.config,
+        });
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct AuthorityRecover<'info> {
+    pub program_authority: Signer<'info>,
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = program_authority @ VaultError::Unauthorized,
+    )]
+    pub config: Account<'info, ProgramConfig>,
+    // mut: the transfer brings the TWAB and receipt accounting up to date
+    #[account(mut)]
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        mut,
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    // funds can only ever go back to the owner, never to the authority
+    #[account(mut, address = vault_state.owner @ VaultError::Unauthorized)]
+    pub owner: SystemAccount<'info>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> AuthorityRecover<'info> {
+    pub fn authority_recover(&mut self) -> Result<()> {
+        require!(
+            self.vault_state.allow_authority_recovery,
+            VaultError::RecoveryNotAllowed
+        );
+        transfer_from_vault(
+            &self.system_program,
+            &self.vault,
+            self.owner.to_account_info(),
+            &mut self.vault_state,
+            self.vault.lamports(),
+        )
+    }
+}
+
+// like VaultView, plus the signer whose permissions are queried; config is only needed
+// to recognise the program authority
+#[derive(Accounts)]
+pub struct Can<'info> {
+    pub signer: Signer<'info>,
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Option<Account<'info, ProgramConfig>>,
+    pub vault_state: Account<'info, VaultState>,
+    #[account(
+        seeds = [b"vault", vault_seed_key(&vault_state), vault_seed_tail(&vault_state)],
+        bump = vault_state.vault_bump,
+    )]
+    pub vault: SystemAccount<'info>,
+}
+
+impl<'info> Can<'info> {
+    pub fn can(&self, action: u8) -> Result<bool> {
+        let state = &self.vault_state;
+        let signer = self.signer.key();
+        let clock = Clock::get()?;
+        let now = clock.unix_timestamp;
+        let balance = self.vault.lamports();
+        let is_owner = signer == state.owner;
+        let is_guardian = state.check_guardian(signer, now).is_ok();
+        let is_authority = self
+            .config
+            .as_ref()
+            .is_some_and(|config| config.program_authority == signer);
+        Ok(match action {
+            ACTION_WITHDRAW => {
+                // the same guards as Payment::withdraw, GuardianWithdraw and AuthorityRecover
+                let owner_ok = is_owner
+                    && state.check_self_withdraw_not_paused().is_ok()
+                    && state.check_min_age(now).is_ok()
+                    && (!state.is_locked(&clock) || state.allow_early_withdraw)
+                    && (state.max_lifetime_withdrawals == 0
+                        || state.withdrawal_count < state.max_lifetime_withdrawals);
+                let guardian_ok = is_guardian
+                    && state.check_not_paused().is_ok()
+                    && state.check_unlocked(&clock).is_ok();
+                let authority_ok = is_authority && state.allow_authority_recovery;
+                balance > 0 && (owner_ok || guardian_ok || authority_ok)
+            }
+            // set_paused goes through Configure, guardian_set_paused through GuardianPause
+            ACTION_PAUSE => is_owner || is_guardian,
+            ACTION_CLOSE => is_owner && state.check_closable(balance).is_ok(),
+            _ => return err!(VaultError::InvalidAction),
+        })
+    }
+}
+
+#[derive(Accounts)]
+pub struct SetSolPrice<'info> {
+    #[account(mut)]
+    pub program_authority: Signer<'info>,
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = program_authority @ VaultError::Unauthorized,
+    )]
+    pub config: Account<'info, ProgramConfig>,
+    #[account(
+        init_if_needed,
+        payer = program_authority,
+        space = PriceFeed::INIT_SPACE,
+        seeds = [b"price_feed"],
+        bump
+    )]
+    pub price_feed: Account<'info, PriceFeed>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> SetSolPrice<'info> {
+    pub fn set_sol_price(
+        &mut self,
+        micro_usd_per_sol: u64,
+        bumps: &SetSolPriceBumps,
+    ) -> Result<()> {
+        require!(micro_usd_per_sol > 0, VaultError::InvalidAmount);
+        self.price_feed.set_inner(PriceFeed {
+            micro_usd_per_sol,
+            updated_at: Clock::get()?.unix_timestamp,
+            bump: bumps.price_feed,
+        });
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct SetRewardProgram<'info> {
+    #[account(mut)]
+    pub program_authority: Signer<'info>,
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+        has_one = program_authority @ VaultError::Unauthorized,
+    )]
+    pub config: Account<'info, ProgramConfig>,
+    #[account(
+        init_if_needed,
+        payer = program_authority,
+        space = RewardConfig::INIT_SPACE,
+        seeds = [b"reward_config"],
+        bump
+    )]
+    pub reward_config: Account<'info, RewardConfig>,
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> SetRewardProgram<'info> {
+    pub fn set_reward_program(
+        &mut self,
+        reward_program: Option<Pubkey>,
+        bumps: &SetRewardProgramBumps,
+    ) -> Result<()> {
+        self.reward_config.set_inner(RewardConfig {
+            reward_program,
+            bump: bumps.reward_config,
+        });
+        Ok(())
+    }
+}
+
+// kept apart from ProgramConfig so existing config accounts keep their layout
+#[account]
+pub struct RewardConfig {
+    pub reward_program: Option<Pubkey>,
+    pub bump: u8,
+}
+
+impl Space for RewardConfig {
+    const INIT_SPACE: usize = 8 // discriminator
+        + (1 + 32) // reward_program
+        + 1; // bump
+}
+
+// Anchor discriminator of credit_points(amount: u64), the instruction deposit_and_notify
+// sends to the reward program
+pub const REWARD_CREDIT_DISCRIMINATOR: [u8; 8] = [140, 43, 121, 246, 176, 80, 167, 176];
+
+// Anchor discriminator of validate_transfer(amount: u64), the instruction withdraw_to
+// sends to a vault's validation hook
+pub const VALIDATE_TRANSFER_DISCRIMINATOR: [u8; 8] = [80, 12, 83, 146, 148, 14, 119, 220];
+
+#[account]
+pub struct PriceFeed {
+    pub micro_usd_per_sol: u64,
+    pub updated_at: i64,
+    pub bump: u8,
+}
+
+impl Space for PriceFeed {
+    const INIT_SPACE: usize = 8 // discriminator
+        + 8 // micro_usd_per_sol
+        + 8 // updated_at
+        + 1; // bump
+}
+
+// prices older than this are rejected by deposit_min_usd
+pub const MAX_PRICE_AGE_SECONDS: i64 = 60;
+
+impl PriceFeed {
+    pub fn fresh_price(&self, now: i64) -> Result<u64> {
+        require!(
+            now.saturating_sub(self.updated_at) <= MAX_PRICE_AGE_SECONDS,
+            VaultError::StalePrice
+        );
+        Ok(self.micro_usd_per_sol)
+    }
+}
+
+#[account]
+pub struct Stream {
+    pub vault_state: Pubkey,
+    pub source: Pubkey,
+    pub rate_per_second: u64,
+    // lamports escrowed at open_stream; claimed never exceeds it
+    pub total: u64,
+    pub claimed: u64,
+    pub start: i64,
+    pub last_claim_at: i64,
+    pub bump: u8,
+}
+
+impl Space for Stream {
+    const INIT_SPACE: usize = 8 // discriminator
+        + 32 // vault_state
+        + 32 // source
+        + 8 // rate_per_second
+        + 8 // total
+        + 8 // claimed
+        + 8 // start
+        + 8 // last_claim_at
+        + 1; // bump
+}
+
+impl Stream {
+    // lamports released since start, capped at the escrowed total
+    pub fn accrued(&self, now: i64) -> u64 {
+        let elapsed = now.saturating_sub(self.start).max(0) as u128;
+        (u128::from(self.rate_per_second) * elapsed).min(u128::from(self.total)) as u64
+    }
+}
+
+// account layout of the release before vaults moved to state/vault PDAs; only read by
+// import_legacy, which closes it
+#[account]
+pub struct LegacyVault {
+    pub owner: Pubkey,
+    pub bump: u8,
+}
+
+#[account]
+pub struct ProgramConfig {
+    pub program_authority: Pubkey,
+    pub bump: u8,
+}
+
+impl Space for ProgramConfig {
+    const INIT_SPACE: usize = 8 // discriminator
+        + 32 // program_authority
+        + 1; // bump
+}
+
+#[account]
+pub struct UserRegistry {
+    pub owner: Pubkey,
+    // bit i is set while the vault at index i is open
+    pub active: [u8; 32],
+    pub bump: u8,
+    // bit i is set while the vault at index i is archived; such vaults are also in active
+    pub archived: [u8; 32],
+}
+
+impl Space for UserRegistry {
+    const INIT_SPACE: usize = 8 // discriminator
+        + 32 // owner
+        + 32 // active (one bit per u8 index)
+        + 1 // bump
+        + 32; // archived
+}
+
+impl UserRegistry {
+    // owner and bump are rewritten every time because init_if_needed may have just created the account
+    pub fn mark_open(&mut self, owner: Pubkey, index: u8, bump: u8) {
+        self.owner = owner;
+        self.bump = bump;
+        self.active[usize::from(index / 8)] |= 1 << (index % 8);
+    }
+
+    pub fn mark_closed(&mut self, index: u8) {
+        self.active[usize::from(index / 8)] &= !(1 << (index % 8));
+        self.set_archived(index, false);
+    }
+
+    pub fn set_archived(&mut self, index: u8, archived: bool) {
+        if archived {
+            self.archived[usize::from(index / 8)] |= 1 << (index % 8);
+        } else {
+            self.archived[usize::from(index / 8)] &= !(1 << (index % 8));
+        }
+    }
+
+    // open vaults that are not archived
+    pub fn indices(&self) -> Vec<u8> {
+        (0..=u8::MAX)
+            .filter(|index| {
+                let bit = 1 << (index % 8);
+                let byte = usize::from(index / 8);
+                self.active[byte] & bit != 0 && self.archived[byte] & bit == 0
+            })
+            .collect()
+    }
+
+    pub fn archived_indices(&self) -> Vec<u8> {
+        (0..=u8::MAX)
+            .filter(|index| self.archived[usize::from(index / 8)] & (1 << (index % 8)) != 0)
+            .collect()
+    }
+}
+
+#[account]
+pub struct AllowlistEntry {
+    pub recipient: Pubkey,
+    pub expires_at: i64,
+    pub bump: u8,
+}
+
+impl Space for AllowlistEntry {
+    const INIT_SPACE: usize = 8 // discriminator
+        + 32 // recipient
+        + 8 // expires_at
+        + 1; // bump
+}
+
+impl AllowlistEntry {
+    pub fn is_active(&self, now: i64) -> bool {
+        now < self.expires_at
+    }
+}
+
+#[account]
+pub struct CouponState {
+    pub vault_state: Pubkey,
+    pub coupon_id: u64,
+    pub amount: u64,
+    pub redeemed: bool,
+    pub bump: u8,
+}
+
+impl Space for CouponState {
+    const INIT_SPACE: usize = 8 // discriminator
+        + 32 // vault_state
+        + 8 // coupon_id
+        + 8 // amount
+        + 1 // redeemed
+        + 1; // bump
+}
+
+#[account]
+pub struct Receipt {
+    pub vault_state: Pubkey,
+    pub depositor: Pubkey,
+    pub receipt_id: u64,
+    pub amount: u64,
+    pub timestamp: i64,
+    pub bump: u8,
+}
+
+impl Space for Receipt {
+    const INIT_SPACE: usize = 8 // discriminator
+        + 32 // vault_state
+        + 32 // depositor
+        + 8 // receipt_id
+        + 8 // amount
+        + 8 // timestamp
+        + 1; // bump
+}
+
+#[account]
+pub struct DepositAllowance {
+    pub delegate: Pubkey,
+    // lamports the delegate may still deposit
+    pub remaining: u64,
+    pub bump: u8,
+}
+
+impl Space for DepositAllowance {
+    const INIT_SPACE: usize = 8 // discriminator
+        + 32 // delegate
+        + 8 // remaining
+        + 1; // bump
+}
+
+// approvals are tracked as a u8 bitmask over the signer slots
+pub const MAX_MULTISIG_SIGNERS: usize = 5;
+
+#[account]
+pub struct MultisigConfig {
+    pub vault_state: Pubkey,
+    // the first signer_count slots are used
+    pub signers: [Pubkey; MAX_MULTISIG_SIGNERS],
+    pub signer_count: u8,
+    pub threshold: u8,
+    // bumped on every configure_multisig to invalidate pending approvals
+    pub nonce: u64,
+    pub bump: u8,
+}
+
+impl Space for MultisigConfig {
+    const INIT_SPACE: usize = 8 // discriminator
+        + 32 // vault_state
+        + 32 * MAX_MULTISIG_SIGNERS // signers
+        + 1 // signer_count
+        + 1 // threshold
+        + 8 // nonce
+        + 1; // bump
+}
+
+impl MultisigConfig {
+    pub fn signer_index(&self, key: &Pubkey) -> Option<usize> {
+        self.signers[..usize::from(self.signer_count)]
+            .iter()
+            .position(|signer| signer == key)
+    }
+}
+
+#[account]
+pub struct WithdrawApproval {
+    pub vault_state: Pubkey,
+    pub amount: u64,
+    // MultisigConfig.nonce this approval was gathered under
+    pub nonce: u64,
+    // bit i set = signers[i] approved; 0 = no approval pending
+    pub approvals: u8,
+    pub bump: u8,
+}
+
+impl Space for WithdrawApproval {
+    const INIT_SPACE: usize = 8 // discriminator
+        + 32 // vault_state
+        + 8 // amount
+        + 8 // nonce
+        + 1 // approvals
+        + 1; // bump
+}
+
+pub const LOG_CAPACITY: usize = 16;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct LogEntry {
+    // lamports into (+) or out of (-) the vault
+    pub delta: i64,
+    pub timestamp: i64,
+}
+
+#[account]
+#[derive(Default)]
+pub struct ActivityLog {
+    pub vault_state: Pubkey,
+    pub entries: [LogEntry; LOG_CAPACITY],
+    // slot the next entry is written to
+    pub head: u8,
+    // number of valid entries, up to LOG_CAPACITY
+    pub len: u8,
+    pub bump: u8,
+}
+
+impl Space for ActivityLog {
+    const INIT_SPACE: usize = 8 // discriminator
+        + 32 // vault_state
+        + (8 + 8) * LOG_CAPACITY // entries
+        + 1 // head
+        + 1 // len
+        + 1; // bump
+}
+
+impl ActivityLog {
+    // overwrites the oldest entry once the buffer is full
+    pub fn push(&mut self, delta: i64, timestamp: i64) {
+        self.entries[usize::from(self.head)] = LogEntry { delta, timestamp };
+        self.head = ((usize::from(self.head) + 1) % LOG_CAPACITY) as u8;
+        self.len = self.len.saturating_add(1).min(LOG_CAPACITY as u8);
+    }
+
+    pub fn summary(&self) -> LogSummary {
+        // until the buffer wraps the valid entries are the first `len` slots, afterwards all of them
+        let net = self.entries[..usize::from(self.len)]
+            .iter()
+            .fold(0i64, |sum, entry| sum.saturating_add(entry.delta));
+        LogSummary {
+            count: self.len,
+            net,
+        }
+    }
+}
+
+#[account]
+pub struct NotifyMarker {
+    pub vault_state: Pubkey,
+    pub watcher: Option<Pubkey>,
+    // incremented by every deposit and withdraw while notifications are on
+    pub sequence: u64,
+    pub bump: u8,
+}
+
+impl Space for NotifyMarker {
+    const INIT_SPACE: usize = 8 // discriminator
+        + 32 // vault_state
+        + (1 + 32) // watcher
+        + 8 // sequence
+        + 1; // bump
+}
+
+#[account]
+pub struct BalanceSnapshot {
+    pub vault_state: Pubkey,
+    pub balance: u64,
+    // consumers should compare this against their own Clock::get()?.slot before trusting balance
+    pub slot: u64,
+    pub timestamp: i64,
+    pub bump: u8,
+}
+
+impl Space for BalanceSnapshot {
+    const INIT_SPACE: usize = 8 // discriminator
+        + 32 // vault_state
+        + 8 // balance
+        + 8 // slot
+        + 8 // timestamp
+        + 1; // bump
+}
+
+// every batch instruction passes its per-item accounts in fixed-size groups
+pub fn check_batch(
+    remaining_accounts: &[AccountInfo],
+    items: usize,
+    accounts_per_item: usize,
+) -> Result<()> {
+    require!(
+        remaining_accounts.len() <= MAX_BATCH_ACCOUNTS,
+        VaultError::BatchTooLarge
+    );
+    require!(
+        items > 0 && remaining_accounts.len() == items * accounts_per_item,
+        VaultError::InvalidRemainingAccounts
+    );
+    Ok(())
+}
+
+pub const BPS_DENOMINATOR: u64 = 10_000;
+
+// 1-8 uppercase letters or digits, followed only by zero padding
+pub fn check_symbol(symbol: &[u8; 8]) -> Result<()> {
+    let len = symbol.iter().position(|b| *b == 0).unwrap_or(symbol.len());
+    require!(
+        len > 0
+            && symbol[..len]
+                .iter()
+                .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+            && symbol[len..].iter().all(|b| *b == 0),
+        VaultError::InvalidSymbol
+    );
+    Ok(())
+}
+
+// a drained vault PDA has no lamports and no longer exists on chain; catch that
+// before the transfer so the client gets a clear error instead of a failed CPI
+pub fn check_funded(vault: &SystemAccount) -> Result<()> {
+    require!(vault.lamports() > 0, VaultError::VaultNotFunded);
+    Ok(())
+}
+
+// deepest proof deposit_with_proof accepts, enough for 2^16 depositors
+pub const MAX_MERKLE_PROOF_LEN: usize = 16;
+
+// folds proof into the root of the tree containing depositor: the leaf is sha256(pubkey)
+// and each node is sha256 of its two children in ascending byte order, so proofs carry
+// no left/right flags
+pub fn merkle_root_of(depositor: Pubkey, proof: &[[u8; 32]]) -> [u8; 32] {
+    proof
+        .iter()
+        .fold(hashv(&[depositor.as_ref()]).to_bytes(), |node, sibling| {
+            let (left, right) = if node <= *sibling {
+                (node, *sibling)
+            } else {
+                (*sibling, node)
+            };
+            hashv(&[&left, &right]).to_bytes()
+        })
+}
+
+// amount * bps / 10_000, rounded down
+pub fn bps_of(amount: u64, bps: u16) -> Result<u64> {
+    require!(u64::from(bps) <= BPS_DENOMINATOR, VaultError::InvalidBps);
+    // u128 keeps the intermediate product from overflowing for large amounts
+    Ok((u128::from(amount) * u128::from(bps) / u128::from(BPS_DENOMINATOR)) as u64)
+}
+
+// reward / principal scaled to a year of `seconds`, in bps (0 with no principal or time)
+pub fn annualized_bps(reward: u64, principal: u64, seconds: i64) -> u64 {
+    if principal == 0 || seconds <= 0 {
+        return 0;
+    }
+    let annual = u128::from(reward) * u128::from(BPS_DENOMINATOR) * u128::from(SECONDS_PER_YEAR)
+        / (u128::from(principal) * seconds as u128);
+    u64::try_from(annual).unwrap_or(u64::MAX)
+}
+
+pub const SECONDS_PER_YEAR: u64 = 365 * 24 * 60 * 60;
+
+// emitted by every limit setter, even when the value is unchanged, so audits see each call
+pub fn emit_limit_change(vault_state: Pubkey, field: LimitField, old: u64, new: u64) {
+    emit!(LimitsChanged {
+        vault_state,
+        field,
+        old,
+        new,
+    });
+}
+
+// the state PDA is [state_seed_prefix, creator, index]
+pub fn state_seed_prefix(vault_state: &Account<'_, VaultState>) -> &'static [u8] {
+    if vault_state.seed_version == SEED_VERSION_V2 {
+        b"state_v2"
+    } else {
+        b"state"
+    }
+}
+
+// the vault PDA is [b"vault", vault_seed_key, vault_seed_tail]; under SEED_VERSION_STATE the
+// tail is empty, which derives the same address as the original [b"vault", state]
+pub fn vault_seed_key<'a>(vault_state: &'a Account<'_, VaultState>) -> &'a [u8] {
+    if vault_state.seed_version == SEED_VERSION_CREATOR {
+        vault_state.creator.as_ref()
+    } else {
+        vault_state.to_account_info().key.as_ref()
+    }
+}
+
+pub fn vault_seed_tail<'a>(vault_state: &'a Account<'_, VaultState>) -> &'a [u8] {
+    if vault_state.seed_version == SEED_VERSION_CREATOR {
+        std::slice::from_ref(&vault_state.index)
+    } else {
+        &[]
+    }
+}
+
+// vault PDA for a vault_state, using the stored bump and seed scheme
+// for accounts that can't be checked with a seeds constraint
+pub fn vault_address(vault_state: &Account<VaultState>) -> Result<Pubkey> {
+    Pubkey::create_program_address(
+        &[
+            b"vault",
+            vault_seed_key(vault_state),
+            vault_seed_tail(vault_state),
+            &[vault_state.vault_bump],
+        ],
+        &crate::ID,
+    )
+    .map_err(|_| error!(VaultError::InvalidPda))
+}
+
+// PDA-signed transfer out of a vault
+// the vault is a SystemAccount, so moving its lamports needs the system program plus the vault seeds
+// the TWAB is brought up to date first, at the balance the vault held until now
+pub fn transfer_from_vault<'info>(
+    system_program: &Program<'info, System>,
+    vault: &SystemAccount<'info>,
+    to: AccountInfo<'info>,
+    vault_state: &mut Account<'info, VaultState>,
+    amount: u64,
+) -> Result<()> {
+    vault_state.accrue_twab(Clock::get()?.unix_timestamp, vault.lamports())?;
+    let cpi_account = Transfer {
+        from: vault.to_account_info(),
+        to,
+    };
+    let seeds = &[
+        b"vault",
+        vault_seed_key(vault_state),
+        vault_seed_tail(vault_state),
+        &[vault_state.vault_bump],
+    ];
+    let signer_seeds = &[&seeds[..]];
+    let cpi_ctx =
+        CpiContext::new_with_signer(system_program.to_account_info(), cpi_account, signer_seeds);
+    transfer(cpi_ctx, amount)?;
+    vault_state.settle_receipts(vault)
+}
+
+// the checks and transfer every lamport deposit shares; the user must cover `pulled`
+// (deposit plus deposit fee) and the part net of the fee must fit under the cap. Only
+// the net amount is moved; the caller pays the returned fee and credits the deposit
+pub fn pull_deposit<'info>(
+    system_program: &Program<'info, System>,
+    user: &Signer<'info>,
+    vault: &SystemAccount<'info>,
+    vault_state: &mut Account<'info, VaultState>,
+    pulled: u64,
+) -> Result<(u64, u64)> {
+    // the transaction fee is charged before the program runs, so the user's current
+    // balance only has to cover what is pulled
+    require!(
+        user.lamports() >= pulled,
+        VaultError::InsufficientUserBalance
+    );
+    let (amount, fee) = vault_state.split_deposit_fee(pulled)?;
+    vault_state.check_cap(vault.lamports(), amount)?;
+    let now = Clock::get()?.unix_timestamp;
+    vault_state.record_window_deposit(now, amount)?;
+    transfer_to_vault(
+        system_program,
+        user.to_account_info(),
+        vault,
+        vault_state,
+        amount,
+    )?;
+    Ok((amount, fee))
+}
+
+// plain system transfer into a vault from a signer; like transfer_from_vault it accrues
+// the TWAB at the balance held until now before the balance changes
+pub fn transfer_to_vault<'info>(
+    system_program: &Program<'info, System>,
+    from: AccountInfo<'info>,
+    vault: &SystemAccount<'info>,
+    vault_state: &mut Account<'info, VaultState>,
+    amount: u64,
+) -> Result<()> {
+    vault_state.accrue_twab(Clock::get()?.unix_timestamp, vault.lamports())?;
+    transfer(
+        CpiContext::new(
+            system_program.to_account_info(),
+            Transfer {
+                from,
+                to: vault.to_account_info(),
+            },
+        ),
+        amount,
+    )
+}
+
+// lamports the reward pool can pay out while staying rent exempt
+pub fn reward_pool_available(reward_pool: &SystemAccount) -> Result<u64> {
+    let rent_exempt = Rent::get()?.minimum_balance(reward_pool.data_len());
+    Ok(reward_pool.lamports().saturating_sub(rent_exempt))
+}
+
+// like transfer_from_vault, signed with the reward pool's seeds; the bump isn't stored
+// (FundRewardPool lets Anchor find it), so the pool address is re-derived here
+pub fn transfer_from_reward_pool<'info>(
+    system_program: &Program<'info, System>,
+    reward_pool: &SystemAccount<'info>,
+    to: AccountInfo<'info>,
+    vault_state: Pubkey,
+    amount: u64,
+) -> Result<()> {
+    let (expected, bump) =
+        Pubkey::find_program_address(&[b"reward_pool", vault_state.as_ref()], &crate::ID);
+    require_keys_eq!(reward_pool.key(), expected, VaultError::InvalidPda);
+    let seeds = &[b"reward_pool", vault_state.as_ref(), &[bump]];
+    transfer(
+        CpiContext::new_with_signer(
+            system_program.to_account_info(),
+            Transfer {
+                from: reward_pool.to_account_info(),
+                to,
+            },
+            &[&seeds[..]],
+        ),
+        amount,
+    )
+}
+
+#[event]
+pub struct VaultInitialized {
+    pub vault_state: Pubkey,
+    pub owner: Pubkey,
+    pub index: u8,
+}
+
+#[event]
+pub struct LabelChanged {
+    pub vault_state: Pubkey,
+    pub new_label: [u8; 32],
+}
+
+#[event]
+pub struct SymbolChanged {
+    pub vault_state: Pubkey,
+    pub new_symbol: [u8; 8],
+}
+
+// the accounting values reset_stats cleared
+#[event]
+pub struct StatsReset {
+    pub vault_state: Pubkey,
+    pub total_deposited: u64,
+    pub withdrawal_count: u32,
+}
+
+// amount is what the vault was credited; fee is what the referrer got from the reward pool
+#[event]
+pub struct ReferralDeposit {
+    pub vault_state: Pubkey,
+    pub depositor: Pubkey,
+    pub referrer: Pubkey,
+    pub amount: u64,
+    pub fee: u64,
+}
+
+// recipient is the vault for deposit bonuses
+#[event]
+pub struct BonusPaid {
+    pub vault_state: Pubkey,
+    pub recipient: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct DepositRefunded {
+    pub vault_state: Pubkey,
+    pub depositor: Pubkey,
+    pub receipt_id: u64,
+    pub amount: u64,
+}
+
+#[event]
+pub struct VaultReconciled {
+    pub vault_state: Pubkey,
+    pub owner: Pubkey,
+}
+
+#[event]
+pub struct GoalReached {
+    pub vault_state: Pubkey,
+    pub goal: u64,
+    pub balance: u64,
+}
+
+// old and new are widened to u64 whatever the field's own type
+#[event]
+pub struct LimitsChanged {
+    pub vault_state: Pubkey,
+    pub field: LimitField,
+    pub old: u64,
+    pub new: u64,
+}
+
+#[event]
+pub struct Migrated {
+    pub from: Pubkey,
+    pub to: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct DepositEvent {
+    pub vault_state: Pubkey,
+    pub topic: [u8; 16],
+    pub user: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct WithdrawEvent {
+    pub vault_state: Pubkey,
+    pub topic: [u8; 16],
+    pub recipient: Pubkey,
+    pub amount: u64,
+}
+
+#[error_code]
+pub enum VaultError {
+    #[msg("Forwarding target accounts were not provided")]
+    ForwardAccountsMissing,
+    #[msg("Forwarding target does not match the linked vault")]
+    ForwardTargetMismatch,
+    #[msg("Forwarding is limited to a single hop")]
+    ForwardingDepthExceeded,
+    #[msg("Vault still holds lamports, tokens or stake")]
+    VaultNotEmpty,
+    #[msg("Account does not match the canonical PDA")]
+    InvalidPda,
+    #[msg("Allowlist TTL must be positive")]
+    InvalidTtl,
+    #[msg("Recipient is not on the allowlist or its entry has expired")]
+    RecipientNotAllowed,
+    #[msg("Amount must be greater than zero")]
+    InvalidAmount,
+    #[msg("Coupon has already been redeemed")]
+    AlreadyRedeemed,
+    #[msg("Duration must not be negative")]
+    InvalidDuration,
+    #[msg("Vault has not reached its minimum age for withdrawals")]
+    VaultTooYoung,
+    #[msg("Arithmetic overflow")]
+    Overflow,
+    #[msg("Fee estimate exceeds the reimbursement limit")]
+    FeeEstimateTooHigh,
+    #[msg("Vault balance is too low for this withdrawal")]
+    InsufficientVaultBalance,
+    #[msg("Basis points must not exceed 10000")]
+    InvalidBps,
+    #[msg("Vault is locked")]
+    VaultLocked,
+    #[msg("A lock can only be extended")]
+    LockShortened,
+    #[msg("Fee treasury is missing, mismatched, or not a writable system account")]
+    InvalidTreasury,
+    #[msg("Vesting schedule has already been set")]
+    VestingAlreadySet,
+    #[msg("Amount exceeds the vested balance")]
+    NotYetVested,
+    #[msg("Deposit is smaller than the rounding unit")]
+    DepositBelowRoundingUnit,
+    #[msg("Close is armed; use close_confirmed with the code")]
+    CloseNotConfirmed,
+    #[msg("Confirmation code does not match")]
+    InvalidConfirmationCode,
+    #[msg("Vault owner has not opted in to authority recovery")]
+    RecoveryNotAllowed,
+    #[msg("Signer is not authorized for this vault")]
+    Unauthorized,
+    #[msg("Too many items in one batch")]
+    BatchTooLarge,
+    #[msg("Remaining accounts do not match the expected layout")]
+    InvalidRemainingAccounts,
+    #[msg("The vault's lifetime withdrawal quota is exhausted")]
+    WithdrawalQuotaExhausted,
+    #[msg("Deposit would exceed the vault's deposit cap")]
+    DepositCapExceeded,
+    #[msg("The vault holds no lamports")]
+    VaultNotFunded,
+    #[msg("Token account mint does not match the vault mint")]
+    MintMismatch,
+    #[msg("The vault is paused")]
+    VaultPaused,
+    #[msg("The vault configuration is frozen")]
+    ConfigFrozen,
+    #[msg("Data length exceeds the maximum account size")]
+    InvalidDataLength,
+    #[msg("Deposit exceeds the delegate's remaining allowance")]
+    AllowanceExceeded,
+    #[msg("Instruction does not apply to the vault's lock mode")]
+    LockModeMismatch,
+    #[msg("Invalid multisig signer set or threshold")]
+    InvalidMultisig,
+    #[msg("Signer is not part of the vault's multisig")]
+    NotMultisigSigner,
+    #[msg("Approval is pending for a different amount")]
+    ApprovalMismatch,
+    #[msg("Withdrawal needs a multisig approval")]
+    ApprovalRequired,
+    #[msg("The vault keeps an activity log that was not passed")]
+    ActivityLogMissing,
+    #[msg("The vault has notifications enabled but the notify marker was not passed")]
+    NotifyMarkerMissing,
+    #[msg("Symbol must be 1-8 uppercase letters or digits, zero padded")]
+    InvalidSymbol,
+    #[msg("Deposit would exceed this period's deposit cap")]
+    PeriodDepositCapExceeded,
+    #[msg("The vault already has a stake account")]
+    StakeAlreadyActive,
+    #[msg("The vault has no stake account")]
+    NoActiveStake,
+    #[msg("Donations have not exceeded the sweep threshold")]
+    DonationsBelowThreshold,
+    #[msg("The vault has not been inactive long enough for a keeper to close it")]
+    VaultStillActive,
+    #[msg("deposit_min_usd needs the price feed account")]
+    PriceFeedMissing,
+    #[msg("The price feed has not been updated recently enough")]
+    StalePrice,
+    #[msg("The deposit is worth less than the USD minimum")]
+    BelowUsdFloor,
+    #[msg("The depositor does not hold enough lamports for this deposit")]
+    InsufficientUserBalance,
+    #[msg("Withdrawal is far above the vault's recent withdrawal average")]
+    WithdrawalSpike,
+    #[msg("The vault has been migrated to v2")]
+    VaultMigrated,
+    #[msg("The state and vault PDAs derive to the same address")]
+    SeedCollision,
+    #[msg("The guardian's delegation has expired")]
+    GuardianExpired,
+    #[msg("This vault only withdraws to its owner")]
+    SelfCustodyOnly,
+    #[msg("Withdrawal would exceed today's withdraw limit")]
+    DailyWithdrawLimitExceeded,
+    #[msg("Unknown vault seed version")]
+    InvalidSeedVersion,
+    #[msg("The inline allowlist takes at most 3 recipients and no default pubkey")]
+    InvalidInlineAllowlist,
+    #[msg("The configured reward program was not provided")]
+    RewardProgramMissing,
+    #[msg("Reward program does not match the configured one")]
+    RewardProgramMismatch,
+    #[msg("This vault only takes third-party deposits through deposit_with_proof")]
+    DepositProofRequired,
+    #[msg("This vault has no deposit Merkle root")]
+    NoMerkleRoot,
+    #[msg("Merkle proof does not lead to the vault's root")]
+    InvalidMerkleProof,
+    #[msg("Stats can't be reset while a lifetime withdrawal quota is set")]
+    WithdrawalQuotaActive,
+    #[msg("Vault balance is not at its rent-exempt minimum")]
+    NotAtRentFloor,
+    #[msg("Unknown action")]
+    InvalidAction,
+    #[msg("The vault's deposit campaign is not open")]
+    CampaignClosed,
+    #[msg("Vault is archived")]
+    VaultArchived,
+    #[msg("Withdrawal is below the vault's minimum")]
+    WithdrawBelowMinimum,
+    #[msg("Another pending operation is already in progress")]
+    PendingOperationExists,
+    #[msg("This vault only pays out through withdraw_if")]
+    ReleaseConditionRequired,
+    #[msg("This vault has no release condition")]
+    NoReleaseCondition,
+    #[msg("Condition account does not match the release condition")]
+    ConditionAccountMismatch,
+    #[msg("Release condition is not met")]
+    ReleaseConditionNotMet,
+    #[msg("This vault does not refund deposits")]
+    AutoRefundDisabled,
+    #[msg("The refund window has not passed yet")]
+    RefundNotDue,
+    #[msg("The vault's mint is locked")]
+    MintLocked,
+    #[msg("This vault has no token account")]
+    NoTokenVault,
+    #[msg("Reward pool account is required")]
+    RewardPoolMissing,
+    #[msg("Referrer must be another account and match the one passed")]
+    InvalidReferrer,
+    #[msg("Withdrawals to third parties are paused")]
+    ExternalWithdrawPaused,
+    #[msg("Validation hook account is required")]
+    ValidationHookMissing,
+    #[msg("Validation hook does not match the vault's hook")]
+    ValidationHookMismatch,
+    #[msg("This vault's validation hook only runs on withdraw_to")]
+    ValidationHookRequired,
+    #[msg("Vault already has a token account")]
+    TokenVaultExists,
 }
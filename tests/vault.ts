@@ -1,6 +1,11 @@
 import * as anchor from "@coral-xyz/anchor";
 import { Program } from "@coral-xyz/anchor";
 import { Vault } from "../target/types/vault";
+import { MockReward } from "../target/types/mock_reward";
+import { assert } from "chai";
+import * as fs from "fs";
+import { createHash } from "crypto";
+import { NATIVE_MINT, createAccount, createMint, getAccount, getAssociatedTokenAddressSync, mintTo } from "@solana/spl-token";
 
 describe("anchor-vault", () => {
   // Configure the client to use the local cluster.
@@ -9,13 +14,127 @@ describe("anchor-vault", () => {
 
   const program = anchor.workspace.Vault as Program<Vault>;
 
-  const vaultState = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("state"), provider.publicKey.toBytes()], program.programId)[0];
+  const vaultState = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("state"), provider.publicKey.toBytes(), Buffer.from([0])], program.programId)[0];
   const vault = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("vault"), vaultState.toBytes()], program.programId)[0];
 
+  // derive the state/vault PDA pair for any user and vault index
+  const vaultPdas = (user: anchor.web3.PublicKey, index = 0) => {
+    const state = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("state"), user.toBytes(), Buffer.from([index])], program.programId)[0];
+    const vault = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("vault"), state.toBytes()], program.programId)[0];
+    return { vaultState: state, vault };
+  };
+
+  // create a funded keypair so each feature test gets its own vault
+  const newUser = async (sol = 5) => {
+    const user = anchor.web3.Keypair.generate();
+    const sig = await provider.connection.requestAirdrop(user.publicKey, sol * anchor.web3.LAMPORTS_PER_SOL);
+    await provider.connection.confirmTransaction(sig, "confirmed");
+    return user;
+  };
+
+  const sleep = (ms: number) => new Promise((resolve) => setTimeout(resolve, ms));
+
+  // decode the Anchor events emitted by a confirmed transaction
+  const eventsOf = async (signature: string) => {
+    const tx = await provider.connection.getTransaction(signature, { commitment: "confirmed", maxSupportedTransactionVersion: 0 });
+    const parser = new anchor.EventParser(program.programId, new anchor.BorshCoder(program.idl));
+    return Array.from(parser.parseLogs(tx.meta.logMessages));
+  };
+
+  const config = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("config")], program.programId)[0];
+
+  // the program config is global, so create it once with the provider (the upgrade authority) as program authority
+  const ensureConfig = async () => {
+    if (await provider.connection.getAccountInfo(config)) {
+      return;
+    }
+    const programData = anchor.web3.PublicKey.findProgramAddressSync(
+      [program.programId.toBytes()],
+      new anchor.web3.PublicKey("BPFLoaderUpgradeab1e11111111111111111111111")
+    )[0];
+    await program.methods
+    .initializeConfig(provider.wallet.publicKey)
+    .accountsPartial({ upgradeAuthority: provider.wallet.publicKey, config, program: program.programId, programData })
+    .rpc();
+  };
+
+  const initializeVault = async (user: anchor.web3.Keypair, index = 0) => {
+    const pdas = vaultPdas(user.publicKey, index);
+    await program.methods
+    .initialize(index)
+    .accountsPartial({
+      user: user.publicKey,
+      ...pdas,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    })
+    .signers([user])
+    .rpc();
+    return pdas;
+  };
+
+  // a new user's vault holding `amount` deposited lamports on top of its rent
+  const fundedVault = async (amount = 2 * anchor.web3.LAMPORTS_PER_SOL, airdrop = 5) => {
+    const user = await newUser(airdrop);
+    const pdas = await initializeVault(user);
+    await program.methods
+    .deposit(new anchor.BN(amount))
+    .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+    .signers([user])
+    .rpc();
+    return { user, pdas, configure: { user: user.publicKey, vaultState: pdas.vaultState } };
+  };
+
+  // delegates `amount` of the vault's lamports to the local validator's vote account
+  const delegateStake = async (user: anchor.web3.Keypair, pdas: ReturnType<typeof vaultPdas>, amount: number) => {
+    const { current } = await provider.connection.getVoteAccounts();
+    const [stakeAccount] = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("stake"), pdas.vaultState.toBuffer()], program.programId);
+    await program.methods
+    .delegateStake(new anchor.BN(amount))
+    .accountsPartial({
+      user: user.publicKey,
+      ...pdas,
+      stakeAccount,
+      voteAccount: new anchor.web3.PublicKey(current[0].votePubkey),
+      stakeHistory: anchor.web3.SYSVAR_STAKE_HISTORY_PUBKEY,
+      stakeConfig: anchor.web3.STAKE_CONFIG_ID,
+      stakeProgram: anchor.web3.StakeProgram.programId,
+    })
+    .signers([user])
+    .rpc();
+    return stakeAccount;
+  };
+
+  // create a fresh mint and the vault's token account for it
+  const initTokenVault = async (user: anchor.web3.Keypair, pdas: ReturnType<typeof vaultPdas>, decimals = 6) => {
+    const mint = await createMint(provider.connection, user, user.publicKey, null, decimals);
+    const tokenVault = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("token_vault"), pdas.vaultState.toBytes()], program.programId)[0];
+    await program.methods
+    .initTokenVault()
+    .accountsPartial({ user: user.publicKey, ...pdas, mint, tokenVault })
+    .signers([user])
+    .rpc();
+    return { mint, tokenVault };
+  };
+
+  // sends one instruction and asserts that the lamports held by `accounts` and the fee payer
+  // changed by exactly the network fee, so the program neither minted nor lost any lamports
+  const assertConserved = async (accounts: anchor.web3.PublicKey[], send: () => Promise<string>) => {
+    const tracked = [provider.wallet.publicKey, ...accounts].filter(
+      (key, i, all) => all.findIndex((other) => other.equals(key)) === i
+    );
+    const total = async () =>
+      (await provider.connection.getMultipleAccountsInfo(tracked, "confirmed")).reduce((sum, info) => sum + (info?.lamports ?? 0), 0);
+
+    const before = await total();
+    const signature = await send();
+    const tx = await provider.connection.getTransaction(signature, { commitment: "confirmed", maxSupportedTransactionVersion: 0 });
+    assert.equal(await total(), before - tx.meta.fee, `lamports not conserved by ${signature}`);
+  };
+
   it("Is initialized!", async () => {
     // Add your test here.
     const tx = await program.methods
-    .initialize()
+    .initialize(0)
     .accountsPartial({
       user: provider.wallet.publicKey,
       vaultState,
@@ -73,4 +192,4550 @@ describe("anchor-vault", () => {
     console.log("\nYour transaction signature", tx);
     console.log("Your vault info", (await provider.connection.getAccountInfo(vault)));
   });
-});
\ No newline at end of file
+
+  describe("forwarding", () => {
+    it("Forwards deposits to the linked vault", async () => {
+      const mailboxOwner = await newUser();
+      const targetOwner = await newUser();
+      const mailbox = await initializeVault(mailboxOwner);
+      const target = await initializeVault(targetOwner);
+
+      await program.methods
+      .setForwardTo(target.vaultState)
+      .accountsPartial({ user: mailboxOwner.publicKey, vaultState: mailbox.vaultState })
+      .signers([mailboxOwner])
+      .rpc();
+
+      const mailboxBefore = await provider.connection.getBalance(mailbox.vault);
+      const targetBefore = await provider.connection.getBalance(target.vault);
+      const amount = 1 * anchor.web3.LAMPORTS_PER_SOL;
+
+      await program.methods
+      .deposit(new anchor.BN(amount))
+      .accountsPartial({
+        user: mailboxOwner.publicKey,
+        ...mailbox,
+        systemProgram: anchor.web3.SystemProgram.programId,
+        forwardState: target.vaultState,
+        forwardVault: target.vault,
+      })
+      .signers([mailboxOwner])
+      .rpc();
+
+      assert.equal(await provider.connection.getBalance(mailbox.vault), mailboxBefore);
+      assert.equal(await provider.connection.getBalance(target.vault), targetBefore + amount);
+    });
+
+    it("Rejects forwarding more than one hop", async () => {
+      const first = await newUser();
+      const second = await newUser();
+      const third = await newUser();
+      const a = await initializeVault(first);
+      const b = await initializeVault(second);
+      const c = await initializeVault(third);
+
+      await program.methods.setForwardTo(b.vaultState).accountsPartial({ user: first.publicKey, vaultState: a.vaultState }).signers([first]).rpc();
+      await program.methods.setForwardTo(c.vaultState).accountsPartial({ user: second.publicKey, vaultState: b.vaultState }).signers([second]).rpc();
+
+      try {
+        await program.methods
+        .deposit(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+        .accountsPartial({
+          user: first.publicKey,
+          ...a,
+          systemProgram: anchor.web3.SystemProgram.programId,
+          forwardState: b.vaultState,
+          forwardVault: b.vault,
+        })
+        .signers([first])
+        .rpc();
+        assert.fail("deposit should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "ForwardingDepthExceeded");
+      }
+    });
+  });
+
+  describe("close state only", () => {
+    it("Rejects closing state while the vault holds funds", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+
+      try {
+        await program.methods
+        .closeStateOnly()
+        .accountsPartial({ user: user.publicKey, ...pdas })
+        .signers([user])
+        .rpc();
+        assert.fail("close should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "VaultNotEmpty");
+      }
+    });
+
+    it("Closes only the state once the vault is empty", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+
+      const balance = await provider.connection.getBalance(pdas.vault);
+      await program.methods
+      .withdraw(new anchor.BN(balance))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+
+      await program.methods
+      .closeStateOnly()
+      .accountsPartial({ user: user.publicKey, ...pdas })
+      .signers([user])
+      .rpc();
+
+      assert.isNull(await provider.connection.getAccountInfo(pdas.vaultState));
+      assert.equal(await provider.connection.getBalance(pdas.vault), 0);
+    });
+
+    it("Rejects closing state while the vault has a token account", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      await initTokenVault(user, pdas);
+      const balance = await provider.connection.getBalance(pdas.vault);
+      await program.methods
+      .withdraw(new anchor.BN(balance))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+
+      try {
+        await program.methods
+        .closeStateOnly()
+        .accountsPartial({ user: user.publicKey, ...pdas })
+        .signers([user])
+        .rpc();
+        assert.fail("close should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "VaultNotEmpty");
+      }
+    });
+
+    it("Rejects closing state while the whole balance is delegated", async () => {
+      const { user, pdas } = await fundedVault(3 * anchor.web3.LAMPORTS_PER_SOL, 10);
+      await delegateStake(user, pdas, await provider.connection.getBalance(pdas.vault));
+      assert.equal(await provider.connection.getBalance(pdas.vault), 0);
+
+      try {
+        await program.methods
+        .closeStateOnly()
+        .accountsPartial({ user: user.publicKey, ...pdas })
+        .signers([user])
+        .rpc();
+        assert.fail("close should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "VaultNotEmpty");
+      }
+    });
+  });
+
+  describe("refresh bumps", () => {
+    it("Keeps canonical bumps", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+
+      await program.methods
+      .refreshBumps()
+      .accountsPartial({ user: user.publicKey, ...pdas })
+      .signers([user])
+      .rpc();
+
+      const [, stateBump] = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("state"), user.publicKey.toBytes(), Buffer.from([0])], program.programId);
+      const [, vaultBump] = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("vault"), pdas.vaultState.toBytes()], program.programId);
+      const state = await program.account.vaultState.fetch(pdas.vaultState);
+      assert.equal(state.stateBump, stateBump);
+      assert.equal(state.vaultBump, vaultBump);
+    });
+
+    it("Rejects a vault that is not the canonical PDA", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+
+      try {
+        await program.methods
+        .refreshBumps()
+        .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState, vault: user.publicKey })
+        .signers([user])
+        .rpc();
+        assert.fail("refresh should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "InvalidPda");
+      }
+    });
+  });
+
+  describe("expiring allowlist", () => {
+    it("Allows withdraw_to before expiry and rejects it after", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const recipient = anchor.web3.Keypair.generate().publicKey;
+      const allowlistEntry = anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("allow"), pdas.vaultState.toBytes(), recipient.toBytes()],
+        program.programId
+      )[0];
+
+      await program.methods
+      .deposit(new anchor.BN(2 * anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+
+      await program.methods
+      .allowRecipient(recipient, new anchor.BN(3))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState, allowlistEntry })
+      .signers([user])
+      .rpc();
+
+      const withdrawTo = () =>
+        program.methods
+        .withdrawTo(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+        .accountsPartial({ user: user.publicKey, ...pdas, recipient, allowlistEntry })
+        .signers([user])
+        .rpc();
+
+      await withdrawTo();
+      assert.equal(await provider.connection.getBalance(recipient), anchor.web3.LAMPORTS_PER_SOL);
+
+      // let the validator clock move past expires_at
+      await sleep(5000);
+      try {
+        await withdrawTo();
+        assert.fail("withdraw_to should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "RecipientNotAllowed");
+      }
+    });
+  });
+
+  describe("coupons", () => {
+    it("Redeems a coupon once", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const rewardPool = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("reward_pool"), pdas.vaultState.toBytes()], program.programId)[0];
+      const couponId = new anchor.BN(1);
+      const coupon = anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("coupon"), pdas.vaultState.toBytes(), couponId.toArrayLike(Buffer, "le", 8)],
+        program.programId
+      )[0];
+      const amount = 0.5 * anchor.web3.LAMPORTS_PER_SOL;
+
+      await program.methods
+      .fundRewardPool(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState, rewardPool })
+      .signers([user])
+      .rpc();
+      await program.methods
+      .issueCoupon(couponId, new anchor.BN(amount))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState, coupon })
+      .signers([user])
+      .rpc();
+
+      const redeem = () =>
+        program.methods
+        .redeemCoupon()
+        .accountsPartial({ user: user.publicKey, ...pdas, rewardPool, coupon })
+        .signers([user])
+        .rpc();
+
+      const before = await provider.connection.getBalance(pdas.vault);
+      await redeem();
+      assert.equal(await provider.connection.getBalance(pdas.vault), before + amount);
+      assert.isTrue((await program.account.couponState.fetch(coupon)).redeemed);
+
+      try {
+        await redeem();
+        assert.fail("second redemption should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "AlreadyRedeemed");
+      }
+    });
+  });
+
+  describe("minimum age", () => {
+    it("Blocks withdrawals until the vault is old enough", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+
+      await program.methods
+      .setMinAge(new anchor.BN(4))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      await program.methods
+      .deposit(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+
+      const withdraw = () =>
+        program.methods
+        .withdraw(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+        .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([user])
+        .rpc();
+
+      try {
+        await withdraw();
+        assert.fail("withdraw should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "VaultTooYoung");
+      }
+
+      await sleep(6000);
+      await withdraw();
+    });
+  });
+
+  describe("split", () => {
+    it("Splits part of a vault into a new index", async () => {
+      const user = await newUser();
+      const source = await initializeVault(user);
+      const target = vaultPdas(user.publicKey, 1);
+      const deposited = 2 * anchor.web3.LAMPORTS_PER_SOL;
+      const moved = 0.5 * anchor.web3.LAMPORTS_PER_SOL;
+
+      await program.methods
+      .deposit(new anchor.BN(deposited))
+      .accountsPartial({ user: user.publicKey, ...source, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+
+      const sourceBefore = await provider.connection.getBalance(source.vault);
+      await program.methods
+      .splitVault(1, new anchor.BN(moved))
+      .accountsPartial({
+        user: user.publicKey,
+        ...source,
+        newVaultState: target.vaultState,
+        newVault: target.vault,
+      })
+      .signers([user])
+      .rpc();
+
+      const rent = await provider.connection.getMinimumBalanceForRentExemption(0);
+      assert.equal(await provider.connection.getBalance(source.vault), sourceBefore - moved);
+      assert.equal(await provider.connection.getBalance(target.vault), rent + moved);
+      assert.equal((await program.account.vaultState.fetch(source.vaultState)).totalDeposited.toNumber(), deposited - moved);
+      const split = await program.account.vaultState.fetch(target.vaultState);
+      assert.equal(split.totalDeposited.toNumber(), moved);
+      assert.equal(split.index, 1);
+    });
+
+    it("Applies the source vault's withdrawal limits to a split", async () => {
+      const { user, pdas: source, configure } = await fundedVault();
+      const target = vaultPdas(user.publicKey, 1);
+      const sol = anchor.web3.LAMPORTS_PER_SOL;
+      await program.methods.setMinWithdraw(new anchor.BN(0.1 * sol)).accountsPartial(configure).signers([user]).rpc();
+      await program.methods.setMaxLifetimeWithdrawals(1).accountsPartial(configure).signers([user]).rpc();
+      const split = (amount: number) =>
+        program.methods
+        .splitVault(1, new anchor.BN(amount))
+        .accountsPartial({ user: user.publicKey, ...source, newVaultState: target.vaultState, newVault: target.vault })
+        .signers([user])
+        .rpc();
+
+      try {
+        await split(0.05 * sol);
+        assert.fail("a split below min_withdraw should be rejected");
+      } catch (e) {
+        assert.include(e.toString(), "WithdrawBelowMinimum");
+      }
+      await split(sol);
+      // the split used up the only withdrawal the source allows
+      try {
+        await program.methods
+        .withdraw(new anchor.BN(0.5 * sol))
+        .accountsPartial({ user: user.publicKey, ...source, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([user])
+        .rpc();
+        assert.fail("the withdrawal quota should be exhausted");
+      } catch (e) {
+        assert.include(e.toString(), "WithdrawalQuotaExhausted");
+      }
+      assert.equal((await program.account.vaultState.fetch(source.vaultState)).withdrawalCount, 1);
+    });
+  });
+
+  describe("withdraw net", () => {
+    it("Pays the user exactly the net amount and reimburses the fee payer", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      await program.methods
+      .deposit(new anchor.BN(2 * anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+
+      const desiredNet = anchor.web3.LAMPORTS_PER_SOL;
+      const feeEstimate = 5000;
+      const userBefore = await provider.connection.getBalance(user.publicKey);
+      const vaultBefore = await provider.connection.getBalance(pdas.vault);
+
+      // the provider wallet is the transaction fee payer, acting as the relayer
+      await program.methods
+      .withdrawNet(new anchor.BN(desiredNet), new anchor.BN(feeEstimate))
+      .accountsPartial({ user: user.publicKey, feePayer: provider.wallet.publicKey, ...pdas })
+      .signers([user])
+      .rpc();
+
+      assert.equal(await provider.connection.getBalance(user.publicKey), userBefore + desiredNet);
+      assert.equal(await provider.connection.getBalance(pdas.vault), vaultBefore - desiredNet - feeEstimate);
+    });
+  });
+
+  describe("balance snapshot", () => {
+    it("Records the vault balance and slot", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const snapshot = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("snapshot"), pdas.vaultState.toBytes()], program.programId)[0];
+      await program.methods
+      .deposit(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+
+      await program.methods
+      .snapshotBalance()
+      .accountsPartial({ payer: provider.wallet.publicKey, ...pdas, snapshot })
+      .rpc({ commitment: "confirmed" });
+
+      const recorded = await program.account.balanceSnapshot.fetch(snapshot, "confirmed");
+      assert.equal(recorded.balance.toNumber(), await provider.connection.getBalance(pdas.vault));
+      assert.isTrue(recorded.slot.toNumber() <= (await provider.connection.getSlot("confirmed")));
+      assert.isTrue(recorded.vaultState.equals(pdas.vaultState));
+    });
+  });
+
+  describe("event verbosity", () => {
+    it("Emits events only when verbose_events is set", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const deposit = () =>
+        program.methods
+        .deposit(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+        .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([user])
+        .rpc({ commitment: "confirmed" });
+      const withdraw = () =>
+        program.methods
+        .withdraw(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+        .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([user])
+        .rpc({ commitment: "confirmed" });
+
+      const depositEvents = await eventsOf(await deposit());
+      assert.deepEqual(depositEvents.map((e) => e.name), ["depositEvent"]);
+      assert.equal(depositEvents[0].data.amount.toNumber(), anchor.web3.LAMPORTS_PER_SOL);
+      assert.deepEqual((await eventsOf(await withdraw())).map((e) => e.name), ["withdrawEvent"]);
+
+      await program.methods
+      .setVerboseEvents(false)
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+
+      assert.isEmpty(await eventsOf(await deposit()));
+      assert.isEmpty(await eventsOf(await withdraw()));
+    });
+  });
+
+  describe("withdraw with penalty", () => {
+    it("Burns the penalty and pays the remainder", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      await program.methods
+      .deposit(new anchor.BN(2 * anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+
+      const amount = anchor.web3.LAMPORTS_PER_SOL;
+      const penalty = amount * 0.1; // 1000 bps
+      const userBefore = await provider.connection.getBalance(user.publicKey);
+      const vaultBefore = await provider.connection.getBalance(pdas.vault);
+
+      await program.methods
+      .withdrawWithPenalty(new anchor.BN(amount), 1000)
+      .accountsPartial({
+        user: user.publicKey,
+        ...pdas,
+        incinerator: new anchor.web3.PublicKey("1nc1nerator11111111111111111111111111111111"),
+      })
+      .signers([user])
+      .rpc();
+
+      // the provider wallet pays the fee, so the user's balance moves only by the net amount
+      assert.equal(await provider.connection.getBalance(user.publicKey), userBefore + amount - penalty);
+      assert.equal(await provider.connection.getBalance(pdas.vault), vaultBefore - amount);
+    });
+  });
+
+  describe("early withdrawal penalty", () => {
+    it("Charges the penalty before unlock_at and nothing after", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const feeTreasury = anchor.web3.Keypair.generate().publicKey;
+      const unlockAt = Math.floor(Date.now() / 1000) + 5;
+
+      await program.methods
+      .deposit(new anchor.BN(3 * anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      await program.methods
+      .setFee(500)
+      .accountsPartial({ user: user.publicKey, ...pdas, feeTreasury })
+      .signers([user])
+      .rpc();
+      await program.methods
+      .setAllowEarlyWithdraw(true)
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      await program.methods
+      .setUnlockAt(new anchor.BN(unlockAt))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+
+      const amount = anchor.web3.LAMPORTS_PER_SOL;
+      const withdraw = () =>
+        program.methods
+        .withdraw(new anchor.BN(amount))
+        .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId, feeTreasury })
+        .signers([user])
+        .rpc();
+
+      let userBefore = await provider.connection.getBalance(user.publicKey);
+      await withdraw();
+      const penalty = amount * 0.05;
+      assert.equal(await provider.connection.getBalance(feeTreasury), penalty);
+      assert.equal(await provider.connection.getBalance(user.publicKey), userBefore + amount - penalty);
+
+      await sleep(7000);
+      userBefore = await provider.connection.getBalance(user.publicKey);
+      await withdraw();
+      assert.equal(await provider.connection.getBalance(feeTreasury), penalty);
+      assert.equal(await provider.connection.getBalance(user.publicKey), userBefore + amount);
+    });
+  });
+
+  describe("vesting", () => {
+    const vestingAmount = 2 * anchor.web3.LAMPORTS_PER_SOL;
+    const duration = 1_000_000;
+
+    // each schedule point gets its own vault since a schedule can only be set once
+    const vaultWithSchedule = async (startOffset: number) => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      await program.methods
+      .deposit(new anchor.BN(vestingAmount))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      const chainNow = await provider.connection.getBlockTime(await provider.connection.getSlot());
+      await program.methods
+      .setVesting(new anchor.BN(chainNow + startOffset), new anchor.BN(duration), new anchor.BN(vestingAmount))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      return pdas;
+    };
+
+    const expectedAvailable = (balance: number, elapsed: number) => {
+      const clamped = Math.min(Math.max(elapsed, 0), duration);
+      return balance - (vestingAmount - Math.floor((vestingAmount * clamped) / duration));
+    };
+
+    it("Matches the linear schedule before, during and after vesting", async () => {
+      for (const startOffset of [duration, -duration / 2, -2 * duration]) {
+        const pdas = await vaultWithSchedule(startOffset);
+        const balance = await provider.connection.getBalance(pdas.vault);
+        const available = (await program.methods.vestedAvailable().accountsPartial(pdas).view()).toNumber();
+        // the view runs a few seconds after the schedule was set at most
+        const low = expectedAvailable(balance, -startOffset);
+        const high = expectedAvailable(balance, -startOffset + 30);
+        assert.isTrue(available >= low && available <= high, `available ${available} not in [${low}, ${high}]`);
+      }
+    });
+
+    it("Rejects withdrawing unvested funds", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      await program.methods
+      .setVesting(new anchor.BN(Math.floor(Date.now() / 1000) + duration), new anchor.BN(duration), new anchor.BN(vestingAmount))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      await program.methods
+      .deposit(new anchor.BN(vestingAmount))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+
+      try {
+        await program.methods
+        .withdraw(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+        .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([user])
+        .rpc();
+        assert.fail("withdraw should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "NotYetVested");
+      }
+    });
+  });
+
+  describe("fee treasury validation", () => {
+    it("Rejects a program or the vault itself as treasury", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+
+      for (const feeTreasury of [anchor.web3.SystemProgram.programId, program.programId, pdas.vault]) {
+        try {
+          await program.methods
+          .setFee(500)
+          .accountsPartial({ user: user.publicKey, ...pdas, feeTreasury })
+          .signers([user])
+          .rpc();
+          assert.fail("set_fee should have been rejected");
+        } catch (e) {
+          assert.include(e.toString(), "InvalidTreasury");
+        }
+      }
+
+      const feeTreasury = anchor.web3.Keypair.generate().publicKey;
+      await program.methods
+      .setFee(500)
+      .accountsPartial({ user: user.publicKey, ...pdas, feeTreasury })
+      .signers([user])
+      .rpc();
+      assert.isTrue((await program.account.vaultState.fetch(pdas.vaultState)).feeTreasury.equals(feeTreasury));
+    });
+  });
+
+  describe("initialize many", () => {
+    it("Creates four vaults in one transaction", async () => {
+      const user = await newUser();
+      const count = 4;
+      const pairs = [...Array(count).keys()].map((index) => vaultPdas(user.publicKey, index));
+
+      await program.methods
+      .initializeMany(count)
+      .accountsPartial({ user: user.publicKey, systemProgram: anchor.web3.SystemProgram.programId })
+      .remainingAccounts(
+        pairs.flatMap(({ vaultState, vault }) => [
+          { pubkey: vaultState, isWritable: true, isSigner: false },
+          { pubkey: vault, isWritable: true, isSigner: false },
+        ])
+      )
+      .signers([user])
+      .rpc();
+
+      const rent = await provider.connection.getMinimumBalanceForRentExemption(0);
+      for (const [index, { vaultState, vault }] of pairs.entries()) {
+        const state = await program.account.vaultState.fetch(vaultState);
+        assert.equal(state.index, index);
+        assert.isTrue(state.verboseEvents);
+        assert.equal(await provider.connection.getBalance(vault), rent);
+      }
+    });
+
+    it("Rejects accounts out of index order", async () => {
+      const user = await newUser();
+      const pairs = [vaultPdas(user.publicKey, 1), vaultPdas(user.publicKey, 0)];
+
+      try {
+        await program.methods
+        .initializeMany(2)
+        .accountsPartial({ user: user.publicKey, systemProgram: anchor.web3.SystemProgram.programId })
+        .remainingAccounts(
+          pairs.flatMap(({ vaultState, vault }) => [
+            { pubkey: vaultState, isWritable: true, isSigner: false },
+            { pubkey: vault, isWritable: true, isSigner: false },
+          ])
+        )
+        .signers([user])
+        .rpc();
+        assert.fail("initialize_many should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "InvalidPda");
+      }
+    });
+  });
+
+  describe("close cost", () => {
+    it("Conserves lamports and keeps the transfer CPI cheap", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      await program.methods
+      .deposit(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+
+      const userBefore = await provider.connection.getBalance(user.publicKey);
+      const vaultBefore = await provider.connection.getBalance(pdas.vault);
+      const stateBefore = await provider.connection.getBalance(pdas.vaultState);
+
+      const signature = await program.methods
+      .close()
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc({ commitment: "confirmed" });
+
+      // the provider pays the fee, so everything held by the vault pair must land with the user
+      assert.equal(await provider.connection.getBalance(user.publicKey), userBefore + vaultBefore + stateBefore);
+
+      // close_state_only reclaims the same state rent with direct lamport moves only, so the
+      // difference is what the vault's transfer CPI (and close's lock checks) cost
+      const drained = await initializeVault(user, 1);
+      await program.methods
+      .withdraw(new anchor.BN(await provider.connection.getBalance(drained.vault)))
+      .accountsPartial({ user: user.publicKey, ...drained, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      const direct = await program.methods
+      .closeStateOnly()
+      .accountsPartial({ user: user.publicKey, ...drained })
+      .signers([user])
+      .rpc({ commitment: "confirmed" });
+
+      const unitsOf = async (sig: string) =>
+        (await provider.connection.getTransaction(sig, { commitment: "confirmed", maxSupportedTransactionVersion: 0 })).meta
+          .computeUnitsConsumed;
+      const cpiCost = (await unitsOf(signature)) - (await unitsOf(direct));
+      assert.isAbove(cpiCost, 0);
+      assert.isBelow(cpiCost, 10_000);
+    });
+  });
+
+  describe("close authorization", () => {
+    it("Rejects a close signed by someone other than the owner", async () => {
+      const owner = await newUser();
+      const stranger = await newUser();
+      const pdas = await initializeVault(owner);
+
+      try {
+        await program.methods
+        .close()
+        .accountsPartial({ user: stranger.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([stranger])
+        .rpc();
+        assert.fail("close should have been rejected");
+      } catch (e) {
+        // the seeds constraint fires before the owner assertion is reached
+        assert.match(e.toString(), /ConstraintSeeds|Unauthorized/);
+      }
+      assert.isTrue((await program.account.vaultState.fetch(pdas.vaultState)).owner.equals(owner.publicKey));
+    });
+  });
+
+  describe("deposit rounding", () => {
+    it("Accepts only whole multiples and leaves the remainder with the user", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const unit = anchor.web3.LAMPORTS_PER_SOL;
+      await program.methods
+      .setRoundDepositsTo(new anchor.BN(unit))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+
+      const deposit = program.methods
+      .deposit(new anchor.BN(2.7 * unit))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user]);
+      assert.equal((await deposit.view()).toNumber(), 2 * unit);
+
+      const userBefore = await provider.connection.getBalance(user.publicKey);
+      const vaultBefore = await provider.connection.getBalance(pdas.vault);
+      await deposit.rpc();
+      assert.equal(await provider.connection.getBalance(pdas.vault), vaultBefore + 2 * unit);
+      assert.equal(await provider.connection.getBalance(user.publicKey), userBefore - 2 * unit);
+      assert.equal((await program.account.vaultState.fetch(pdas.vaultState)).totalDeposited.toNumber(), 2 * unit);
+
+      try {
+        await program.methods
+        .deposit(new anchor.BN(0.5 * unit))
+        .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([user])
+        .rpc();
+        assert.fail("deposit should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "DepositBelowRoundingUnit");
+      }
+    });
+  });
+
+  describe("user registry", () => {
+    it("Lists open vault indices across initialize and close", async () => {
+      const user = await newUser();
+      const registry = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("registry"), user.publicKey.toBytes()], program.programId)[0];
+      const listVaults = async () => Array.from(await program.methods.listVaults().accountsPartial({ registry }).view());
+
+      await initializeVault(user, 0);
+      await initializeVault(user, 2);
+      const third = await initializeVault(user, 5);
+      assert.deepEqual(await listVaults(), [0, 2, 5]);
+
+      await program.methods
+      .close()
+      .accountsPartial({ user: user.publicKey, ...vaultPdas(user.publicKey, 2), registry, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      assert.deepEqual(await listVaults(), [0, 5]);
+
+      await program.methods
+      .close()
+      .accountsPartial({ user: user.publicKey, ...third, registry, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      assert.deepEqual(await listVaults(), [0]);
+    });
+  });
+
+  describe("initialize with deposit", () => {
+    it("Funds rent and the first deposit together", async () => {
+      const user = await newUser();
+      const pdas = vaultPdas(user.publicKey);
+      const amount = 1.5 * anchor.web3.LAMPORTS_PER_SOL;
+
+      const signature = await program.methods
+      .initializeWithDeposit(0, new anchor.BN(amount))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc({ commitment: "confirmed" });
+
+      const rent = await provider.connection.getMinimumBalanceForRentExemption(0);
+      assert.equal(await provider.connection.getBalance(pdas.vault), rent + amount);
+      assert.equal((await program.account.vaultState.fetch(pdas.vaultState)).totalDeposited.toNumber(), amount);
+      assert.deepEqual((await eventsOf(signature)).map((e) => e.name), ["vaultInitialized", "depositEvent"]);
+    });
+
+    it("Rejects a first deposit the user can't cover, like deposit does", async () => {
+      const user = await newUser(1);
+      const pdas = vaultPdas(user.publicKey);
+
+      try {
+        await program.methods
+        .initializeWithDeposit(0, new anchor.BN(2 * anchor.web3.LAMPORTS_PER_SOL))
+        .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([user])
+        .rpc();
+        assert.fail("the deposit should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "InsufficientUserBalance");
+      }
+    });
+  });
+
+  describe("batch limits", () => {
+    // the same vault pair repeated keeps the transaction small, since duplicate keys are deduplicated
+    const repeatedPairs = (pdas: { vaultState: anchor.web3.PublicKey; vault: anchor.web3.PublicKey }, count: number) =>
+      [...Array(count)].flatMap(() => [
+        { pubkey: pdas.vaultState, isWritable: true, isSigner: false },
+        { pubkey: pdas.vault, isWritable: true, isSigner: false },
+      ]);
+
+    it("Processes a batch at the cap", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const amount = 0.1 * anchor.web3.LAMPORTS_PER_SOL;
+
+      await program.methods
+      .depositMany(Array(10).fill(new anchor.BN(amount)), { allOrNothing: {} })
+      .accountsPartial({ user: user.publicKey, systemProgram: anchor.web3.SystemProgram.programId })
+      .remainingAccounts(repeatedPairs(pdas, 10))
+      .signers([user])
+      .rpc();
+
+      assert.equal((await program.account.vaultState.fetch(pdas.vaultState)).totalDeposited.toNumber(), 10 * amount);
+    });
+
+    it("Rejects a batch one item over the cap", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+
+      try {
+        await program.methods
+        .depositMany(Array(11).fill(new anchor.BN(1000)), { allOrNothing: {} })
+        .accountsPartial({ user: user.publicKey, systemProgram: anchor.web3.SystemProgram.programId })
+        .remainingAccounts(repeatedPairs(pdas, 11))
+        .signers([user])
+        .rpc();
+        assert.fail("deposit_many should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "BatchTooLarge");
+      }
+    });
+
+    it("Sends to several allowlisted recipients", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const recipients = [...Array(3)].map(() => anchor.web3.Keypair.generate().publicKey);
+      const entries = recipients.map((recipient) =>
+        anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("allow"), pdas.vaultState.toBytes(), recipient.toBytes()], program.programId)[0]
+      );
+      await program.methods
+      .deposit(new anchor.BN(2 * anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      for (const [i, recipient] of recipients.entries()) {
+        await program.methods
+        .allowRecipient(recipient, new anchor.BN(3600))
+        .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState, allowlistEntry: entries[i] })
+        .signers([user])
+        .rpc();
+      }
+
+      const amount = 0.1 * anchor.web3.LAMPORTS_PER_SOL;
+      await program.methods
+      .withdrawToMany(recipients.map(() => new anchor.BN(amount)), { allOrNothing: {} })
+      .accountsPartial({ user: user.publicKey, ...pdas })
+      .remainingAccounts(
+        recipients.flatMap((recipient, i) => [
+          { pubkey: recipient, isWritable: true, isSigner: false },
+          { pubkey: entries[i], isWritable: false, isSigner: false },
+        ])
+      )
+      .signers([user])
+      .rpc();
+
+      for (const recipient of recipients) {
+        assert.equal(await provider.connection.getBalance(recipient), amount);
+      }
+    });
+  });
+
+  describe("confirmed close", () => {
+    it("Requires the armed code", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const accounts = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId };
+
+      await program.methods
+      .armClose([1, 2, 3, 4])
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+
+      try {
+        await program.methods.close().accountsPartial(accounts).signers([user]).rpc();
+        assert.fail("close should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "CloseNotConfirmed");
+      }
+      try {
+        await program.methods.closeConfirmed([4, 3, 2, 1]).accountsPartial(accounts).signers([user]).rpc();
+        assert.fail("close should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "InvalidConfirmationCode");
+      }
+
+      await program.methods.closeConfirmed([1, 2, 3, 4]).accountsPartial(accounts).signers([user]).rpc();
+      assert.isNull(await provider.connection.getAccountInfo(pdas.vaultState));
+    });
+  });
+
+  describe("slot lock", () => {
+    it("Locks withdrawals until the configured slot", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      await program.methods
+      .deposit(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+
+      const unlockSlot = (await provider.connection.getSlot()) + 20;
+      await program.methods
+      .setLockMode({ slot: {} })
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      await program.methods
+      .setUnlockSlot(new anchor.BN(unlockSlot))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+
+      const withdraw = () =>
+        program.methods
+        .withdraw(new anchor.BN(0.5 * anchor.web3.LAMPORTS_PER_SOL))
+        .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([user])
+        .rpc();
+
+      try {
+        await withdraw();
+        assert.fail("withdraw should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "VaultLocked");
+      }
+
+      // slots advance roughly every 400ms on the local validator
+      while ((await provider.connection.getSlot()) <= unlockSlot) {
+        await sleep(400);
+      }
+      await withdraw();
+    });
+  });
+
+  describe("authority recovery", () => {
+    before(ensureConfig);
+
+    it("Lets the authority recover funds to the owner only after opt-in", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      await program.methods
+      .deposit(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+
+      const recover = () =>
+        program.methods
+        .authorityRecover()
+        .accountsPartial({ programAuthority: provider.wallet.publicKey, config, ...pdas, owner: user.publicKey })
+        .rpc();
+
+      try {
+        await recover();
+        assert.fail("recovery should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "RecoveryNotAllowed");
+      }
+
+      await program.methods
+      .setAllowAuthorityRecovery(true)
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+
+      const vaultBefore = await provider.connection.getBalance(pdas.vault);
+      const ownerBefore = await provider.connection.getBalance(user.publicKey);
+      const stateBefore = await program.account.vaultState.fetch(pdas.vaultState);
+      await sleep(2000);
+      await recover();
+      assert.equal(await provider.connection.getBalance(pdas.vault), 0);
+      assert.equal(await provider.connection.getBalance(user.publicKey), ownerBefore + vaultBefore);
+      // the recovery's TWAB accrual is persisted like any other withdrawal's
+      const state = await program.account.vaultState.fetch(pdas.vaultState);
+      const elapsed = state.twabLastUpdate.toNumber() - stateBefore.twabLastUpdate.toNumber();
+      assert.isAbove(elapsed, 0);
+      assert.equal(
+        state.twabAccumulator.toString(),
+        (BigInt(stateBefore.twabAccumulator.toString()) + BigInt(vaultBefore) * BigInt(elapsed)).toString()
+      );
+    });
+  });
+
+  describe("deposit receipts", () => {
+    it("Creates a receipt for a third-party deposit and lets the depositor close it", async () => {
+      const owner = await newUser();
+      const depositor = await newUser();
+      const pdas = await initializeVault(owner);
+      const receiptId = new anchor.BN(1);
+      const [receipt] = anchor.web3.PublicKey.findProgramAddressSync(
+        [
+          Buffer.from("receipt"),
+          pdas.vaultState.toBuffer(),
+          depositor.publicKey.toBuffer(),
+          receiptId.toArrayLike(Buffer, "le", 8),
+        ],
+        program.programId
+      );
+
+      const amount = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL);
+      await program.methods
+      .depositWithReceipt(receiptId, amount)
+      .accountsPartial({ depositor: depositor.publicKey, ...pdas, receipt })
+      .signers([depositor])
+      .rpc();
+
+      const data = await program.account.receipt.fetch(receipt);
+      assert.equal(data.amount.toString(), amount.toString());
+      assert.ok(data.depositor.equals(depositor.publicKey));
+      assert.ok(data.timestamp.toNumber() > 0);
+
+      const rent = await provider.connection.getBalance(receipt);
+      const before = await provider.connection.getBalance(depositor.publicKey);
+      await program.methods
+      .closeReceipt()
+      .accountsPartial({ depositor: depositor.publicKey, receipt })
+      .signers([depositor])
+      .rpc();
+      assert.isNull(await provider.connection.getAccountInfo(receipt));
+      assert.isAtLeast(await provider.connection.getBalance(depositor.publicKey), before + rent - 10_000);
+      assert.equal(await provider.connection.getBalance(pdas.vault), amount.toNumber());
+    });
+  });
+
+  describe("lifetime withdrawal quota", () => {
+    it("Rejects withdrawals once max_lifetime_withdrawals is reached", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      await program.methods
+      .deposit(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      await program.methods
+      .setMaxLifetimeWithdrawals(2)
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+
+      const withdraw = () =>
+        program.methods
+        .withdraw(new anchor.BN(1_000_000))
+        .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([user])
+        .rpc();
+
+      await withdraw();
+      await withdraw();
+      try {
+        await withdraw();
+        assert.fail("third withdrawal should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "WithdrawalQuotaExhausted");
+      }
+      const state = await program.account.vaultState.fetch(pdas.vaultState);
+      assert.equal(state.withdrawalCount, 2);
+    });
+  });
+
+  describe("system program validation", () => {
+    it("Rejects a spoofed system program on deposit, withdraw and initialize", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const fake = anchor.web3.Keypair.generate().publicKey;
+
+      const attempts = [
+        program.methods
+        .deposit(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+        .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: fake }),
+        program.methods
+        .withdraw(new anchor.BN(1))
+        .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: fake }),
+        program.methods
+        .initialize(1)
+        .accountsPartial({ user: user.publicKey, ...vaultPdas(user.publicKey, 1), systemProgram: fake }),
+      ];
+      for (const attempt of attempts) {
+        try {
+          await attempt.signers([user]).rpc();
+          assert.fail("spoofed system program should have been rejected");
+        } catch (e) {
+          assert.include(e.toString(), "InvalidProgramId");
+        }
+      }
+    });
+  });
+
+  describe("deposit cap", () => {
+    it("Rejects deposits past the cap and lets deposit_capped fill it exactly", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const sol = anchor.web3.LAMPORTS_PER_SOL;
+      await program.methods
+      .setDepositCap(new anchor.BN(2 * sol))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+
+      const accounts = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId };
+      await program.methods.deposit(new anchor.BN(1.5 * sol)).accountsPartial(accounts).signers([user]).rpc();
+
+      try {
+        await program.methods.deposit(new anchor.BN(sol)).accountsPartial(accounts).signers([user]).rpc();
+        assert.fail("deposit past the cap should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "DepositCapExceeded");
+      }
+
+      const userBefore = await provider.connection.getBalance(user.publicKey);
+      await program.methods.depositCapped(new anchor.BN(sol)).accountsPartial(accounts).signers([user]).rpc();
+      assert.equal(await provider.connection.getBalance(pdas.vault), 2 * sol);
+      // only the half that fit was taken; the rest stayed with the user
+      assert.equal(await provider.connection.getBalance(user.publicKey), userBefore - 0.5 * sol);
+    });
+  });
+
+  describe("reserved space", () => {
+    it("Sizes VaultState to include the reserve, zeroed on a new vault", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const info = await provider.connection.getAccountInfo(pdas.vaultState);
+      assert.equal(info.data.length, program.account.vaultState.size);
+
+      const state = await program.account.vaultState.fetch(pdas.vaultState);
+      const reserved: number[] = state.reserved[0];
+      assert.equal(reserved.length, 64);
+      assert.ok(reserved.every((b) => b === 0));
+    });
+
+    it("Stores the reserved sub-fields at their fixed offsets", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const configure = { user: user.publicKey, vaultState: pdas.vaultState };
+      const hook = anchor.web3.Keypair.generate().publicKey;
+      const reserved = async () => Buffer.from((await program.account.vaultState.fetch(pdas.vaultState)).reserved[0]);
+
+      await program.methods.setReferralFee(250).accountsPartial(configure).signers([user]).rpc();
+      await program.methods.setExternalWithdrawPaused(true).accountsPartial(configure).signers([user]).rpc();
+      await program.methods.setValidationHook(hook).accountsPartial(configure).signers([user]).rpc();
+      let bytes = await reserved();
+      // RESERVED_REFERRAL_FEE_BPS, RESERVED_EXTERNAL_WITHDRAW_PAUSED, RESERVED_VALIDATION_HOOK
+      assert.equal(bytes.readUInt16LE(0), 250);
+      assert.equal(bytes[2], 1);
+      assert.equal(bytes[3], 1);
+      assert.isTrue(new anchor.web3.PublicKey(bytes.subarray(4, 36)).equals(hook));
+      assert.ok(bytes.subarray(36).every((b) => b === 0));
+
+      // clearing the hook zeroes its whole slot rather than leaving the old key behind
+      await program.methods.setValidationHook(null).accountsPartial(configure).signers([user]).rpc();
+      bytes = await reserved();
+      assert.ok(bytes.subarray(3, 36).every((b) => b === 0));
+      assert.equal(bytes.readUInt16LE(0), 250);
+    });
+  });
+
+  describe("unfunded vault", () => {
+    it("Rejects withdrawing from a drained vault and a never-initialized one", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const withdraw = (accounts: ReturnType<typeof vaultPdas>, amount: number) =>
+        program.methods
+        .withdraw(new anchor.BN(amount))
+        .accountsPartial({ user: user.publicKey, ...accounts, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([user])
+        .rpc();
+
+      await withdraw(pdas, await provider.connection.getBalance(pdas.vault));
+      try {
+        await withdraw(pdas, 1);
+        assert.fail("withdrawing from a drained vault should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "VaultNotFunded");
+      }
+
+      // without a vault_state there is nothing to check against, so Anchor rejects it first
+      try {
+        await withdraw(vaultPdas(user.publicKey, 7), 1);
+        assert.fail("withdrawing from a never-initialized vault should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "AccountNotInitialized");
+      }
+    });
+  });
+
+  describe("deposit from swap", () => {
+    it("Moves a swap's output tokens into the token vault and rejects other mints", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const { mint, tokenVault } = await initTokenVault(user, pdas);
+
+      // stands in for the output account a swap just paid into
+      const swapOutput = await createAccount(provider.connection, user, mint, user.publicKey, anchor.web3.Keypair.generate());
+      await mintTo(provider.connection, user, mint, swapOutput, user, 1_500_000);
+
+      await program.methods
+      .depositFromSwap()
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState, mint, tokenVault, swapOutput })
+      .signers([user])
+      .rpc();
+      assert.equal((await getAccount(provider.connection, tokenVault)).amount, BigInt(1_500_000));
+      assert.equal((await getAccount(provider.connection, swapOutput)).amount, BigInt(0));
+
+      const otherMint = await createMint(provider.connection, user, user.publicKey, null, 6);
+      const otherOutput = await createAccount(provider.connection, user, otherMint, user.publicKey, anchor.web3.Keypair.generate());
+      await mintTo(provider.connection, user, otherMint, otherOutput, user, 1_000);
+      try {
+        await program.methods
+        .depositFromSwap()
+        .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState, mint, tokenVault, swapOutput: otherOutput })
+        .signers([user])
+        .rpc();
+        assert.fail("a different mint should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "MintMismatch");
+      }
+    });
+  });
+
+  describe("health", () => {
+    it("Reports balance, rent exemption, pause and lock state", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const configure = { user: user.publicKey, vaultState: pdas.vaultState };
+
+      let health = await program.methods.health().accountsPartial(pdas).view();
+      assert.isTrue(health.rentExempt);
+      assert.equal(health.balance.toNumber(), await provider.connection.getBalance(pdas.vault));
+      assert.isFalse(health.paused);
+      assert.isFalse(health.locked);
+
+      await program.methods.setPaused(true).accountsPartial(configure).signers([user]).rpc();
+      await program.methods
+      .setUnlockAt(new anchor.BN(Math.floor(Date.now() / 1000) + 3600))
+      .accountsPartial(configure)
+      .signers([user])
+      .rpc();
+      health = await program.methods.health().accountsPartial(pdas).view();
+      assert.isTrue(health.paused);
+      assert.isTrue(health.locked);
+
+      try {
+        await program.methods
+        .deposit(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+        .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([user])
+        .rpc();
+        assert.fail("deposit into a paused vault should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "VaultPaused");
+      }
+    });
+  });
+
+  describe("label", () => {
+    it("Emits LabelChanged with the new label bytes", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const label = Array.from(Buffer.concat([Buffer.from("savings"), Buffer.alloc(25)]));
+
+      const signature = await program.methods
+      .setLabel(label)
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc({ commitment: "confirmed" });
+
+      const events = await eventsOf(signature);
+      assert.deepEqual(events.map((e) => e.name), ["labelChanged"]);
+      assert.ok(events[0].data.vaultState.equals(pdas.vaultState));
+      assert.deepEqual(Array.from(events[0].data.newLabel), label);
+      assert.deepEqual(Array.from((await program.account.vaultState.fetch(pdas.vaultState)).label), label);
+    });
+  });
+
+  describe("deposit queue", () => {
+    it("Queues sub-minimum deposits and commits them together once they cross min_deposit", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const sol = anchor.web3.LAMPORTS_PER_SOL;
+      await program.methods
+      .setMinDeposit(new anchor.BN(sol))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      const deposit = (amount: number) =>
+        program.methods
+        .deposit(new anchor.BN(amount))
+        .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([user])
+        .rpc();
+
+      await deposit(0.3 * sol);
+      await deposit(0.3 * sol);
+      let state = await program.account.vaultState.fetch(pdas.vaultState);
+      assert.equal(state.pendingDeposit.toNumber(), 0.6 * sol);
+      assert.equal(state.totalDeposited.toNumber(), 0);
+
+      await deposit(0.5 * sol);
+      state = await program.account.vaultState.fetch(pdas.vaultState);
+      assert.equal(state.pendingDeposit.toNumber(), 0);
+      assert.equal(state.totalDeposited.toNumber(), 1.1 * sol);
+    });
+  });
+
+  describe("frozen config", () => {
+    it("Rejects limit, fee and label changes once the config is frozen", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const configure = { user: user.publicKey, vaultState: pdas.vaultState };
+      await program.methods.setDepositCap(new anchor.BN(10 * anchor.web3.LAMPORTS_PER_SOL)).accountsPartial(configure).signers([user]).rpc();
+      await program.methods.freezeConfig().accountsPartial(configure).signers([user]).rpc();
+
+      const feeTreasury = (await newUser(1)).publicKey;
+      const changes = [
+        program.methods.setDepositCap(new anchor.BN(0)).accountsPartial(configure),
+        program.methods.setLabel(Array(32).fill(1)).accountsPartial(configure),
+        program.methods.setFee(100).accountsPartial({ ...configure, vault: pdas.vault, feeTreasury }),
+        program.methods.setMinAge(new anchor.BN(60)).accountsPartial(configure),
+        program.methods.setDonationThreshold(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL)).accountsPartial(configure),
+        // who may withdraw and how a pause behaves are terms as well
+        program.methods.setGuardian(feeTreasury, new anchor.BN(2_000_000_000)).accountsPartial(configure),
+        program.methods.setOwnerBypassPause(true).accountsPartial(configure),
+        program.methods.setQueueOnPause(true).accountsPartial(configure),
+      ];
+      for (const change of changes) {
+        try {
+          await change.signers([user]).rpc();
+          assert.fail("config change should have been rejected");
+        } catch (e) {
+          assert.include(e.toString(), "ConfigFrozen");
+        }
+      }
+      const state = await program.account.vaultState.fetch(pdas.vaultState);
+      assert.equal(state.depositCap.toNumber(), 10 * anchor.web3.LAMPORTS_PER_SOL);
+      assert.isNull(state.guardian);
+
+      // operational switches are not part of the terms and still work
+      await program.methods.setPaused(true).accountsPartial(configure).signers([user]).rpc();
+    });
+  });
+
+  describe("lamport conservation", () => {
+    it("Conserves lamports across the vault lifecycle", async () => {
+      const user = await newUser();
+      const depositor = await newUser();
+      const feeTreasury = anchor.web3.Keypair.generate().publicKey;
+      const pdas = vaultPdas(user.publicKey);
+      const [registry] = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("registry"), user.publicKey.toBytes()], program.programId);
+      const receiptId = new anchor.BN(1);
+      const [receipt] = anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("receipt"), pdas.vaultState.toBuffer(), depositor.publicKey.toBuffer(), receiptId.toArrayLike(Buffer, "le", 8)],
+        program.programId
+      );
+      const accounts = [user.publicKey, depositor.publicKey, pdas.vaultState, pdas.vault, registry, receipt, feeTreasury];
+      const sol = anchor.web3.LAMPORTS_PER_SOL;
+      const payment = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId };
+      const configure = { user: user.publicKey, vaultState: pdas.vaultState };
+
+      await assertConserved(accounts, () =>
+        program.methods.initialize(0).accountsPartial(payment).signers([user]).rpc({ commitment: "confirmed" })
+      );
+      await assertConserved(accounts, () =>
+        program.methods.deposit(new anchor.BN(2 * sol)).accountsPartial(payment).signers([user]).rpc({ commitment: "confirmed" })
+      );
+      await assertConserved(accounts, () =>
+        program.methods
+        .depositWithReceipt(receiptId, new anchor.BN(sol))
+        .accountsPartial({ depositor: depositor.publicKey, ...pdas, receipt })
+        .signers([depositor])
+        .rpc({ commitment: "confirmed" })
+      );
+      await assertConserved(accounts, () =>
+        program.methods
+        .closeReceipt()
+        .accountsPartial({ depositor: depositor.publicKey, receipt })
+        .signers([depositor])
+        .rpc({ commitment: "confirmed" })
+      );
+
+      // an early withdrawal splits the amount between the user and the fee treasury
+      await program.methods.setFee(500).accountsPartial({ ...configure, vault: pdas.vault, feeTreasury }).signers([user]).rpc();
+      await program.methods.setAllowEarlyWithdraw(true).accountsPartial(configure).signers([user]).rpc();
+      await program.methods
+      .setUnlockAt(new anchor.BN(Math.floor(Date.now() / 1000) + 3600))
+      .accountsPartial(configure)
+      .signers([user])
+      .rpc({ commitment: "confirmed" });
+      await assertConserved(accounts, () =>
+        program.methods.withdraw(new anchor.BN(sol)).accountsPartial({ ...payment, feeTreasury }).signers([user]).rpc({ commitment: "confirmed" })
+      );
+    });
+  });
+
+  describe("rent_for", () => {
+    it("Matches the cluster's rent-exempt minimum for several sizes", async () => {
+      for (const dataLen of [0, 165, program.account.vaultState.size, 10_240]) {
+        const rent = await program.methods.rentFor(new anchor.BN(dataLen)).view();
+        assert.equal(rent.toNumber(), await provider.connection.getMinimumBalanceForRentExemption(dataLen));
+      }
+      try {
+        await program.methods.rentFor(new anchor.BN(10 * 1024 * 1024 + 1)).view();
+        assert.fail("oversized data length should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "InvalidDataLength");
+      }
+    });
+  });
+
+  describe("deposit allowance", () => {
+    it("Lets a delegate deposit up to its allowance and rejects anything beyond", async () => {
+      const owner = await newUser();
+      const sponsor = await newUser();
+      const pdas = await initializeVault(owner);
+      const sol = anchor.web3.LAMPORTS_PER_SOL;
+      const [allowance] = anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("allowance"), pdas.vaultState.toBuffer(), sponsor.publicKey.toBuffer()],
+        program.programId
+      );
+
+      await program.methods
+      .approveDepositor(sponsor.publicKey, new anchor.BN(2 * sol))
+      .accountsPartial({ user: owner.publicKey, vaultState: pdas.vaultState, allowance })
+      .signers([owner])
+      .rpc();
+
+      const deposit = (amount: number) =>
+        program.methods
+        .depositDelegated(new anchor.BN(amount))
+        .accountsPartial({ delegate: sponsor.publicKey, ...pdas, allowance })
+        .signers([sponsor])
+        .rpc();
+
+      const vaultBefore = await provider.connection.getBalance(pdas.vault);
+      await deposit(1.5 * sol);
+      assert.equal(await provider.connection.getBalance(pdas.vault), vaultBefore + 1.5 * sol);
+      assert.equal((await program.account.depositAllowance.fetch(allowance)).remaining.toNumber(), 0.5 * sol);
+
+      try {
+        await deposit(sol);
+        assert.fail("deposit over the allowance should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "AllowanceExceeded");
+      }
+      await deposit(0.5 * sol);
+      assert.equal((await program.account.depositAllowance.fetch(allowance)).remaining.toNumber(), 0);
+    });
+  });
+
+  describe("rotate and lock", () => {
+    it("Hands the vault to a new owner and locks withdrawals", async () => {
+      const user = await newUser();
+      const newOwner = await newUser();
+      const pdas = await initializeVault(user);
+      await program.methods
+      .deposit(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+
+      await program.methods
+      .rotateAndLock(newOwner.publicKey, new anchor.BN(3600))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      const state = await program.account.vaultState.fetch(pdas.vaultState);
+      assert.ok(state.owner.equals(newOwner.publicKey));
+      assert.ok(state.creator.equals(user.publicKey));
+
+      // the addresses are unchanged; only who may sign for them moved
+      const withdraw = (signer: anchor.web3.Keypair) =>
+        program.methods
+        .withdraw(new anchor.BN(1_000_000))
+        .accountsPartial({ user: signer.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([signer])
+        .rpc();
+      try {
+        await withdraw(newOwner);
+        assert.fail("withdrawal should be locked after the handoff");
+      } catch (e) {
+        assert.include(e.toString(), "VaultLocked");
+      }
+      try {
+        await withdraw(user);
+        assert.fail("the previous owner should no longer have access");
+      } catch (e) {
+        assert.include(e.toString(), "Unauthorized");
+      }
+    });
+  });
+
+  describe("queue on pause", () => {
+    it("Queues deposits while paused and commits them on unpause", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const sol = anchor.web3.LAMPORTS_PER_SOL;
+      const configure = { user: user.publicKey, vaultState: pdas.vaultState };
+      await program.methods.setQueueOnPause(true).accountsPartial(configure).signers([user]).rpc();
+      await program.methods.setPaused(true).accountsPartial(configure).signers([user]).rpc();
+
+      const vaultBefore = await provider.connection.getBalance(pdas.vault);
+      await program.methods
+      .deposit(new anchor.BN(sol))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      assert.equal(await provider.connection.getBalance(pdas.vault), vaultBefore + sol);
+      let state = await program.account.vaultState.fetch(pdas.vaultState);
+      assert.equal(state.pendingDeposit.toNumber(), sol);
+      assert.equal(state.totalDeposited.toNumber(), 0);
+
+      await program.methods.setPaused(false).accountsPartial(configure).signers([user]).rpc();
+      state = await program.account.vaultState.fetch(pdas.vaultState);
+      assert.equal(state.pendingDeposit.toNumber(), 0);
+      assert.equal(state.totalDeposited.toNumber(), sol);
+    });
+  });
+
+  describe("multisig", () => {
+    it("Gates large withdrawals behind 2-of-3 approvals", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const signers = [await newUser(1), await newUser(1), await newUser(1)];
+      const sol = anchor.web3.LAMPORTS_PER_SOL;
+      const [multisig] = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("multisig"), pdas.vaultState.toBuffer()], program.programId);
+      const [approval] = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("approval"), pdas.vaultState.toBuffer()], program.programId);
+      const payment = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId };
+
+      await program.methods.deposit(new anchor.BN(2 * sol)).accountsPartial(payment).signers([user]).rpc();
+      await program.methods
+      .configureMultisig(signers.map((s) => s.publicKey), 2, new anchor.BN(0.5 * sol))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState, multisig })
+      .signers([user])
+      .rpc();
+
+      const withdraw = (amount: number) =>
+        program.methods.withdraw(new anchor.BN(amount)).accountsPartial({ ...payment, multisig, approval }).signers([user]).rpc();
+      const approve = (signer: anchor.web3.Keypair) =>
+        program.methods
+        .approveWithdrawal(new anchor.BN(sol))
+        .accountsPartial({ signer: signer.publicKey, vaultState: pdas.vaultState, multisig, approval })
+        .signers([signer])
+        .rpc();
+
+      // small withdrawals are not gated and need no approval accounts
+      await program.methods.withdraw(new anchor.BN(0.1 * sol)).accountsPartial(payment).signers([user]).rpc();
+
+      await approve(signers[0]);
+      try {
+        await withdraw(sol);
+        assert.fail("one approval should not be enough");
+      } catch (e) {
+        assert.include(e.toString(), "ApprovalRequired");
+      }
+
+      await approve(signers[2]);
+      const vaultBefore = await provider.connection.getBalance(pdas.vault);
+      await withdraw(sol);
+      assert.equal(await provider.connection.getBalance(pdas.vault), vaultBefore - sol);
+
+      // the approval was used up
+      assert.equal((await program.account.withdrawApproval.fetch(approval)).approvals, 0);
+      try {
+        await withdraw(sol);
+        assert.fail("a used approval should not authorize another withdrawal");
+      } catch (e) {
+        assert.include(e.toString(), "ApprovalRequired");
+      }
+    });
+  });
+
+  describe("activity log", () => {
+    it("Summarizes the buffered deposits and withdrawals", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const sol = anchor.web3.LAMPORTS_PER_SOL;
+      const [activityLog] = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("log"), pdas.vaultState.toBuffer()], program.programId);
+      await program.methods
+      .initActivityLog()
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState, activityLog })
+      .signers([user])
+      .rpc();
+
+      const payment = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId, activityLog };
+      await program.methods.deposit(new anchor.BN(sol)).accountsPartial(payment).signers([user]).rpc();
+      await program.methods.deposit(new anchor.BN(0.5 * sol)).accountsPartial(payment).signers([user]).rpc();
+      await program.methods.withdraw(new anchor.BN(0.2 * sol)).accountsPartial(payment).signers([user]).rpc();
+
+      const summary = await program.methods.logSummary().accountsPartial({ vaultState: pdas.vaultState, activityLog }).view();
+      assert.equal(summary.count, 3);
+      assert.equal(summary.net.toNumber(), 1.3 * sol);
+
+      const log = await program.account.activityLog.fetch(activityLog);
+      const buffered = log.entries.slice(0, log.len).reduce((sum, entry) => sum + entry.delta.toNumber(), 0);
+      assert.equal(summary.net.toNumber(), buffered);
+
+      try {
+        await program.methods
+        .deposit(new anchor.BN(sol))
+        .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([user])
+        .rpc();
+        assert.fail("deposit without the log should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "ActivityLogMissing");
+      }
+    });
+  });
+
+  describe("deposit quote", () => {
+    it("Quotes exactly what a deposit credits after the deposit fee", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const feeTreasury = anchor.web3.Keypair.generate().publicKey;
+      await program.methods
+      .setDepositFee(150)
+      .accountsPartial({ user: user.publicKey, ...pdas, feeTreasury })
+      .signers([user])
+      .rpc();
+
+      const amount = new anchor.BN(2 * anchor.web3.LAMPORTS_PER_SOL);
+      const quote = await program.methods.quoteDeposit(amount).accountsPartial(pdas).view();
+      assert.equal(quote.fee.toNumber(), amount.toNumber() * 0.015);
+      assert.equal(quote.netCredited.toNumber() + quote.fee.toNumber(), amount.toNumber());
+
+      const vaultBefore = await provider.connection.getBalance(pdas.vault);
+      await program.methods
+      .deposit(amount)
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId, feeTreasury })
+      .signers([user])
+      .rpc();
+      assert.equal(await provider.connection.getBalance(pdas.vault), vaultBefore + quote.netCredited.toNumber());
+      assert.equal(await provider.connection.getBalance(feeTreasury), quote.fee.toNumber());
+      assert.equal((await program.account.vaultState.fetch(pdas.vaultState)).totalDeposited.toNumber(), quote.netCredited.toNumber());
+    });
+  });
+
+  describe("close mixed", () => {
+    it("Recovers both SOL and tokens and closes every account", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const { mint, tokenVault } = await initTokenVault(user, pdas);
+      await program.methods
+      .deposit(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      await mintTo(provider.connection, user, mint, tokenVault, user, 2_500_000);
+
+      const userToken = getAssociatedTokenAddressSync(mint, user.publicKey);
+      await program.methods
+      .closeMixed()
+      .accountsPartial({ user: user.publicKey, ...pdas, mint, tokenVault, userToken })
+      .signers([user])
+      .rpc();
+
+      assert.equal((await getAccount(provider.connection, userToken)).amount, BigInt(2_500_000));
+      assert.isNull(await provider.connection.getAccountInfo(tokenVault));
+      assert.isNull(await provider.connection.getAccountInfo(pdas.vaultState));
+      assert.equal(await provider.connection.getBalance(pdas.vault), 0);
+    });
+
+    it("Refuses the lamport-only closes while a token account is open", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const { mint, tokenVault } = await initTokenVault(user, pdas);
+      await mintTo(provider.connection, user, mint, tokenVault, user, 1_000_000);
+
+      try {
+        await program.methods
+        .close()
+        .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([user])
+        .rpc();
+        assert.fail("close should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "VaultNotEmpty");
+      }
+      assert.equal((await getAccount(provider.connection, tokenVault)).amount, BigInt(1_000_000));
+    });
+  });
+
+  describe("notify marker", () => {
+    it("Increments the marker sequence on every deposit and withdraw", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const watcher = anchor.web3.Keypair.generate().publicKey;
+      const [notifyMarker] = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("notify"), pdas.vaultState.toBuffer()], program.programId);
+      await program.methods
+      .setNotify(watcher)
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState, notifyMarker })
+      .signers([user])
+      .rpc();
+
+      const payment = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId, notifyMarker };
+      const sequence = async () => (await program.account.notifyMarker.fetch(notifyMarker)).sequence.toNumber();
+      assert.equal(await sequence(), 0);
+
+      await program.methods.deposit(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL)).accountsPartial(payment).signers([user]).rpc();
+      assert.equal(await sequence(), 1);
+      await program.methods.deposit(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL)).accountsPartial(payment).signers([user]).rpc();
+      assert.equal(await sequence(), 2);
+      await program.methods.withdraw(new anchor.BN(1_000_000)).accountsPartial(payment).signers([user]).rpc();
+      assert.equal(await sequence(), 3);
+
+      const marker = await program.account.notifyMarker.fetch(notifyMarker);
+      assert.ok(marker.watcher.equals(watcher));
+    });
+  });
+
+  describe("symbol", () => {
+    it("Starts as SOL, can be changed by the owner and rejects malformed symbols", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const toSymbol = (text: string) => Array.from(Buffer.concat([Buffer.from(text), Buffer.alloc(8 - text.length)]));
+      const configure = { user: user.publicKey, vaultState: pdas.vaultState };
+
+      assert.deepEqual(Array.from((await program.account.vaultState.fetch(pdas.vaultState)).symbol), toSymbol("SOL"));
+
+      const signature = await program.methods.setSymbol(toSymbol("USDC")).accountsPartial(configure).signers([user]).rpc({ commitment: "confirmed" });
+      assert.deepEqual(Array.from((await program.account.vaultState.fetch(pdas.vaultState)).symbol), toSymbol("USDC"));
+      const events = await eventsOf(signature);
+      assert.deepEqual(events.map((e) => e.name), ["symbolChanged"]);
+      assert.deepEqual(Array.from(events[0].data.newSymbol), toSymbol("USDC"));
+
+      // empty, lowercase, and a character after the padding
+      for (const bad of [toSymbol(""), toSymbol("usdc"), [85, 83, 0, 0, 65, 0, 0, 0]]) {
+        try {
+          await program.methods.setSymbol(bad).accountsPartial(configure).signers([user]).rpc();
+          assert.fail("malformed symbol should have been rejected");
+        } catch (e) {
+          assert.include(e.toString(), "InvalidSymbol");
+        }
+      }
+    });
+  });
+
+  describe("owner pause bypass", () => {
+    it("Lets the owner withdraw under pause only when the bypass is set", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const configure = { user: user.publicKey, vaultState: pdas.vaultState };
+      const payment = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId };
+      await program.methods.deposit(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL)).accountsPartial(payment).signers([user]).rpc();
+      await program.methods.setPaused(true).accountsPartial(configure).signers([user]).rpc();
+
+      const withdraw = () => program.methods.withdraw(new anchor.BN(1_000_000)).accountsPartial(payment).signers([user]).rpc();
+      try {
+        await withdraw();
+        assert.fail("withdrawal should be paused");
+      } catch (e) {
+        assert.include(e.toString(), "VaultPaused");
+      }
+
+      await program.methods.setOwnerBypassPause(true).accountsPartial(configure).signers([user]).rpc();
+      const vaultBefore = await provider.connection.getBalance(pdas.vault);
+      await withdraw();
+      assert.equal(await provider.connection.getBalance(pdas.vault), vaultBefore - 1_000_000);
+
+      // deposits stay blocked
+      try {
+        await program.methods.deposit(new anchor.BN(1_000_000)).accountsPartial(payment).signers([user]).rpc();
+        assert.fail("deposit should still be paused");
+      } catch (e) {
+        assert.include(e.toString(), "VaultPaused");
+      }
+    });
+  });
+
+  describe("periodic deposit cap", () => {
+    it("Rejects deposits past the period cap and accepts them after the window resets", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const sol = anchor.web3.LAMPORTS_PER_SOL;
+      await program.methods
+      .setPeriodDepositCap(new anchor.BN(sol), new anchor.BN(5))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      const deposit = (amount: number) =>
+        program.methods
+        .deposit(new anchor.BN(amount))
+        .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([user])
+        .rpc();
+
+      await deposit(0.6 * sol);
+      await deposit(0.4 * sol);
+      try {
+        await deposit(0.1 * sol);
+        assert.fail("deposit past the period cap should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "PeriodDepositCapExceeded");
+      }
+
+      await sleep(7000);
+      await deposit(0.5 * sol);
+      const state = await program.account.vaultState.fetch(pdas.vaultState);
+      assert.equal(state.depositedInWindow.toNumber(), 0.5 * sol);
+    });
+  });
+
+  describe("program id", () => {
+    it("Returns the declared program ID", async () => {
+      const id = await program.methods.programId().view();
+      assert.ok(id.equals(program.programId));
+      assert.equal(id.toBase58(), "UCrARA7PhDE2jwhXLj8jUUptRRjXZjneUViRFRYCJt1");
+    });
+  });
+
+  describe("staking", () => {
+    it("Delegates idle SOL to a validator, deactivates and withdraws it back", async () => {
+      const sol = anchor.web3.LAMPORTS_PER_SOL;
+      const { user, pdas } = await fundedVault(5 * sol, 10);
+
+      const vaultBefore = await provider.connection.getBalance(pdas.vault);
+      const stakeAccount = await delegateStake(user, pdas, 3 * sol);
+      assert.equal(await provider.connection.getBalance(pdas.vault), vaultBefore - 3 * sol);
+      let state = await program.account.vaultState.fetch(pdas.vaultState);
+      assert.ok(state.stakeAccount.equals(stakeAccount));
+
+      const stakeAction = {
+        user: user.publicKey,
+        vaultState: pdas.vaultState,
+        vault: pdas.vault,
+        stakeAccount,
+        stakeHistory: anchor.web3.SYSVAR_STAKE_HISTORY_PUBKEY,
+        stakeProgram: anchor.web3.StakeProgram.programId,
+      };
+      await program.methods.deactivateStake().accountsPartial(stakeAction).signers([user]).rpc();
+      // deactivated in the epoch it was delegated, so it never became effective and is withdrawable now
+      await program.methods.withdrawStake().accountsPartial(stakeAction).signers([user]).rpc();
+      assert.equal(await provider.connection.getBalance(pdas.vault), vaultBefore);
+      state = await program.account.vaultState.fetch(pdas.vaultState);
+      assert.isNull(state.stakeAccount);
+    });
+
+    it("Refuses close and keeper_close while stake is delegated", async () => {
+      const { user: owner, pdas } = await fundedVault(5 * anchor.web3.LAMPORTS_PER_SOL, 10);
+      const keeper = await newUser();
+      await program.methods
+      .setKeeperClose(new anchor.BN(1), 500)
+      .accountsPartial({ user: owner.publicKey, vaultState: pdas.vaultState })
+      .signers([owner])
+      .rpc();
+      await delegateStake(owner, pdas, 3 * anchor.web3.LAMPORTS_PER_SOL);
+      await sleep(2000);
+
+      try {
+        await program.methods
+        .close()
+        .accountsPartial({ user: owner.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([owner])
+        .rpc();
+        assert.fail("close should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "VaultNotEmpty");
+      }
+      try {
+        await program.methods
+        .keeperClose()
+        .accountsPartial({ keeper: keeper.publicKey, owner: owner.publicKey, ...pdas })
+        .signers([keeper])
+        .rpc();
+        assert.fail("keeper_close should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "VaultNotEmpty");
+      }
+      assert.isNotNull(await provider.connection.getAccountInfo(pdas.vaultState));
+    });
+  });
+
+  describe("withdrawable", () => {
+    const sol = anchor.web3.LAMPORTS_PER_SOL;
+
+    const withdrawable = async (pdas) =>
+      (await program.methods.withdrawable().accountsPartial(pdas).view()).toNumber();
+
+    const holdBack = async (user, configure, amount: number) => {
+      // a schedule starting in an hour keeps the whole vesting_amount locked
+      const chainNow = await provider.connection.getBlockTime(await provider.connection.getSlot());
+      await program.methods
+      .setVesting(new anchor.BN(chainNow + 3600), new anchor.BN(3600), new anchor.BN(amount))
+      .accountsPartial(configure)
+      .signers([user])
+      .rpc();
+    };
+
+    it("Returns the full balance without guards and excludes unvested funds", async () => {
+      const { user, pdas, configure } = await fundedVault();
+      const balance = await provider.connection.getBalance(pdas.vault);
+      assert.equal(await withdrawable(pdas), balance);
+      await holdBack(user, configure, sol);
+      assert.equal(await withdrawable(pdas), balance - sol);
+    });
+
+    it("Keeps a partial withdrawal above the rent floor", async () => {
+      const { user, pdas, configure } = await fundedVault();
+      const balance = await provider.connection.getBalance(pdas.vault);
+      await holdBack(user, configure, 1);
+      const floor = await provider.connection.getMinimumBalanceForRentExemption(0);
+      assert.equal(await withdrawable(pdas), balance - floor);
+    });
+
+    it("Returns zero while paused or once the withdrawal quota is used up", async () => {
+      const { user, pdas, configure } = await fundedVault();
+      await program.methods.setPaused(true).accountsPartial(configure).signers([user]).rpc();
+      assert.equal(await withdrawable(pdas), 0);
+      await program.methods.setPaused(false).accountsPartial(configure).signers([user]).rpc();
+
+      await program.methods.setMaxLifetimeWithdrawals(1).accountsPartial(configure).signers([user]).rpc();
+      await program.methods
+      .withdraw(new anchor.BN(sol))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      assert.equal(await withdrawable(pdas), 0);
+    });
+
+    it("Caps the amount at the withdrawal spike limit", async () => {
+      const { user, pdas, configure } = await fundedVault();
+      await program.methods.setWithdrawalSpikeMultiple(2).accountsPartial(configure).signers([user]).rpc();
+      // the first withdrawal seeds the moving average the spike check compares against
+      await program.methods
+      .withdraw(new anchor.BN(0.1 * sol))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      assert.equal(await withdrawable(pdas), 0.2 * sol);
+    });
+
+    it("Returns zero for a vault that only pays out through withdraw_if", async () => {
+      const { user, pdas, configure } = await fundedVault();
+      await program.methods
+      .setReleaseCondition({
+        account: anchor.web3.Keypair.generate().publicKey,
+        owner: anchor.web3.Keypair.generate().publicKey,
+        offset: 0,
+        value: 1,
+      })
+      .accountsPartial(configure)
+      .signers([user])
+      .rpc();
+      assert.equal(await withdrawable(pdas), 0);
+    });
+  });
+
+  describe("donations", () => {
+    it("Sweeps donations into total_deposited only after the threshold is crossed", async () => {
+      const owner = await newUser();
+      const donor = await newUser();
+      const pdas = await initializeVault(owner);
+      const sol = anchor.web3.LAMPORTS_PER_SOL;
+      const configure = { user: owner.publicKey, vaultState: pdas.vaultState };
+      const donate = (amount: number) =>
+        program.methods
+        .donate(new anchor.BN(amount))
+        .accountsPartial({ donor: donor.publicKey, ...pdas })
+        .signers([donor])
+        .rpc();
+      const sweep = () => program.methods.sweepDonations().accountsPartial(configure).signers([owner]).rpc();
+
+      await program.methods.setDonationThreshold(new anchor.BN(sol)).accountsPartial(configure).signers([owner]).rpc();
+      await donate(0.6 * sol);
+      let state = await program.account.vaultState.fetch(pdas.vaultState);
+      assert.equal(state.donatedTotal.toNumber(), 0.6 * sol);
+      assert.equal(state.totalDeposited.toNumber(), 0);
+      try {
+        await sweep();
+        assert.fail("sweep below the threshold should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "DonationsBelowThreshold");
+      }
+
+      await donate(0.6 * sol);
+      await sweep();
+      state = await program.account.vaultState.fetch(pdas.vaultState);
+      assert.equal(state.donatedTotal.toNumber(), 0);
+      assert.equal(state.totalDeposited.toNumber(), 1.2 * sol);
+    });
+  });
+
+  describe("keeper close", () => {
+    it("Pays the keeper its bounty and the owner the rest once the vault is idle", async () => {
+      const owner = await newUser();
+      const keeper = await newUser();
+      const pdas = await initializeVault(owner);
+      const sol = anchor.web3.LAMPORTS_PER_SOL;
+      await program.methods
+      .setKeeperClose(new anchor.BN(3), 500)
+      .accountsPartial({ user: owner.publicKey, vaultState: pdas.vaultState })
+      .signers([owner])
+      .rpc();
+      await program.methods
+      .deposit(new anchor.BN(sol))
+      .accountsPartial({ user: owner.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([owner])
+      .rpc();
+      const keeperClose = () =>
+        program.methods
+        .keeperClose()
+        .accountsPartial({ keeper: keeper.publicKey, owner: owner.publicKey, ...pdas })
+        .signers([keeper])
+        .rpc({ commitment: "confirmed" });
+
+      try {
+        await keeperClose();
+        assert.fail("keeper_close on an active vault should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "VaultStillActive");
+      }
+
+      await sleep(5000);
+      const balance = await provider.connection.getBalance(pdas.vault);
+      const stateRent = await provider.connection.getBalance(pdas.vaultState);
+      const ownerBefore = await provider.connection.getBalance(owner.publicKey);
+      const keeperBefore = await provider.connection.getBalance(keeper.publicKey);
+      // the provider pays the transaction fee, so the keeper receives the whole bounty
+      await keeperClose();
+      const bounty = Math.floor((balance * 500) / 10_000);
+      assert.equal(await provider.connection.getBalance(keeper.publicKey), keeperBefore + bounty);
+      assert.equal(await provider.connection.getBalance(owner.publicKey), ownerBefore + balance - bounty + stateRent);
+      assert.isNull(await provider.connection.getAccountInfo(pdas.vaultState));
+    });
+  });
+
+  describe("USD floor deposits", () => {
+    it("Accepts deposits worth at least min_usd at the published price and rejects the rest", async () => {
+      await ensureConfig();
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const sol = anchor.web3.LAMPORTS_PER_SOL;
+      const priceFeed = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("price_feed")], program.programId)[0];
+      // $150 per SOL, in micro-USD
+      await program.methods
+      .setSolPrice(new anchor.BN(150_000_000))
+      .accountsPartial({ programAuthority: provider.wallet.publicKey, config, priceFeed })
+      .rpc();
+      const depositMinUsd = (amount: number, minUsd: number) =>
+        program.methods
+        .depositMinUsd(new anchor.BN(amount), new anchor.BN(minUsd))
+        .accountsPartial({ user: user.publicKey, ...pdas, priceFeed, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([user])
+        .rpc();
+
+      const before = await provider.connection.getBalance(pdas.vault);
+      // 0.1 SOL is $15
+      await depositMinUsd(0.1 * sol, 15_000_000);
+      assert.equal(await provider.connection.getBalance(pdas.vault), before + 0.1 * sol);
+      try {
+        await depositMinUsd(0.1 * sol, 15_000_001);
+        assert.fail("deposit below the USD floor should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "BelowUsdFloor");
+      }
+
+      // a lower price moves the same deposit below the floor
+      await program.methods
+      .setSolPrice(new anchor.BN(100_000_000))
+      .accountsPartial({ programAuthority: provider.wallet.publicKey, config, priceFeed })
+      .rpc();
+      try {
+        await depositMinUsd(0.1 * sol, 15_000_000);
+        assert.fail("deposit below the USD floor should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "BelowUsdFloor");
+      }
+    });
+  });
+
+  describe("partial close", () => {
+    it("Routes `keep` lamports to the residual recipient and the rest to the owner", async () => {
+      const user = await newUser();
+      const recipient = await newUser(1);
+      const pdas = await initializeVault(user);
+      const sol = anchor.web3.LAMPORTS_PER_SOL;
+      await program.methods
+      .deposit(new anchor.BN(sol))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      const closePartial = (keep: number) =>
+        program.methods
+        .closePartial(new anchor.BN(keep))
+        .accountsPartial({ user: user.publicKey, ...pdas, residualRecipient: recipient.publicKey })
+        .signers([user])
+        .rpc({ commitment: "confirmed" });
+
+      const balance = await provider.connection.getBalance(pdas.vault);
+      try {
+        await closePartial(balance + 1);
+        assert.fail("keep above the balance should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "InsufficientVaultBalance");
+      }
+
+      const keep = 0.25 * sol;
+      const stateRent = await provider.connection.getBalance(pdas.vaultState);
+      const userBefore = await provider.connection.getBalance(user.publicKey);
+      const recipientBefore = await provider.connection.getBalance(recipient.publicKey);
+      // the provider pays the transaction fee
+      await closePartial(keep);
+      assert.equal(await provider.connection.getBalance(recipient.publicKey), recipientBefore + keep);
+      assert.equal(await provider.connection.getBalance(user.publicKey), userBefore + balance - keep + stateRent);
+      assert.equal(await provider.connection.getBalance(pdas.vault), 0);
+    });
+
+    it("Holds the residual recipient to the vault's recipient rules", async () => {
+      const user = await newUser();
+      const recipient = anchor.web3.Keypair.generate().publicKey;
+      const pdas = await initializeVault(user);
+      await program.methods
+      .setSelfCustodyOnly(true)
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+
+      try {
+        await program.methods
+        .closePartial(new anchor.BN(1_000_000))
+        .accountsPartial({ user: user.publicKey, ...pdas, residualRecipient: recipient })
+        .signers([user])
+        .rpc();
+        assert.fail("a residual to a third party should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "SelfCustodyOnly");
+      }
+      // nothing is sent to the recipient when keep is 0
+      await program.methods
+      .closePartial(new anchor.BN(0))
+      .accountsPartial({ user: user.publicKey, ...pdas, residualRecipient: recipient })
+      .signers([user])
+      .rpc();
+      assert.isNull(await provider.connection.getAccountInfo(pdas.vaultState));
+    });
+  });
+
+  describe("event topics", () => {
+    it("Tags deposit and withdraw events with the vault's topic", async () => {
+      const user = await newUser();
+      const pdas = vaultPdas(user.publicKey);
+      const topic = Array.from(Buffer.from("campaign-2026\0\0\0"));
+      await program.methods
+      .initializeWithTopic(0, topic)
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      const payment = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId };
+
+      const depositSig = await program.methods
+      .deposit(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial(payment)
+      .signers([user])
+      .rpc({ commitment: "confirmed" });
+      const withdrawSig = await program.methods
+      .withdraw(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial(payment)
+      .signers([user])
+      .rpc({ commitment: "confirmed" });
+
+      const [depositEvent] = await eventsOf(depositSig);
+      const [withdrawEvent] = await eventsOf(withdrawSig);
+      assert.deepEqual(Array.from(depositEvent.data.topic), topic);
+      assert.deepEqual(Array.from(withdrawEvent.data.topic), topic);
+    });
+  });
+
+  describe("deposit lock extension", () => {
+    it("Extends unlock_at on every deposit up to the maximum lock", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      await program.methods
+      .setDepositExtendsLock(true, new anchor.BN(100), new anchor.BN(250))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      // last_activity is the deposit's timestamp, so this is how far ahead the lock reaches
+      const depositAndReadLock = async () => {
+        await program.methods
+        .deposit(new anchor.BN(0.1 * anchor.web3.LAMPORTS_PER_SOL))
+        .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([user])
+        .rpc();
+        const state = await program.account.vaultState.fetch(pdas.vaultState);
+        return state.unlockAt.toNumber() - state.lastActivity.toNumber();
+      };
+
+      assert.equal(await depositAndReadLock(), 100);
+      const second = await depositAndReadLock();
+      // a few seconds may pass between the deposits
+      assert.isTrue(second > 190 && second <= 200, `lock ${second}s ahead`);
+      assert.equal(await depositAndReadLock(), 250);
+      assert.equal(await depositAndReadLock(), 250);
+    });
+  });
+
+  describe("time-weighted average balance", () => {
+    it("Accumulates balance-seconds across deposits and withdrawals", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const sol = anchor.web3.LAMPORTS_PER_SOL;
+      const payment = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId };
+      const rent = await provider.connection.getBalance(pdas.vault);
+      const created = (await program.account.vaultState.fetch(pdas.vaultState)).createdAt.toNumber();
+
+      await sleep(2000);
+      await program.methods.deposit(new anchor.BN(sol)).accountsPartial(payment).signers([user]).rpc();
+      let state = await program.account.vaultState.fetch(pdas.vaultState);
+      const t1 = state.twabLastUpdate.toNumber();
+      assert.equal(state.twabAccumulator.toString(), (BigInt(rent) * BigInt(t1 - created)).toString());
+
+      await sleep(3000);
+      await program.methods.withdraw(new anchor.BN(sol / 2)).accountsPartial(payment).signers([user]).rpc();
+      state = await program.account.vaultState.fetch(pdas.vaultState);
+      const t2 = state.twabLastUpdate.toNumber();
+      const accumulated = BigInt(rent) * BigInt(t1 - created) + BigInt(rent + sol) * BigInt(t2 - t1);
+      assert.equal(state.twabAccumulator.toString(), accumulated.toString());
+
+      // the time after the withdrawal is charged at the balance it left behind
+      await sleep(2000);
+      await program.methods.deposit(new anchor.BN(sol)).accountsPartial(payment).signers([user]).rpc();
+      state = await program.account.vaultState.fetch(pdas.vaultState);
+      const t3 = state.twabLastUpdate.toNumber();
+      assert.equal(
+        state.twabAccumulator.toString(),
+        (accumulated + BigInt(rent + sol / 2) * BigInt(t3 - t2)).toString()
+      );
+
+      // the view adds the current balance held since t2; it stays between the lowest and highest balance
+      const twab = (await program.methods.getTwab().accountsPartial(pdas).view()).toNumber();
+      assert.isTrue(twab > rent && twab < rent + sol, `twab ${twab}`);
+    });
+
+    it("Accrues through donate and withdraw_to as well", async () => {
+      const user = await newUser();
+      const donor = await newUser();
+      const pdas = await initializeVault(user);
+      const sol = anchor.web3.LAMPORTS_PER_SOL;
+      const recipient = anchor.web3.Keypair.generate().publicKey;
+      await program.methods
+      .setInlineAllowlist([recipient])
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      const rent = await provider.connection.getBalance(pdas.vault);
+      const created = (await program.account.vaultState.fetch(pdas.vaultState)).createdAt.toNumber();
+
+      await sleep(2000);
+      await program.methods.donate(new anchor.BN(sol)).accountsPartial({ donor: donor.publicKey, ...pdas }).signers([donor]).rpc();
+      let state = await program.account.vaultState.fetch(pdas.vaultState);
+      const t1 = state.twabLastUpdate.toNumber();
+      assert.equal(state.twabAccumulator.toString(), (BigInt(rent) * BigInt(t1 - created)).toString());
+
+      await sleep(3000);
+      await program.methods
+      .withdrawTo(new anchor.BN(sol / 2))
+      .accountsPartial({ user: user.publicKey, ...pdas, recipient, allowlistEntry: null, validationHook: null })
+      .signers([user])
+      .rpc();
+      state = await program.account.vaultState.fetch(pdas.vaultState);
+      const t2 = state.twabLastUpdate.toNumber();
+      const accumulated = BigInt(rent) * BigInt(t1 - created) + BigInt(rent + sol) * BigInt(t2 - t1);
+      assert.equal(state.twabAccumulator.toString(), accumulated.toString());
+    });
+  });
+
+  describe("legacy import", () => {
+    // preloaded by Anchor.toml from tests/fixtures
+    const legacy = new anchor.web3.PublicKey("FEikUJRWYmJrSy1vcecDUmF8366hpx9SFcieuricbkHH");
+    const legacyOwner = anchor.web3.Keypair.fromSecretKey(
+      Uint8Array.from(JSON.parse(fs.readFileSync("tests/fixtures/legacy_owner.json", "utf8")))
+    );
+
+    it("Rejects a legacy account from another owner and migrates the owner's own", async () => {
+      const stranger = await newUser();
+      const strangerVault = await initializeVault(stranger);
+      try {
+        await program.methods
+        .importLegacy()
+        .accountsPartial({ user: stranger.publicKey, ...strangerVault, legacy })
+        .signers([stranger])
+        .rpc();
+        assert.fail("importing someone else's legacy account should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "Unauthorized");
+      }
+
+      const sig = await provider.connection.requestAirdrop(legacyOwner.publicKey, anchor.web3.LAMPORTS_PER_SOL);
+      await provider.connection.confirmTransaction(sig, "confirmed");
+      const pdas = await initializeVault(legacyOwner);
+      const legacyLamports = await provider.connection.getBalance(legacy);
+      const vaultBefore = await provider.connection.getBalance(pdas.vault);
+      await program.methods
+      .importLegacy()
+      .accountsPartial({ user: legacyOwner.publicKey, ...pdas, legacy })
+      .signers([legacyOwner])
+      .rpc();
+      assert.equal(await provider.connection.getBalance(pdas.vault), vaultBefore + legacyLamports);
+      assert.isNull(await provider.connection.getAccountInfo(legacy));
+      const state = await program.account.vaultState.fetch(pdas.vaultState);
+      assert.equal(state.totalDeposited.toNumber(), legacyLamports);
+    });
+
+    it("Rejects accounts without the LegacyVault discriminator", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const registry = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("registry"), user.publicKey.toBytes()], program.programId)[0];
+      try {
+        await program.methods
+        .importLegacy()
+        .accountsPartial({ user: user.publicKey, ...pdas, legacy: registry })
+        .signers([user])
+        .rpc();
+        assert.fail("a UserRegistry should not be accepted as a legacy account");
+      } catch (e) {
+        assert.include(e.toString(), "AccountDiscriminatorMismatch");
+      }
+    });
+  });
+
+  describe("depositor balance check", () => {
+    it("Fails with InsufficientUserBalance when the depositor cannot cover the amount", async () => {
+      const user = await newUser(1);
+      const pdas = await initializeVault(user);
+      const balance = await provider.connection.getBalance(user.publicKey);
+      try {
+        await program.methods
+        .deposit(new anchor.BN(balance))
+        .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([user])
+        .rpc();
+        assert.fail("deposit above the user's balance should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "InsufficientUserBalance");
+      }
+    });
+  });
+
+  describe("withdrawal velocity", () => {
+    it("Rejects a withdrawal far above the average of recent ones", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const sol = anchor.web3.LAMPORTS_PER_SOL;
+      const payment = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId };
+      await program.methods.deposit(new anchor.BN(2 * sol)).accountsPartial(payment).signers([user]).rpc();
+      await program.methods
+      .setWithdrawalSpikeMultiple(5)
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      const withdraw = (amount: number) =>
+        program.methods.withdraw(new anchor.BN(amount)).accountsPartial(payment).signers([user]).rpc();
+
+      for (let i = 0; i < 3; i++) {
+        await withdraw(0.01 * sol);
+      }
+      const state = await program.account.vaultState.fetch(pdas.vaultState);
+      assert.equal(state.withdrawalEma.toNumber(), 0.01 * sol);
+
+      try {
+        await withdraw(0.1 * sol);
+        assert.fail("a withdrawal 10x the average should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "WithdrawalSpike");
+      }
+      await withdraw(0.05 * sol);
+    });
+
+    it("Applies the spike check to withdraw_net and withdraw_with_penalty too", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const sol = anchor.web3.LAMPORTS_PER_SOL;
+      const payment = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId };
+      await program.methods.deposit(new anchor.BN(2 * sol)).accountsPartial(payment).signers([user]).rpc();
+      await program.methods
+      .setWithdrawalSpikeMultiple(5)
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      await program.methods.withdraw(new anchor.BN(0.01 * sol)).accountsPartial(payment).signers([user]).rpc();
+
+      const spikes = [
+        program.methods
+        .withdrawNet(new anchor.BN(0.1 * sol), new anchor.BN(0))
+        .accountsPartial({ user: user.publicKey, feePayer: provider.wallet.publicKey, ...pdas }),
+        program.methods
+        .withdrawWithPenalty(new anchor.BN(0.1 * sol), 1000)
+        .accountsPartial({
+          user: user.publicKey,
+          ...pdas,
+          incinerator: new anchor.web3.PublicKey("1nc1nerator11111111111111111111111111111111"),
+        }),
+      ];
+      for (const spike of spikes) {
+        try {
+          await spike.signers([user]).rpc();
+          assert.fail("a withdrawal 10x the average should have been rejected");
+        } catch (e) {
+          assert.include(e.toString(), "WithdrawalSpike");
+        }
+      }
+    });
+  });
+
+  describe("v2 migration", () => {
+    it("Moves funds and state to the v2 PDAs, retires the old vault and keeps the new one usable", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const payment = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId };
+      await program.methods.deposit(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL)).accountsPartial(payment).signers([user]).rpc();
+      const newVaultState = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("state_v2"), user.publicKey.toBuffer(), Buffer.from([0])], program.programId)[0];
+      const newVault = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("vault"), newVaultState.toBuffer()], program.programId)[0];
+      const balance = await provider.connection.getBalance(pdas.vault);
+
+      const sig = await program.methods
+      .migrateToV2()
+      .accountsPartial({ user: user.publicKey, ...pdas, newVaultState, newVault })
+      .signers([user])
+      .rpc({ commitment: "confirmed" });
+      const [migrated] = (await eventsOf(sig)).filter((e) => e.name === "migrated");
+      assert.ok(migrated.data.to.equals(newVaultState));
+      assert.equal(migrated.data.amount.toNumber(), balance);
+      assert.equal(await provider.connection.getBalance(newVault), balance);
+      assert.equal(await provider.connection.getBalance(pdas.vault), 0);
+      const copied = await program.account.vaultState.fetch(newVaultState);
+      assert.equal(copied.totalDeposited.toNumber(), anchor.web3.LAMPORTS_PER_SOL);
+      assert.isNull(copied.migratedTo);
+      assert.equal(copied.seedVersion, 2);
+
+      try {
+        await program.methods.deposit(new anchor.BN(1_000_000)).accountsPartial(payment).signers([user]).rpc();
+        assert.fail("deposit into a migrated vault should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "VaultMigrated");
+      }
+
+      // the v2 pair is loaded and signed for like any other vault
+      const v2 = { user: user.publicKey, vaultState: newVaultState, vault: newVault, systemProgram: anchor.web3.SystemProgram.programId };
+      const userBefore = await provider.connection.getBalance(user.publicKey);
+      await program.methods.withdraw(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL / 2)).accountsPartial(v2).signers([user]).rpc();
+      assert.equal(await provider.connection.getBalance(newVault), balance - anchor.web3.LAMPORTS_PER_SOL / 2);
+      assert.equal(await provider.connection.getBalance(user.publicKey), userBefore + anchor.web3.LAMPORTS_PER_SOL / 2);
+      await program.methods.deposit(new anchor.BN(1_000_000)).accountsPartial(v2).signers([user]).rpc();
+      assert.equal(await provider.connection.getBalance(newVault), balance - anchor.web3.LAMPORTS_PER_SOL / 2 + 1_000_000);
+    });
+  });
+
+  describe("batch modes", () => {
+    // Vec<u8> return data: u32 length prefix followed by the skipped indices
+    const skippedIndices = async (signature: string) => {
+      const tx = await provider.connection.getTransaction(signature, { commitment: "confirmed", maxSupportedTransactionVersion: 0 });
+      const data = Buffer.from(tx.meta.returnData.data[0], "base64");
+      return Array.from(data.subarray(4, 4 + data.readUInt32LE(0)));
+    };
+
+    it("Reverts a deposit batch with a bad item unless it is best effort", async () => {
+      const user = await newUser();
+      const other = await newUser();
+      const mine = await initializeVault(user);
+      const theirs = await initializeVault(other);
+      const amount = 0.1 * anchor.web3.LAMPORTS_PER_SOL;
+      const depositMany = (mode) =>
+        program.methods
+        .depositMany([new anchor.BN(amount), new anchor.BN(amount), new anchor.BN(amount)], mode)
+        .accountsPartial({ user: user.publicKey, systemProgram: anchor.web3.SystemProgram.programId })
+        .remainingAccounts(
+          [mine, theirs, mine].flatMap((pdas) => [
+            { pubkey: pdas.vaultState, isWritable: true, isSigner: false },
+            { pubkey: pdas.vault, isWritable: true, isSigner: false },
+          ])
+        )
+        .signers([user])
+        .rpc({ commitment: "confirmed" });
+
+      try {
+        await depositMany({ allOrNothing: {} });
+        assert.fail("a batch with someone else's vault should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "Unauthorized");
+      }
+      assert.equal((await program.account.vaultState.fetch(mine.vaultState)).totalDeposited.toNumber(), 0);
+
+      const sig = await depositMany({ bestEffort: {} });
+      assert.deepEqual(await skippedIndices(sig), [1]);
+      assert.equal((await program.account.vaultState.fetch(mine.vaultState)).totalDeposited.toNumber(), 2 * amount);
+      assert.equal((await program.account.vaultState.fetch(theirs.vaultState)).totalDeposited.toNumber(), 0);
+    });
+
+    it("Skips recipients that are not allowlisted in best-effort withdrawals", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      await program.methods
+      .deposit(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      const [allowed, stranger] = [0, 1].map(() => anchor.web3.Keypair.generate().publicKey);
+      const entry = (recipient: anchor.web3.PublicKey) =>
+        anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("allow"), pdas.vaultState.toBytes(), recipient.toBytes()], program.programId)[0];
+      await program.methods
+      .allowRecipient(allowed, new anchor.BN(3600))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState, allowlistEntry: entry(allowed) })
+      .signers([user])
+      .rpc();
+      const amount = 0.1 * anchor.web3.LAMPORTS_PER_SOL;
+      const withdrawToMany = (mode) =>
+        program.methods
+        .withdrawToMany([new anchor.BN(amount), new anchor.BN(amount)], mode)
+        .accountsPartial({ user: user.publicKey, ...pdas })
+        .remainingAccounts(
+          [allowed, stranger].flatMap((recipient) => [
+            { pubkey: recipient, isWritable: true, isSigner: false },
+            { pubkey: entry(recipient), isWritable: false, isSigner: false },
+          ])
+        )
+        .signers([user])
+        .rpc({ commitment: "confirmed" });
+
+      try {
+        await withdrawToMany({ allOrNothing: {} });
+        assert.fail("a batch with a recipient missing from the allowlist should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "AccountNotInitialized");
+      }
+
+      const sig = await withdrawToMany({ bestEffort: {} });
+      assert.deepEqual(await skippedIndices(sig), [1]);
+      assert.equal(await provider.connection.getBalance(allowed), amount);
+      assert.equal(await provider.connection.getBalance(stranger), 0);
+    });
+  });
+
+  describe("seed collision guard", () => {
+    it("Derives distinct state and vault addresses for every index", async () => {
+      const user = anchor.web3.Keypair.generate().publicKey;
+      for (let index = 0; index < 256; index++) {
+        const { vaultState, vault } = vaultPdas(user, index);
+        assert.isFalse(vaultState.equals(vault), `index ${index} collides`);
+      }
+      // initialize runs the same check on-chain before creating anything
+      const owner = await newUser();
+      const pdas = await initializeVault(owner);
+      assert.isFalse(pdas.vaultState.equals(pdas.vault));
+    });
+  });
+
+  describe("token limits", () => {
+    for (const decimals of [6, 9]) {
+      it(`Applies a whole-token cap and minimum at raw amounts for a ${decimals}-decimal mint`, async () => {
+        const user = await newUser();
+        const pdas = await initializeVault(user);
+        const { mint, tokenVault } = await initTokenVault(user, pdas, decimals);
+        const unit = 10 ** decimals;
+        await program.methods
+        .setTokenLimits(new anchor.BN(5), new anchor.BN(1))
+        .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState, mint })
+        .signers([user])
+        .rpc();
+        const state = await program.account.vaultState.fetch(pdas.vaultState);
+        assert.equal(state.tokenDepositCap.toString(), (BigInt(5) * BigInt(unit)).toString());
+
+        const depositRaw = async (amount: bigint) => {
+          const swapOutput = await createAccount(provider.connection, user, mint, user.publicKey, anchor.web3.Keypair.generate());
+          await mintTo(provider.connection, user, mint, swapOutput, user, amount);
+          return program.methods
+          .depositFromSwap()
+          .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState, mint, tokenVault, swapOutput })
+          .signers([user])
+          .rpc();
+        };
+
+        try {
+          await depositRaw(BigInt(unit) - BigInt(1));
+          assert.fail("a deposit below one whole token should have been rejected");
+        } catch (e) {
+          assert.include(e.toString(), "InvalidAmount");
+        }
+        await depositRaw(BigInt(4) * BigInt(unit));
+        await depositRaw(BigInt(unit));
+        assert.equal((await getAccount(provider.connection, tokenVault)).amount, BigInt(5) * BigInt(unit));
+        try {
+          await depositRaw(BigInt(unit));
+          assert.fail("a deposit past the cap should have been rejected");
+        } catch (e) {
+          assert.include(e.toString(), "DepositCapExceeded");
+        }
+      });
+    }
+  });
+
+  describe("guardian", () => {
+    it("Lets the guardian pause and withdraw until the delegation expires", async () => {
+      const user = await newUser();
+      const guardian = await newUser(1);
+      const pdas = await initializeVault(user);
+      const sol = anchor.web3.LAMPORTS_PER_SOL;
+      await program.methods
+      .deposit(new anchor.BN(sol))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      const chainNow = await provider.connection.getBlockTime(await provider.connection.getSlot());
+      await program.methods
+      .setGuardian(guardian.publicKey, new anchor.BN(chainNow + 6))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      const guardianWithdraw = (amount: number) =>
+        program.methods
+        .guardianWithdraw(new anchor.BN(amount))
+        .accountsPartial({ guardian: guardian.publicKey, ...pdas, recipient: guardian.publicKey })
+        .signers([guardian])
+        .rpc();
+      const guardianSetPaused = (paused: boolean) =>
+        program.methods
+        .guardianSetPaused(paused)
+        .accountsPartial({ guardian: guardian.publicKey, vaultState: pdas.vaultState })
+        .signers([guardian])
+        .rpc();
+
+      await guardianSetPaused(true);
+      assert.isTrue((await program.account.vaultState.fetch(pdas.vaultState)).paused);
+      await guardianSetPaused(false);
+      const before = await provider.connection.getBalance(guardian.publicKey);
+      await guardianWithdraw(0.1 * sol);
+      // the guardian also paid the transaction fee
+      assert.isAbove(await provider.connection.getBalance(guardian.publicKey), before + 0.09 * sol);
+
+      await sleep(8000);
+      try {
+        await guardianWithdraw(0.1 * sol);
+        assert.fail("an expired guardian should not be able to withdraw");
+      } catch (e) {
+        assert.include(e.toString(), "GuardianExpired");
+      }
+      try {
+        await guardianSetPaused(true);
+        assert.fail("an expired guardian should not be able to pause");
+      } catch (e) {
+        assert.include(e.toString(), "GuardianExpired");
+      }
+    });
+  });
+
+  describe("self custody", () => {
+    it("Rejects third-party withdraw_to recipients while self_custody_only is set", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const sol = anchor.web3.LAMPORTS_PER_SOL;
+      await program.methods
+      .deposit(new anchor.BN(sol))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      const third = anchor.web3.Keypair.generate().publicKey;
+      const entry = (recipient: anchor.web3.PublicKey) =>
+        anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("allow"), pdas.vaultState.toBytes(), recipient.toBytes()], program.programId)[0];
+      for (const recipient of [third, user.publicKey]) {
+        await program.methods
+        .allowRecipient(recipient, new anchor.BN(3600))
+        .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState, allowlistEntry: entry(recipient) })
+        .signers([user])
+        .rpc();
+      }
+      await program.methods
+      .setSelfCustodyOnly(true)
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      const withdrawTo = (recipient: anchor.web3.PublicKey) =>
+        program.methods
+        .withdrawTo(new anchor.BN(0.1 * sol))
+        .accountsPartial({ user: user.publicKey, ...pdas, recipient, allowlistEntry: entry(recipient) })
+        .signers([user])
+        .rpc();
+
+      try {
+        await withdrawTo(third);
+        assert.fail("a third-party recipient should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "SelfCustodyOnly");
+      }
+      const vaultBefore = await provider.connection.getBalance(pdas.vault);
+      await withdrawTo(user.publicKey);
+      assert.equal(await provider.connection.getBalance(pdas.vault), vaultBefore - 0.1 * sol);
+    });
+  });
+
+  describe("deposit streams", () => {
+    it("Releases the accrued amount into the vault on each claim and stops at the total", async () => {
+      const user = await newUser();
+      const source = await newUser();
+      const pdas = await initializeVault(user);
+      const rate = 100_000;
+      const total = 1_000_000;
+      const stream = anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("stream"), pdas.vaultState.toBuffer(), source.publicKey.toBuffer()],
+        program.programId
+      )[0];
+      await program.methods
+      .openStream(new anchor.BN(rate), new anchor.BN(total))
+      .accountsPartial({ source: source.publicKey, vaultState: pdas.vaultState, stream })
+      .signers([source])
+      .rpc();
+      const claim = () => program.methods.claimStream().accountsPartial({ ...pdas, stream }).rpc();
+
+      await sleep(3000);
+      const vaultBefore = await provider.connection.getBalance(pdas.vault);
+      await claim();
+      let state = await program.account.stream.fetch(stream);
+      const expected = rate * (state.lastClaimAt.toNumber() - state.start.toNumber());
+      assert.isAbove(expected, 0);
+      assert.equal(state.claimed.toNumber(), expected);
+      assert.equal(await provider.connection.getBalance(pdas.vault), vaultBefore + expected);
+
+      // 10 seconds covers the whole total
+      await sleep(11000);
+      await claim();
+      state = await program.account.stream.fetch(stream);
+      assert.equal(state.claimed.toNumber(), total);
+      assert.equal(await provider.connection.getBalance(pdas.vault), vaultBefore + total);
+      assert.equal((await program.account.vaultState.fetch(pdas.vaultState)).totalDeposited.toNumber(), total);
+    });
+  });
+
+  describe("flags", () => {
+    it("Packs the active flags into one bitmask", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const configure = { user: user.publicKey, vaultState: pdas.vaultState };
+      const flags = async () => await program.methods.flags().accountsPartial(pdas).view();
+      const [PAUSED, CONFIG_FROZEN, VERBOSE_EVENTS, QUEUE_ON_PAUSE] = [1 << 0, 1 << 1, 1 << 3, 1 << 6];
+
+      // new vaults only have verbose events on
+      assert.equal(await flags(), VERBOSE_EVENTS);
+
+      await program.methods.setPaused(true).accountsPartial(configure).signers([user]).rpc();
+      await program.methods.setQueueOnPause(true).accountsPartial(configure).signers([user]).rpc();
+      await program.methods.setVerboseEvents(false).accountsPartial(configure).signers([user]).rpc();
+      assert.equal(await flags(), PAUSED | QUEUE_ON_PAUSE);
+
+      await program.methods.freezeConfig().accountsPartial(configure).signers([user]).rpc();
+      await program.methods.setPaused(false).accountsPartial(configure).signers([user]).rpc();
+      assert.equal(await flags(), CONFIG_FROZEN | QUEUE_ON_PAUSE);
+    });
+  });
+
+  describe("state migration", () => {
+    // preloaded by Anchor.toml from tests/fixtures: a VaultState padded with 2048 bytes of data
+    const padded = new anchor.web3.PublicKey("2HBznpdSJApxGhFBCgm717XEfz3FjhKgcEpgA3teWWrq");
+    const legacyOwner = anchor.web3.Keypair.fromSecretKey(
+      Uint8Array.from(JSON.parse(fs.readFileSync("tests/fixtures/legacy_owner.json", "utf8")))
+    );
+
+    it("Shrinks a padded state and refunds the freed rent to the owner", async () => {
+      const sig = await provider.connection.requestAirdrop(legacyOwner.publicKey, anchor.web3.LAMPORTS_PER_SOL);
+      await provider.connection.confirmTransaction(sig, "confirmed");
+      const before = await provider.connection.getAccountInfo(padded);
+      assert.equal(before.data.length, 2048);
+      const ownerBefore = await provider.connection.getBalance(legacyOwner.publicKey);
+
+      await program.methods
+      .migrateState()
+      .accountsPartial({ user: legacyOwner.publicKey, vaultState: padded })
+      .signers([legacyOwner])
+      .rpc();
+
+      const after = await provider.connection.getAccountInfo(padded);
+      const space = program.account.vaultState.size;
+      assert.equal(after.data.length, space);
+      const rent = await provider.connection.getMinimumBalanceForRentExemption(space);
+      assert.equal(after.lamports, rent);
+      const refund = before.lamports - rent;
+      // the provider wallet pays the transaction fee, so the owner gets the whole refund
+      assert.equal(await provider.connection.getBalance(legacyOwner.publicKey), ownerBefore + refund);
+    });
+
+    it("Rejects a migration signed by someone other than the owner", async () => {
+      const stranger = await newUser();
+      try {
+        await program.methods
+        .migrateState()
+        .accountsPartial({ user: stranger.publicKey, vaultState: padded })
+        .signers([stranger])
+        .rpc();
+        assert.fail("a stranger should not be able to resize the state");
+      } catch (e) {
+        assert.include(e.toString(), "Unauthorized");
+      }
+    });
+  });
+
+  describe("limit change events", () => {
+    it("Emits LimitsChanged with the old and new values", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const configure = { user: user.publicKey, vaultState: pdas.vaultState };
+
+      const first = await eventsOf(await program.methods.setDepositCap(new anchor.BN(1000)).accountsPartial(configure).signers([user]).rpc());
+      assert.equal(first.length, 1);
+      assert.equal(first[0].name, "limitsChanged");
+      assert.deepEqual(first[0].data.field, { depositCap: {} });
+      assert.equal(first[0].data.old.toNumber(), 0);
+      assert.equal(first[0].data.new.toNumber(), 1000);
+
+      const second = await eventsOf(await program.methods.setDepositCap(new anchor.BN(250)).accountsPartial(configure).signers([user]).rpc());
+      assert.equal(second[0].data.old.toNumber(), 1000);
+      assert.equal(second[0].data.new.toNumber(), 250);
+
+      const spike = await eventsOf(await program.methods.setWithdrawalSpikeMultiple(3).accountsPartial(configure).signers([user]).rpc());
+      assert.deepEqual(spike[0].data.field, { withdrawalSpikeMultiple: {} });
+      assert.equal(spike[0].data.new.toNumber(), 3);
+    });
+
+    it("Emits for min_deposit even when the value is unchanged", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const configure = { user: user.publicKey, vaultState: pdas.vaultState };
+      const events = await eventsOf(await program.methods.setMinDeposit(new anchor.BN(0)).accountsPartial(configure).signers([user]).rpc());
+      assert.deepEqual(events[0].data.field, { minDeposit: {} });
+      assert.equal(events[0].data.old.toNumber(), 0);
+      assert.equal(events[0].data.new.toNumber(), 0);
+    });
+  });
+
+  describe("close to wsol", () => {
+    it("Wraps the vault balance into the user's WSOL account", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      await program.methods
+      .deposit(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      const returned = await provider.connection.getBalance(pdas.vault);
+
+      const userWsol = getAssociatedTokenAddressSync(NATIVE_MINT, user.publicKey);
+      await program.methods
+      .closeToWsol()
+      .accountsPartial({ user: user.publicKey, ...pdas, wsolMint: NATIVE_MINT, userWsol })
+      .signers([user])
+      .rpc();
+
+      assert.equal((await getAccount(provider.connection, userWsol)).amount, BigInt(returned));
+      assert.isNull(await provider.connection.getAccountInfo(pdas.vaultState));
+      assert.equal(await provider.connection.getBalance(pdas.vault), 0);
+    });
+
+    it("Rejects a mint other than native SOL", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const mint = await createMint(provider.connection, user, user.publicKey, null, 9);
+      try {
+        await program.methods
+        .closeToWsol()
+        .accountsPartial({ user: user.publicKey, ...pdas, wsolMint: mint, userWsol: getAssociatedTokenAddressSync(mint, user.publicKey) })
+        .signers([user])
+        .rpc();
+        assert.fail("a non-native mint should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "ConstraintAddress");
+      }
+    });
+  });
+
+  describe("daily withdraw limit", () => {
+    const sol = anchor.web3.LAMPORTS_PER_SOL;
+    const remaining = async (pdas) => (await program.methods.remainingDaily().accountsPartial(pdas).view()).toString();
+
+    const limitedVault = async (limit: number, windowSeconds: number) => {
+      const { user, pdas, configure } = await fundedVault(3 * sol);
+      const payment = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId };
+      await program.methods
+      .setWithdrawLimit(new anchor.BN(limit), new anchor.BN(windowSeconds))
+      .accountsPartial(configure)
+      .signers([user])
+      .rpc();
+      const withdraw = (amount: number) => program.methods.withdraw(new anchor.BN(amount)).accountsPartial(payment).signers([user]).rpc();
+      return { pdas, withdraw };
+    };
+
+    it("Reports the unlimited sentinel when no limit is set", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      assert.equal(await remaining(pdas), "18446744073709551615");
+    });
+
+    it("Counts down partway through a window and rejects going past it", async () => {
+      const { pdas, withdraw } = await limitedVault(sol, 86_400);
+      assert.equal(await remaining(pdas), String(sol));
+      await withdraw(0.4 * sol);
+      assert.equal(await remaining(pdas), String(0.6 * sol));
+      try {
+        await withdraw(0.7 * sol);
+        assert.fail("withdrawing past the daily limit should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "DailyWithdrawLimitExceeded");
+      }
+      await withdraw(0.6 * sol);
+      assert.equal(await remaining(pdas), "0");
+    });
+
+    it("Restores the full allowance once the window resets", async () => {
+      const { pdas, withdraw } = await limitedVault(sol, 2);
+      await withdraw(sol);
+      assert.equal(await remaining(pdas), "0");
+      await sleep(3000);
+      assert.equal(await remaining(pdas), String(sol));
+      await withdraw(0.5 * sol);
+      assert.equal(await remaining(pdas), String(0.5 * sol));
+    });
+  });
+
+  describe("vault seed versions", () => {
+    const sol = anchor.web3.LAMPORTS_PER_SOL;
+
+    const openWithVersion = async (user: anchor.web3.Keypair, seedVersion: number, vault: anchor.web3.PublicKey) => {
+      const vaultState = vaultPdas(user.publicKey).vaultState;
+      await program.methods
+      .initializeWithSeedVersion(0, seedVersion)
+      .accountsPartial({ user: user.publicKey, vaultState, vault })
+      .signers([user])
+      .rpc();
+      return { vaultState, vault };
+    };
+
+    const roundTrip = async (user: anchor.web3.Keypair, pdas) => {
+      const payment = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId };
+      const before = await provider.connection.getBalance(pdas.vault);
+      await program.methods.deposit(new anchor.BN(sol)).accountsPartial(payment).signers([user]).rpc();
+      assert.equal(await provider.connection.getBalance(pdas.vault), before + sol);
+      await program.methods.withdraw(new anchor.BN(0.5 * sol)).accountsPartial(payment).signers([user]).rpc();
+      assert.equal(await provider.connection.getBalance(pdas.vault), before + 0.5 * sol);
+    };
+
+    it("Derives a version 1 vault from the user and index alone", async () => {
+      const user = await newUser();
+      const [vault] = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("vault"), user.publicKey.toBytes(), Buffer.from([0])], program.programId);
+      const pdas = await openWithVersion(user, 1, vault);
+      assert.equal((await program.account.vaultState.fetch(pdas.vaultState)).seedVersion, 1);
+      await roundTrip(user, pdas);
+    });
+
+    it("Keeps the state-derived address for version 0", async () => {
+      const user = await newUser();
+      const pdas = await openWithVersion(user, 0, vaultPdas(user.publicKey).vault);
+      await roundTrip(user, pdas);
+    });
+
+    it("Rejects a vault address from the other scheme", async () => {
+      const user = await newUser();
+      try {
+        await openWithVersion(user, 1, vaultPdas(user.publicKey).vault);
+        assert.fail("a state-derived vault should not be accepted for version 1");
+      } catch (e) {
+        assert.include(e.toString(), "InvalidPda");
+      }
+    });
+  });
+
+  describe("inline allowlist", () => {
+    const sol = anchor.web3.LAMPORTS_PER_SOL;
+
+    it("Pays an inline recipient without an allowlist entry and rejects everyone else", async () => {
+      const { user, pdas } = await fundedVault();
+      const allowed = anchor.web3.Keypair.generate().publicKey;
+      const stranger = anchor.web3.Keypair.generate().publicKey;
+      await program.methods
+      .setInlineAllowlist([allowed])
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+
+      await program.methods
+      .withdrawTo(new anchor.BN(0.5 * sol))
+      .accountsPartial({ user: user.publicKey, ...pdas, recipient: allowed, allowlistEntry: null })
+      .signers([user])
+      .rpc();
+      assert.equal(await provider.connection.getBalance(allowed), 0.5 * sol);
+
+      try {
+        await program.methods
+        .withdrawTo(new anchor.BN(0.5 * sol))
+        .accountsPartial({ user: user.publicKey, ...pdas, recipient: stranger, allowlistEntry: null })
+        .signers([user])
+        .rpc();
+        assert.fail("a recipient outside the inline allowlist should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "RecipientNotAllowed");
+      }
+    });
+
+    it("Replaces the list and rejects more than three recipients", async () => {
+      const { user, pdas } = await fundedVault();
+      const configure = { user: user.publicKey, vaultState: pdas.vaultState };
+      const keys = [0, 1, 2, 3].map(() => anchor.web3.Keypair.generate().publicKey);
+      try {
+        await program.methods.setInlineAllowlist(keys).accountsPartial(configure).signers([user]).rpc();
+        assert.fail("a fourth inline recipient should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "InvalidInlineAllowlist");
+      }
+      await program.methods.setInlineAllowlist(keys.slice(0, 3)).accountsPartial(configure).signers([user]).rpc();
+      await program.methods.setInlineAllowlist([keys[3]]).accountsPartial(configure).signers([user]).rpc();
+      const state = await program.account.vaultState.fetch(pdas.vaultState);
+      assert.ok(state.inlineAllowlist[0].equals(keys[3]));
+      assert.ok(state.inlineAllowlist[1].equals(anchor.web3.PublicKey.default));
+    });
+  });
+
+  describe("deposit and notify", () => {
+    const rewardProgram = (anchor.workspace.MockReward as Program<MockReward>).programId;
+    const rewardConfig = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("reward_config")], program.programId)[0];
+    const setRewardProgram = (target: anchor.web3.PublicKey | null) =>
+      program.methods
+      .setRewardProgram(target)
+      .accountsPartial({ programAuthority: provider.wallet.publicKey, config, rewardConfig })
+      .rpc();
+
+    before(ensureConfig);
+
+    it("Credits the depositor through the reward program in the same transaction", async () => {
+      await setRewardProgram(rewardProgram);
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const sig = await program.methods
+      .depositAndNotify(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial({ user: user.publicKey, ...pdas, rewardConfig, rewardProgram, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc({ commitment: "confirmed" });
+      const tx = await provider.connection.getTransaction(sig, { commitment: "confirmed", maxSupportedTransactionVersion: 0 });
+      assert.include(tx.meta.logMessages.join("\n"), `credited ${anchor.web3.LAMPORTS_PER_SOL} points to ${user.publicKey.toBase58()}`);
+    });
+
+    it("Rejects a reward program other than the configured one", async () => {
+      await setRewardProgram(rewardProgram);
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      try {
+        await program.methods
+        .depositAndNotify(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+        .accountsPartial({ user: user.publicKey, ...pdas, rewardConfig, rewardProgram: anchor.web3.SystemProgram.programId, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([user])
+        .rpc();
+        assert.fail("an unconfigured reward program should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "RewardProgramMismatch");
+      }
+    });
+
+    it("Is a plain deposit once the reward program is cleared", async () => {
+      await setRewardProgram(null);
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const before = await provider.connection.getBalance(pdas.vault);
+      await program.methods
+      .depositAndNotify(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial({ user: user.publicKey, ...pdas, rewardConfig, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      assert.equal(await provider.connection.getBalance(pdas.vault), before + anchor.web3.LAMPORTS_PER_SOL);
+    });
+  });
+
+  describe("merkle deposit allowlist", () => {
+    const sol = anchor.web3.LAMPORTS_PER_SOL;
+    const sha256 = (...parts: Buffer[]) => createHash("sha256").update(Buffer.concat(parts)).digest();
+    // children are hashed in ascending byte order, matching merkle_root_of
+    const node = (a: Buffer, b: Buffer) => (Buffer.compare(a, b) <= 0 ? sha256(a, b) : sha256(b, a));
+
+    const gatedVault = async () => {
+      const owner = await newUser();
+      const pdas = await initializeVault(owner);
+      const depositors = [await newUser(), await newUser(), await newUser()];
+      const [l0, l1, l2] = depositors.map((d) => sha256(d.publicKey.toBuffer()));
+      const n01 = node(l0, l1);
+      const root = node(n01, l2);
+      await program.methods
+      .setDepositMerkleRoot([...root])
+      .accountsPartial({ user: owner.publicKey, vaultState: pdas.vaultState })
+      .signers([owner])
+      .rpc();
+      const proofs = [[l1, l2], [l0, l2], [n01]];
+      return { pdas, depositors, proofs };
+    };
+
+    const depositWithProof = (depositor: anchor.web3.Keypair, pdas, proof: Buffer[]) =>
+      program.methods
+      .depositWithProof(new anchor.BN(0.5 * sol), proof.map((p) => [...p]))
+      .accountsPartial({ depositor: depositor.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([depositor])
+      .rpc();
+
+    it("Accepts deposits from every leaf with a valid proof", async () => {
+      const { pdas, depositors, proofs } = await gatedVault();
+      const before = await provider.connection.getBalance(pdas.vault);
+      for (const [i, depositor] of depositors.entries()) {
+        await depositWithProof(depositor, pdas, proofs[i]);
+      }
+      assert.equal(await provider.connection.getBalance(pdas.vault), before + 1.5 * sol);
+    });
+
+    it("Rejects an outsider and a proof borrowed from another depositor", async () => {
+      const { pdas, depositors, proofs } = await gatedVault();
+      const outsider = await newUser();
+      for (const [who, proof] of [[outsider, proofs[0]], [depositors[1], proofs[2]]] as [anchor.web3.Keypair, Buffer[]][]) {
+        try {
+          await depositWithProof(who, pdas, proof);
+          assert.fail("an invalid proof should have been rejected");
+        } catch (e) {
+          assert.include(e.toString(), "InvalidMerkleProof");
+        }
+      }
+    });
+
+    it("Closes deposit_with_receipt while a root is set", async () => {
+      const { pdas, depositors } = await gatedVault();
+      const receiptId = new anchor.BN(1);
+      const receipt = anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("receipt"), pdas.vaultState.toBuffer(), depositors[0].publicKey.toBuffer(), receiptId.toArrayLike(Buffer, "le", 8)],
+        program.programId
+      )[0];
+      try {
+        await program.methods
+        .depositWithReceipt(receiptId, new anchor.BN(0.5 * sol))
+        .accountsPartial({ depositor: depositors[0].publicKey, ...pdas, receipt })
+        .signers([depositors[0]])
+        .rpc();
+        assert.fail("a receipt deposit should need a proof on a gated vault");
+      } catch (e) {
+        assert.include(e.toString(), "DepositProofRequired");
+      }
+    });
+  });
+
+  describe("withdraw to program PDA", () => {
+    const sol = anchor.web3.LAMPORTS_PER_SOL;
+    const escrowProgram = (anchor.workspace.MockReward as Program<MockReward>).programId;
+
+    const escrowVault = async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      await program.methods
+      .deposit(new anchor.BN(2 * sol))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      const [escrow, bump] = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("escrow"), user.publicKey.toBuffer()], escrowProgram);
+      await program.methods
+      .setInlineAllowlist([escrow])
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      const seeds = [Buffer.from("escrow"), user.publicKey.toBuffer(), Buffer.from([bump])];
+      return { user, pdas, escrow, seeds };
+    };
+
+    it("Funds another program's PDA when the seeds derive it", async () => {
+      const { user, pdas, escrow, seeds } = await escrowVault();
+      await program.methods
+      .withdrawToPda(new anchor.BN(sol), escrowProgram, seeds)
+      .accountsPartial({ user: user.publicKey, ...pdas, recipient: escrow, allowlistEntry: null })
+      .signers([user])
+      .rpc();
+      assert.equal(await provider.connection.getBalance(escrow), sol);
+    });
+
+    it("Rejects seeds or a program that derive a different address", async () => {
+      const { user, pdas, escrow, seeds } = await escrowVault();
+      const wrongSeeds = [Buffer.from("escrow"), anchor.web3.Keypair.generate().publicKey.toBuffer(), seeds[2]];
+      for (const [programId, s] of [[escrowProgram, wrongSeeds], [program.programId, seeds]] as [anchor.web3.PublicKey, Buffer[]][]) {
+        try {
+          await program.methods
+          .withdrawToPda(new anchor.BN(sol), programId, s)
+          .accountsPartial({ user: user.publicKey, ...pdas, recipient: escrow, allowlistEntry: null })
+          .signers([user])
+          .rpc();
+          assert.fail("a recipient the seeds don't derive should have been rejected");
+        } catch (e) {
+          assert.include(e.toString(), "InvalidPda");
+        }
+      }
+    });
+  });
+
+  describe("reset stats", () => {
+    const sol = anchor.web3.LAMPORTS_PER_SOL;
+
+    it("Clears the accounting without moving any lamports", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const payment = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId };
+      await program.methods.deposit(new anchor.BN(2 * sol)).accountsPartial(payment).signers([user]).rpc();
+      await program.methods.withdraw(new anchor.BN(0.5 * sol)).accountsPartial(payment).signers([user]).rpc();
+      const balance = await provider.connection.getBalance(pdas.vault);
+
+      const sig = await program.methods
+      .resetStats()
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc({ commitment: "confirmed" });
+      const [event] = await eventsOf(sig);
+      assert.equal(event.name, "statsReset");
+      assert.equal(event.data.totalDeposited.toNumber(), 2 * sol);
+      assert.equal(event.data.withdrawalCount, 1);
+
+      assert.equal(await provider.connection.getBalance(pdas.vault), balance);
+      const state = await program.account.vaultState.fetch(pdas.vaultState);
+      assert.equal(state.totalDeposited.toNumber(), 0);
+      assert.equal(state.withdrawalCount, 0);
+      assert.equal(state.twabAccumulator.toNumber(), 0);
+      assert.isAbove(state.statsResetAt.toNumber(), 0);
+    });
+
+    it("Refuses while a lifetime withdrawal quota depends on the count", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const configure = { user: user.publicKey, vaultState: pdas.vaultState };
+      await program.methods.setMaxLifetimeWithdrawals(3).accountsPartial(configure).signers([user]).rpc();
+      try {
+        await program.methods.resetStats().accountsPartial(configure).signers([user]).rpc();
+        assert.fail("resetting under a quota should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "WithdrawalQuotaActive");
+      }
+    });
+  });
+
+  describe("rent floor", () => {
+    const sol = anchor.web3.LAMPORTS_PER_SOL;
+
+    // withdraws in two steps so only the second one lands on the rent-exempt minimum
+    const vaultAtFloor = async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const payment = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId };
+      await program.methods.deposit(new anchor.BN(sol)).accountsPartial(payment).signers([user]).rpc();
+      await program.methods.withdraw(new anchor.BN(0.5 * sol)).accountsPartial(payment).signers([user]).rpc();
+      assert.isFalse((await program.account.vaultState.fetch(pdas.vaultState)).atRentFloor);
+      await program.methods.withdraw(new anchor.BN(0.5 * sol)).accountsPartial(payment).signers([user]).rpc();
+      return { user, pdas };
+    };
+
+    it("Flags a vault drained to exactly its rent-exempt minimum", async () => {
+      const { pdas } = await vaultAtFloor();
+      const rent = await provider.connection.getMinimumBalanceForRentExemption(0);
+      assert.equal(await provider.connection.getBalance(pdas.vault), rent);
+      assert.isTrue((await program.account.vaultState.fetch(pdas.vaultState)).atRentFloor);
+    });
+
+    it("Tops the vault up and clears the flag", async () => {
+      const { user, pdas } = await vaultAtFloor();
+      const rent = await provider.connection.getMinimumBalanceForRentExemption(0);
+      await program.methods
+      .topUpOrClose(new anchor.BN(0.2 * sol))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      assert.equal(await provider.connection.getBalance(pdas.vault), rent + 0.2 * sol);
+      assert.isFalse((await program.account.vaultState.fetch(pdas.vaultState)).atRentFloor);
+    });
+
+    it("Closes the vault when no top-up is given", async () => {
+      const { user, pdas } = await vaultAtFloor();
+      await program.methods
+      .topUpOrClose(new anchor.BN(0))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      assert.equal(await provider.connection.getBalance(pdas.vault), 0);
+      assert.isNull(await provider.connection.getAccountInfo(pdas.vaultState));
+    });
+
+    it("Rejects a vault above the floor", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const payment = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId };
+      await program.methods.deposit(new anchor.BN(sol)).accountsPartial(payment).signers([user]).rpc();
+      try {
+        await program.methods.topUpOrClose(new anchor.BN(0)).accountsPartial(payment).signers([user]).rpc();
+        assert.fail("a vault above its rent minimum should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "NotAtRentFloor");
+      }
+    });
+  });
+
+  describe("deposit percent of balance", () => {
+    const reserve = 10_000_000; // DEPOSIT_FEE_RESERVE
+    const txFee = 5000;
+
+    it("Deposits the requested share of the balance above the reserve", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const before = await provider.connection.getBalance(user.publicKey);
+      const vaultBefore = await provider.connection.getBalance(pdas.vault);
+      await program.methods
+      .depositPercentOfBalance(1000)
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      // the signature fee is charged before the program reads the balance
+      const expected = Math.floor(((before - txFee - reserve) * 1000) / 10_000);
+      assert.equal(await provider.connection.getBalance(pdas.vault), vaultBefore + expected);
+      assert.equal(await provider.connection.getBalance(user.publicKey), before - txFee - expected);
+    });
+
+    it("Leaves the fee reserve in the wallet even at 100%", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      await program.methods
+      .depositPercentOfBalance(10_000)
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      assert.equal(await provider.connection.getBalance(user.publicKey), reserve);
+    });
+  });
+
+  describe("permission query", () => {
+    const [WITHDRAW, PAUSE, CLOSE] = [0, 1, 2];
+
+    const can = async (signer: anchor.web3.Keypair | null, pdas: ReturnType<typeof vaultPdas>, action: number) => {
+      const builder = program.methods
+      .can(action)
+      .accountsPartial({ signer: signer ? signer.publicKey : provider.wallet.publicKey, config, ...pdas });
+      return signer ? builder.signers([signer]).view() : builder.view();
+    };
+
+    it("Answers per role and action", async () => {
+      await ensureConfig();
+      const user = await newUser();
+      const guardian = await newUser(1);
+      const stranger = await newUser(1);
+      const pdas = await initializeVault(user);
+      await program.methods
+      .deposit(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      const chainNow = await provider.connection.getBlockTime(await provider.connection.getSlot());
+      await program.methods
+      .setGuardian(guardian.publicKey, new anchor.BN(chainNow + 600))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+
+      // null is the provider wallet, which ensureConfig made the program authority
+      const expected: [anchor.web3.Keypair | null, boolean[]][] = [
+        [user, [true, true, true]],
+        [guardian, [true, true, false]],
+        [null, [false, false, false]],
+        [stranger, [false, false, false]],
+      ];
+      for (const [signer, answers] of expected) {
+        for (const action of [WITHDRAW, PAUSE, CLOSE]) {
+          assert.equal(await can(signer, pdas, action), answers[action], `action ${action}`);
+        }
+      }
+
+      // opting into recovery lets the program authority withdraw (to the owner)
+      await program.methods
+      .setAllowAuthorityRecovery(true)
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      assert.isTrue(await can(null, pdas, WITHDRAW));
+    });
+
+    it("Follows the pause flag", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      await program.methods
+      .deposit(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      await program.methods.setPaused(true).accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState }).signers([user]).rpc();
+      assert.isFalse(await can(user, pdas, WITHDRAW));
+      assert.isFalse(await can(user, pdas, CLOSE));
+      assert.isTrue(await can(user, pdas, PAUSE));
+    });
+
+    it("Rejects an unknown action", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      try {
+        await can(user, pdas, 9);
+        assert.fail("an unknown action should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "InvalidAction");
+      }
+    });
+  });
+
+  describe("deposit campaign", () => {
+    const sol = anchor.web3.LAMPORTS_PER_SOL;
+
+    it("Accepts deposits only inside the window and never blocks withdrawals", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const payment = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId };
+      const chainNow = await provider.connection.getBlockTime(await provider.connection.getSlot());
+      await program.methods
+      .setCampaign(new anchor.BN(chainNow + 3), new anchor.BN(chainNow + 8))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      const depositRejected = async (when: string) => {
+        try {
+          await program.methods.deposit(new anchor.BN(sol)).accountsPartial(payment).signers([user]).rpc();
+          assert.fail(`a deposit ${when} the campaign should have been rejected`);
+        } catch (e) {
+          assert.include(e.toString(), "CampaignClosed");
+        }
+      };
+
+      await depositRejected("before");
+      await sleep(4000);
+      await program.methods.deposit(new anchor.BN(sol)).accountsPartial(payment).signers([user]).rpc();
+      await sleep(6000);
+      await depositRejected("after");
+
+      const before = await provider.connection.getBalance(pdas.vault);
+      await program.methods.withdraw(new anchor.BN(0.5 * sol)).accountsPartial(payment).signers([user]).rpc();
+      assert.equal(await provider.connection.getBalance(pdas.vault), before - 0.5 * sol);
+    });
+
+    it("Rejects a window that ends before it starts", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      try {
+        await program.methods
+        .setCampaign(new anchor.BN(100), new anchor.BN(50))
+        .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+        .signers([user])
+        .rpc();
+        assert.fail("an inverted window should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "InvalidDuration");
+      }
+    });
+  });
+
+  describe("savings goal", () => {
+    const sol = anchor.web3.LAMPORTS_PER_SOL;
+
+    it("Reports progress and emits GoalReached only once", async () => {
+      const user = await newUser(10);
+      const pdas = await initializeVault(user);
+      const payment = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId };
+      await program.methods
+      .setGoal(new anchor.BN(2 * sol))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      const deposit = async () => {
+        const sig = await program.methods.deposit(new anchor.BN(sol)).accountsPartial(payment).signers([user]).rpc({ commitment: "confirmed" });
+        return (await eventsOf(sig)).filter((e) => e.name === "goalReached");
+      };
+
+      assert.lengthOf(await deposit(), 0);
+      const halfway = await program.methods.getProgress().accountsPartial(pdas).view();
+      const balance = await provider.connection.getBalance(pdas.vault);
+      assert.equal(halfway.balance.toNumber(), balance);
+      assert.equal(halfway.goal.toNumber(), 2 * sol);
+      assert.equal(halfway.bpsComplete, Math.floor((balance * 10_000) / (2 * sol)));
+
+      const [reached] = await deposit();
+      assert.equal(reached.data.goal.toNumber(), 2 * sol);
+      assert.isAtLeast(reached.data.balance.toNumber(), 2 * sol);
+      assert.equal((await program.methods.getProgress().accountsPartial(pdas).view()).bpsComplete, 10_000);
+
+      assert.lengthOf(await deposit(), 0);
+    });
+  });
+
+  describe("archive", () => {
+    const sol = anchor.web3.LAMPORTS_PER_SOL;
+
+    it("Hides the vault from list_vaults, rejects deposits and still pays out", async () => {
+      const user = await newUser();
+      const kept = await initializeVault(user, 0);
+      const pdas = await initializeVault(user, 1);
+      const registry = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("registry"), user.publicKey.toBytes()], program.programId)[0];
+      const payment = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId };
+      const listed = async () => ({
+        open: Array.from(await program.methods.listVaults().accountsPartial({ registry }).view()),
+        archived: Array.from(await program.methods.listArchivedVaults().accountsPartial({ registry }).view()),
+      });
+      await program.methods.deposit(new anchor.BN(sol)).accountsPartial(payment).signers([user]).rpc();
+
+      await program.methods.archive().accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState }).signers([user]).rpc();
+      assert.deepEqual(await listed(), { open: [0], archived: [1] });
+      assert.isTrue((await program.account.vaultState.fetch(pdas.vaultState)).archived);
+      try {
+        await program.methods.deposit(new anchor.BN(sol)).accountsPartial(payment).signers([user]).rpc();
+        assert.fail("deposits into an archived vault should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "VaultArchived");
+      }
+      const before = await provider.connection.getBalance(pdas.vault);
+      await program.methods.withdraw(new anchor.BN(0.5 * sol)).accountsPartial(payment).signers([user]).rpc();
+      assert.equal(await provider.connection.getBalance(pdas.vault), before - 0.5 * sol);
+
+      await program.methods.unarchive().accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState }).signers([user]).rpc();
+      assert.deepEqual(await listed(), { open: [0, 1], archived: [] });
+      await program.methods.deposit(new anchor.BN(sol)).accountsPartial(payment).signers([user]).rpc();
+      assert.isFalse((await program.account.vaultState.fetch(kept.vaultState)).archived);
+    });
+
+    it("Only lets the owner archive", async () => {
+      const user = await newUser();
+      const stranger = await newUser(1);
+      const pdas = await initializeVault(user);
+      try {
+        await program.methods
+        .archive()
+        .accountsPartial({ user: stranger.publicKey, vaultState: pdas.vaultState })
+        .signers([stranger])
+        .rpc();
+        assert.fail("a stranger should not be able to archive the vault");
+      } catch (e) {
+        assert.include(e.toString(), "Unauthorized");
+      }
+    });
+  });
+
+  describe("multi balance", () => {
+    const sol = anchor.web3.LAMPORTS_PER_SOL;
+    const asRemaining = (pairs: ReturnType<typeof vaultPdas>[]) =>
+      pairs.flatMap(({ vaultState, vault }) => [
+        { pubkey: vaultState, isWritable: false, isSigner: false },
+        { pubkey: vault, isWritable: false, isSigner: false },
+      ]);
+
+    it("Reads three vaults' balances in one call", async () => {
+      const user = await newUser(10);
+      const pairs = [];
+      for (const [index, amount] of [0.5, 1, 2].entries()) {
+        const pdas = await initializeVault(user, index);
+        await program.methods
+        .deposit(new anchor.BN(amount * sol))
+        .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([user])
+        .rpc();
+        pairs.push(pdas);
+      }
+      const balances = await program.methods.multiBalance().remainingAccounts(asRemaining(pairs)).view();
+      const expected = await Promise.all(pairs.map(({ vault }) => provider.connection.getBalance(vault)));
+      assert.deepEqual(balances.map((b) => b.toNumber()), expected);
+    });
+
+    it("Rejects an account that is not the state's vault PDA", async () => {
+      const user = await newUser();
+      const a = await initializeVault(user, 0);
+      const b = await initializeVault(user, 1);
+      try {
+        await program.methods.multiBalance().remainingAccounts(asRemaining([{ vaultState: a.vaultState, vault: b.vault }])).view();
+        assert.fail("a mismatched vault should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "InvalidPda");
+      }
+    });
+  });
+
+  describe("minimum withdrawal", () => {
+    const sol = anchor.web3.LAMPORTS_PER_SOL;
+    const min = 0.1 * sol;
+
+    const vaultWithMinimum = async () => {
+      const { user, pdas, configure } = await fundedVault(sol);
+      await program.methods.setMinWithdraw(new anchor.BN(min)).accountsPartial(configure).signers([user]).rpc();
+      return { user, pdas };
+    };
+
+    it("Accepts a withdraw at the minimum and rejects one below it", async () => {
+      const { user, pdas } = await vaultWithMinimum();
+      const payment = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId };
+      const before = await provider.connection.getBalance(pdas.vault);
+      await program.methods.withdraw(new anchor.BN(min)).accountsPartial(payment).signers([user]).rpc();
+      assert.equal(await provider.connection.getBalance(pdas.vault), before - min);
+      try {
+        await program.methods.withdraw(new anchor.BN(min - 1)).accountsPartial(payment).signers([user]).rpc();
+        assert.fail("a withdrawal below the minimum should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "WithdrawBelowMinimum");
+      }
+    });
+
+    it("Applies the same minimum to withdraw_to", async () => {
+      const { user, pdas } = await vaultWithMinimum();
+      const recipient = anchor.web3.Keypair.generate().publicKey;
+      await program.methods
+      .setInlineAllowlist([recipient])
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      const withdrawTo = (amount: number) =>
+        program.methods
+        .withdrawTo(new anchor.BN(amount))
+        .accountsPartial({ user: user.publicKey, ...pdas, recipient, allowlistEntry: null })
+        .signers([user])
+        .rpc();
+      try {
+        await withdrawTo(min - 1);
+        assert.fail("a withdrawal below the minimum should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "WithdrawBelowMinimum");
+      }
+      await withdrawTo(min);
+      assert.equal(await provider.connection.getBalance(recipient), min);
+    });
+
+    it("Applies the minimum to withdraw_net and withdraw_with_penalty", async () => {
+      const { user, pdas } = await vaultWithMinimum();
+      const belowMinimum = [
+        program.methods
+        .withdrawNet(new anchor.BN(min - 1), new anchor.BN(0))
+        .accountsPartial({ user: user.publicKey, feePayer: provider.wallet.publicKey, ...pdas }),
+        program.methods
+        .withdrawWithPenalty(new anchor.BN(min - 1), 1000)
+        .accountsPartial({
+          user: user.publicKey,
+          ...pdas,
+          incinerator: new anchor.web3.PublicKey("1nc1nerator11111111111111111111111111111111"),
+        }),
+      ];
+      for (const withdrawal of belowMinimum) {
+        try {
+          await withdrawal.signers([user]).rpc();
+          assert.fail("a withdrawal below the minimum should have been rejected");
+        } catch (e) {
+          assert.include(e.toString(), "WithdrawBelowMinimum");
+        }
+      }
+    });
+
+    it("Checks each withdraw_to_many item against the minimum", async () => {
+      const { user, pdas } = await vaultWithMinimum();
+      const recipients = [...Array(2)].map(() => anchor.web3.Keypair.generate().publicKey);
+      const entries = recipients.map((recipient) =>
+        anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("allow"), pdas.vaultState.toBytes(), recipient.toBytes()], program.programId)[0]
+      );
+      for (const [i, recipient] of recipients.entries()) {
+        await program.methods
+        .allowRecipient(recipient, new anchor.BN(3600))
+        .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState, allowlistEntry: entries[i] })
+        .signers([user])
+        .rpc();
+      }
+      const withdrawToMany = (mode) =>
+        program.methods
+        .withdrawToMany([new anchor.BN(min), new anchor.BN(min - 1)], mode)
+        .accountsPartial({ user: user.publicKey, ...pdas })
+        .remainingAccounts(
+          recipients.flatMap((recipient, i) => [
+            { pubkey: recipient, isWritable: true, isSigner: false },
+            { pubkey: entries[i], isWritable: false, isSigner: false },
+          ])
+        )
+        .signers([user]);
+
+      try {
+        await withdrawToMany({ allOrNothing: {} }).rpc();
+        assert.fail("an item below the minimum should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "WithdrawBelowMinimum");
+      }
+      // best effort skips the short item and pays the other
+      await withdrawToMany({ bestEffort: {} }).rpc();
+      assert.equal(await provider.connection.getBalance(recipients[0]), min);
+      assert.equal(await provider.connection.getBalance(recipients[1]), 0);
+    });
+  });
+
+  describe("pending operations", () => {
+    it("Transfers ownership in two steps", async () => {
+      const user = await newUser();
+      const next = await newUser(1);
+      const pdas = await initializeVault(user);
+      await program.methods
+      .proposeOwner(next.publicKey)
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      await program.methods.acceptOwner().accountsPartial({ newOwner: next.publicKey, vaultState: pdas.vaultState }).signers([next]).rpc();
+      const state = await program.account.vaultState.fetch(pdas.vaultState);
+      assert.isTrue(state.owner.equals(next.publicKey));
+      assert.isNull(state.pendingOwner);
+    });
+
+    it("Refuses to propose an owner while a close is armed", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const configure = { user: user.publicKey, vaultState: pdas.vaultState };
+      await program.methods.armClose([1, 2, 3, 4]).accountsPartial(configure).signers([user]).rpc();
+      try {
+        await program.methods.proposeOwner(anchor.web3.Keypair.generate().publicKey).accountsPartial(configure).signers([user]).rpc();
+        assert.fail("a second pending operation should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "PendingOperationExists");
+      }
+    });
+
+    it("Refuses to arm a close while an owner transfer is pending, until it is cancelled", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const configure = { user: user.publicKey, vaultState: pdas.vaultState };
+      await program.methods.proposeOwner(anchor.web3.Keypair.generate().publicKey).accountsPartial(configure).signers([user]).rpc();
+      try {
+        await program.methods.armClose([1, 2, 3, 4]).accountsPartial(configure).signers([user]).rpc();
+        assert.fail("a second pending operation should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "PendingOperationExists");
+      }
+      await program.methods.proposeOwner(null).accountsPartial(configure).signers([user]).rpc();
+      await program.methods.armClose([1, 2, 3, 4]).accountsPartial(configure).signers([user]).rpc();
+    });
+  });
+
+  describe("sponsored initialize", () => {
+    const sol = anchor.web3.LAMPORTS_PER_SOL;
+
+    it("Lets a sponsor pay the rent while the owner controls the funds", async () => {
+      const owner = anchor.web3.Keypair.generate();
+      const sponsor = await newUser();
+      const pdas = vaultPdas(owner.publicKey);
+      const registry = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("registry"), owner.publicKey.toBytes()], program.programId)[0];
+      const sponsorBefore = await provider.connection.getBalance(sponsor.publicKey);
+      await program.methods
+      .initializeSponsored(0)
+      .accountsPartial({ user: owner.publicKey, rentPayer: sponsor.publicKey, ...pdas, registry, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([owner, sponsor])
+      .rpc();
+
+      // the owner never held any lamports, so all rent came from the sponsor
+      assert.equal(await provider.connection.getBalance(owner.publicKey), 0);
+      const rent = await Promise.all(
+        [pdas.vaultState, pdas.vault, registry].map((key) => provider.connection.getBalance(key))
+      );
+      assert.equal(await provider.connection.getBalance(sponsor.publicKey), sponsorBefore - rent.reduce((a, b) => a + b));
+      assert.isTrue((await program.account.vaultState.fetch(pdas.vaultState)).owner.equals(owner.publicKey));
+
+      const sig = await provider.connection.requestAirdrop(owner.publicKey, 2 * sol);
+      await provider.connection.confirmTransaction(sig, "confirmed");
+      const payment = { ...pdas, systemProgram: anchor.web3.SystemProgram.programId };
+      await program.methods.deposit(new anchor.BN(sol)).accountsPartial({ user: owner.publicKey, ...payment }).signers([owner]).rpc();
+      try {
+        await program.methods.withdraw(new anchor.BN(sol)).accountsPartial({ user: sponsor.publicKey, ...payment }).signers([sponsor]).rpc();
+        assert.fail("the sponsor should not control the vault");
+      } catch (e) {
+        assert.include(e.toString(), "Unauthorized");
+      }
+      await program.methods.withdraw(new anchor.BN(sol)).accountsPartial({ user: owner.publicKey, ...payment }).signers([owner]).rpc();
+    });
+  });
+
+  describe("estimated apy", () => {
+    const sol = anchor.web3.LAMPORTS_PER_SOL;
+    const year = 365 * 24 * 60 * 60;
+
+    it("Annualizes rewards accrued in the stake account, then keeps the realised figure", async () => {
+      const { user, pdas } = await fundedVault(5 * sol, 10);
+      const stakeAccount = await delegateStake(user, pdas, 3 * sol);
+
+      // a plain transfer into the stake account stands in for epoch rewards
+      const reward = 0.001 * sol;
+      await provider.sendAndConfirm(
+        new anchor.web3.Transaction().add(
+          anchor.web3.SystemProgram.transfer({ fromPubkey: provider.wallet.publicKey, toPubkey: stakeAccount, lamports: reward })
+        )
+      );
+      await sleep(2000);
+      const startedAt = (await program.account.vaultState.fetch(pdas.vaultState)).stakeStartedAt.toNumber();
+      const before = await provider.connection.getBlockTime(await provider.connection.getSlot());
+      const apy = (await program.methods.estimatedApy().accountsPartial({ vaultState: pdas.vaultState, stakeAccount }).view()).toNumber();
+      const after = await provider.connection.getBlockTime(await provider.connection.getSlot());
+      const at = (elapsed: number) => Math.floor((reward * 10_000 * year) / (3 * sol * elapsed));
+      assert.isAtMost(apy, at(Math.max(before - startedAt - 1, 1)));
+      assert.isAtLeast(apy, at(after - startedAt + 1));
+
+      const stakeAction = {
+        user: user.publicKey,
+        vaultState: pdas.vaultState,
+        vault: pdas.vault,
+        stakeAccount,
+        stakeHistory: anchor.web3.SYSVAR_STAKE_HISTORY_PUBKEY,
+        stakeProgram: anchor.web3.StakeProgram.programId,
+      };
+      await program.methods.deactivateStake().accountsPartial(stakeAction).signers([user]).rpc();
+      await program.methods.withdrawStake().accountsPartial(stakeAction).signers([user]).rpc();
+      const realised = (await program.account.vaultState.fetch(pdas.vaultState)).lastStakeApyBps.toNumber();
+      assert.isAbove(realised, 0);
+      const view = await program.methods.estimatedApy().accountsPartial({ vaultState: pdas.vaultState, stakeAccount: null }).view();
+      assert.equal(view.toNumber(), realised);
+    });
+
+    it("Is zero for a vault that never staked", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const apy = await program.methods.estimatedApy().accountsPartial({ vaultState: pdas.vaultState, stakeAccount: null }).view();
+      assert.equal(apy.toNumber(), 0);
+    });
+  });
+
+  describe("conditional withdrawal", () => {
+    const sol = anchor.web3.LAMPORTS_PER_SOL;
+    const mock = anchor.workspace.MockReward as Program<MockReward>;
+
+    const conditionedVault = async () => {
+      const { user, pdas } = await fundedVault();
+      // the flag belongs to a separate authority, as it would for a real cross-program release
+      const authority = await newUser(1);
+      const setFlag = (set: boolean) =>
+        mock.methods.setFlag(set).accountsPartial({ authority: authority.publicKey }).signers([authority]).rpc();
+      await setFlag(false);
+      const [flag] = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("flag"), authority.publicKey.toBuffer()], mock.programId);
+      await program.methods
+      .setReleaseCondition({ account: flag, owner: mock.programId, offset: 40, value: 1 })
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      const withdrawIf = (conditionAccount: anchor.web3.PublicKey) =>
+        program.methods
+        .withdrawIf(new anchor.BN(sol))
+        .accountsPartial({ user: user.publicKey, ...pdas, conditionAccount, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([user])
+        .rpc();
+      return { user, pdas, flag, setFlag, withdrawIf };
+    };
+
+    it("Pays out only while the external flag is set", async () => {
+      const { pdas, flag, setFlag, withdrawIf } = await conditionedVault();
+      try {
+        await withdrawIf(flag);
+        assert.fail("the withdrawal should wait for the flag");
+      } catch (e) {
+        assert.include(e.toString(), "ReleaseConditionNotMet");
+      }
+      await setFlag(true);
+      const before = await provider.connection.getBalance(pdas.vault);
+      await withdrawIf(flag);
+      assert.equal(await provider.connection.getBalance(pdas.vault), before - sol);
+      await setFlag(false);
+      try {
+        await withdrawIf(flag);
+        assert.fail("clearing the flag should block withdrawals again");
+      } catch (e) {
+        assert.include(e.toString(), "ReleaseConditionNotMet");
+      }
+    });
+
+    it("Rejects another account and the unconditional withdraw", async () => {
+      const { user, pdas, setFlag, withdrawIf } = await conditionedVault();
+      await setFlag(true);
+      try {
+        await withdrawIf(pdas.vaultState);
+        assert.fail("a different condition account should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "ConditionAccountMismatch");
+      }
+      try {
+        await program.methods
+        .withdraw(new anchor.BN(sol))
+        .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+        .signers([user])
+        .rpc();
+        assert.fail("plain withdraw should be refused while a condition is set");
+      } catch (e) {
+        assert.include(e.toString(), "ReleaseConditionRequired");
+      }
+    });
+
+    it("Refuses every other way out of the vault while a condition is set", async () => {
+      const { user, pdas, setFlag } = await conditionedVault();
+      // even a satisfied condition only releases through withdraw_if
+      await setFlag(true);
+      const target = vaultPdas(user.publicKey, 1);
+      const exits = [
+        program.methods
+        .withdrawTo(new anchor.BN(sol))
+        .accountsPartial({ user: user.publicKey, ...pdas, recipient: user.publicKey, allowlistEntry: null, validationHook: null }),
+        program.methods
+        .withdrawNet(new anchor.BN(sol), new anchor.BN(5000))
+        .accountsPartial({ user: user.publicKey, feePayer: provider.wallet.publicKey, ...pdas }),
+        program.methods
+        .splitVault(1, new anchor.BN(sol))
+        .accountsPartial({ user: user.publicKey, ...pdas, newVaultState: target.vaultState, newVault: target.vault }),
+        program.methods
+        .close()
+        .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId }),
+      ];
+      for (const exit of exits) {
+        try {
+          await exit.signers([user]).rpc();
+          assert.fail("the exit should be refused while a condition is set");
+        } catch (e) {
+          assert.include(e.toString(), "ReleaseConditionRequired");
+        }
+      }
+      assert.isAtLeast(await provider.connection.getBalance(pdas.vault), 2 * sol);
+    });
+  });
+
+  describe("auto refund", () => {
+    const sol = anchor.web3.LAMPORTS_PER_SOL;
+
+    const receiptFor = (pdas: ReturnType<typeof vaultPdas>, depositor: anchor.web3.PublicKey, id: number) =>
+      anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("receipt"), pdas.vaultState.toBuffer(), depositor.toBuffer(), new anchor.BN(id).toArrayLike(Buffer, "le", 8)],
+        program.programId
+      )[0];
+
+    // two depositors put SOL into an escrow vault that refunds after 2 seconds
+    const escrow = async () => {
+      const owner = await newUser(1);
+      const pdas = await initializeVault(owner);
+      await program.methods
+      .setAutoRefund(new anchor.BN(2))
+      .accountsPartial({ user: owner.publicKey, vaultState: pdas.vaultState })
+      .signers([owner])
+      .rpc();
+      const depositors = [await newUser(), await newUser()];
+      for (const [i, depositor] of depositors.entries()) {
+        await program.methods
+        .depositWithReceipt(new anchor.BN(1), new anchor.BN((i + 1) * sol))
+        .accountsPartial({ depositor: depositor.publicKey, ...pdas, receipt: receiptFor(pdas, depositor.publicKey, 1) })
+        .signers([depositor])
+        .rpc();
+      }
+      const refund = (depositor: anchor.web3.Keypair) =>
+        program.methods
+        .triggerRefund()
+        .accountsPartial({ ...pdas, depositor: depositor.publicKey, receipt: receiptFor(pdas, depositor.publicKey, 1) })
+        .rpc();
+      return { owner, pdas, depositors, refund };
+    };
+
+    it("Returns each deposit to its depositor once the window has passed", async () => {
+      const { pdas, depositors, refund } = await escrow();
+      try {
+        await refund(depositors[0]);
+        assert.fail("the refund should not be due yet");
+      } catch (e) {
+        assert.include(e.toString(), "RefundNotDue");
+      }
+      await sleep(3000);
+      for (const [i, depositor] of depositors.entries()) {
+        const receipt = receiptFor(pdas, depositor.publicKey, 1);
+        const receiptRent = await provider.connection.getBalance(receipt);
+        const before = await provider.connection.getBalance(depositor.publicKey);
+        await refund(depositor);
+        assert.equal(await provider.connection.getBalance(depositor.publicKey), before + (i + 1) * sol + receiptRent);
+        assert.isNull(await provider.connection.getAccountInfo(receipt));
+      }
+      const rent = await provider.connection.getMinimumBalanceForRentExemption(0);
+      assert.equal(await provider.connection.getBalance(pdas.vault), rent);
+    });
+
+    it("Only refunds what the owner left unclaimed", async () => {
+      const { owner, pdas, depositors, refund } = await escrow();
+      await program.methods
+      .withdraw(new anchor.BN(2.5 * sol))
+      .accountsPartial({ user: owner.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([owner])
+      .rpc();
+      await sleep(3000);
+      const before = await provider.connection.getBalance(depositors[1].publicKey);
+      const receiptRent = await provider.connection.getBalance(receiptFor(pdas, depositors[1].publicKey, 1));
+      await refund(depositors[1]);
+      assert.equal(await provider.connection.getBalance(depositors[1].publicKey), before + 0.5 * sol + receiptRent);
+    });
+
+    it("Leaves the owner's own later deposits in the vault", async () => {
+      const { owner, pdas, depositors, refund } = await escrow();
+      await program.methods
+      .withdraw(new anchor.BN(2.5 * sol))
+      .accountsPartial({ user: owner.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([owner])
+      .rpc();
+      // the owner puts 2 SOL of their own back; it must not fund the receipts they claimed
+      await program.methods
+      .deposit(new anchor.BN(2 * sol))
+      .accountsPartial({ user: owner.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([owner])
+      .rpc();
+      const depositedBefore = (await program.account.vaultState.fetch(pdas.vaultState)).totalDeposited;
+      await sleep(3000);
+      const before = await Promise.all(depositors.map((d) => provider.connection.getBalance(d.publicKey)));
+      const receiptRent = await provider.connection.getBalance(receiptFor(pdas, depositors[1].publicKey, 1));
+      await refund(depositors[1]);
+      await refund(depositors[0]);
+      assert.equal(await provider.connection.getBalance(depositors[1].publicKey), before[1] + 0.5 * sol + receiptRent);
+      assert.equal(await provider.connection.getBalance(depositors[0].publicKey), before[0] + receiptRent);
+      const rent = await provider.connection.getMinimumBalanceForRentExemption(0);
+      assert.equal(await provider.connection.getBalance(pdas.vault), rent + 2 * sol);
+      const state = await program.account.vaultState.fetch(pdas.vaultState);
+      assert.equal(state.totalDeposited.toNumber(), depositedBefore.toNumber() - 0.5 * sol);
+    });
+  });
+
+  describe("mint lock", () => {
+    const closeTokenVault = (user: anchor.web3.Keypair, pdas: ReturnType<typeof vaultPdas>, tokenVault: anchor.web3.PublicKey) =>
+      program.methods
+      .closeTokenVault()
+      .accountsPartial({ user: user.publicKey, ...pdas, tokenVault })
+      .signers([user])
+      .rpc();
+
+    it("Lets an unlocked vault switch to another mint", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const { tokenVault } = await initTokenVault(user, pdas);
+      await closeTokenVault(user, pdas, tokenVault);
+      const { mint } = await initTokenVault(user, pdas);
+      assert.isTrue((await program.account.vaultState.fetch(pdas.vaultState)).tokenMint.equals(mint));
+    });
+
+    it("Keeps a locked vault on its mint for good", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const { mint, tokenVault } = await initTokenVault(user, pdas);
+      const configure = { user: user.publicKey, vaultState: pdas.vaultState };
+      await program.methods.lockMint().accountsPartial(configure).signers([user]).rpc();
+      // locking again is a no-op, and there is no instruction that clears the lock
+      await program.methods.lockMint().accountsPartial(configure).signers([user]).rpc();
+      try {
+        await closeTokenVault(user, pdas, tokenVault);
+        assert.fail("a locked vault should not be able to drop its mint");
+      } catch (e) {
+        assert.include(e.toString(), "MintLocked");
+      }
+      const state = await program.account.vaultState.fetch(pdas.vaultState);
+      assert.isTrue(state.mintLocked);
+      assert.isTrue(state.tokenMint.equals(mint));
+    });
+
+    it("Needs a token vault to lock", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      try {
+        await program.methods.lockMint().accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState }).signers([user]).rpc();
+        assert.fail("a SOL-only vault has no mint to lock");
+      } catch (e) {
+        assert.include(e.toString(), "NoTokenVault");
+      }
+    });
+  });
+
+  describe("config snapshot", () => {
+    it("Matches the on-chain state field by field", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const configure = { user: user.publicKey, vaultState: pdas.vaultState };
+      const sol = anchor.web3.LAMPORTS_PER_SOL;
+      await program.methods.setDepositCap(new anchor.BN(10 * sol)).accountsPartial(configure).signers([user]).rpc();
+      await program.methods.setMinWithdraw(new anchor.BN(0.01 * sol)).accountsPartial(configure).signers([user]).rpc();
+      await program.methods.setMaxLifetimeWithdrawals(7).accountsPartial(configure).signers([user]).rpc();
+      await program.methods.setCampaign(new anchor.BN(0), new anchor.BN(4_000_000_000)).accountsPartial(configure).signers([user]).rpc();
+      await program.methods.setPaused(true).accountsPartial(configure).signers([user]).rpc();
+
+      const config = await program.methods.getConfig().accountsPartial(pdas).view();
+      const state = await program.account.vaultState.fetch(pdas.vaultState);
+      for (const [key, value] of Object.entries(config)) {
+        if (key === "flags") {
+          continue;
+        }
+        assert.equal(String(value), String(state[key]), key);
+      }
+      assert.equal(config.depositCap.toNumber(), 10 * sol);
+      assert.equal(config.maxLifetimeWithdrawals, 7);
+      assert.equal(config.flags, await program.methods.flags().accountsPartial(pdas).view());
+      assert.equal(config.flags & 1, 1); // FLAG_PAUSED
+    });
+  });
+
+  describe("deposit bonus", () => {
+    const sol = anchor.web3.LAMPORTS_PER_SOL;
+
+    it("Pays bps of each deposit from the reward pool until the pool runs dry", async () => {
+      const user = await newUser(10);
+      const pdas = await initializeVault(user);
+      const rewardPool = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("reward_pool"), pdas.vaultState.toBuffer()], program.programId)[0];
+      const rent = await provider.connection.getMinimumBalanceForRentExemption(0);
+      const funded = 0.01 * sol;
+      await program.methods
+      .fundRewardPool(new anchor.BN(rent + funded))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState, rewardPool })
+      .signers([user])
+      .rpc();
+      await program.methods
+      .setDepositBonus(1000, new anchor.BN(sol))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      const payment = { user: user.publicKey, ...pdas, rewardPool, systemProgram: anchor.web3.SystemProgram.programId };
+      const deposit = async (amount: number) => {
+        const before = await provider.connection.getBalance(pdas.vault);
+        await program.methods.deposit(new anchor.BN(amount)).accountsPartial(payment).signers([user]).rpc();
+        return (await provider.connection.getBalance(pdas.vault)) - before - amount;
+      };
+
+      assert.equal(await deposit(0.05 * sol), 0.005 * sol);
+      assert.equal(await provider.connection.getBalance(rewardPool), rent + 0.005 * sol);
+      // the full bonus would be 0.1 SOL, but only 0.005 SOL is left above the pool's rent
+      assert.equal(await deposit(sol), 0.005 * sol);
+      assert.equal(await provider.connection.getBalance(rewardPool), rent);
+      assert.equal(await deposit(sol), 0);
+      assert.equal((await program.account.vaultState.fetch(pdas.vaultState)).bonusPaid.toNumber(), funded);
+    });
+
+    it("Stops at the bonus cap", async () => {
+      const user = await newUser(10);
+      const pdas = await initializeVault(user);
+      const rewardPool = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("reward_pool"), pdas.vaultState.toBuffer()], program.programId)[0];
+      await program.methods
+      .fundRewardPool(new anchor.BN(sol))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState, rewardPool })
+      .signers([user])
+      .rpc();
+      await program.methods
+      .setDepositBonus(1000, new anchor.BN(0.15 * sol))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      const payment = { user: user.publicKey, ...pdas, rewardPool, systemProgram: anchor.web3.SystemProgram.programId };
+      for (let i = 0; i < 2; i++) {
+        await program.methods.deposit(new anchor.BN(sol)).accountsPartial(payment).signers([user]).rpc();
+      }
+      assert.equal((await program.account.vaultState.fetch(pdas.vaultState)).bonusPaid.toNumber(), 0.15 * sol);
+      assert.equal(await provider.connection.getBalance(rewardPool), 0.85 * sol);
+    });
+  });
+
+  describe("referral deposits", () => {
+    const sol = anchor.web3.LAMPORTS_PER_SOL;
+
+    it("Emits the referral and pays the referrer's fee from the reward pool", async () => {
+      const user = await newUser();
+      const referrer = await newUser(1);
+      const pdas = await initializeVault(user);
+      const rewardPool = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("reward_pool"), pdas.vaultState.toBuffer()], program.programId)[0];
+      await program.methods
+      .fundRewardPool(new anchor.BN(sol))
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState, rewardPool })
+      .signers([user])
+      .rpc();
+      await program.methods.setReferralFee(100).accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState }).signers([user]).rpc();
+
+      const before = await provider.connection.getBalance(referrer.publicKey);
+      const sig = await program.methods
+      .depositWithReferral(new anchor.BN(2 * sol), referrer.publicKey)
+      .accountsPartial({ user: user.publicKey, ...pdas, rewardPool, referrer: referrer.publicKey, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc({ commitment: "confirmed" });
+      const event = (await eventsOf(sig)).find((e) => e.name === "referralDeposit");
+      assert.isTrue(event.data.referrer.equals(referrer.publicKey));
+      assert.isTrue(event.data.depositor.equals(user.publicKey));
+      assert.equal(event.data.amount.toNumber(), 2 * sol);
+      assert.equal(event.data.fee.toNumber(), 0.02 * sol);
+      assert.equal(await provider.connection.getBalance(referrer.publicKey), before + 0.02 * sol);
+      assert.equal(await provider.connection.getBalance(rewardPool), 0.98 * sol);
+    });
+
+    it("Only records the referral when no fee is configured", async () => {
+      const user = await newUser();
+      const referrer = anchor.web3.Keypair.generate().publicKey;
+      const pdas = await initializeVault(user);
+      const payment = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId };
+      const sig = await program.methods
+      .depositWithReferral(new anchor.BN(sol), referrer)
+      .accountsPartial(payment)
+      .signers([user])
+      .rpc({ commitment: "confirmed" });
+      const event = (await eventsOf(sig)).find((e) => e.name === "referralDeposit");
+      assert.isTrue(event.data.referrer.equals(referrer));
+      assert.equal(event.data.fee.toNumber(), 0);
+
+      try {
+        await program.methods.depositWithReferral(new anchor.BN(sol), user.publicKey).accountsPartial(payment).signers([user]).rpc();
+        assert.fail("self-referral should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "InvalidReferrer");
+      }
+    });
+  });
+
+  describe("external withdraw pause", () => {
+    const sol = anchor.web3.LAMPORTS_PER_SOL;
+
+    it("Blocks third-party sends while the owner keeps withdrawing", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const recipient = anchor.web3.Keypair.generate().publicKey;
+      const configure = { user: user.publicKey, vaultState: pdas.vaultState };
+      const payment = { user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId };
+      await program.methods.deposit(new anchor.BN(2 * sol)).accountsPartial(payment).signers([user]).rpc();
+      await program.methods.setInlineAllowlist([recipient]).accountsPartial(configure).signers([user]).rpc();
+      await program.methods.setExternalWithdrawPaused(true).accountsPartial(configure).signers([user]).rpc();
+      const withdrawTo = () =>
+        program.methods
+        .withdrawTo(new anchor.BN(0.5 * sol))
+        .accountsPartial({ user: user.publicKey, ...pdas, recipient, allowlistEntry: null })
+        .signers([user])
+        .rpc();
+
+      try {
+        await withdrawTo();
+        assert.fail("a third-party send should be paused");
+      } catch (e) {
+        assert.include(e.toString(), "ExternalWithdrawPaused");
+      }
+      const before = await provider.connection.getBalance(pdas.vault);
+      await program.methods.withdraw(new anchor.BN(0.5 * sol)).accountsPartial(payment).signers([user]).rpc();
+      assert.equal(await provider.connection.getBalance(pdas.vault), before - 0.5 * sol);
+
+      await program.methods.setExternalWithdrawPaused(false).accountsPartial(configure).signers([user]).rpc();
+      await withdrawTo();
+      assert.equal(await provider.connection.getBalance(recipient), 0.5 * sol);
+    });
+  });
+
+  describe("reconcile", () => {
+    // preloaded by Anchor.toml from tests/fixtures: a live VaultState whose vault holds nothing
+    const drained = new anchor.web3.PublicKey("EXqL3xphuCV864XwCxv9cVvTcbm5acRefecPvmtL6NwQ");
+    const legacyOwner = anchor.web3.Keypair.fromSecretKey(
+      Uint8Array.from(JSON.parse(fs.readFileSync("tests/fixtures/legacy_owner.json", "utf8")))
+    );
+
+    it("Rejects reconciling a vault that still holds funds", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+
+      try {
+        await program.methods
+        .reconcile()
+        .accountsPartial({ user: user.publicKey, ...pdas })
+        .signers([user])
+        .rpc();
+        assert.fail("reconcile should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "VaultNotEmpty");
+      }
+    });
+
+    // drained by withdrawing the whole balance, so only the state account is left
+    const drainedVault = async () => {
+      const user = await newUser(10);
+      const pdas = await initializeVault(user);
+      const balance = await provider.connection.getBalance(pdas.vault);
+      await program.methods
+      .withdraw(new anchor.BN(balance))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      return { user, pdas };
+    };
+
+    it("Rejects reconciling a vault whose close is armed", async () => {
+      const { user, pdas } = await drainedVault();
+      await program.methods
+      .armClose([1, 2, 3, 4])
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+
+      try {
+        await program.methods.reconcile().accountsPartial({ user: user.publicKey, ...pdas }).signers([user]).rpc();
+        assert.fail("reconcile should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "CloseNotConfirmed");
+      }
+    });
+
+    it("Rejects reconciling a vault whose whole balance is delegated", async () => {
+      const { user, pdas } = await fundedVault(3 * anchor.web3.LAMPORTS_PER_SOL, 10);
+      await delegateStake(user, pdas, await provider.connection.getBalance(pdas.vault));
+      assert.equal(await provider.connection.getBalance(pdas.vault), 0);
+
+      try {
+        await program.methods.reconcile().accountsPartial({ user: user.publicKey, ...pdas }).signers([user]).rpc();
+        assert.fail("reconcile should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "VaultNotEmpty");
+      }
+    });
+
+    it("Rejects a reconcile signed by someone other than the owner", async () => {
+      const other = await newUser();
+      const [vault] = anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("vault"), drained.toBuffer()],
+        program.programId
+      );
+
+      try {
+        await program.methods
+        .reconcile()
+        .accountsPartial({ user: other.publicKey, vault, vaultState: drained, registry: null })
+        .signers([other])
+        .rpc();
+        assert.fail("reconcile should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "Unauthorized");
+      }
+    });
+
+    it("Closes a crafted state left behind with an empty vault", async () => {
+      const sig = await provider.connection.requestAirdrop(legacyOwner.publicKey, anchor.web3.LAMPORTS_PER_SOL);
+      await provider.connection.confirmTransaction(sig, "confirmed");
+      const [vault] = anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("vault"), drained.toBuffer()],
+        program.programId
+      );
+      assert.isNull(await provider.connection.getAccountInfo(vault));
+      const stateRent = await provider.connection.getBalance(drained);
+      const ownerBefore = await provider.connection.getBalance(legacyOwner.publicKey);
+
+      await program.methods
+      .reconcile()
+      .accountsPartial({ user: legacyOwner.publicKey, vault, vaultState: drained, registry: null })
+      .signers([legacyOwner])
+      .rpc();
+
+      assert.isNull(await provider.connection.getAccountInfo(drained));
+      assert.equal(await provider.connection.getBalance(legacyOwner.publicKey), ownerBefore + stateRent);
+    });
+
+    it("Clears the registry bit of a drained vault", async () => {
+      const { user, pdas } = await drainedVault();
+
+      await program.methods
+      .reconcile()
+      .accountsPartial({ user: user.publicKey, ...pdas })
+      .signers([user])
+      .rpc();
+
+      assert.isNull(await provider.connection.getAccountInfo(pdas.vaultState));
+      const registry = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("registry"), user.publicKey.toBytes()], program.programId)[0];
+      assert.deepEqual(Array.from(await program.methods.listVaults().accountsPartial({ registry }).view()), []);
+    });
+  });
+
+  describe("validation hook", () => {
+    const sol = anchor.web3.LAMPORTS_PER_SOL;
+    // the mock approves sends up to 1 SOL and rejects anything larger
+    const hook = (anchor.workspace.MockReward as Program<MockReward>).programId;
+
+    const hookedVault = async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const recipient = anchor.web3.Keypair.generate().publicKey;
+      await program.methods
+      .deposit(new anchor.BN(3 * sol))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+      await program.methods
+      .setInlineAllowlist([recipient])
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      await program.methods
+      .setValidationHook(hook)
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+      return { user, pdas, recipient };
+    };
+
+    const withdrawTo = (user: anchor.web3.Keypair, pdas: ReturnType<typeof vaultPdas>, recipient: anchor.web3.PublicKey, amount: number, validationHook = hook) =>
+      program.methods
+      .withdrawTo(new anchor.BN(amount))
+      .accountsPartial({ user: user.publicKey, ...pdas, recipient, allowlistEntry: null, validationHook })
+      .signers([user])
+      .rpc();
+
+    it("Sends when the hook approves", async () => {
+      const { user, pdas, recipient } = await hookedVault();
+      await withdrawTo(user, pdas, recipient, 0.5 * sol);
+      assert.equal(await provider.connection.getBalance(recipient), 0.5 * sol);
+    });
+
+    it("Aborts the send when the hook rejects", async () => {
+      const { user, pdas, recipient } = await hookedVault();
+      try {
+        await withdrawTo(user, pdas, recipient, 2 * sol);
+        assert.fail("the hook should have rejected the send");
+      } catch (e) {
+        assert.include(e.toString(), "TransferRejected");
+      }
+      assert.equal(await provider.connection.getBalance(recipient), 0);
+    });
+
+    it("Requires the configured hook program", async () => {
+      const { user, pdas, recipient } = await hookedVault();
+      try {
+        await withdrawTo(user, pdas, recipient, 0.5 * sol, null);
+        assert.fail("a send without the hook should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "ValidationHookMissing");
+      }
+      try {
+        await withdrawTo(user, pdas, recipient, 0.5 * sol, anchor.web3.SystemProgram.programId);
+        assert.fail("a send through another program should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "ValidationHookMismatch");
+      }
+    });
+
+    it("Stops checking once the hook is removed", async () => {
+      const { user, pdas, recipient } = await hookedVault();
+      await program.methods
+      .setValidationHook(null)
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState })
+      .signers([user])
+      .rpc();
+
+      await withdrawTo(user, pdas, recipient, 2 * sol, null);
+      assert.equal(await provider.connection.getBalance(recipient), 2 * sol);
+    });
+  });
+
+  describe("convert to token vault", () => {
+    const convert = async (user: anchor.web3.Keypair, pdas: ReturnType<typeof vaultPdas>) => {
+      const mint = await createMint(provider.connection, user, user.publicKey, null, 6);
+      const tokenVault = anchor.web3.PublicKey.findProgramAddressSync([Buffer.from("token_vault"), pdas.vaultState.toBytes()], program.programId)[0];
+      await program.methods
+      .convertToTokenVault()
+      .accountsPartial({ user: user.publicKey, ...pdas, mint, tokenVault })
+      .signers([user])
+      .rpc();
+      return { mint, tokenVault };
+    };
+
+    it("Converts an empty vault in place and then takes token deposits", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      const before = await program.account.vaultState.fetch(pdas.vaultState);
+
+      const { mint, tokenVault } = await convert(user, pdas);
+      const after = await program.account.vaultState.fetch(pdas.vaultState);
+      assert.isTrue(after.tokenMint.equals(mint));
+      assert.equal(after.createdAt.toNumber(), before.createdAt.toNumber());
+
+      const source = await createAccount(provider.connection, user, mint, user.publicKey, anchor.web3.Keypair.generate());
+      await mintTo(provider.connection, user, mint, source, user, 2_000_000);
+      await program.methods
+      .depositFromSwap()
+      .accountsPartial({ user: user.publicKey, vaultState: pdas.vaultState, mint, tokenVault, swapOutput: source })
+      .signers([user])
+      .rpc();
+      assert.equal((await getAccount(provider.connection, tokenVault)).amount, BigInt(2_000_000));
+    });
+
+    it("Rejects converting a vault that still holds SOL", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      await program.methods
+      .deposit(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+      .accountsPartial({ user: user.publicKey, ...pdas, systemProgram: anchor.web3.SystemProgram.programId })
+      .signers([user])
+      .rpc();
+
+      try {
+        await convert(user, pdas);
+        assert.fail("conversion should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "VaultNotEmpty");
+      }
+    });
+
+    it("Rejects converting a vault that already has a token account", async () => {
+      const user = await newUser();
+      const pdas = await initializeVault(user);
+      await initTokenVault(user, pdas);
+
+      try {
+        await convert(user, pdas);
+        assert.fail("conversion should have been rejected");
+      } catch (e) {
+        assert.include(e.toString(), "TokenVaultExists");
+      }
+    });
+  });
+});